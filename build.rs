@@ -0,0 +1,104 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// テストでつなぐディスクイメージ(IDEのスレーブとvirtio-blk)と、その大きさ
+const TEST_DISKS: &[(&str, u64)] = &[
+    ("target/test-disk.img", 1024 * 1024),
+    ("target/test-virtio.img", 1024 * 1024),
+];
+
+// カーネルに埋め込むinitramfsの中身
+const INITRAMFS_DIR: &str = "initramfs";
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed={}", INITRAMFS_DIR);
+    // src/cmdline.rsが埋め込むカーネルのコマンドライン
+    println!("cargo:rerun-if-env-changed=KERNEL_CMDLINE");
+
+    for &(disk, size) in TEST_DISKS {
+        let path = Path::new(disk);
+        if !path.exists() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path).unwrap().set_len(size).unwrap();
+        }
+    }
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let mut archive = Vec::new();
+    write_cpio(&mut archive, Path::new(INITRAMFS_DIR)).unwrap();
+    fs::write(out_dir.join("initramfs.cpio"), archive).unwrap();
+}
+
+// ディレクトリの中身をnewc形式のcpioアーカイブにする
+fn write_cpio(archive: &mut Vec<u8>, root: &Path) -> io::Result<()> {
+    let mut entries = Vec::new();
+    if root.exists() {
+        collect(root, &mut entries)?;
+    }
+    entries.sort();
+    for (ino, path) in entries.iter().enumerate() {
+        println!("cargo:rerun-if-changed={}", path.display());
+        let name = path.strip_prefix(root).unwrap().to_str().unwrap();
+        let (mode, data) = if path.is_dir() {
+            (0o040755, Vec::new())
+        } else {
+            (0o100644, fs::read(path)?)
+        };
+        write_cpio_entry(archive, ino as u32 + 1, mode, name, &data)?;
+    }
+    write_cpio_entry(archive, 0, 0, "TRAILER!!!", &[])
+}
+
+fn collect(dir: &Path, entries: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        entries.push(path.clone());
+        if path.is_dir() {
+            collect(&path, entries)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_cpio_entry(
+    archive: &mut Vec<u8>,
+    ino: u32,
+    mode: u32,
+    name: &str,
+    data: &[u8],
+) -> io::Result<()> {
+    let fields = [
+        ino,
+        mode,
+        0,
+        0,
+        1,
+        0,
+        data.len() as u32,
+        0,
+        0,
+        0,
+        0,
+        name.len() as u32 + 1,
+        0,
+    ];
+    archive.write_all(b"070701")?;
+    for field in &fields {
+        write!(archive, "{:08x}", field)?;
+    }
+    archive.write_all(name.as_bytes())?;
+    archive.push(0);
+    pad4(archive);
+    archive.write_all(data)?;
+    pad4(archive);
+    Ok(())
+}
+
+fn pad4(archive: &mut Vec<u8>) {
+    while archive.len() % 4 != 0 {
+        archive.push(0);
+    }
+}
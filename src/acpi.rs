@@ -0,0 +1,110 @@
+use crate::memory::phys_to_virt;
+use x86_64::PhysAddr;
+
+// RSDPを探すBIOSの領域
+const BIOS_AREA_START: u64 = 0x000e_0000;
+const BIOS_AREA_END: u64 = 0x0010_0000;
+// EBDA(Extended BIOS Data Area)のセグメントが書かれているアドレス
+const EBDA_POINTER: u64 = 0x40e;
+
+/// ACPIのテーブルに共通のヘッダ
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+// RSDP(ACPI 2.0以降はXSDTのアドレスも持つ)
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // 以下はrevision >= 2の場合のみ
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// シグネチャが`signature`のACPIテーブルを探して、その物理アドレスを返す。
+///
+/// `memory::init`の後で呼び出すこと(物理メモリのマッピングを使ってテーブルを読むため)。
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let rsdp = find_rsdp()?;
+    // ACPI 2.0以降ならXSDT(64bitのアドレス)、そうでなければRSDT(32bitのアドレス)を使う
+    let (sdt, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (PhysAddr::new(rsdp.xsdt_address), 8)
+    } else {
+        (PhysAddr::new(u64::from(rsdp.rsdt_address)), 4)
+    };
+    let header = unsafe { read_header(sdt) };
+    let entries = (header.length as usize - core::mem::size_of::<SdtHeader>()) / entry_size;
+    let entries_start = phys_to_virt(sdt) + core::mem::size_of::<SdtHeader>();
+
+    (0..entries).find_map(|i| {
+        let ptr = (entries_start + i * entry_size).as_ptr::<u8>();
+        let table = unsafe {
+            if entry_size == 8 {
+                (ptr as *const u64).read_unaligned()
+            } else {
+                u64::from((ptr as *const u32).read_unaligned())
+            }
+        };
+        let table = PhysAddr::new(table);
+        let header = unsafe { read_header(table) };
+        if &header.signature == signature && checksum_ok(table, header.length as usize) {
+            Some(table)
+        } else {
+            None
+        }
+    })
+}
+
+/// 物理アドレス`table`にあるテーブルのヘッダを読む。
+///
+/// # Safety
+/// `table`はACPIのテーブルを指していなければならない。
+pub unsafe fn read_header(table: PhysAddr) -> SdtHeader {
+    phys_to_virt(table).as_ptr::<SdtHeader>().read_unaligned()
+}
+
+// EBDAの先頭1KiBとBIOSの領域から、16バイト境界にあるRSDPを探す
+fn find_rsdp() -> Option<Rsdp> {
+    let ebda = u64::from(unsafe {
+        phys_to_virt(PhysAddr::new(EBDA_POINTER))
+            .as_ptr::<u16>()
+            .read_unaligned()
+    }) << 4;
+    let ebda_area = (ebda..ebda + 1024).step_by(16);
+    let bios_area = (BIOS_AREA_START..BIOS_AREA_END).step_by(16);
+
+    ebda_area.chain(bios_area).find_map(|addr| {
+        let phys = PhysAddr::new(addr);
+        let rsdp = unsafe { phys_to_virt(phys).as_ptr::<Rsdp>().read_unaligned() };
+        // revision 0のRSDPは先頭20バイトだけがチェックサムの対象
+        if &rsdp.signature == b"RSD PTR " && checksum_ok(phys, 20) {
+            Some(rsdp)
+        } else {
+            None
+        }
+    })
+}
+
+// 全バイトの和が0になっているか
+fn checksum_ok(phys: PhysAddr, len: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(phys_to_virt(phys).as_ptr::<u8>(), len) };
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
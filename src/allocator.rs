@@ -1,8 +1,7 @@
-
-
+use crate::memory::huge::{map_range, FrameAllocatorAllSizes};
 use linked_list_allocator::LockedHeap;
-use x86_64::structures::paging::mapper::MapToError;
-use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::structures::paging::mapper::{MapToError, MapperAllSizes};
+use x86_64::structures::paging::{PageTableFlags, Size4KiB};
 use x86_64::VirtAddr;
 
 #[global_allocator]
@@ -12,33 +11,23 @@ pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024;
 
 /// mapperとframe_allocatorを引数にとる
+/// アラインメントが許せば2MiB/1GiBページでマップする(今のHEAP_SIZEは2MiBより小さいので4KiBページになる)
 pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    mapper: &mut impl MapperAllSizes,
+    frame_allocator: &mut impl FrameAllocatorAllSizes,
 ) -> Result<(), MapToError<Size4KiB>> {
-    //
-    let page_range = {
-        // Heapの開始アドレスを仮想アドレスに変更
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        // Heapの終端アドレス(端が含まれてほしいので1を引く)
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        // 指定したページ範囲の作成
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        // ページのマップされるフレームを取得する
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        // Flag準備
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        // ページテーブルに新たに対応を作る
-        // 最後にflushを呼ぶことでTLB(変換内容のキャッシュ)を更新する
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
-    }
+    // Flag準備
+    // ヒープ上のデータが実行されないようにNO_EXECUTEを立てる
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    // Heapの範囲をマップする
+    // map_rangeの中でページごとにflushを呼んでTLB(変換内容のキャッシュ)を更新する
+    map_range(
+        mapper,
+        frame_allocator,
+        VirtAddr::new(HEAP_START as u64),
+        HEAP_SIZE as u64,
+        flags,
+    )?;
 
     // Allocatorの初期化
     unsafe {
@@ -0,0 +1,361 @@
+use super::{check_request, BlockDevice, BlockError, BlockFuture};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
+
+/// ATAのセクタサイズ
+pub const SECTOR_SIZE: usize = 512;
+
+// プライマリチャネルのI/Oポート
+const PRIMARY_IO_BASE: u16 = 0x1f0;
+const PRIMARY_CONTROL: u16 = 0x3f6;
+
+// コマンド
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_READ_SECTORS_EXT: u8 = 0x24;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_WRITE_SECTORS_EXT: u8 = 0x34;
+const COMMAND_CACHE_FLUSH: u8 = 0xe7;
+const COMMAND_CACHE_FLUSH_EXT: u8 = 0xea;
+const COMMAND_IDENTIFY: u8 = 0xec;
+
+// ステータスレジスタのビット
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+// デバイスコントロールレジスタのビット、割り込みを使わずポーリングするのでnIENを立てる
+const CONTROL_NIEN: u8 = 1 << 1;
+
+// ステータスをポーリングする回数の上限
+const POLL_LIMIT: usize = 1_000_000;
+
+// 一度のコマンドで転送するセクタ数の上限(4KiB)
+// コマンドの間は割り込みを止めてチャネルをロックするので、大きな要求は分けて発行する
+const MAX_SECTORS_PER_COMMAND: u64 = 8;
+
+/// チャネルにつながっている2台のドライブのどちらか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+    Master,
+    Slave,
+}
+
+impl Drive {
+    // ドライブ選択レジスタに書くビット(bit4)
+    fn select_bit(self) -> u8 {
+        match self {
+            Drive::Master => 0x00,
+            Drive::Slave => 0x10,
+        }
+    }
+}
+
+/// IDEチャネルのレジスタ
+pub struct Channel {
+    data: Port<u16>,
+    error: PortReadOnly<u8>,
+    sector_count: Port<u8>,
+    lba_low: Port<u8>,
+    lba_mid: Port<u8>,
+    lba_high: Port<u8>,
+    drive_select: Port<u8>,
+    status: PortReadOnly<u8>,
+    command: PortWriteOnly<u8>,
+    alternate_status: PortReadOnly<u8>,
+    control: PortWriteOnly<u8>,
+}
+
+/// プライマリチャネル
+/// 同じチャネルの2台のドライブはレジスタを共有するので、ロックして使う
+pub static PRIMARY: Mutex<Channel> = Mutex::new(Channel::new(PRIMARY_IO_BASE, PRIMARY_CONTROL));
+
+impl Channel {
+    pub const fn new(io_base: u16, control: u16) -> Channel {
+        Channel {
+            data: Port::new(io_base),
+            error: PortReadOnly::new(io_base + 1),
+            sector_count: Port::new(io_base + 2),
+            lba_low: Port::new(io_base + 3),
+            lba_mid: Port::new(io_base + 4),
+            lba_high: Port::new(io_base + 5),
+            drive_select: Port::new(io_base + 6),
+            status: PortReadOnly::new(io_base + 7),
+            command: PortWriteOnly::new(io_base + 7),
+            alternate_status: PortReadOnly::new(control),
+            control: PortWriteOnly::new(control),
+        }
+    }
+
+    // ドライブを選択する
+    // 選択後はステータスが安定するまで400ns待つ必要があるので、代替ステータスを4回読む
+    unsafe fn select(&mut self, drive: Drive, lba_high_bits: u8) {
+        // bit7,5は常に1、bit6はLBAモード
+        self.drive_select
+            .write(0xe0 | drive.select_bit() | (lba_high_bits & 0x0f));
+        for _ in 0..4 {
+            self.alternate_status.read();
+        }
+    }
+
+    // BSYが消えるのを待つ
+    unsafe fn wait_not_busy(&mut self) -> Result<u8, BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.status.read();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+        }
+        Err(BlockError::Timeout)
+    }
+
+    // データの転送準備(DRQ)ができるのを待つ
+    unsafe fn wait_data_ready(&mut self) -> Result<(), BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.status.read();
+            if status & STATUS_BSY != 0 {
+                continue;
+            }
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err(BlockError::DeviceError(self.error.read()));
+            }
+            if status & STATUS_DRQ != 0 {
+                return Ok(());
+            }
+        }
+        Err(BlockError::Timeout)
+    }
+
+    // LBAとセクタ数を設定してコマンドを送る
+    // LBA48では上位バイトを先に書く
+    unsafe fn issue(&mut self, drive: Drive, lba: u64, count: u16, lba48: bool, command: u8) {
+        if lba48 {
+            self.select(drive, 0);
+            self.sector_count.write((count >> 8) as u8);
+            self.lba_low.write((lba >> 24) as u8);
+            self.lba_mid.write((lba >> 32) as u8);
+            self.lba_high.write((lba >> 40) as u8);
+        } else {
+            self.select(drive, (lba >> 24) as u8);
+        }
+        self.sector_count.write(count as u8);
+        self.lba_low.write(lba as u8);
+        self.lba_mid.write((lba >> 8) as u8);
+        self.lba_high.write((lba >> 16) as u8);
+        self.command.write(command);
+    }
+
+    // IDENTIFY DEVICEを送って256ワードのデータを読む
+    // ドライブがない、またはATAPIなどATAでないデバイスの場合はNone
+    unsafe fn identify(&mut self, drive: Drive) -> Option<[u16; 256]> {
+        self.control.write(CONTROL_NIEN);
+        self.select(drive, 0);
+        self.sector_count.write(0);
+        self.lba_low.write(0);
+        self.lba_mid.write(0);
+        self.lba_high.write(0);
+        self.command.write(COMMAND_IDENTIFY);
+
+        // ステータスが0ならドライブがない
+        if self.status.read() == 0 {
+            return None;
+        }
+        self.wait_not_busy().ok()?;
+        // LBA mid/highが0でなければATAPIかSATA
+        if self.lba_mid.read() != 0 || self.lba_high.read() != 0 {
+            return None;
+        }
+        self.wait_data_ready().ok()?;
+
+        let mut data = [0u16; 256];
+        for word in data.iter_mut() {
+            *word = self.data.read();
+        }
+        Some(data)
+    }
+}
+
+/// ATA PIOでアクセスするドライブ
+pub struct AtaDrive {
+    name: String,
+    channel: &'static Mutex<Channel>,
+    drive: Drive,
+    sectors: u64,
+    lba48: bool,
+    model: String,
+}
+
+impl AtaDrive {
+    /// ドライブを識別する。ドライブがなければNone
+    pub fn identify(
+        name: String,
+        channel: &'static Mutex<Channel>,
+        drive: Drive,
+    ) -> Option<AtaDrive> {
+        let data = x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            channel.lock().identify(drive)
+        })?;
+
+        // word 83のbit10が立っていればLBA48に対応している
+        let lba48 = data[83] & (1 << 10) != 0;
+        let sectors = if lba48 {
+            u64::from(data[100])
+                | u64::from(data[101]) << 16
+                | u64::from(data[102]) << 32
+                | u64::from(data[103]) << 48
+        } else {
+            u64::from(data[60]) | u64::from(data[61]) << 16
+        };
+        // モデル名はword 27-46で、各ワードの上位バイトが先
+        let model: String = data[27..47]
+            .iter()
+            .flat_map(|word| word.to_be_bytes().to_vec())
+            .map(char::from)
+            .collect();
+
+        Some(AtaDrive {
+            name,
+            channel,
+            drive,
+            sectors,
+            lba48,
+            model: String::from(model.trim()),
+        })
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn drive(&self) -> Drive {
+        self.drive
+    }
+
+    fn read_sync(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let count = check_request(self, lba, buf.len())?;
+        let mut done = 0;
+        while done < count {
+            let n = (count - done).min(MAX_SECTORS_PER_COMMAND);
+            let chunk = &mut buf[done as usize * SECTOR_SIZE..(done + n) as usize * SECTOR_SIZE];
+            self.transfer(lba + done, n, false, |channel| unsafe {
+                for sector in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                    channel.wait_data_ready()?;
+                    for bytes in sector.chunks_exact_mut(2) {
+                        bytes.copy_from_slice(&channel.data.read().to_le_bytes());
+                    }
+                }
+                Ok(())
+            })?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn write_sync(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let count = check_request(self, lba, buf.len())?;
+        let mut done = 0;
+        while done < count {
+            let n = (count - done).min(MAX_SECTORS_PER_COMMAND);
+            let chunk = &buf[done as usize * SECTOR_SIZE..(done + n) as usize * SECTOR_SIZE];
+            self.transfer(lba + done, n, true, |channel| unsafe {
+                for sector in chunk.chunks_exact(SECTOR_SIZE) {
+                    channel.wait_data_ready()?;
+                    for bytes in sector.chunks_exact(2) {
+                        channel.data.write(u16::from_le_bytes([bytes[0], bytes[1]]));
+                    }
+                }
+                Ok(())
+            })?;
+            done += n;
+        }
+        self.flush_sync()
+    }
+
+    fn flush_sync(&self) -> Result<(), BlockError> {
+        let command = if self.lba48 {
+            COMMAND_CACHE_FLUSH_EXT
+        } else {
+            COMMAND_CACHE_FLUSH
+        };
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            let mut channel = self.channel.lock();
+            channel.select(self.drive, 0);
+            channel.command.write(command);
+            let status = channel.wait_not_busy()?;
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err(BlockError::DeviceError(channel.error.read()));
+            }
+            Ok(())
+        })
+    }
+
+    // コマンドを発行して`f`でデータを転送する
+    fn transfer(
+        &self,
+        lba: u64,
+        count: u64,
+        write: bool,
+        f: impl FnOnce(&mut Channel) -> Result<(), BlockError>,
+    ) -> Result<(), BlockError> {
+        let command = match (self.lba48, write) {
+            (true, false) => COMMAND_READ_SECTORS_EXT,
+            (true, true) => COMMAND_WRITE_SECTORS_EXT,
+            (false, false) => COMMAND_READ_SECTORS,
+            (false, true) => COMMAND_WRITE_SECTORS,
+        };
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            let mut channel = self.channel.lock();
+            channel.wait_not_busy()?;
+            channel.issue(self.drive, lba, count as u16, self.lba48, command);
+            f(&mut channel)
+        })
+    }
+}
+
+// PIOではCPUがデータを転送するので、futureは最初のpollで完了する
+impl BlockDevice for AtaDrive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move { self.read_sync(lba, buf) })
+    }
+
+    fn write_blocks<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move { self.write_sync(lba, buf) })
+    }
+
+    fn flush(&self) -> BlockFuture<'_, ()> {
+        Box::pin(async move { self.flush_sync() })
+    }
+}
+
+/// プライマリチャネルのマスタとスレーブを識別し、見つかったドライブを登録する。
+///
+/// ヒープの初期化後に呼び出すこと。
+pub fn init() {
+    let mut found = Vec::new();
+    for drive in [Drive::Master, Drive::Slave].iter().copied() {
+        let name = format!("ata{}", found.len());
+        if let Some(ata) = AtaDrive::identify(name, &PRIMARY, drive) {
+            found.push(Arc::new(ata));
+        }
+    }
+    for ata in found {
+        super::register(ata);
+    }
+}
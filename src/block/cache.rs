@@ -0,0 +1,288 @@
+use super::{check_request, BlockDevice, BlockError, BlockFuture};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// キャッシュの統計
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// 追い出しやflushでデバイスに書き戻したブロックの数
+    pub writebacks: u64,
+    /// 追い出すときに書き戻しに失敗したブロックの数
+    /// 失敗したブロックはdirtyのままキャッシュに戻し、後で書き戻す
+    pub writeback_errors: u64,
+}
+
+// キャッシュされたブロック
+struct Entry {
+    data: Box<[u8]>,
+    dirty: bool,
+    // 最後に使われた時刻(LRUで追い出すブロックを選ぶのに使う)
+    last_used: u64,
+}
+
+struct CacheState {
+    entries: BTreeMap<u64, Entry>,
+    // 追い出されて書き戻し中のブロック(書き戻しが終わるまではここから読む)
+    writing: BTreeMap<u64, Arc<[u8]>>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+/// ブロックデバイスをブロック単位でキャッシュするバッファキャッシュ
+///
+/// 書き込みはキャッシュにだけ行い(write-back)、ブロックが追い出されるときか
+/// `flush`のときにデバイスに書き戻す。キャッシュ自体もBlockDeviceとして使える。
+pub struct BufferCache {
+    device: Arc<dyn BlockDevice>,
+    capacity: usize,
+    // spinlockなので、デバイスの読み書きをawaitしている間は保持しない
+    state: Mutex<CacheState>,
+}
+
+impl BufferCache {
+    /// 最大`capacity`ブロックをキャッシュする。
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> BufferCache {
+        assert!(capacity > 0, "キャッシュの容量は1ブロック以上必要です");
+        BufferCache {
+            device,
+            capacity,
+            state: Mutex::new(CacheState {
+                entries: BTreeMap::new(),
+                writing: BTreeMap::new(),
+                clock: 0,
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    /// キャッシュしているデバイス
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().stats
+    }
+
+    /// キャッシュしているブロックの数
+    pub fn cached_blocks(&self) -> usize {
+        self.state.lock().entries.len()
+    }
+
+    /// まだ書き戻していないブロックの数
+    pub fn dirty_blocks(&self) -> usize {
+        let state = self.state.lock();
+        state.entries.values().filter(|entry| entry.dirty).count()
+    }
+
+    // 1ブロックを読む
+    async fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        if self.lookup(lba, |data| buf.copy_from_slice(data)) {
+            return Ok(());
+        }
+        let mut data = vec![0; self.device.block_size()].into_boxed_slice();
+        self.device.read_blocks(lba, &mut data).await?;
+        buf.copy_from_slice(&data);
+        self.insert(lba, data, false).await
+    }
+
+    // 1ブロックを書く
+    async fn write_block(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        {
+            let mut state = self.state.lock();
+            state.clock += 1;
+            let clock = state.clock;
+            if let Some(entry) = state.entries.get_mut(&lba) {
+                entry.data.copy_from_slice(buf);
+                entry.dirty = true;
+                entry.last_used = clock;
+                state.stats.hits += 1;
+                return Ok(());
+            }
+            state.stats.misses += 1;
+        }
+        // ブロック全体を書くので、デバイスから読む必要はない
+        self.insert(lba, buf.into(), true).await
+    }
+
+    // キャッシュにあれば`f`にデータを渡してtrueを返す
+    fn lookup(&self, lba: u64, f: impl FnOnce(&[u8])) -> bool {
+        let mut state = self.state.lock();
+        state.clock += 1;
+        let clock = state.clock;
+        if let Some(entry) = state.entries.get_mut(&lba) {
+            entry.last_used = clock;
+            f(&entry.data);
+            state.stats.hits += 1;
+            return true;
+        }
+        if let Some(data) = state.writing.get(&lba) {
+            f(data);
+            state.stats.hits += 1;
+            return true;
+        }
+        state.stats.misses += 1;
+        false
+    }
+
+    // ブロックをキャッシュに入れる。容量を超えたら最も長く使われていないブロックを追い出す
+    async fn insert(&self, lba: u64, data: Box<[u8]>, dirty: bool) -> Result<(), BlockError> {
+        let evicted = {
+            let mut state = self.state.lock();
+            let clock = state.clock;
+            if dirty {
+                // 書き戻し中の古いデータは使わない
+                state.writing.remove(&lba);
+            }
+            match state.entries.get_mut(&lba) {
+                // デバイスを読んでいる間に他のタスクが入れていれば、そちらを優先する
+                Some(entry) if !dirty => {
+                    entry.last_used = clock;
+                    return Ok(());
+                }
+                Some(entry) => {
+                    entry.data = data;
+                    entry.dirty = true;
+                    entry.last_used = clock;
+                    return Ok(());
+                }
+                None => {}
+            }
+            state.entries.insert(
+                lba,
+                Entry {
+                    data,
+                    dirty,
+                    last_used: clock,
+                },
+            );
+            if state.entries.len() > self.capacity {
+                self.evict(&mut state)
+            } else {
+                None
+            }
+        };
+
+        if let Some((lba, data)) = evicted {
+            let result = self.device.write_blocks(lba, &data).await;
+            let mut state = self.state.lock();
+            // 書き戻し中に新しく書かれていなければ取り除く
+            let current = state
+                .writing
+                .get(&lba)
+                .map_or(false, |writing| Arc::ptr_eq(writing, &data));
+            if current {
+                state.writing.remove(&lba);
+            }
+            match result {
+                Ok(()) => state.stats.writebacks += 1,
+                // 呼び出し元のブロックとは関係ないので、エラーは返さずにdirtyのままキャッシュに戻す
+                // (容量を一時的に超えるが、次に入れるときに追い出される)
+                Err(_) => {
+                    state.stats.writeback_errors += 1;
+                    if current {
+                        state.clock += 1;
+                        let last_used = state.clock;
+                        state.entries.insert(
+                            lba,
+                            Entry {
+                                data: Box::from(&*data),
+                                dirty: true,
+                                last_used,
+                            },
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // LRUのブロックを取り除き、dirtyなら書き戻すデータを返す
+    fn evict(&self, state: &mut CacheState) -> Option<(u64, Arc<[u8]>)> {
+        let (&lba, _) = state
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)?;
+        let entry = state.entries.remove(&lba)?;
+        if !entry.dirty {
+            return None;
+        }
+        let data: Arc<[u8]> = Arc::from(entry.data);
+        state.writing.insert(lba, data.clone());
+        Some((lba, data))
+    }
+
+    /// dirtyなブロックを全てデバイスに書き戻し、デバイスのflushを呼ぶ。
+    ///
+    /// 書き込みに失敗したら、そのブロックとまだ書いていないブロックはdirtyのまま残る。
+    pub async fn sync(&self) -> Result<(), BlockError> {
+        let dirty: Vec<(u64, Vec<u8>)> = {
+            let state = self.state.lock();
+            state
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.dirty)
+                .map(|(&lba, entry)| (lba, entry.data.to_vec()))
+                .collect()
+        };
+        for (lba, data) in &dirty {
+            self.device.write_blocks(*lba, data).await?;
+            // 書いている間に追い出されたり書き換えられたりしていなければcleanにする
+            let mut state = self.state.lock();
+            if let Some(entry) = state.entries.get_mut(lba) {
+                if *entry.data == **data {
+                    entry.dirty = false;
+                }
+            }
+            state.stats.writebacks += 1;
+        }
+        self.device.flush().await
+    }
+}
+
+impl BlockDevice for BufferCache {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            check_request(self, lba, buf.len())?;
+            let block_size = self.block_size();
+            for (i, block) in buf.chunks_exact_mut(block_size).enumerate() {
+                self.read_block(lba + i as u64, block).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            check_request(self, lba, buf.len())?;
+            let block_size = self.block_size();
+            for (i, block) in buf.chunks_exact(block_size).enumerate() {
+                self.write_block(lba + i as u64, block).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_, ()> {
+        Box::pin(self.sync())
+    }
+}
@@ -0,0 +1,109 @@
+use crate::println;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use spin::Mutex;
+
+pub mod ata;
+pub mod cache;
+pub mod partition;
+pub mod ramdisk;
+pub mod virtio;
+
+/// ブロックデバイスの操作が返すfuture
+/// BlockDeviceをtrait objectとして扱えるように、Boxに入れて返す
+pub type BlockFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, BlockError>> + 'a>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// デバイスの範囲外のブロックを指定した
+    OutOfRange,
+    /// バッファの長さがブロックサイズの倍数でない
+    InvalidBufferSize,
+    /// デバイスがエラーを返した(値はデバイス固有のエラーコード)
+    DeviceError(u8),
+    /// デバイスが応答しなかった
+    Timeout,
+    /// 書き込みできないデバイス
+    ReadOnly,
+}
+
+/// セクタ(ブロック)単位で読み書きするデバイス
+pub trait BlockDevice: Send + Sync {
+    /// `lsblk`などで表示する名前
+    fn name(&self) -> &str;
+
+    /// 1ブロックのバイト数
+    fn block_size(&self) -> usize;
+
+    /// ブロックの数
+    fn block_count(&self) -> u64;
+
+    /// `lba`から`buf.len() / block_size()`ブロックを読み込む。
+    fn read_blocks<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a, ()>;
+
+    /// `lba`から`buf.len() / block_size()`ブロックを書き込む。
+    fn write_blocks<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a, ()>;
+
+    /// デバイスのキャッシュを書き出す。
+    fn flush(&self) -> BlockFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// 読み書きの範囲とバッファの長さを確認し、ブロック数を返す。
+/// BlockDeviceの実装から使う
+pub fn check_request(device: &dyn BlockDevice, lba: u64, len: usize) -> Result<u64, BlockError> {
+    let block_size = device.block_size();
+    if len % block_size != 0 {
+        return Err(BlockError::InvalidBufferSize);
+    }
+    let count = (len / block_size) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+// 見つかったブロックデバイス
+static DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+
+/// ブロックデバイスを登録する。
+pub fn register(device: Arc<dyn BlockDevice>) {
+    println!(
+        "block: {} ({} blocks of {} bytes)",
+        device.name(),
+        device.block_count(),
+        device.block_size()
+    );
+    DEVICES.lock().push(device);
+}
+
+/// 登録されているブロックデバイスの一覧を返す。
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock().clone()
+}
+
+/// 名前でブロックデバイスを探す。
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
+
+/// 登録されているブロックデバイスの一覧を表示する。
+pub fn print_devices() {
+    for device in DEVICES.lock().iter() {
+        let bytes = device.block_count() * device.block_size() as u64;
+        println!(
+            "{:<10} {:>10} blocks {:>6} KiB",
+            device.name(),
+            device.block_count(),
+            bytes / 1024
+        );
+    }
+}
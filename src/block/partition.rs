@@ -0,0 +1,340 @@
+use super::{check_request, BlockDevice, BlockError, BlockFuture};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt;
+
+// MBRのパーティションテーブルの位置とブートシグネチャ
+const MBR_PARTITION_TABLE: usize = 0x1be;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+// GPTの保護MBRのパーティションタイプ
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+// 拡張パーティション(論理パーティションは扱わない)
+const MBR_TYPE_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+// パーティションエントリの数の上限(壊れたヘッダで巨大な確保をしないように)
+const GPT_MAX_ENTRIES: u32 = 1024;
+
+/// パーティションテーブルの種類ごとのパーティションの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// MBRのパーティションタイプ
+    Mbr(u8),
+    /// GPTのパーティションタイプGUID
+    Gpt(Guid),
+}
+
+/// GPTで使うGUID(ディスク上のバイト列のまま持つ)
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// EFIシステムパーティション
+    pub const EFI_SYSTEM: Guid = Guid([
+        0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9,
+        0x3b,
+    ]);
+    /// Linuxのファイルシステム
+    pub const LINUX_FILESYSTEM: Guid = Guid([
+        0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d,
+        0xe4,
+    ]);
+    /// Microsoftの基本データパーティション(FATなど)
+    pub const MICROSOFT_BASIC_DATA: Guid = Guid([
+        0xa2, 0xa0, 0xd0, 0xeb, 0xe5, 0xb9, 0x33, 0x44, 0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99,
+        0xc7,
+    ]);
+
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|&b| b == 0)
+    }
+}
+
+// 先頭3つのフィールドはリトルエンディアンで格納されている
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9]
+        )?;
+        for byte in &b[10..] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Guid({})", self)
+    }
+}
+
+/// ディスクの一部をブロックデバイスとして見せるパーティション
+pub struct Partition {
+    name: String,
+    device: Arc<dyn BlockDevice>,
+    /// 1から始まるパーティション番号
+    pub number: usize,
+    /// ディスク上の先頭のブロック
+    pub start: u64,
+    /// ブロック数
+    pub count: u64,
+    pub kind: PartitionKind,
+    /// GPTのパーティション名(MBRでは空)
+    pub label: String,
+}
+
+impl Partition {
+    /// パーティションがあるディスク
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.count
+    }
+
+    fn read_blocks<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            check_request(self, lba, buf.len())?;
+            self.device.read_blocks(self.start + lba, buf).await
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            check_request(self, lba, buf.len())?;
+            self.device.write_blocks(self.start + lba, buf).await
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_, ()> {
+        self.device.flush()
+    }
+}
+
+/// ディスクのパーティションテーブル(GPTまたはMBR)を読み、パーティションの一覧を返す。
+/// パーティションテーブルがなければ空のVecを返す
+pub async fn scan(device: Arc<dyn BlockDevice>) -> Result<Vec<Partition>, BlockError> {
+    let block_size = device.block_size();
+    if block_size < 512 || device.block_count() < 2 {
+        return Ok(Vec::new());
+    }
+    let mut mbr = vec![0; block_size];
+    device.read_blocks(0, &mut mbr).await?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+
+    let entries: Vec<MbrEntry> = (0..4)
+        .map(|i| MbrEntry::parse(&mbr[MBR_PARTITION_TABLE + i * 16..][..16]))
+        .collect();
+    let partitions = if entries
+        .iter()
+        .any(|entry| entry.status == 0x00 && entry.kind == MBR_TYPE_GPT_PROTECTIVE)
+    {
+        scan_gpt(&device).await?
+    } else {
+        entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.is_valid())
+            .map(|(i, entry)| TableEntry {
+                number: i + 1,
+                start: u64::from(entry.start),
+                count: u64::from(entry.count),
+                kind: PartitionKind::Mbr(entry.kind),
+                label: String::new(),
+            })
+            .collect()
+    };
+
+    let disk_blocks = device.block_count();
+    Ok(partitions
+        .into_iter()
+        // ディスクからはみ出しているパーティションは無視する
+        .filter(|entry| {
+            entry
+                .start
+                .checked_add(entry.count)
+                .map_or(false, |end| end <= disk_blocks)
+        })
+        .map(|entry| Partition {
+            name: partition_name(device.name(), entry.number),
+            device: device.clone(),
+            number: entry.number,
+            start: entry.start,
+            count: entry.count,
+            kind: entry.kind,
+            label: entry.label,
+        })
+        .collect())
+}
+
+/// 登録されている全てのディスクのパーティションを探して登録する。
+pub async fn register_partitions() {
+    for device in super::devices() {
+        match scan(device.clone()).await {
+            Ok(partitions) => {
+                for partition in partitions {
+                    super::register(Arc::new(partition));
+                }
+            }
+            Err(err) => crate::println!(
+                "block: failed to read partition table of {}: {:?}",
+                device.name(),
+                err
+            ),
+        }
+    }
+}
+
+// Linuxと同じく、名前が数字で終わっていれば"p"を挟む(vda -> vda1, nvme0n1 -> nvme0n1p1)
+fn partition_name(disk: &str, number: usize) -> String {
+    if disk.ends_with(|c: char| c.is_ascii_digit()) {
+        format!("{}p{}", disk, number)
+    } else {
+        format!("{}{}", disk, number)
+    }
+}
+
+// パーティションテーブルから読んだエントリ
+struct TableEntry {
+    number: usize,
+    start: u64,
+    count: u64,
+    kind: PartitionKind,
+    label: String,
+}
+
+struct MbrEntry {
+    status: u8,
+    kind: u8,
+    start: u32,
+    count: u32,
+}
+
+impl MbrEntry {
+    fn parse(entry: &[u8]) -> MbrEntry {
+        MbrEntry {
+            status: entry[0],
+            kind: entry[4],
+            start: read_u32(entry, 8),
+            count: read_u32(entry, 12),
+        }
+    }
+
+    // FATのブートセクタもシグネチャが同じなので、ステータスが0x00か0x80のものだけを見る
+    fn is_valid(&self) -> bool {
+        (self.status == 0x00 || self.status == 0x80)
+            && self.kind != 0
+            && self.count != 0
+            && !MBR_TYPE_EXTENDED.contains(&self.kind)
+    }
+}
+
+// GPTのヘッダ(LBA 1)とパーティションエントリを読む
+async fn scan_gpt(device: &Arc<dyn BlockDevice>) -> Result<Vec<TableEntry>, BlockError> {
+    let block_size = device.block_size();
+    let mut header = vec![0; block_size];
+    device.read_blocks(1, &mut header).await?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return Ok(Vec::new());
+    }
+    let header_size = read_u32(&header, 12) as usize;
+    if header_size < 92 || header_size > block_size {
+        return Ok(Vec::new());
+    }
+    // ヘッダのCRC32はフィールド自体を0にして計算する
+    let header_crc = read_u32(&header, 16);
+    let mut crc_input = header[..header_size].to_vec();
+    crc_input[16..20].fill(0);
+    if crc32(&crc_input) != header_crc {
+        return Ok(Vec::new());
+    }
+
+    let entries_lba = read_u64(&header, 72);
+    let entry_count = read_u32(&header, 80);
+    let entry_size = read_u32(&header, 84) as usize;
+    let entries_crc = read_u32(&header, 88);
+    if entry_count > GPT_MAX_ENTRIES || entry_size < 128 || entry_size % 8 != 0 {
+        return Ok(Vec::new());
+    }
+    let entries_len = entry_count as usize * entry_size;
+    let blocks = (entries_len + block_size - 1) / block_size;
+    let mut entries = vec![0; blocks * block_size];
+    device.read_blocks(entries_lba, &mut entries).await?;
+    let entries = &entries[..entries_len];
+    if crc32(entries) != entries_crc {
+        return Ok(Vec::new());
+    }
+
+    Ok(entries
+        .chunks_exact(entry_size)
+        .enumerate()
+        .filter_map(|(i, entry)| {
+            let kind = Guid(entry[0..16].try_into().unwrap());
+            if kind.is_zero() {
+                return None;
+            }
+            let first = read_u64(entry, 32);
+            let last = read_u64(entry, 40);
+            if last < first {
+                return None;
+            }
+            // 名前はUTF-16LEで、0で終わる
+            let name: Vec<u16> = entry[56..128]
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .take_while(|&c| c != 0)
+                .collect();
+            let label = String::from_utf16_lossy(&name);
+            Some(TableEntry {
+                number: i + 1,
+                start: first,
+                count: last - first + 1,
+                kind: PartitionKind::Gpt(kind),
+                label,
+            })
+        })
+        .collect())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// GPTで使うCRC32(IEEE 802.3、多項式0xEDB88320)
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
@@ -0,0 +1,68 @@
+use super::{check_request, BlockDevice, BlockError, BlockFuture};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// ヒープ上のメモリをディスクとして使うブロックデバイス
+/// テストや、イメージをメモリに展開して使う場合に使う
+pub struct RamDisk {
+    name: String,
+    block_size: usize,
+    data: Mutex<Vec<u8>>,
+}
+
+impl RamDisk {
+    /// 0で埋めた`block_count`ブロックのディスクを作る。
+    pub fn new(name: &str, block_size: usize, block_count: u64) -> RamDisk {
+        RamDisk::from_bytes(name, block_size, vec![0; block_size * block_count as usize])
+    }
+
+    /// `data`を中身とするディスクを作る。長さはブロックサイズの倍数でなければならない
+    pub fn from_bytes(name: &str, block_size: usize, data: Vec<u8>) -> RamDisk {
+        assert!(block_size > 0 && data.len() % block_size == 0);
+        RamDisk {
+            name: String::from(name),
+            block_size,
+            data: Mutex::new(data),
+        }
+    }
+
+    fn range(&self, lba: u64, len: usize) -> Result<core::ops::Range<usize>, BlockError> {
+        check_request(self, lba, len)?;
+        let start = lba as usize * self.block_size;
+        Ok(start..start + len)
+    }
+}
+
+// メモリのコピーで終わるので、futureは最初のpollで完了する
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.lock().len() / self.block_size) as u64
+    }
+
+    fn read_blocks<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            let range = self.range(lba, buf.len())?;
+            buf.copy_from_slice(&self.data.lock()[range]);
+            Ok(())
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            let range = self.range(lba, buf.len())?;
+            self.data.lock()[range].copy_from_slice(buf);
+            Ok(())
+        })
+    }
+}
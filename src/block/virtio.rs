@@ -0,0 +1,372 @@
+use super::{check_request, BlockDevice, BlockError, BlockFuture};
+use crate::interrupts;
+use crate::pci::{DeviceId, PciDevice, PciDriver};
+use crate::virtio::queue::{dma_buffers, Buffer, VirtQueue};
+use crate::virtio::{self, VirtioPci};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use futures_util::future::poll_fn;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{PageSize, Size4KiB};
+use x86_64::VirtAddr;
+
+/// virtio-blkのセクタサイズ(容量は常にこの単位)
+pub const SECTOR_SIZE: usize = 512;
+
+// 移行期(legacyとmodernの両対応)とmodernのデバイスID
+const DEVICE_ID_TRANSITIONAL: u16 = 0x1001;
+const DEVICE_ID_MODERN: u16 = 0x1042;
+
+// デバイス固有の機能
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+// リクエストの種類
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+// リクエストの結果
+const S_OK: u8 = 0;
+
+// デバイス固有のコンフィギュレーションでの容量のオフセット
+const CONFIG_CAPACITY: usize = 0;
+
+// キューのディスクリプタ数の上限
+const QUEUE_SIZE: u16 = 128;
+// 1リクエストで転送する最大のバイト数
+// キューが小さいときはmax_request_bytesでさらに制限する
+const MAX_REQUEST_BYTES: usize = 64 * 1024;
+
+/// ドライバ
+/// `pci::register_driver(&block::virtio::DRIVER)`で登録する
+pub static DRIVER: VirtioBlkDriver = VirtioBlkDriver;
+
+static ID_TABLE: [DeviceId; 2] = [
+    DeviceId::new(virtio::VENDOR_ID, DEVICE_ID_TRANSITIONAL),
+    DeviceId::new(virtio::VENDOR_ID, DEVICE_ID_MODERN),
+];
+
+// 割り込みハンドラから完了を処理するデバイス
+static DEVICES: Mutex<Vec<Arc<VirtioBlk>>> = Mutex::new(Vec::new());
+
+// リクエストのヘッダ(デバイスが読む)
+#[repr(C)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+// リクエストの完了を割り込みハンドラからタスクに伝える
+struct Completion {
+    done: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl Completion {
+    fn new() -> Completion {
+        Completion {
+            done: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    fn poll(&self, cx: &mut Context) -> Poll<()> {
+        if self.done.load(Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+        self.waker.register(cx.waker());
+        if self.done.load(Ordering::Acquire) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+struct QueueState {
+    queue: VirtQueue,
+    // ディスクリプタのチェーンの先頭ごとの完了待ち
+    pending: BTreeMap<u16, Arc<Completion>>,
+    // ディスクリプタが空くのを待っているタスク
+    waiting: Vec<Waker>,
+}
+
+/// virtio-blkのディスク
+///
+/// リクエストはキューに積んだ後、割り込みで完了を受け取ってwakerでタスクを起こす。
+pub struct VirtioBlk {
+    name: String,
+    transport: VirtioPci,
+    // 割り込みハンドラと共有するので、タスク側は割り込み禁止でロックする
+    state: Mutex<QueueState>,
+    capacity: u64,
+    read_only: bool,
+    flush_supported: bool,
+    // 1リクエストで転送する最大のバイト数(キューの大きさで決まる)
+    max_request_bytes: usize,
+}
+
+impl VirtioBlk {
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    // 1つのリクエストを送って完了を待つ
+    // `data`はデータのバッファの仮想アドレス、長さ、デバイスが書き込むかどうか
+    async fn request(
+        &self,
+        kind: u32,
+        sector: u64,
+        data: Option<(VirtAddr, usize, bool)>,
+    ) -> Result<(), BlockError> {
+        // ヘッダとステータスもDMAするのでヒープに置く
+        let header = Box::new(RequestHeader {
+            kind,
+            reserved: 0,
+            sector,
+        });
+        let status = Box::new(u8::MAX);
+
+        let mut buffers = dma_buffers(
+            VirtAddr::from_ptr(&*header),
+            size_of::<RequestHeader>(),
+            false,
+        )
+        .expect("request header is not mapped");
+        if let Some((addr, len, device_writable)) = data {
+            buffers.extend(dma_buffers(addr, len, device_writable).expect("buffer is not mapped"));
+        }
+        buffers.extend(dma_buffers(VirtAddr::from_ptr(&*status), 1, true).unwrap());
+
+        let completion = Arc::new(Completion::new());
+        poll_fn(|cx| self.submit(&buffers, &completion, cx)).await;
+        // futureが途中でdropされても、デバイスがバッファを使い終わるまで待つ
+        let in_flight = InFlight {
+            device: self,
+            completion: &completion,
+        };
+        poll_fn(|cx| completion.poll(cx)).await;
+        drop(in_flight);
+
+        match unsafe { core::ptr::read_volatile(&*status) } {
+            S_OK => Ok(()),
+            error => Err(BlockError::DeviceError(error)),
+        }
+    }
+
+    // ディスクリプタが空いていればリクエストをキューに積んでデバイスに通知する
+    fn submit(
+        &self,
+        buffers: &[Buffer],
+        completion: &Arc<Completion>,
+        cx: &mut Context,
+    ) -> Poll<()> {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            let head = match state.queue.add(buffers) {
+                Some(head) => head,
+                None => {
+                    state.waiting.push(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            };
+            state.pending.insert(head, completion.clone());
+            self.transport.notify(&state.queue);
+            Poll::Ready(())
+        })
+    }
+
+    // usedリングから完了したリクエストを取り出して、待っているタスクを起こす
+    fn process_used(&self) {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            let mut completed = false;
+            while let Some((head, _len)) = state.queue.pop_used() {
+                if let Some(completion) = state.pending.remove(&head) {
+                    completion.done.store(true, Ordering::Release);
+                    completion.waker.wake();
+                }
+                completed = true;
+            }
+            if completed {
+                for waker in state.waiting.drain(..) {
+                    waker.wake();
+                }
+            }
+        })
+    }
+
+    // 読み書きを`max_request_bytes`ごとのリクエストに分けて行う
+    async fn transfer(
+        &self,
+        kind: u32,
+        lba: u64,
+        addr: VirtAddr,
+        len: usize,
+    ) -> Result<(), BlockError> {
+        let device_writable = kind == T_IN;
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(self.max_request_bytes);
+            let sector = lba + (done / SECTOR_SIZE) as u64;
+            self.request(kind, sector, Some((addr + done, n, device_writable)))
+                .await?;
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+// キューの大きさから1リクエストで転送できる最大のバイト数を求める。小さすぎればNone
+// ヘッダ(ページをまたぐと2つ)とステータスで3つ、データはページごとに断片化すると
+// nバイトで最大n/4096+1個のディスクリプタを使う
+fn max_request_bytes(queue_size: u16) -> Option<usize> {
+    let data_descriptors = usize::from(queue_size).checked_sub(3)?;
+    let pages = data_descriptors.checked_sub(1)?;
+    if pages == 0 {
+        return None;
+    }
+    Some((pages * Size4KiB::SIZE as usize).min(MAX_REQUEST_BYTES))
+}
+
+// 送信済みのリクエストが完了するまでdropをブロックする
+struct InFlight<'a> {
+    device: &'a VirtioBlk,
+    completion: &'a Completion,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        while !self.completion.done.load(Ordering::Acquire) {
+            self.device.process_used();
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_blocks<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            check_request(self, lba, buf.len())?;
+            let addr = VirtAddr::from_ptr(buf.as_mut_ptr());
+            self.transfer(T_IN, lba, addr, buf.len()).await
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a, ()> {
+        Box::pin(async move {
+            if self.read_only {
+                return Err(BlockError::ReadOnly);
+            }
+            check_request(self, lba, buf.len())?;
+            let addr = VirtAddr::from_ptr(buf.as_ptr());
+            self.transfer(T_OUT, lba, addr, buf.len()).await
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_, ()> {
+        Box::pin(async move {
+            if !self.flush_supported {
+                return Ok(());
+            }
+            self.request(T_FLUSH, 0, None).await
+        })
+    }
+}
+
+// virtio-blkのデバイスが共有しているIRQのハンドラ
+// 自分のデバイスの割り込みかはISRで確認する
+fn handle_interrupt() {
+    for device in DEVICES.lock().iter() {
+        if device.transport.read_isr() & virtio::ISR_QUEUE != 0 {
+            device.process_used();
+        }
+    }
+}
+
+pub struct VirtioBlkDriver;
+
+impl PciDriver for VirtioBlkDriver {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn id_table(&self) -> &'static [DeviceId] {
+        &ID_TABLE
+    }
+
+    fn probe(&self, pci: &PciDevice) -> Result<(), &'static str> {
+        // 従来のINTxで割り込みを受け取るので、IRQが割り当てられている必要がある
+        if pci.interrupt_pin == 0 || pci.interrupt_line >= 16 {
+            return Err("no legacy interrupt line");
+        }
+        pci.enable();
+        let transport = VirtioPci::new(pci)?;
+        let features = transport.initialize(F_RO | F_FLUSH)?;
+        let queue = match transport.setup_queue(0, QUEUE_SIZE) {
+            Ok(queue) => queue,
+            Err(err) => {
+                transport.fail();
+                return Err(err);
+            }
+        };
+        // キューがデバイスの都合で小さくなっていても、1リクエストのディスクリプタが収まるようにする
+        let max_request_bytes = match max_request_bytes(queue.size()) {
+            Some(bytes) => bytes,
+            None => {
+                transport.fail();
+                return Err("virtqueue is too small");
+            }
+        };
+        let capacity = transport.read_config_u64(CONFIG_CAPACITY);
+        if let Err(err) = interrupts::register_irq_handler(pci.interrupt_line, handle_interrupt) {
+            transport.fail();
+            return Err(err);
+        }
+
+        let device = without_interrupts(|| {
+            let mut devices = DEVICES.lock();
+            let device = Arc::new(VirtioBlk {
+                name: format!("vd{}", (b'a' + devices.len() as u8) as char),
+                transport,
+                state: Mutex::new(QueueState {
+                    queue,
+                    pending: BTreeMap::new(),
+                    waiting: Vec::new(),
+                }),
+                capacity,
+                read_only: features & F_RO != 0,
+                flush_supported: features & F_FLUSH != 0,
+                max_request_bytes,
+            });
+            devices.push(device.clone());
+            device
+        });
+        device.transport.driver_ok();
+        super::register(device);
+        Ok(())
+    }
+}
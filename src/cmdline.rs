@@ -0,0 +1,18 @@
+/// 埋め込まれたコマンドライン全体
+///
+/// bootloader 0.9はコマンドラインを渡してくれないので、ビルド時の環境変数
+/// `KERNEL_CMDLINE`を埋め込む(例: `KERNEL_CMDLINE="keyboard.layout=jis" cargo run`)。
+/// 引数は空白で区切った`key=value`か`key`の並び。
+pub fn get() -> &'static str {
+    option_env!("KERNEL_CMDLINE").unwrap_or("")
+}
+
+/// `key=value`と`key`(値は空文字列)を順に返す
+pub fn params(cmdline: &str) -> impl Iterator<Item = (&str, &str)> {
+    cmdline
+        .split_whitespace()
+        .map(|param| match param.find('=') {
+            Some(i) => (&param[..i], &param[i + 1..]),
+            None => (param, ""),
+        })
+}
@@ -0,0 +1,521 @@
+use super::{DirEntry, FileSystem, FileType, FsError, FsFuture, Inode, Metadata};
+use crate::block::BlockDevice;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+// スーパーブロックはデバイスの先頭から1024バイト目にある
+const SUPERBLOCK_OFFSET: usize = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const EXT2_MAGIC: u16 = 0xef53;
+const ROOT_INO: u32 = 2;
+// リビジョン0のinodeの大きさ
+const GOOD_OLD_INODE_SIZE: usize = 128;
+const GROUP_DESC_SIZE: usize = 32;
+
+// 対応しているincompatの機能(ディレクトリエントリのファイルの種類と、flex_bg)
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+
+// inodeのi_modeのファイルの種類
+const S_IFMT: u16 = 0xf000;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFLNK: u16 = 0xa000;
+
+// i_blockのうち直接ブロックの数と、間接ブロックの位置
+const DIRECT_BLOCKS: usize = 12;
+const INDIRECT: usize = 12;
+const DOUBLE_INDIRECT: usize = 13;
+const TRIPLE_INDIRECT: usize = 14;
+const BLOCK_POINTERS: usize = 15;
+// i_blockに直接入る短いシンボリックリンクの長さ
+const FAST_SYMLINK_MAX: u64 = 60;
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    label: String,
+    block_size: usize,
+    // ext2のブロックあたりのデバイスのブロック数
+    device_blocks_per_block: u64,
+    inode_count: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    // ブロックグループごとのinodeテーブルの先頭ブロック
+    inode_tables: Vec<u32>,
+    has_filetype: bool,
+}
+
+impl Volume {
+    async fn open(device: Arc<dyn BlockDevice>) -> Result<Volume, FsError> {
+        // スーパーブロックの終わりまでを、デバイスのブロック単位で先頭から読む
+        let device_block = device.block_size();
+        let head_blocks = (SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE + device_block - 1) / device_block;
+        if device.block_count() < head_blocks as u64 {
+            return Err(FsError::Unsupported);
+        }
+        let mut head = vec![0; head_blocks * device_block];
+        device.read_blocks(0, &mut head).await?;
+        let sb = &head[SUPERBLOCK_OFFSET..SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE];
+        if read_u16(sb, 56) != EXT2_MAGIC {
+            return Err(FsError::Unsupported);
+        }
+
+        let inode_count = read_u32(sb, 0);
+        let block_count = read_u32(sb, 4);
+        let first_data_block = read_u32(sb, 20);
+        let log_block_size = read_u32(sb, 24);
+        let blocks_per_group = read_u32(sb, 32);
+        let inodes_per_group = read_u32(sb, 40);
+        let revision = read_u32(sb, 76);
+        let (inode_size, incompat) = if revision == 0 {
+            (GOOD_OLD_INODE_SIZE, 0)
+        } else {
+            (usize::from(read_u16(sb, 88)), read_u32(sb, 96))
+        };
+        if incompat & !SUPPORTED_INCOMPAT != 0 {
+            return Err(FsError::Unsupported);
+        }
+        // ブロックは1KiBから64KiBまで
+        if log_block_size > 6 {
+            return Err(FsError::Corrupted);
+        }
+        let block_size = 1024usize << log_block_size;
+        if block_size % device_block != 0
+            || blocks_per_group == 0
+            || inodes_per_group == 0
+            || inode_size < GOOD_OLD_INODE_SIZE
+            || inode_size > block_size
+            || !inode_size.is_power_of_two()
+            || first_data_block >= block_count
+            || u64::from(block_count) * block_size as u64
+                > device.block_count() * device_block as u64
+        {
+            return Err(FsError::Corrupted);
+        }
+
+        let label_bytes = &sb[120..136];
+        let label_len = label_bytes.iter().position(|&b| b == 0).unwrap_or(16);
+        let mut volume = Volume {
+            device,
+            label: String::from_utf8_lossy(&label_bytes[..label_len]).into(),
+            block_size,
+            device_blocks_per_block: (block_size / device_block) as u64,
+            inode_count,
+            inodes_per_group,
+            inode_size,
+            inode_tables: Vec::new(),
+            has_filetype: incompat & INCOMPAT_FILETYPE != 0,
+        };
+
+        // ブロックグループディスクリプタはスーパーブロックの次のブロックから並ぶ
+        // スーパーブロックの値で計算するので、u32であふれないようにu64で計算する
+        let data_blocks = u64::from(block_count - first_data_block);
+        let group_count =
+            (data_blocks + u64::from(blocks_per_group) - 1) / u64::from(blocks_per_group);
+        let table_len = group_count as usize * GROUP_DESC_SIZE;
+        let table_blocks = (table_len + block_size - 1) / block_size;
+        // 全てのinodeがどこかのグループに入り、ディスクリプタのテーブルがファイルシステムに収まること
+        if group_count * u64::from(inodes_per_group) < u64::from(inode_count)
+            || table_blocks as u64 >= data_blocks
+        {
+            return Err(FsError::Corrupted);
+        }
+        let mut table = vec![0; table_blocks * block_size];
+        for i in 0..table_blocks {
+            let block = u64::from(first_data_block) + 1 + i as u64;
+            volume
+                .read_block(block, &mut table[i * block_size..(i + 1) * block_size])
+                .await?;
+        }
+        volume.inode_tables = table[..table_len]
+            .chunks_exact(GROUP_DESC_SIZE)
+            .map(|desc| read_u32(desc, 8))
+            .collect();
+        Ok(volume)
+    }
+
+    async fn read_block(&self, block: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        self.device
+            .read_blocks(block * self.device_blocks_per_block, buf)
+            .await?;
+        Ok(())
+    }
+
+    async fn read_inode(&self, ino: u32) -> Result<RawInode, FsError> {
+        if ino == 0 || ino > self.inode_count {
+            return Err(FsError::Corrupted);
+        }
+        let index = ino - 1;
+        let table = *self
+            .inode_tables
+            .get((index / self.inodes_per_group) as usize)
+            .ok_or(FsError::Corrupted)?;
+        let offset = (index % self.inodes_per_group) as usize * self.inode_size;
+        let mut block = vec![0; self.block_size];
+        self.read_block(
+            u64::from(table) + (offset / self.block_size) as u64,
+            &mut block,
+        )
+        .await?;
+        Ok(RawInode::parse(&block[offset % self.block_size..]))
+    }
+}
+
+// ディスク上のinodeのうち使う部分
+#[derive(Clone, Copy)]
+struct RawInode {
+    mode: u16,
+    size: u64,
+    // 512バイト単位の使用量(拡張属性のブロックも含む)
+    sectors: u32,
+    // 拡張属性のブロック(なければ0)
+    file_acl: u32,
+    block: [u32; BLOCK_POINTERS],
+}
+
+impl RawInode {
+    fn parse(bytes: &[u8]) -> RawInode {
+        let mode = read_u16(bytes, 0);
+        let mut block = [0; BLOCK_POINTERS];
+        for (i, pointer) in block.iter_mut().enumerate() {
+            *pointer = read_u32(bytes, 40 + i * 4);
+        }
+        // 通常のファイルでは、リビジョン1の上位32ビットも大きさに使う
+        let size_high = match mode & S_IFMT {
+            S_IFREG => read_u32(bytes, 108),
+            _ => 0,
+        };
+        RawInode {
+            mode,
+            size: u64::from(size_high) << 32 | u64::from(read_u32(bytes, 4)),
+            sectors: read_u32(bytes, 28),
+            file_acl: read_u32(bytes, 104),
+            block,
+        }
+    }
+
+    fn file_type(&self) -> Result<FileType, FsError> {
+        match self.mode & S_IFMT {
+            S_IFREG => Ok(FileType::File),
+            S_IFDIR => Ok(FileType::Directory),
+            S_IFLNK => Ok(FileType::Symlink),
+            // デバイスファイルなどは扱わない
+            _ => Err(FsError::Unsupported),
+        }
+    }
+
+    // リンク先がi_blockに直接入っているシンボリックリンク
+    // 拡張属性のブロックがあればi_blocksに数えられているので、その分を除いて調べる
+    fn is_fast_symlink(&self, block_size: usize) -> bool {
+        let acl_sectors = if self.file_acl != 0 {
+            (block_size / 512) as u32
+        } else {
+            0
+        };
+        self.mode & S_IFMT == S_IFLNK && self.size < FAST_SYMLINK_MAX && self.sectors == acl_sectors
+    }
+}
+
+/// ext2ファイルシステム(読み込みのみ)
+///
+/// Linuxの`mke2fs -t ext2`で作ったイメージを読める。ブロックの割り当ては直接・間接・二重間接・
+/// 三重間接ブロックで表されるもの(extentsでないもの)だけに対応している。
+pub struct Ext2Fs {
+    volume: Arc<Volume>,
+    root: Arc<Ext2Inode>,
+}
+
+impl Ext2Fs {
+    /// ブロックデバイス上のext2ボリュームを開く。ext2でなければ`Unsupported`を返す
+    pub async fn new(device: Arc<dyn BlockDevice>) -> Result<Ext2Fs, FsError> {
+        let volume = Arc::new(Volume::open(device).await?);
+        let root = Ext2Inode::load(&volume, ROOT_INO).await?;
+        if root.file_type != FileType::Directory {
+            return Err(FsError::Corrupted);
+        }
+        Ok(Ext2Fs {
+            volume,
+            root: Arc::new(root),
+        })
+    }
+
+    /// ボリューム名
+    pub fn label(&self) -> &str {
+        &self.volume.label
+    }
+
+    /// ブロックのバイト数
+    pub fn block_size(&self) -> usize {
+        self.volume.block_size
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+// 読み込みのみなので、開いたときのinodeの内容をそのまま持つ
+struct Ext2Inode {
+    volume: Arc<Volume>,
+    ino: u32,
+    file_type: FileType,
+    raw: RawInode,
+}
+
+impl Ext2Inode {
+    async fn load(volume: &Arc<Volume>, ino: u32) -> Result<Ext2Inode, FsError> {
+        let raw = volume.read_inode(ino).await?;
+        Ok(Ext2Inode {
+            volume: volume.clone(),
+            ino,
+            file_type: raw.file_type()?,
+            raw,
+        })
+    }
+
+    // ファイル内の`index`番目のブロックのディスク上の位置を返す。穴なら0
+    async fn map_block(&self, index: u64, cache: &mut PointerCache) -> Result<u32, FsError> {
+        let per_block = (self.volume.block_size / 4) as u64;
+        if index < DIRECT_BLOCKS as u64 {
+            return Ok(self.raw.block[index as usize]);
+        }
+        // 間接ブロックの段数と、その中での位置を求める
+        let mut index = index - DIRECT_BLOCKS as u64;
+        let mut span = per_block;
+        let mut level = 1;
+        let mut root = INDIRECT;
+        while index >= span {
+            index -= span;
+            level += 1;
+            span *= per_block;
+            root = match level {
+                2 => DOUBLE_INDIRECT,
+                3 => TRIPLE_INDIRECT,
+                _ => return Err(FsError::InvalidArgument),
+            };
+        }
+        let mut block = self.raw.block[root];
+        while level > 0 {
+            if block == 0 {
+                return Ok(0);
+            }
+            span /= per_block;
+            let slot = (index / span) as usize;
+            index %= span;
+            block = cache.get(&self.volume, block, slot).await?;
+            level -= 1;
+        }
+        Ok(block)
+    }
+
+    // ファイルの`offset`から読む。ファイルの終わりで止まる
+    async fn read_data(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if offset >= self.raw.size {
+            return Ok(0);
+        }
+        let len = buf.len().min((self.raw.size - offset) as usize);
+        if self.raw.is_fast_symlink(self.volume.block_size) {
+            let target: Vec<u8> = self
+                .raw
+                .block
+                .iter()
+                .flat_map(|b| b.to_le_bytes())
+                .collect();
+            let start = offset as usize;
+            buf[..len].copy_from_slice(&target[start..start + len]);
+            return Ok(len);
+        }
+
+        let block_size = self.volume.block_size;
+        let mut cache = PointerCache::default();
+        let mut block_buf = vec![0; block_size];
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let within = (pos % block_size as u64) as usize;
+            let n = (block_size - within).min(len - done);
+            match self.map_block(pos / block_size as u64, &mut cache).await? {
+                // 穴は0として読む
+                0 => buf[done..done + n].fill(0),
+                block => {
+                    self.volume
+                        .read_block(u64::from(block), &mut block_buf)
+                        .await?;
+                    buf[done..done + n].copy_from_slice(&block_buf[within..within + n]);
+                }
+            }
+            done += n;
+        }
+        Ok(len)
+    }
+
+    // ディレクトリのエントリを全て読む("."と".."を含む)
+    // 大きさはディスクから読んだ値なので、全体を一度に確保せず1ブロックずつ読む
+    async fn entries(&self) -> Result<Vec<(String, u32, u8)>, FsError> {
+        if self.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let block_size = self.volume.block_size;
+        let mut block = vec![0; block_size];
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < self.raw.size {
+            let len = self.read_data(pos, &mut block).await?;
+            // エントリはブロックをまたがない
+            self.parse_entries(&block[..len], &mut entries)?;
+            pos += block_size as u64;
+        }
+        Ok(entries)
+    }
+
+    // ディレクトリの1ブロックのエントリを`entries`に足す
+    fn parse_entries(
+        &self,
+        data: &[u8],
+        entries: &mut Vec<(String, u32, u8)>,
+    ) -> Result<(), FsError> {
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let entry = &data[offset..];
+            let ino = read_u32(entry, 0);
+            let rec_len = usize::from(read_u16(entry, 4));
+            // FILETYPEがなければ名前の長さは16ビット
+            let (name_len, kind) = if self.volume.has_filetype {
+                (usize::from(entry[6]), entry[7])
+            } else {
+                (usize::from(read_u16(entry, 6)), 0)
+            };
+            if rec_len < 8 || offset + rec_len > data.len() || 8 + name_len > rec_len {
+                return Err(FsError::Corrupted);
+            }
+            // inode番号が0のエントリは使われていない
+            if ino != 0 {
+                let name = String::from_utf8_lossy(&entry[8..8 + name_len]).into();
+                entries.push((name, ino, kind));
+            }
+            offset += rec_len;
+        }
+        Ok(())
+    }
+}
+
+// 間接ブロックを読んだ結果を覚えておく(連続したブロックを読むときに同じ間接ブロックを何度も読まない)
+#[derive(Default)]
+struct PointerCache {
+    blocks: Vec<(u32, Vec<u32>)>,
+}
+
+impl PointerCache {
+    // 段数の分(3つ)だけ覚えておく
+    const CAPACITY: usize = 3;
+
+    async fn get(&mut self, volume: &Volume, block: u32, slot: usize) -> Result<u32, FsError> {
+        if let Some((_, pointers)) = self.blocks.iter().find(|(b, _)| *b == block) {
+            return Ok(pointers[slot]);
+        }
+        let mut data = vec![0; volume.block_size];
+        volume.read_block(u64::from(block), &mut data).await?;
+        let pointers: Vec<u32> = data.chunks_exact(4).map(|c| read_u32(c, 0)).collect();
+        let pointer = pointers[slot];
+        if self.blocks.len() == Self::CAPACITY {
+            self.blocks.remove(0);
+        }
+        self.blocks.push((block, pointers));
+        Ok(pointer)
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            ino: u64::from(self.ino),
+            file_type: self.file_type,
+            size: self.raw.size,
+        }
+    }
+
+    // シンボリックリンクを読むとリンク先のパスが返る
+    fn read_at<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            if self.file_type == FileType::Directory {
+                return Err(FsError::IsADirectory);
+            }
+            self.read_data(offset, buf).await
+        })
+    }
+
+    fn write_at<'a>(&'a self, _offset: u64, _buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+
+    fn truncate(&self, _size: u64) -> FsFuture<'_, ()> {
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            let ino = self
+                .entries()
+                .await?
+                .into_iter()
+                .find(|(entry, _, _)| entry == name)
+                .map(|(_, ino, _)| ino)
+                .ok_or(FsError::NotFound)?;
+            Ok(Arc::new(Ext2Inode::load(&self.volume, ino).await?) as Arc<dyn Inode>)
+        })
+    }
+
+    fn create<'a>(&'a self, _name: &'a str, _file_type: FileType) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+
+    fn unlink<'a>(&'a self, _name: &'a str) -> FsFuture<'a, ()> {
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+
+    fn readdir(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async move {
+            let mut entries = Vec::new();
+            for (name, ino, kind) in self.entries().await? {
+                if name == "." || name == ".." {
+                    continue;
+                }
+                let file_type = match kind {
+                    1 => FileType::File,
+                    2 => FileType::Directory,
+                    7 => FileType::Symlink,
+                    // 種類が書かれていなければinodeを読む(デバイスファイルなどは表示しない)
+                    _ => match self.volume.read_inode(ino).await?.file_type() {
+                        Ok(file_type) => file_type,
+                        Err(_) => continue,
+                    },
+                };
+                entries.push(DirEntry {
+                    name,
+                    ino: u64::from(ino),
+                    file_type,
+                });
+            }
+            Ok(entries)
+        })
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
@@ -0,0 +1,1287 @@
+use super::{path, DirEntry, FileSystem, FileType, FsError, FsFuture, Inode, Metadata};
+use crate::block::BlockDevice;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use spin::Mutex;
+
+const DIR_ENTRY_SIZE: usize = 32;
+
+// ディレクトリエントリの属性
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+// 名前の先頭のバイトの特別な値
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xe5;
+// 先頭が0xe5の名前は0x05として格納される
+const ENTRY_KANJI_E5: u8 = 0x05;
+
+// 短い名前のエントリのNTの予約バイトで、名前や拡張子が小文字であることを示すビット
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXT: u8 = 0x10;
+
+// 長い名前のエントリ
+const LFN_LAST: u8 = 0x40;
+const LFN_ORDER_MASK: u8 = 0x1f;
+const LFN_CHARS: usize = 13;
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+// 長い名前はUTF-16で255文字まで
+const LFN_MAX: usize = 255;
+
+// FATのエントリは下位28ビットだけを使う
+const FAT_MASK: u32 = 0x0fff_ffff;
+const FAT_FREE: u32 = 0;
+const FAT_BAD: u32 = 0x0fff_fff7;
+const FAT_EOC: u32 = 0x0fff_ffff;
+// これ以上の値はチェーンの終わり
+const FAT_EOC_MIN: u32 = 0x0fff_fff8;
+const FIRST_CLUSTER: u32 = 2;
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_TRAIL_SIGNATURE: u32 = 0xaa55_0000;
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+// 1980年1月1日(FATの日付の最小値)
+const FAT_EPOCH_DATE: u16 = (1 << 5) | 1;
+
+// 短い名前に使える記号(英大文字と数字のほか)
+const SHORT_NAME_SYMBOLS: &[u8] = b"$%'-_@~`!(){}^#&";
+// 長い名前に使えない文字
+const INVALID_CHARS: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+// ボリュームの情報と、ブロックデバイスへのアクセス
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    label: String,
+    bytes_per_sector: usize,
+    sectors_per_cluster: u64,
+    reserved_sectors: u64,
+    fat_count: u64,
+    fat_sectors: u64,
+    data_start: u64,
+    cluster_count: u32,
+    root_cluster: u32,
+    fs_info_sector: Option<u64>,
+    alloc: Mutex<AllocState>,
+    // 同じエントリに対して同じinodeを返すための表(inode番号で引く)
+    inodes: Mutex<BTreeMap<u64, Weak<FatInode>>>,
+}
+
+struct AllocState {
+    // 次に空きを探し始めるクラスタ
+    next_free: u32,
+    // 空きクラスタの数(FSInfoが信用できなければNone)
+    free_count: Option<u32>,
+    // FSInfoに書き戻していない変更がある
+    dirty: bool,
+}
+
+impl Volume {
+    async fn open(device: Arc<dyn BlockDevice>) -> Result<Volume, FsError> {
+        let block_size = device.block_size();
+        if block_size < 512 || device.block_count() == 0 {
+            return Err(FsError::Unsupported);
+        }
+        let mut boot = vec![0; block_size];
+        device.read_blocks(0, &mut boot).await?;
+        if boot[510..512] != [0x55, 0xaa] {
+            return Err(FsError::Unsupported);
+        }
+
+        let bytes_per_sector = usize::from(read_u16(&boot, 11));
+        let sectors_per_cluster = u64::from(boot[13]);
+        let reserved_sectors = u64::from(read_u16(&boot, 14));
+        let fat_count = u64::from(boot[16]);
+        let root_entries = read_u16(&boot, 17);
+        let fat16_sectors = read_u16(&boot, 22);
+        let total_sectors = match read_u16(&boot, 19) {
+            0 => u64::from(read_u32(&boot, 32)),
+            sectors => u64::from(sectors),
+        };
+        let fat_sectors = u64::from(read_u32(&boot, 36));
+        let root_cluster = read_u32(&boot, 44);
+        let fs_info_sector = u64::from(read_u16(&boot, 48));
+
+        // FAT12/16はルートディレクトリの領域を持ち、FAT16用のFATの大きさが0でない
+        if !bytes_per_sector.is_power_of_two()
+            || !(512..=4096).contains(&bytes_per_sector)
+            || !sectors_per_cluster.is_power_of_two()
+            || root_entries != 0
+            || fat16_sectors != 0
+            || fat_sectors == 0
+        {
+            return Err(FsError::Unsupported);
+        }
+        // セクタとブロックの大きさが違うボリュームは扱わない
+        if bytes_per_sector != block_size {
+            return Err(FsError::Unsupported);
+        }
+        let data_start = reserved_sectors + fat_count * fat_sectors;
+        if reserved_sectors == 0
+            || fat_count == 0
+            || total_sectors > device.block_count()
+            || data_start >= total_sectors
+        {
+            return Err(FsError::Corrupted);
+        }
+        // FATに入るエントリの数でも制限する
+        let cluster_count = ((total_sectors - data_start) / sectors_per_cluster)
+            .min(fat_sectors * bytes_per_sector as u64 / 4 - u64::from(FIRST_CLUSTER))
+            .min(u64::from(FAT_BAD - FIRST_CLUSTER)) as u32;
+        if root_cluster < FIRST_CLUSTER || root_cluster >= FIRST_CLUSTER + cluster_count {
+            return Err(FsError::Corrupted);
+        }
+
+        let label = String::from_utf8_lossy(&boot[71..82]).trim_end().into();
+        let mut volume = Volume {
+            device,
+            label,
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            fat_count,
+            fat_sectors,
+            data_start,
+            cluster_count,
+            root_cluster,
+            fs_info_sector: None,
+            alloc: Mutex::new(AllocState {
+                next_free: FIRST_CLUSTER,
+                free_count: None,
+                dirty: false,
+            }),
+            inodes: Mutex::new(BTreeMap::new()),
+        };
+
+        // FSInfoは空きの数のヒントなので、壊れていても無視して使わないだけにする
+        if fs_info_sector != 0 && fs_info_sector < reserved_sectors {
+            let info = volume.read_sector(fs_info_sector).await?;
+            if read_u32(&info, 0) == FSINFO_LEAD_SIGNATURE
+                && read_u32(&info, 484) == FSINFO_STRUCT_SIGNATURE
+            {
+                volume.fs_info_sector = Some(fs_info_sector);
+                let mut alloc = volume.alloc.lock();
+                let free_count = read_u32(&info, 488);
+                if free_count <= cluster_count {
+                    alloc.free_count = Some(free_count);
+                }
+                let next_free = read_u32(&info, 492);
+                if volume.is_valid_cluster(next_free) {
+                    alloc.next_free = next_free;
+                }
+            }
+        }
+        Ok(volume)
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * self.bytes_per_sector
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= FIRST_CLUSTER && cluster - FIRST_CLUSTER < self.cluster_count
+    }
+
+    fn cluster_lba(&self, cluster: u32) -> u64 {
+        self.data_start + u64::from(cluster - FIRST_CLUSTER) * self.sectors_per_cluster
+    }
+
+    async fn read_sector(&self, lba: u64) -> Result<Vec<u8>, FsError> {
+        let mut sector = vec![0; self.bytes_per_sector];
+        self.device.read_blocks(lba, &mut sector).await?;
+        Ok(sector)
+    }
+
+    // FATのエントリの位置(1つ目のFATでのセクタと、セクタ内のオフセット)
+    fn fat_position(&self, cluster: u32) -> (u64, usize) {
+        let offset = cluster as usize * 4;
+        (
+            self.reserved_sectors + (offset / self.bytes_per_sector) as u64,
+            offset % self.bytes_per_sector,
+        )
+    }
+
+    async fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let (lba, offset) = self.fat_position(cluster);
+        let sector = self.read_sector(lba).await?;
+        Ok(read_u32(&sector, offset) & FAT_MASK)
+    }
+
+    // 全てのFATのコピーを更新する。上位4ビットは予約なので残す
+    async fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let (lba, offset) = self.fat_position(cluster);
+        for fat in 0..self.fat_count {
+            let lba = lba + fat * self.fat_sectors;
+            let mut sector = self.read_sector(lba).await?;
+            let old = read_u32(&sector, offset);
+            write_u32(&mut sector, offset, (old & !FAT_MASK) | (value & FAT_MASK));
+            self.device.write_blocks(lba, &sector).await?;
+        }
+        Ok(())
+    }
+
+    // クラスタチェーンをたどる。`first`が0なら空
+    async fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while cluster != FAT_FREE {
+            // ループしているチェーンを検出する
+            if !self.is_valid_cluster(cluster) || chain.len() >= self.cluster_count as usize {
+                return Err(FsError::Corrupted);
+            }
+            chain.push(cluster);
+            let next = self.fat_entry(cluster).await?;
+            cluster = if next >= FAT_EOC_MIN {
+                FAT_FREE
+            } else if next == FAT_FREE {
+                return Err(FsError::Corrupted);
+            } else {
+                next
+            };
+        }
+        Ok(chain)
+    }
+
+    // 空きクラスタを1つ確保して0で埋め、`prev`の次につなぐ
+    async fn allocate_cluster(&self, prev: Option<u32>) -> Result<u32, FsError> {
+        let start = self.alloc.lock().next_free;
+        let cluster = self.find_free_cluster(start).await?;
+        self.set_fat_entry(cluster, FAT_EOC).await?;
+        {
+            let mut alloc = self.alloc.lock();
+            alloc.next_free = if self.is_valid_cluster(cluster + 1) {
+                cluster + 1
+            } else {
+                FIRST_CLUSTER
+            };
+            alloc.free_count = alloc.free_count.map(|count| count.saturating_sub(1));
+            alloc.dirty = true;
+        }
+
+        let zero = vec![0; self.cluster_size()];
+        self.device
+            .write_blocks(self.cluster_lba(cluster), &zero)
+            .await?;
+        if let Some(prev) = prev {
+            self.set_fat_entry(prev, cluster).await?;
+        }
+        Ok(cluster)
+    }
+
+    // `start`から順に(最後まで行けば先頭に戻って)空きクラスタを探す
+    async fn find_free_cluster(&self, start: u32) -> Result<u32, FsError> {
+        let entries_per_sector = (self.bytes_per_sector / 4) as u32;
+        let start = if self.is_valid_cluster(start) {
+            start
+        } else {
+            FIRST_CLUSTER
+        };
+        let mut checked = 0;
+        let mut cluster = start;
+        while checked < self.cluster_count {
+            let (lba, _) = self.fat_position(cluster);
+            let sector = self.read_sector(lba).await?;
+            // 同じセクタに入っているエントリをまとめて調べる
+            let sector_end = (cluster / entries_per_sector + 1) * entries_per_sector;
+            while cluster < sector_end && checked < self.cluster_count {
+                if self.is_valid_cluster(cluster) {
+                    let offset = (cluster % entries_per_sector) as usize * 4;
+                    if read_u32(&sector, offset) & FAT_MASK == FAT_FREE {
+                        return Ok(cluster);
+                    }
+                    checked += 1;
+                    cluster += 1;
+                } else {
+                    cluster = FIRST_CLUSTER;
+                    break;
+                }
+            }
+            if !self.is_valid_cluster(cluster) {
+                cluster = FIRST_CLUSTER;
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+
+    // チェーンの全てのクラスタを解放する
+    async fn free_chain(&self, chain: &[u32]) -> Result<(), FsError> {
+        for &cluster in chain {
+            self.set_fat_entry(cluster, FAT_FREE).await?;
+            let mut alloc = self.alloc.lock();
+            alloc.free_count = alloc.free_count.map(|count| count + 1);
+            alloc.dirty = true;
+        }
+        Ok(())
+    }
+
+    // チェーンで表されるデータの`offset`から読む
+    async fn read_chain(&self, chain: &[u32], offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let sector_size = self.bytes_per_sector;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let (lba, within) = self.locate(chain, pos)?;
+            let n = (sector_size - within).min(buf.len() - done);
+            if n == sector_size {
+                self.device
+                    .read_blocks(lba, &mut buf[done..done + n])
+                    .await?;
+            } else {
+                let sector = self.read_sector(lba).await?;
+                buf[done..done + n].copy_from_slice(&sector[within..within + n]);
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    // チェーンで表されるデータの`offset`に書く。セクタの一部だけなら読んでから書く
+    async fn write_chain(&self, chain: &[u32], offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        let sector_size = self.bytes_per_sector;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let (lba, within) = self.locate(chain, pos)?;
+            let n = (sector_size - within).min(buf.len() - done);
+            if n == sector_size {
+                self.device.write_blocks(lba, &buf[done..done + n]).await?;
+            } else {
+                let mut sector = self.read_sector(lba).await?;
+                sector[within..within + n].copy_from_slice(&buf[done..done + n]);
+                self.device.write_blocks(lba, &sector).await?;
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    // チェーン内の位置を、セクタとセクタ内のオフセットにする
+    fn locate(&self, chain: &[u32], pos: u64) -> Result<(u64, usize), FsError> {
+        let cluster_size = self.cluster_size() as u64;
+        let cluster = *chain
+            .get((pos / cluster_size) as usize)
+            .ok_or(FsError::Corrupted)?;
+        let within = (pos % cluster_size) as usize;
+        Ok((
+            self.cluster_lba(cluster) + (within / self.bytes_per_sector) as u64,
+            within % self.bytes_per_sector,
+        ))
+    }
+
+    // クラスタを1つ読む
+    async fn read_cluster(&self, cluster: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        self.read_chain(&[cluster], 0, buf).await
+    }
+
+    // ディレクトリのエントリを全て読む。ディレクトリは大きいことがあるので、1クラスタずつ読む
+    async fn read_dir(&self, chain: &[u32]) -> Result<Vec<RawEntry>, FsError> {
+        let cluster_size = self.cluster_size();
+        let mut data = vec![0; cluster_size];
+        let mut entries = Vec::new();
+        let mut lfn = LfnBuilder::default();
+        'clusters: for (index, &cluster) in chain.iter().enumerate() {
+            self.read_cluster(cluster, &mut data).await?;
+            for (i, entry) in data.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+                let offset = (index * cluster_size + i * DIR_ENTRY_SIZE) as u64;
+                match entry[0] {
+                    ENTRY_END => break 'clusters,
+                    ENTRY_DELETED => {
+                        lfn = LfnBuilder::default();
+                        continue;
+                    }
+                    _ => {}
+                }
+                let attr = entry[11];
+                if attr & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                    lfn.push(entry, offset);
+                    continue;
+                }
+                let short_name: [u8; 11] = entry[0..11].try_into().unwrap();
+                let long_name = lfn.finish(&short_name);
+                lfn = LfnBuilder::default();
+                if attr & ATTR_VOLUME_ID != 0 || short_name[0] == b'.' {
+                    continue;
+                }
+
+                let first_cluster =
+                    u32::from(read_u16(entry, 20)) << 16 | u32::from(read_u16(entry, 26));
+                let (lba, within) = self.locate(chain, offset)?;
+                let (name, start) = match long_name {
+                    Some((name, start)) => (name, start),
+                    None => (format_short_name(&short_name, entry[12]), offset),
+                };
+                entries.push(RawEntry {
+                    name,
+                    short_name,
+                    attr,
+                    first_cluster,
+                    size: read_u32(entry, 28),
+                    start,
+                    offset,
+                    ino: (lba * self.bytes_per_sector as u64 + within as u64)
+                        / DIR_ENTRY_SIZE as u64,
+                });
+            }
+        }
+        Ok(entries)
+    }
+}
+
+// ディレクトリから読んだエントリ
+struct RawEntry {
+    // 長い名前があればそれ、なければ短い名前
+    name: String,
+    short_name: [u8; 11],
+    attr: u8,
+    first_cluster: u32,
+    size: u32,
+    // ディレクトリ内の、最初の長い名前のエントリの位置
+    start: u64,
+    // ディレクトリ内の、短い名前のエントリの位置
+    offset: u64,
+    // 短い名前のエントリのディスク上の位置から決めたinode番号
+    ino: u64,
+}
+
+impl RawEntry {
+    fn file_type(&self) -> FileType {
+        if self.attr & ATTR_DIRECTORY != 0 {
+            FileType::Directory
+        } else {
+            FileType::File
+        }
+    }
+
+    // 大文字と小文字を区別せずに、長い名前か短い名前と比べる
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || format_short_name(&self.short_name, 0).eq_ignore_ascii_case(name)
+    }
+}
+
+// 長い名前のエントリを集める
+#[derive(Default)]
+struct LfnBuilder {
+    chars: Vec<u16>,
+    checksum: u8,
+    // 次に来るはずの順番(1まで来れば完成)
+    next_order: u8,
+    start: u64,
+}
+
+impl LfnBuilder {
+    fn push(&mut self, entry: &[u8], offset: u64) {
+        let order = entry[0] & LFN_ORDER_MASK;
+        if entry[0] & LFN_LAST != 0 {
+            // 長い名前は最後の部分から順に並んでいる
+            *self = LfnBuilder {
+                chars: vec![0xffff; usize::from(order) * LFN_CHARS],
+                checksum: entry[13],
+                next_order: order,
+                start: offset,
+            };
+        } else if order == 0 || order != self.next_order || entry[13] != self.checksum {
+            *self = LfnBuilder::default();
+            return;
+        }
+        if order == 0 {
+            return;
+        }
+        let base = usize::from(order - 1) * LFN_CHARS;
+        for (i, &char_offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+            self.chars[base + i] = read_u16(entry, char_offset);
+        }
+        self.next_order = order - 1;
+    }
+
+    // 全ての部分がそろっていて、短い名前のチェックサムが合えば名前と開始位置を返す
+    fn finish(&self, short_name: &[u8; 11]) -> Option<(String, u64)> {
+        if self.chars.is_empty()
+            || self.next_order != 0
+            || self.checksum != short_name_checksum(short_name)
+        {
+            return None;
+        }
+        let len = self
+            .chars
+            .iter()
+            .position(|&c| c == 0 || c == 0xffff)
+            .unwrap_or(self.chars.len());
+        Some((String::from_utf16_lossy(&self.chars[..len]), self.start))
+    }
+}
+
+// 短い名前を"NAME.EXT"の形にする
+fn format_short_name(short_name: &[u8; 11], nt_flags: u8) -> String {
+    let mut base: Vec<u8> = short_name[0..8].to_vec();
+    if base[0] == ENTRY_KANJI_E5 {
+        base[0] = ENTRY_DELETED;
+    }
+    let convert = |bytes: &[u8], lower: bool| -> String {
+        bytes
+            .iter()
+            .rev()
+            .skip_while(|&&b| b == b' ')
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .map(|&b| {
+                let c = char::from(b);
+                if lower {
+                    c.to_ascii_lowercase()
+                } else {
+                    c
+                }
+            })
+            .collect()
+    };
+    let mut name = convert(&base, nt_flags & NT_LOWER_BASE != 0);
+    let ext = convert(&short_name[8..11], nt_flags & NT_LOWER_EXT != 0);
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+fn is_short_name_char(b: u8) -> bool {
+    b.is_ascii_uppercase() || b.is_ascii_digit() || SHORT_NAME_SYMBOLS.contains(&b)
+}
+
+// そのまま短い名前にできる名前("README.TXT"など)なら、短い名前を返す
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty()
+        || base.len() > 8
+        || ext.len() > 3
+        || !base.bytes().chain(ext.bytes()).all(is_short_name_char)
+    {
+        return None;
+    }
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.as_bytes());
+    short_name[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(short_name)
+}
+
+// 長い名前から"BASE~N.EXT"の形の短い名前を作る。`existing`と重ならない番号を選ぶ
+fn generate_short_name(name: &str, existing: &[[u8; 11]]) -> Result<[u8; 11], FsError> {
+    let sanitize = |part: &str, max: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let b = if c.is_ascii() {
+                    c.to_ascii_uppercase() as u8
+                } else {
+                    b'_'
+                };
+                if is_short_name_char(b) {
+                    b
+                } else {
+                    b'_'
+                }
+            })
+            .take(max)
+            .collect()
+    };
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (sanitize(&name[..dot], 8), sanitize(&name[dot + 1..], 3)),
+        _ => (sanitize(name, 8), Vec::new()),
+    };
+
+    for n in 1..1_000_000u32 {
+        let suffix = format!("~{}", n);
+        let keep = base.len().min(8 - suffix.len());
+        let mut short_name = [b' '; 11];
+        short_name[..keep].copy_from_slice(&base[..keep]);
+        short_name[keep..keep + suffix.len()].copy_from_slice(suffix.as_bytes());
+        short_name[8..8 + ext.len()].copy_from_slice(&ext);
+        if !existing.contains(&short_name) {
+            return Ok(short_name);
+        }
+    }
+    Err(FsError::NoSpace)
+}
+
+// FATのエントリとして使える名前か確認する
+fn check_fat_name(name: &str) -> Result<(), FsError> {
+    path::check_name(name)?;
+    if name.contains(INVALID_CHARS) || name.chars().any(|c| c < ' ') {
+        return Err(FsError::InvalidPath);
+    }
+    if name.encode_utf16().count() > LFN_MAX {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
+
+// 短い名前のエントリを作る
+fn short_entry(short_name: &[u8; 11], attr: u8, first_cluster: u32, size: u32) -> [u8; 32] {
+    let mut entry = [0; DIR_ENTRY_SIZE];
+    entry[0..11].copy_from_slice(short_name);
+    entry[11] = attr;
+    // 作成日時、アクセス日、更新日時
+    write_u16(&mut entry, 16, FAT_EPOCH_DATE);
+    write_u16(&mut entry, 18, FAT_EPOCH_DATE);
+    write_u16(&mut entry, 24, FAT_EPOCH_DATE);
+    set_first_cluster(&mut entry, first_cluster);
+    write_u32(&mut entry, 28, size);
+    entry
+}
+
+fn set_first_cluster(entry: &mut [u8], cluster: u32) {
+    write_u16(entry, 20, (cluster >> 16) as u16);
+    write_u16(entry, 26, cluster as u16);
+}
+
+// 長い名前のエントリの列を、ディスク上の順(最後の部分から)で作る
+fn lfn_entries(name: &str, checksum: u8) -> Vec<[u8; 32]> {
+    let mut chars: Vec<u16> = name.encode_utf16().collect();
+    let count = (chars.len() + LFN_CHARS - 1) / LFN_CHARS;
+    // 途中で終わる場合は0を1つ置き、残りは0xffffで埋める
+    if chars.len() % LFN_CHARS != 0 {
+        chars.push(0);
+    }
+    chars.resize(count * LFN_CHARS, 0xffff);
+
+    (1..=count)
+        .rev()
+        .map(|order| {
+            let mut entry = [0; DIR_ENTRY_SIZE];
+            entry[0] = order as u8;
+            if order == count {
+                entry[0] |= LFN_LAST;
+            }
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            let part = &chars[(order - 1) * LFN_CHARS..order * LFN_CHARS];
+            for (&c, &offset) in part.iter().zip(LFN_CHAR_OFFSETS.iter()) {
+                write_u16(&mut entry, offset, c);
+            }
+            entry
+        })
+        .collect()
+}
+
+/// FAT32ファイルシステム
+///
+/// ホストで`mkfs.fat -F 32`などで作ったイメージをQEMUにつないで読み書きできる。
+/// 長いファイル名(VFAT)に対応している。タイムスタンプは記録しない(RTCがないため固定値)。
+///
+/// メタデータの更新は複数のブロックの読み書きに分かれるので、同じボリュームの同じディレクトリを
+/// 複数のタスクから同時に変更することは想定していない。
+pub struct FatFs {
+    volume: Arc<Volume>,
+    root: Arc<FatInode>,
+}
+
+impl FatFs {
+    /// ブロックデバイス上のFAT32ボリュームを開く。FAT32でなければ`Unsupported`を返す
+    pub async fn new(device: Arc<dyn BlockDevice>) -> Result<FatFs, FsError> {
+        let volume = Arc::new(Volume::open(device).await?);
+        let root = Arc::new(FatInode {
+            volume: volume.clone(),
+            ino: ROOT_INO,
+            file_type: FileType::Directory,
+            entry: None,
+            state: Mutex::new(NodeState {
+                first_cluster: volume.root_cluster,
+                size: 0,
+            }),
+        });
+        Ok(FatFs { volume, root })
+    }
+
+    /// ボリュームラベル
+    pub fn label(&self) -> &str {
+        &self.volume.label
+    }
+
+    /// クラスタのバイト数
+    pub fn cluster_size(&self) -> usize {
+        self.volume.cluster_size()
+    }
+
+    /// 空きクラスタの数を数える。
+    pub async fn free_clusters(&self) -> Result<u32, FsError> {
+        let volume = &self.volume;
+        let mut free = 0;
+        for cluster in FIRST_CLUSTER..FIRST_CLUSTER + volume.cluster_count {
+            if volume.fat_entry(cluster).await? == FAT_FREE {
+                free += 1;
+            }
+        }
+        Ok(free)
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &str {
+        "fat32"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    // FSInfoの空きクラスタの情報を更新してから、デバイスのキャッシュを書き出す
+    fn sync(&self) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            let volume = &self.volume;
+            let update = {
+                let mut alloc = volume.alloc.lock();
+                let update = if alloc.dirty {
+                    Some((alloc.free_count, alloc.next_free))
+                } else {
+                    None
+                };
+                alloc.dirty = false;
+                update
+            };
+            if let (Some(lba), Some((free_count, next_free))) = (volume.fs_info_sector, update) {
+                let mut info = volume.read_sector(lba).await?;
+                write_u32(&mut info, 488, free_count.unwrap_or(FSINFO_UNKNOWN));
+                write_u32(&mut info, 492, next_free);
+                volume.device.write_blocks(lba, &info).await?;
+            }
+            volume.device.flush().await?;
+            Ok(())
+        })
+    }
+}
+
+// ルートディレクトリにはエントリがないので、エントリの位置から作る番号と重ならない1を使う
+const ROOT_INO: u64 = 1;
+
+struct FatInode {
+    volume: Arc<Volume>,
+    ino: u64,
+    file_type: FileType,
+    // 親ディレクトリの先頭クラスタと、その中の短い名前のエントリの位置(ルートはNone)
+    entry: Option<(u32, u64)>,
+    state: Mutex<NodeState>,
+}
+
+#[derive(Clone, Copy)]
+struct NodeState {
+    // 空のファイルは0
+    first_cluster: u32,
+    size: u32,
+}
+
+impl FatInode {
+    fn state(&self) -> NodeState {
+        *self.state.lock()
+    }
+
+    async fn chain(&self) -> Result<Vec<u32>, FsError> {
+        self.volume.chain(self.state().first_cluster).await
+    }
+
+    // エントリに対応するinodeを返す。すでにあれば同じものを返す
+    fn inode_for(&self, entry: &RawEntry) -> Arc<FatInode> {
+        let mut inodes = self.volume.inodes.lock();
+        if let Some(inode) = inodes.get(&entry.ino).and_then(Weak::upgrade) {
+            return inode;
+        }
+        let inode = Arc::new(FatInode {
+            volume: self.volume.clone(),
+            ino: entry.ino,
+            file_type: entry.file_type(),
+            entry: Some((self.state().first_cluster, entry.offset)),
+            state: Mutex::new(NodeState {
+                first_cluster: entry.first_cluster,
+                size: entry.size,
+            }),
+        });
+        inodes.retain(|_, inode| inode.strong_count() > 0);
+        inodes.insert(entry.ino, Arc::downgrade(&inode));
+        inode
+    }
+
+    // 親ディレクトリのエントリの先頭クラスタと大きさを更新する
+    async fn update_entry(&self) -> Result<(), FsError> {
+        let (dir_cluster, offset) = match self.entry {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        let state = self.state();
+        let chain = self.volume.chain(dir_cluster).await?;
+        let mut entry = [0; DIR_ENTRY_SIZE];
+        self.volume.read_chain(&chain, offset, &mut entry).await?;
+        set_first_cluster(&mut entry, state.first_cluster);
+        if self.file_type == FileType::File {
+            write_u32(&mut entry, 28, state.size);
+            entry[11] |= ATTR_ARCHIVE;
+        }
+        self.volume.write_chain(&chain, offset, &entry).await
+    }
+
+    // チェーンを`clusters`個以上にのばす
+    async fn extend_chain(&self, clusters: usize) -> Result<Vec<u32>, FsError> {
+        let mut chain = self.chain().await?;
+        while chain.len() < clusters {
+            let cluster = self.volume.allocate_cluster(chain.last().copied()).await?;
+            if chain.is_empty() {
+                self.state.lock().first_cluster = cluster;
+            }
+            chain.push(cluster);
+        }
+        Ok(chain)
+    }
+
+    // ファイルに書き込み、必要ならのばす。エントリの更新は呼び出し側で行う
+    async fn write_data(&self, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        let end = offset + buf.len() as u64;
+        let cluster_size = self.volume.cluster_size() as u64;
+        let chain = self
+            .extend_chain(((end + cluster_size - 1) / cluster_size) as usize)
+            .await?;
+        self.volume.write_chain(&chain, offset, buf).await?;
+        let mut state = self.state.lock();
+        state.size = state.size.max(end as u32);
+        Ok(())
+    }
+
+    // `from`から`to`までを0で埋める
+    async fn write_zeros(&self, from: u64, to: u64) -> Result<(), FsError> {
+        let zero = vec![0; self.volume.bytes_per_sector];
+        let mut pos = from;
+        while pos < to {
+            let n = (to - pos).min(zero.len() as u64) as usize;
+            self.write_data(pos, &zero[..n]).await?;
+            pos += n as u64;
+        }
+        Ok(())
+    }
+
+    fn check_file(&self) -> Result<(), FsError> {
+        match self.file_type {
+            FileType::Directory => Err(FsError::IsADirectory),
+            _ => Ok(()),
+        }
+    }
+
+    async fn entries(&self) -> Result<Vec<RawEntry>, FsError> {
+        if self.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let chain = self.chain().await?;
+        self.volume.read_dir(&chain).await
+    }
+
+    async fn find(&self, name: &str) -> Result<RawEntry, FsError> {
+        self.entries()
+            .await?
+            .into_iter()
+            .find(|entry| entry.matches(name))
+            .ok_or(FsError::NotFound)
+    }
+
+    // ディレクトリに`count`個の連続した空きエントリを探し、なければクラスタを足す。
+    // ディレクトリのチェーンと、空きの先頭の位置を返す
+    async fn reserve_slots(&self, count: usize) -> Result<(Vec<u32>, u64), FsError> {
+        let volume = &self.volume;
+        let cluster_size = volume.cluster_size();
+        let mut chain = self.chain().await?;
+        let mut data = vec![0; cluster_size];
+        let mut run = 0;
+        let mut index = 0;
+        // 空きはクラスタの境目をまたいでもよいので、runはクラスタをまたいで数える
+        loop {
+            if index < chain.len() {
+                volume.read_cluster(chain[index], &mut data).await?;
+            } else {
+                // 新しいクラスタは0で埋められているので、全て空きになる
+                let cluster = volume.allocate_cluster(chain.last().copied()).await?;
+                chain.push(cluster);
+                data.iter_mut().for_each(|byte| *byte = 0);
+            }
+            for (i, entry) in data.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+                if entry[0] == ENTRY_END || entry[0] == ENTRY_DELETED {
+                    run += 1;
+                    if run == count {
+                        let end = index * cluster_size + (i + 1) * DIR_ENTRY_SIZE;
+                        return Ok((chain, (end - count * DIR_ENTRY_SIZE) as u64));
+                    }
+                } else {
+                    run = 0;
+                }
+            }
+            index += 1;
+        }
+    }
+
+    async fn create_entry(
+        &self,
+        name: &str,
+        file_type: FileType,
+    ) -> Result<Arc<FatInode>, FsError> {
+        check_fat_name(name)?;
+        let entries = self.entries().await?;
+        if entries.iter().any(|entry| entry.matches(name)) {
+            return Err(FsError::AlreadyExists);
+        }
+        let (short_name, needs_lfn) = match exact_short_name(name) {
+            Some(short_name) => (short_name, false),
+            None => {
+                let existing: Vec<[u8; 11]> =
+                    entries.iter().map(|entry| entry.short_name).collect();
+                (generate_short_name(name, &existing)?, true)
+            }
+        };
+
+        let volume = &self.volume;
+        let (attr, first_cluster) = match file_type {
+            FileType::File => (ATTR_ARCHIVE, 0),
+            FileType::Directory => {
+                // "."と".."を作る。ルートを指す".."のクラスタは0にする
+                let cluster = volume.allocate_cluster(None).await?;
+                let parent = match self.entry {
+                    Some(_) => self.state().first_cluster,
+                    None => 0,
+                };
+                let mut dots = [0; DIR_ENTRY_SIZE * 2];
+                dots[..DIR_ENTRY_SIZE].copy_from_slice(&short_entry(
+                    b".          ",
+                    ATTR_DIRECTORY,
+                    cluster,
+                    0,
+                ));
+                dots[DIR_ENTRY_SIZE..].copy_from_slice(&short_entry(
+                    b"..         ",
+                    ATTR_DIRECTORY,
+                    parent,
+                    0,
+                ));
+                volume.write_chain(&[cluster], 0, &dots).await?;
+                (ATTR_DIRECTORY, cluster)
+            }
+            FileType::Symlink => return Err(FsError::Unsupported),
+        };
+
+        let mut raw = Vec::new();
+        if needs_lfn {
+            for entry in lfn_entries(name, short_name_checksum(&short_name)) {
+                raw.extend_from_slice(&entry);
+            }
+        }
+        raw.extend_from_slice(&short_entry(&short_name, attr, first_cluster, 0));
+        let slots = raw.len() / DIR_ENTRY_SIZE;
+        let result = match self.reserve_slots(slots).await {
+            Ok((chain, start)) => volume.write_chain(&chain, start, &raw).await.map(|_| start),
+            Err(err) => Err(err),
+        };
+        let start = match result {
+            Ok(start) => start,
+            Err(err) => {
+                // 作ったディレクトリのクラスタを戻す
+                if first_cluster != 0 {
+                    volume.free_chain(&[first_cluster]).await?;
+                }
+                return Err(err);
+            }
+        };
+
+        let offset = start + ((slots - 1) * DIR_ENTRY_SIZE) as u64;
+        let chain = self.chain().await?;
+        let (lba, within) = volume.locate(&chain, offset)?;
+        Ok(self.inode_for(&RawEntry {
+            name: String::from(name),
+            short_name,
+            attr,
+            first_cluster,
+            size: 0,
+            start,
+            offset,
+            ino: (lba * volume.bytes_per_sector as u64 + within as u64) / DIR_ENTRY_SIZE as u64,
+        }))
+    }
+
+    async fn remove_entry(&self, name: &str) -> Result<(), FsError> {
+        let volume = &self.volume;
+        let entry = self.find(name).await?;
+        // 開いているinodeがあれば、その情報の方が新しい
+        let inode = self.inode_for(&entry);
+        let chain = inode.chain().await?;
+        if inode.file_type == FileType::Directory && !volume.read_dir(&chain).await?.is_empty() {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+
+        let dir_chain = self.chain().await?;
+        let mut offset = entry.start;
+        while offset <= entry.offset {
+            volume
+                .write_chain(&dir_chain, offset, &[ENTRY_DELETED])
+                .await?;
+            offset += DIR_ENTRY_SIZE as u64;
+        }
+        // 開いたままのinodeからは空のファイルに見えるようにする
+        *inode.state.lock() = NodeState {
+            first_cluster: 0,
+            size: 0,
+        };
+        volume.inodes.lock().remove(&entry.ino);
+        volume.free_chain(&chain).await
+    }
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            ino: self.ino,
+            file_type: self.file_type,
+            size: match self.file_type {
+                FileType::Directory => 0,
+                _ => u64::from(self.state().size),
+            },
+        }
+    }
+
+    fn read_at<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            self.check_file()?;
+            let size = u64::from(self.state().size);
+            if offset >= size {
+                return Ok(0);
+            }
+            let n = buf.len().min((size - offset) as usize);
+            let chain = self.chain().await?;
+            self.volume
+                .read_chain(&chain, offset, &mut buf[..n])
+                .await?;
+            Ok(n)
+        })
+    }
+
+    fn write_at<'a>(&'a self, offset: u64, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            self.check_file()?;
+            // FAT32のファイルは4GiB未満
+            offset
+                .checked_add(buf.len() as u64)
+                .filter(|&end| end <= u64::from(u32::MAX))
+                .ok_or(FsError::NoSpace)?;
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            let size = u64::from(self.state().size);
+            let result = async {
+                if offset > size {
+                    self.write_zeros(size, offset).await?;
+                }
+                self.write_data(offset, buf).await
+            }
+            .await;
+            // 途中で失敗しても、確保したクラスタと大きさはエントリに反映する
+            let updated = self.update_entry().await;
+            result?;
+            updated?;
+            Ok(buf.len())
+        })
+    }
+
+    fn truncate(&self, size: u64) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            self.check_file()?;
+            if size > u64::from(u32::MAX) {
+                return Err(FsError::NoSpace);
+            }
+            let old = u64::from(self.state().size);
+            if size > old {
+                let result = self.write_zeros(old, size).await;
+                self.update_entry().await?;
+                return result;
+            }
+
+            let cluster_size = self.volume.cluster_size() as u64;
+            let keep = ((size + cluster_size - 1) / cluster_size) as usize;
+            let chain = self.chain().await?;
+            if keep < chain.len() {
+                match keep {
+                    0 => self.state.lock().first_cluster = 0,
+                    _ => self.volume.set_fat_entry(chain[keep - 1], FAT_EOC).await?,
+                }
+            }
+            self.state.lock().size = size as u32;
+            self.update_entry().await?;
+            if keep < chain.len() {
+                self.volume.free_chain(&chain[keep..]).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            let entry = self.find(name).await?;
+            Ok(self.inode_for(&entry) as Arc<dyn Inode>)
+        })
+    }
+
+    fn create<'a>(&'a self, name: &'a str, file_type: FileType) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move { Ok(self.create_entry(name, file_type).await? as Arc<dyn Inode>) })
+    }
+
+    fn unlink<'a>(&'a self, name: &'a str) -> FsFuture<'a, ()> {
+        Box::pin(self.remove_entry(name))
+    }
+
+    fn readdir(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async move {
+            Ok(self
+                .entries()
+                .await?
+                .into_iter()
+                .map(|entry| DirEntry {
+                    file_type: entry.file_type(),
+                    ino: entry.ino,
+                    name: entry.name,
+                })
+                .collect())
+        })
+    }
+}
+
+/// `device`全体をFAT32でフォーマットする。
+///
+/// 小さいデバイスでも作れるように、FAT32のクラスタ数の下限(65525)は確認しない。
+pub async fn format(device: &dyn BlockDevice, label: &str) -> Result<(), FsError> {
+    let sector_size = device.block_size();
+    let total_sectors = device.block_count();
+    if !sector_size.is_power_of_two() || !(512..=4096).contains(&sector_size) {
+        return Err(FsError::Unsupported);
+    }
+    if total_sectors > u64::from(u32::MAX) {
+        return Err(FsError::Unsupported);
+    }
+    let sectors_per_cluster: u64 = match total_sectors * sector_size as u64 {
+        size if size < 260 << 20 => 1,
+        size if size < 8 << 30 => 8,
+        size if size < 16 << 30 => 16,
+        _ => 32,
+    };
+    // 予約領域はブートセクタ(0)、FSInfo(1)、そのバックアップ(6, 7)を含む
+    let reserved_sectors = 32u64;
+    let fat_count = 2u64;
+    // FATの大きさはクラスタ数に依存するので、収まるまで繰り返す
+    let mut fat_sectors = 1u64;
+    let cluster_count = loop {
+        let used = reserved_sectors + fat_count * fat_sectors;
+        if used >= total_sectors {
+            return Err(FsError::NoSpace);
+        }
+        let clusters = (total_sectors - used) / sectors_per_cluster;
+        let needed = ((clusters + 2) * 4 + sector_size as u64 - 1) / sector_size as u64;
+        if needed <= fat_sectors {
+            break clusters as u32;
+        }
+        fat_sectors = needed;
+    };
+    if cluster_count == 0 {
+        return Err(FsError::NoSpace);
+    }
+
+    let mut boot = vec![0; sector_size];
+    boot[0..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"MY_OS   ");
+    write_u16(&mut boot, 11, sector_size as u16);
+    boot[13] = sectors_per_cluster as u8;
+    write_u16(&mut boot, 14, reserved_sectors as u16);
+    boot[16] = fat_count as u8;
+    // 固定ディスク
+    boot[21] = 0xf8;
+    write_u16(&mut boot, 24, 32);
+    write_u16(&mut boot, 26, 64);
+    write_u32(&mut boot, 32, total_sectors as u32);
+    write_u32(&mut boot, 36, fat_sectors as u32);
+    write_u32(&mut boot, 44, FIRST_CLUSTER);
+    write_u16(&mut boot, 48, 1);
+    write_u16(&mut boot, 50, 6);
+    boot[64] = 0x80;
+    boot[66] = 0x29;
+    write_u32(&mut boot, 67, 0x1234_5678);
+    let mut volume_label = [b' '; 11];
+    for (dst, src) in volume_label.iter_mut().zip(label.bytes()) {
+        *dst = src.to_ascii_uppercase();
+    }
+    boot[71..82].copy_from_slice(&volume_label);
+    boot[82..90].copy_from_slice(b"FAT32   ");
+    boot[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+    let mut info = vec![0; sector_size];
+    write_u32(&mut info, 0, FSINFO_LEAD_SIGNATURE);
+    write_u32(&mut info, 484, FSINFO_STRUCT_SIGNATURE);
+    // ルートディレクトリに1つ使う
+    write_u32(&mut info, 488, cluster_count - 1);
+    write_u32(&mut info, 492, FIRST_CLUSTER + 1);
+    write_u32(&mut info, 508, FSINFO_TRAIL_SIGNATURE);
+
+    let zero = vec![0; sector_size];
+    for lba in 0..reserved_sectors {
+        let sector = match lba {
+            0 | 6 => &boot,
+            1 | 7 => &info,
+            _ => &zero,
+        };
+        device.write_blocks(lba, sector).await?;
+    }
+
+    // FATの先頭の2つは予約で、3つ目はルートディレクトリ
+    let mut first_fat_sector = zero.clone();
+    write_u32(&mut first_fat_sector, 0, 0x0fff_ff00 | u32::from(boot[21]));
+    write_u32(&mut first_fat_sector, 4, FAT_EOC);
+    write_u32(&mut first_fat_sector, 8, FAT_EOC);
+    for fat in 0..fat_count {
+        let start = reserved_sectors + fat * fat_sectors;
+        for i in 0..fat_sectors {
+            let sector = if i == 0 { &first_fat_sector } else { &zero };
+            device.write_blocks(start + i, sector).await?;
+        }
+    }
+
+    // ルートディレクトリのクラスタを空にする
+    let data_start = reserved_sectors + fat_count * fat_sectors;
+    for i in 0..sectors_per_cluster {
+        device.write_blocks(data_start + i, &zero).await?;
+    }
+    device.flush().await?;
+    Ok(())
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
@@ -0,0 +1,242 @@
+use super::{path, FileType, FsError};
+use crate::println;
+use crate::task::simple_executor::block_on;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::str;
+
+/// カーネルに埋め込んだinitramfs(build.rsがinitramfsディレクトリからnewc形式で作る)
+///
+/// bootloader 0.9はモジュールを渡せないので、イメージに直接埋め込んでいる
+pub static ARCHIVE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initramfs.cpio"));
+
+// ustarのヘッダとデータはこの単位で並ぶ
+const TAR_BLOCK: usize = 512;
+const CPIO_HEADER: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+// cpioのmodeのファイルの種類
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// アーカイブの形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Ustar,
+    /// newc形式のcpio(070701と、チェックサム付きの070702)
+    Cpio,
+}
+
+/// アーカイブのエントリの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// シンボリックリンクやデバイスなど、展開しないもの
+    Other,
+}
+
+/// アーカイブの1つのエントリ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry<'a> {
+    /// アーカイブ内のパス(先頭の"./"などはそのまま)
+    pub path: String,
+    pub kind: EntryKind,
+    pub data: &'a [u8],
+}
+
+/// 展開した結果
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub files: usize,
+    pub directories: usize,
+    /// 展開しなかったエントリの数
+    pub skipped: usize,
+}
+
+/// 先頭のマジックからアーカイブの形式を判定する。
+pub fn detect(archive: &[u8]) -> Option<Format> {
+    if archive.starts_with(b"070701") || archive.starts_with(b"070702") {
+        Some(Format::Cpio)
+    } else if archive.len() >= TAR_BLOCK && &archive[257..262] == b"ustar" {
+        Some(Format::Ustar)
+    } else {
+        None
+    }
+}
+
+/// アーカイブのエントリの一覧を返す。空のアーカイブは空のVecになる
+pub fn entries(archive: &[u8]) -> Result<Vec<Entry<'_>>, FsError> {
+    if archive.is_empty() {
+        return Ok(Vec::new());
+    }
+    match detect(archive) {
+        Some(Format::Cpio) => cpio_entries(archive),
+        Some(Format::Ustar) => tar_entries(archive),
+        None => Err(FsError::Corrupted),
+    }
+}
+
+/// アーカイブを`dest`ディレクトリの下に展開する。
+///
+/// 親ディレクトリがアーカイブになくても作り、すでにあるファイルは上書きする。
+pub async fn unpack(archive: &[u8], dest: &str) -> Result<Summary, FsError> {
+    let dest = path::components(dest)?;
+    let mut summary = Summary::default();
+    for entry in entries(archive)? {
+        let mut components = dest.clone();
+        components.extend(path::components(&entry.path)?);
+        // "."のように展開先そのものを指すエントリ
+        if components.len() == dest.len() {
+            continue;
+        }
+        match entry.kind {
+            EntryKind::Directory => {
+                create_dirs(&components).await?;
+                summary.directories += 1;
+            }
+            EntryKind::File => {
+                create_dirs(&components[..components.len() - 1]).await?;
+                super::write_file(&path::join(&components), entry.data).await?;
+                summary.files += 1;
+            }
+            EntryKind::Other => summary.skipped += 1,
+        }
+    }
+    Ok(summary)
+}
+
+/// 埋め込んだinitramfsをルートに展開する。
+pub fn init() {
+    match block_on(unpack(ARCHIVE, "/")) {
+        Ok(summary) => println!(
+            "initramfs: {} files, {} directories",
+            summary.files, summary.directories
+        ),
+        Err(err) => println!("initramfs: failed to unpack: {}", err),
+    }
+}
+
+// 途中のディレクトリも含めて作る(すでにあれば何もしない)
+async fn create_dirs(components: &[&str]) -> Result<(), FsError> {
+    for depth in 1..=components.len() {
+        let path = path::join(&components[..depth]);
+        match super::stat(&path).await {
+            Ok(metadata) if metadata.file_type == FileType::Directory => {}
+            Ok(_) => return Err(FsError::NotADirectory),
+            Err(FsError::NotFound) => super::create_dir(&path).await?,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+fn cpio_entries(archive: &[u8]) -> Result<Vec<Entry<'_>>, FsError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let header = archive
+            .get(offset..offset + CPIO_HEADER)
+            .ok_or(FsError::Corrupted)?;
+        if !header.starts_with(b"070701") && !header.starts_with(b"070702") {
+            return Err(FsError::Corrupted);
+        }
+        // マジックの後に8桁の16進数のフィールドが13個並ぶ
+        let field = |index: usize| parse_number(&header[6 + index * 8..][..8], 16);
+        let mode = field(1)?;
+        let size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        // 名前とデータはそれぞれ4バイト境界まで埋められている
+        let name_start = offset + CPIO_HEADER;
+        let name = archive
+            .get(name_start..name_start + name_size)
+            .ok_or(FsError::Corrupted)?;
+        let name = parse_str(name)?;
+        let data_start = align_up(name_start + name_size, 4);
+        let data = archive
+            .get(data_start..data_start + size)
+            .ok_or(FsError::Corrupted)?;
+        offset = align_up(data_start + size, 4);
+
+        if name == CPIO_TRAILER {
+            return Ok(entries);
+        }
+        let kind = match mode & S_IFMT {
+            S_IFREG => EntryKind::File,
+            S_IFDIR => EntryKind::Directory,
+            _ => EntryKind::Other,
+        };
+        entries.push(Entry {
+            path: String::from(name),
+            kind,
+            data,
+        });
+    }
+}
+
+fn tar_entries(archive: &[u8]) -> Result<Vec<Entry<'_>>, FsError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    // 終わりは0で埋めたブロックで示される(アーカイブの終わりでも終わりとする)
+    while let Some(header) = archive.get(offset..offset + TAR_BLOCK) {
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        if &header[257..262] != b"ustar" {
+            return Err(FsError::Corrupted);
+        }
+        // チェックサムはチェックサムのフィールドを空白として計算したバイトの和
+        let checksum = parse_number(&header[148..156], 8)?;
+        let sum: u32 = header[..148]
+            .iter()
+            .chain(&header[156..])
+            .map(|&b| u32::from(b))
+            .sum::<u32>()
+            + 8 * u32::from(b' ');
+        if sum != checksum {
+            return Err(FsError::Corrupted);
+        }
+
+        let size = parse_number(&header[124..136], 8)? as usize;
+        let data_start = offset + TAR_BLOCK;
+        let data = archive
+            .get(data_start..data_start + size)
+            .ok_or(FsError::Corrupted)?;
+        offset = data_start + align_up(size, TAR_BLOCK);
+
+        let name = parse_str(&header[0..100])?;
+        let prefix = parse_str(&header[345..500])?;
+        let path = if prefix.is_empty() {
+            String::from(name)
+        } else {
+            path::join(&[prefix, name])
+        };
+        let kind = match header[156] {
+            b'0' | 0 => EntryKind::File,
+            b'5' => EntryKind::Directory,
+            _ => EntryKind::Other,
+        };
+        entries.push(Entry { path, kind, data });
+    }
+    Ok(entries)
+}
+
+// NUL(と空白)で終わる数字の文字列を読む
+fn parse_number(field: &[u8], radix: u32) -> Result<u32, FsError> {
+    let digits = parse_str(field)?.trim_matches(' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u32::from_str_radix(digits, radix).map_err(|_| FsError::Corrupted)
+}
+
+// NULで終わる文字列を読む
+fn parse_str(field: &[u8]) -> Result<&str, FsError> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..len]).map_err(|_| FsError::Corrupted)
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) / align * align
+}
@@ -0,0 +1,581 @@
+use crate::block::{BlockDevice, BlockError};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::Future;
+use core::ops::BitOr;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+pub mod ext2;
+pub mod fat;
+pub mod initramfs;
+pub mod path;
+pub mod tmpfs;
+
+/// ファイルシステムの操作が返すfuture
+/// ディスク上のファイルシステムはブロックデバイスを読み書きするので、BlockDeviceと同じくasyncにしている
+pub type FsFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, FsError>> + 'a>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    /// ファイルやディレクトリが存在しない
+    NotFound,
+    /// 同じ名前のエントリがすでにある
+    AlreadyExists,
+    /// ディレクトリでないものをディレクトリとして使った
+    NotADirectory,
+    /// ディレクトリに対してファイルの操作をした
+    IsADirectory,
+    /// 空でないディレクトリを削除しようとした
+    DirectoryNotEmpty,
+    /// パスや名前が正しくない
+    InvalidPath,
+    /// 名前が長すぎる
+    NameTooLong,
+    /// 引数が正しくない(負のオフセットへのシークなど)
+    InvalidArgument,
+    /// 読み込み専用のファイルシステム、または書き込み用に開いていないファイル
+    ReadOnly,
+    /// 読み込み用に開いていないファイル
+    WriteOnly,
+    /// 開かれていないファイルディスクリプタ
+    BadFileDescriptor,
+    /// マウントポイントなど、使用中のものを操作しようとした
+    Busy,
+    /// 空き領域がない
+    NoSpace,
+    /// ファイルシステムが対応していない操作
+    Unsupported,
+    /// ディスク上のデータが壊れている
+    Corrupted,
+    /// ブロックデバイスのエラー
+    Io(BlockError),
+}
+
+// シェルで表示するのでASCIIのメッセージにしている
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            FsError::NotFound => "no such file or directory",
+            FsError::AlreadyExists => "file exists",
+            FsError::NotADirectory => "not a directory",
+            FsError::IsADirectory => "is a directory",
+            FsError::DirectoryNotEmpty => "directory not empty",
+            FsError::InvalidPath => "invalid path",
+            FsError::NameTooLong => "file name too long",
+            FsError::InvalidArgument => "invalid argument",
+            FsError::ReadOnly => "read-only file system",
+            FsError::WriteOnly => "file not open for reading",
+            FsError::BadFileDescriptor => "bad file descriptor",
+            FsError::Busy => "device or resource busy",
+            FsError::NoSpace => "no space left on device",
+            FsError::Unsupported => "operation not supported",
+            FsError::Corrupted => "file system corrupted",
+            FsError::Io(err) => return write!(f, "I/O error: {:?}", err),
+        };
+        f.write_str(message)
+    }
+}
+
+impl From<BlockError> for FsError {
+    fn from(err: BlockError) -> FsError {
+        FsError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// `stat`で返すファイルの情報
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// ファイルシステム内で一意なinode番号
+    pub ino: u64,
+    pub file_type: FileType,
+    /// バイト数
+    pub size: u64,
+}
+
+/// `readdir`で返すディレクトリのエントリ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub ino: u64,
+    pub file_type: FileType,
+}
+
+/// ファイルシステム上のファイルやディレクトリ
+///
+/// ファイルにはread_at/write_at/truncateを、ディレクトリにはlookup/create/unlink/readdirを実装する。
+/// 実装しない操作はデフォルトでエラーを返す。
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    /// `offset`から読み込み、読んだバイト数を返す。ファイルの終わりでは0
+    fn read_at<'a>(&'a self, _offset: u64, _buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async { Err(FsError::IsADirectory) })
+    }
+
+    /// `offset`に書き込み、書いたバイト数を返す。ファイルの終わりを超えれば大きくなる
+    fn write_at<'a>(&'a self, _offset: u64, _buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async { Err(FsError::IsADirectory) })
+    }
+
+    /// ファイルの大きさを`size`にする。
+    fn truncate(&self, _size: u64) -> FsFuture<'_, ()> {
+        Box::pin(async { Err(FsError::IsADirectory) })
+    }
+
+    /// ディレクトリから`name`のエントリを探す。
+    fn lookup<'a>(&'a self, _name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async { Err(FsError::NotADirectory) })
+    }
+
+    /// ディレクトリに`name`のファイルかディレクトリを作る。
+    fn create<'a>(&'a self, _name: &'a str, _file_type: FileType) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async { Err(FsError::NotADirectory) })
+    }
+
+    /// ディレクトリから`name`のエントリを削除する。ディレクトリは空の場合のみ削除できる
+    fn unlink<'a>(&'a self, _name: &'a str) -> FsFuture<'a, ()> {
+        Box::pin(async { Err(FsError::NotADirectory) })
+    }
+
+    /// ディレクトリのエントリの一覧を返す。`.`と`..`は含まない
+    fn readdir(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async { Err(FsError::NotADirectory) })
+    }
+}
+
+/// マウントできるファイルシステム
+pub trait FileSystem: Send + Sync {
+    /// `mount`などで表示する種類の名前("tmpfs"など)
+    fn name(&self) -> &str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// 書き込みをデバイスに反映する。
+    fn sync(&self) -> FsFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+struct Mount {
+    /// 正規化したマウントポイントのパス
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+// マウントされているファイルシステム
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// 空のtmpfsをルートにマウントする。
+pub fn init() {
+    mount_root(Arc::new(tmpfs::TmpFs::new()))
+        .expect("ルートファイルシステムのマウントに失敗しました");
+}
+
+/// ルートにファイルシステムをマウントする。ルートは一度しかマウントできない
+pub fn mount_root(fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == "/") {
+        return Err(FsError::Busy);
+    }
+    mounts.push(Mount {
+        path: String::from("/"),
+        fs,
+    });
+    Ok(())
+}
+
+/// 既存のディレクトリ`path`にファイルシステムをマウントする。
+pub async fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let path = path::normalize(path)?;
+    if resolve(&path).await?.metadata().file_type != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(FsError::Busy);
+    }
+    mounts.push(Mount { path, fs });
+    Ok(())
+}
+
+/// ブロックデバイス上のファイルシステムの種類を判別して開く。
+///
+/// 対応しているファイルシステムを順に試し、どれでもなければ`Unsupported`を返す。
+pub async fn probe(device: Arc<dyn BlockDevice>) -> Result<Arc<dyn FileSystem>, FsError> {
+    match fat::FatFs::new(device.clone()).await {
+        Ok(fs) => return Ok(Arc::new(fs)),
+        Err(FsError::Unsupported) => {}
+        Err(err) => return Err(err),
+    }
+    Ok(Arc::new(ext2::Ext2Fs::new(device).await?))
+}
+
+/// ブロックデバイス上のファイルシステムを`path`にマウントする。
+pub async fn mount_device(path: &str, device: Arc<dyn BlockDevice>) -> Result<(), FsError> {
+    let fs = probe(device).await?;
+    mount(path, fs).await
+}
+
+/// `path`にマウントされているファイルシステムを外す。下にマウントがあれば外せない
+pub async fn unmount(path: &str) -> Result<(), FsError> {
+    let path = path::normalize(path)?;
+    let fs = {
+        let mounts = MOUNTS.lock();
+        let prefix = if path == "/" {
+            String::from("/")
+        } else {
+            path.clone() + "/"
+        };
+        if mounts
+            .iter()
+            .any(|mount| mount.path != path && mount.path.starts_with(&prefix))
+        {
+            return Err(FsError::Busy);
+        }
+        mounts
+            .iter()
+            .find(|mount| mount.path == path)
+            .ok_or(FsError::InvalidArgument)?
+            .fs
+            .clone()
+    };
+    fs.sync().await?;
+    MOUNTS.lock().retain(|mount| mount.path != path);
+    Ok(())
+}
+
+/// マウントポイントとファイルシステムの種類の一覧を返す。
+pub fn mounts() -> Vec<(String, String)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|mount| (mount.path.clone(), String::from(mount.fs.name())))
+        .collect()
+}
+
+/// 全てのファイルシステムの書き込みをデバイスに反映する。
+pub async fn sync() -> Result<(), FsError> {
+    let filesystems: Vec<Arc<dyn FileSystem>> =
+        MOUNTS.lock().iter().map(|mount| mount.fs.clone()).collect();
+    for fs in filesystems {
+        fs.sync().await?;
+    }
+    Ok(())
+}
+
+// 名前の列に対して最も長く一致するマウントを探し、そのファイルシステムと残りの名前の数を返す
+fn find_mount(components: &[&str]) -> Result<(Arc<dyn FileSystem>, usize), FsError> {
+    let mounts = MOUNTS.lock();
+    mounts
+        .iter()
+        .filter_map(|mount| {
+            let mount_components = path::components(&mount.path).ok()?;
+            if components.starts_with(&mount_components) {
+                Some((mount.fs.clone(), mount_components.len()))
+            } else {
+                None
+            }
+        })
+        .max_by_key(|&(_, depth)| depth)
+        .ok_or(FsError::NotFound)
+}
+
+fn is_mount_point(components: &[&str]) -> bool {
+    let path = path::join(components);
+    MOUNTS.lock().iter().any(|mount| mount.path == path)
+}
+
+async fn resolve_components(components: &[&str]) -> Result<Arc<dyn Inode>, FsError> {
+    let (fs, depth) = find_mount(components)?;
+    let mut inode = fs.root();
+    for name in &components[depth..] {
+        let next = inode.lookup(name).await?;
+        inode = next;
+    }
+    Ok(inode)
+}
+
+/// パスを解決してinodeを返す。
+pub async fn resolve(path: &str) -> Result<Arc<dyn Inode>, FsError> {
+    resolve_components(&path::components(path)?).await
+}
+
+/// `path`の情報を返す。
+pub async fn stat(path: &str) -> Result<Metadata, FsError> {
+    Ok(resolve(path).await?.metadata())
+}
+
+/// ディレクトリ`path`のエントリの一覧を返す。
+pub async fn readdir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    resolve(path).await?.readdir().await
+}
+
+/// `path`にファイルかディレクトリを作る。
+pub async fn create(path: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+    let (parent, name) = path::split_last(path)?;
+    resolve_components(&parent)
+        .await?
+        .create(name, file_type)
+        .await
+}
+
+/// ディレクトリを作る。
+pub async fn create_dir(path: &str) -> Result<(), FsError> {
+    create(path, FileType::Directory).await.map(|_| ())
+}
+
+/// ファイルか空のディレクトリを削除する。マウントポイントは削除できない
+pub async fn remove(path: &str) -> Result<(), FsError> {
+    let (parent, name) = path::split_last(path)?;
+    let mut components = parent.clone();
+    components.push(name);
+    if is_mount_point(&components) {
+        return Err(FsError::Busy);
+    }
+    resolve_components(&parent).await?.unlink(name).await
+}
+
+// read_fileで一度に読むバイト数
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// ファイルの中身を全て読む。
+pub async fn read_file(path: &str) -> Result<Vec<u8>, FsError> {
+    let inode = resolve(path).await?;
+    let metadata = inode.metadata();
+    if metadata.file_type == FileType::Directory {
+        return Err(FsError::IsADirectory);
+    }
+    // ディスク上のサイズは信用できないので、少しずつ読んでヒープが足りなければNoSpaceにする
+    let mut data = Vec::new();
+    loop {
+        let done = data.len();
+        data.try_reserve(READ_CHUNK_SIZE)
+            .map_err(|_| FsError::NoSpace)?;
+        data.resize(done + READ_CHUNK_SIZE, 0);
+        let n = inode.read_at(done as u64, &mut data[done..]).await?;
+        data.truncate(done + n);
+        if n == 0 {
+            return Ok(data);
+        }
+    }
+}
+
+/// ファイルを`data`で置き換える。ファイルがなければ作る
+pub async fn write_file(path: &str, data: &[u8]) -> Result<(), FsError> {
+    let file = open(
+        path,
+        OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+    )
+    .await?;
+    let result = file.write_all(data).await;
+    close(file.fd())?;
+    result
+}
+
+/// `open`に渡すフラグ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(1 << 0);
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    /// なければ作る
+    pub const CREATE: OpenFlags = OpenFlags(1 << 2);
+    /// 開くときに大きさを0にする
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 3);
+    /// 書き込みは常にファイルの終わりに行う
+    pub const APPEND: OpenFlags = OpenFlags(1 << 4);
+    pub const READ_WRITE: OpenFlags = OpenFlags(Self::READ.0 | Self::WRITE.0);
+
+    pub fn contains(self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, other: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | other.0)
+    }
+}
+
+/// `seek`の基準
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// ファイルディスクリプタ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Fd(pub u32);
+
+/// 開いているファイル
+pub struct File {
+    fd: Fd,
+    inode: Arc<dyn Inode>,
+    flags: OpenFlags,
+    offset: AtomicU64,
+}
+
+// 開いているファイルの表(ファイルディスクリプタの番号で引く)
+static FILES: Mutex<Vec<Option<Arc<File>>>> = Mutex::new(Vec::new());
+
+impl File {
+    pub fn fd(&self) -> Fd {
+        self.fd
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    /// 現在の位置から読み込み、読んだバイト数だけ位置を進める。
+    pub async fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(FsError::WriteOnly);
+        }
+        let offset = self.offset.load(Ordering::Relaxed);
+        let n = self.inode.read_at(offset, buf).await?;
+        self.offset.store(offset + n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    /// 現在の位置(APPENDならファイルの終わり)に書き込み、書いたバイト数だけ位置を進める。
+    pub async fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::ReadOnly);
+        }
+        let offset = if self.flags.contains(OpenFlags::APPEND) {
+            self.inode.metadata().size
+        } else {
+            self.offset.load(Ordering::Relaxed)
+        };
+        let n = self.inode.write_at(offset, buf).await?;
+        self.offset.store(offset + n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    /// `buf`を全て書き込む。
+    pub async fn write_all(&self, buf: &[u8]) -> Result<(), FsError> {
+        let mut done = 0;
+        while done < buf.len() {
+            match self.write(&buf[done..]).await? {
+                0 => return Err(FsError::NoSpace),
+                n => done += n,
+            }
+        }
+        Ok(())
+    }
+
+    /// 位置を変更し、新しい位置を返す。
+    pub fn seek(&self, pos: SeekFrom) -> Result<u64, FsError> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::Current(delta) => (self.offset.load(Ordering::Relaxed), delta),
+            SeekFrom::End(delta) => (self.inode.metadata().size, delta),
+        };
+        let offset = if delta >= 0 {
+            base.checked_add(delta as u64)
+        } else {
+            base.checked_sub(delta.unsigned_abs())
+        }
+        .ok_or(FsError::InvalidArgument)?;
+        self.offset.store(offset, Ordering::Relaxed);
+        Ok(offset)
+    }
+
+    pub fn stat(&self) -> Metadata {
+        self.inode.metadata()
+    }
+
+    pub async fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        self.inode.readdir().await
+    }
+}
+
+/// ファイルを開いて、ファイルディスクリプタの表に登録する。
+pub async fn open(path: &str, flags: OpenFlags) -> Result<Arc<File>, FsError> {
+    let inode = match resolve(path).await {
+        Ok(inode) => inode,
+        Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            create(path, FileType::File).await?
+        }
+        Err(err) => return Err(err),
+    };
+    let is_dir = inode.metadata().file_type == FileType::Directory;
+    if is_dir && flags.contains(OpenFlags::WRITE) {
+        return Err(FsError::IsADirectory);
+    }
+    if flags.contains(OpenFlags::TRUNCATE) && flags.contains(OpenFlags::WRITE) {
+        inode.truncate(0).await?;
+    }
+
+    let mut files = FILES.lock();
+    // 空いている最小の番号を使う
+    let index = match files.iter().position(|file| file.is_none()) {
+        Some(index) => index,
+        None => {
+            files.push(None);
+            files.len() - 1
+        }
+    };
+    let file = Arc::new(File {
+        fd: Fd(index as u32),
+        inode,
+        flags,
+        offset: AtomicU64::new(0),
+    });
+    files[index] = Some(file.clone());
+    Ok(file)
+}
+
+/// ファイルディスクリプタに対応する開いているファイルを返す。
+pub fn get(fd: Fd) -> Result<Arc<File>, FsError> {
+    FILES
+        .lock()
+        .get(fd.0 as usize)
+        .cloned()
+        .flatten()
+        .ok_or(FsError::BadFileDescriptor)
+}
+
+pub async fn read(fd: Fd, buf: &mut [u8]) -> Result<usize, FsError> {
+    get(fd)?.read(buf).await
+}
+
+pub async fn write(fd: Fd, buf: &[u8]) -> Result<usize, FsError> {
+    get(fd)?.write(buf).await
+}
+
+pub fn seek(fd: Fd, pos: SeekFrom) -> Result<u64, FsError> {
+    get(fd)?.seek(pos)
+}
+
+pub fn fstat(fd: Fd) -> Result<Metadata, FsError> {
+    Ok(get(fd)?.stat())
+}
+
+/// ファイルディスクリプタを閉じる。
+pub fn close(fd: Fd) -> Result<(), FsError> {
+    FILES
+        .lock()
+        .get_mut(fd.0 as usize)
+        .and_then(Option::take)
+        .map(|_| ())
+        .ok_or(FsError::BadFileDescriptor)
+}
@@ -0,0 +1,63 @@
+use super::FsError;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// ファイル名の最大の長さ(バイト)
+pub const NAME_MAX: usize = 255;
+
+/// パスを正規化して、ルートからの名前の列にする。
+///
+/// パスは常にルートからのものとして扱う。`.`と空の要素は取り除き、`..`は1つ上に戻る
+/// (ルートの`..`はルート)。シンボリックリンクはたどらないので、`..`は字面どおりに解決する。
+pub fn components(path: &str) -> Result<Vec<&str>, FsError> {
+    let mut components = Vec::new();
+    for name in path.split('/') {
+        match name {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => {
+                check_name(name)?;
+                components.push(name);
+            }
+        }
+    }
+    Ok(components)
+}
+
+/// パスを正規化した文字列("/a/b"の形)にする。
+pub fn normalize(path: &str) -> Result<String, FsError> {
+    Ok(join(&components(path)?))
+}
+
+/// 名前の列を"/a/b"の形のパスにする。
+pub fn join(components: &[&str]) -> String {
+    if components.is_empty() {
+        return String::from("/");
+    }
+    let mut path = String::new();
+    for name in components {
+        path.push('/');
+        path.push_str(name);
+    }
+    path
+}
+
+/// 親ディレクトリの名前の列と、最後の名前に分ける。ルートには親がないのでエラー
+pub fn split_last<'a>(path: &'a str) -> Result<(Vec<&'a str>, &'a str), FsError> {
+    let mut components = components(path)?;
+    let name = components.pop().ok_or(FsError::InvalidPath)?;
+    Ok((components, name))
+}
+
+/// ディレクトリのエントリとして使える名前か確認する。
+pub fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\0') {
+        return Err(FsError::InvalidPath);
+    }
+    if name.len() > NAME_MAX {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
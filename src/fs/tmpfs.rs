@@ -0,0 +1,210 @@
+use super::{path, DirEntry, FileSystem, FileType, FsError, FsFuture, Inode, Metadata};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+// 1つのファイルの大きさの上限。ヒープが小さいので、1つのファイルで使い切らないようにする
+const MAX_FILE_SIZE: u64 = 64 * 1024;
+
+/// 全てをヒープ上に持つファイルシステム
+pub struct TmpFs {
+    root: Arc<TmpInode>,
+}
+
+impl TmpFs {
+    pub fn new() -> TmpFs {
+        let next_ino = Arc::new(AtomicU64::new(1));
+        TmpFs {
+            root: TmpInode::new(&next_ino, FileType::Directory),
+        }
+    }
+}
+
+impl Default for TmpFs {
+    fn default() -> Self {
+        TmpFs::new()
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+enum Data {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<TmpInode>>),
+}
+
+struct TmpInode {
+    ino: u64,
+    // 同じファイルシステムのinodeで共有する、次に割り当てるinode番号
+    next_ino: Arc<AtomicU64>,
+    data: Mutex<Data>,
+}
+
+impl TmpInode {
+    fn new(next_ino: &Arc<AtomicU64>, file_type: FileType) -> Arc<TmpInode> {
+        let data = match file_type {
+            FileType::Directory => Data::Directory(BTreeMap::new()),
+            _ => Data::File(Vec::new()),
+        };
+        Arc::new(TmpInode {
+            ino: next_ino.fetch_add(1, Ordering::Relaxed),
+            next_ino: next_ino.clone(),
+            data: Mutex::new(data),
+        })
+    }
+
+    fn file_type(&self) -> FileType {
+        match *self.data.lock() {
+            Data::File(_) => FileType::File,
+            Data::Directory(_) => FileType::Directory,
+        }
+    }
+}
+
+// ファイルの大きさを変える。上限を超えるか、ヒープが足りなければNoSpace
+fn resize(data: &mut Vec<u8>, size: u64) -> Result<(), FsError> {
+    if size > MAX_FILE_SIZE {
+        return Err(FsError::NoSpace);
+    }
+    let size = size as usize;
+    if size > data.len() {
+        data.try_reserve_exact(size - data.len())
+            .map_err(|_| FsError::NoSpace)?;
+    }
+    data.resize(size, 0);
+    Ok(())
+}
+
+// メモリ上の操作なので、futureは最初のpollで完了する
+impl Inode for TmpInode {
+    fn metadata(&self) -> Metadata {
+        let (file_type, size) = match &*self.data.lock() {
+            Data::File(data) => (FileType::File, data.len() as u64),
+            Data::Directory(entries) => (FileType::Directory, entries.len() as u64),
+        };
+        Metadata {
+            ino: self.ino,
+            file_type,
+            size,
+        }
+    }
+
+    fn read_at<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            match &*self.data.lock() {
+                Data::File(data) => {
+                    let start = (offset as usize).min(data.len());
+                    let n = buf.len().min(data.len() - start);
+                    buf[..n].copy_from_slice(&data[start..start + n]);
+                    Ok(n)
+                }
+                Data::Directory(_) => Err(FsError::IsADirectory),
+            }
+        })
+    }
+
+    fn write_at<'a>(&'a self, offset: u64, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            match &mut *self.data.lock() {
+                Data::File(data) => {
+                    let end = offset
+                        .checked_add(buf.len() as u64)
+                        .ok_or(FsError::InvalidArgument)?;
+                    if end > data.len() as u64 {
+                        resize(data, end)?;
+                    }
+                    data[offset as usize..end as usize].copy_from_slice(buf);
+                    Ok(buf.len())
+                }
+                Data::Directory(_) => Err(FsError::IsADirectory),
+            }
+        })
+    }
+
+    fn truncate(&self, size: u64) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            match &mut *self.data.lock() {
+                Data::File(data) => resize(data, size),
+                Data::Directory(_) => Err(FsError::IsADirectory),
+            }
+        })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            match &*self.data.lock() {
+                Data::Directory(entries) => match entries.get(name) {
+                    Some(inode) => Ok(inode.clone() as Arc<dyn Inode>),
+                    None => Err(FsError::NotFound),
+                },
+                Data::File(_) => Err(FsError::NotADirectory),
+            }
+        })
+    }
+
+    fn create<'a>(&'a self, name: &'a str, file_type: FileType) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            path::check_name(name)?;
+            if file_type == FileType::Symlink {
+                return Err(FsError::Unsupported);
+            }
+            match &mut *self.data.lock() {
+                Data::Directory(entries) => {
+                    if entries.contains_key(name) {
+                        return Err(FsError::AlreadyExists);
+                    }
+                    let inode = TmpInode::new(&self.next_ino, file_type);
+                    entries.insert(String::from(name), inode.clone());
+                    Ok(inode as Arc<dyn Inode>)
+                }
+                Data::File(_) => Err(FsError::NotADirectory),
+            }
+        })
+    }
+
+    fn unlink<'a>(&'a self, name: &'a str) -> FsFuture<'a, ()> {
+        Box::pin(async move {
+            match &mut *self.data.lock() {
+                Data::Directory(entries) => {
+                    let inode = entries.get(name).ok_or(FsError::NotFound)?;
+                    if let Data::Directory(children) = &*inode.data.lock() {
+                        if !children.is_empty() {
+                            return Err(FsError::DirectoryNotEmpty);
+                        }
+                    }
+                    entries.remove(name);
+                    Ok(())
+                }
+                Data::File(_) => Err(FsError::NotADirectory),
+            }
+        })
+    }
+
+    fn readdir(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async move {
+            match &*self.data.lock() {
+                Data::Directory(entries) => Ok(entries
+                    .iter()
+                    .map(|(name, inode)| DirEntry {
+                        name: name.clone(),
+                        ino: inode.ino,
+                        file_type: inode.file_type(),
+                    })
+                    .collect()),
+                Data::File(_) => Err(FsError::NotADirectory),
+            }
+        })
+    }
+}
@@ -1,3 +1,4 @@
+use crate::irq_mutex::IrqSafeMutex;
 use crate::print;
 use crate::println;
 use crate::{gdt, hlt_loop};
@@ -20,6 +21,12 @@ lazy_static! {
             .set_handler_fn(timer_interrupt_handler); // timer割り込みハンドラ追加
         idt[InterruptIndex::Keyboard.as_usize()]
         .set_handler_fn(keyboard_interrupt_handler); // keyboard割り込みハンドラ追加
+        // デバイスドライバが登録するIRQ(タイマ、キーボード、カスケード以外)
+        for (irq, &handler) in IRQ_ENTRIES.iter().enumerate() {
+            if let Some(handler) = handler {
+                idt[usize::from(PIC_1_OFFSET) + irq].set_handler_fn(handler);
+            }
+        }
 
         idt
     };
@@ -34,8 +41,8 @@ pub fn init_idt() {
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: IrqSafeMutex<ChainedPics> =
+    IrqSafeMutex::new("PICS", unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 // PICの割り込みベクタ管理用
 #[derive(Debug, Clone, Copy)]
@@ -55,6 +62,126 @@ impl InterruptIndex {
     }
 }
 
+// IRQごとに登録できるハンドラの数(PCIの割り込みは複数のデバイスで共有されることがある)
+const HANDLERS_PER_IRQ: usize = 4;
+
+type IrqHandlers = [[Option<fn()>; HANDLERS_PER_IRQ]; 16];
+
+// ドライバが登録したIRQのハンドラ
+static IRQ_HANDLERS: spin::Mutex<IrqHandlers> = spin::Mutex::new([[None; HANDLERS_PER_IRQ]; 16]);
+
+/// PICの`irq`番(0~15)の割り込みで`handler`が呼ばれるようにして、そのIRQのマスクを外す。
+///
+/// 同じハンドラを二度登録しても一度しか呼ばれない。ハンドラは割り込み禁止の状態で呼ばれ、
+/// EOIはハンドラの後に送られる。
+pub fn register_irq_handler(irq: u8, handler: fn()) -> Result<(), &'static str> {
+    if !matches!(IRQ_ENTRIES.get(usize::from(irq)), Some(Some(_))) {
+        return Err("IRQ is reserved or out of range");
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let slots = &mut handlers[usize::from(irq)];
+        if slots
+            .iter()
+            .flatten()
+            .any(|&h| h as usize == handler as usize)
+        {
+            return Ok(());
+        }
+        let slot = slots
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or("too many handlers for IRQ")?;
+        *slot = Some(handler);
+        unmask_irq(irq);
+        Ok(())
+    })
+}
+
+// PICのIMR(割り込みマスクレジスタ)で`irq`のマスクを外す
+// スレーブのIRQの場合はマスタのカスケード(IRQ2)も外す
+fn unmask_irq(irq: u8) {
+    use x86_64::instructions::port::Port;
+
+    // PICSをロックして、初期化などとポートへのアクセスが重ならないようにする
+    let _pics = PICS.lock();
+    let mut master: Port<u8> = Port::new(0x21);
+    let mut slave: Port<u8> = Port::new(0xa1);
+    unsafe {
+        if irq < 8 {
+            let mask = master.read();
+            master.write(mask & !(1 << irq));
+        } else {
+            let mask = slave.read();
+            slave.write(mask & !(1 << (irq - 8)));
+            let mask = master.read();
+            master.write(mask & !(1 << 2));
+        }
+    }
+}
+
+// 登録されたハンドラを呼び出してEOIを送る
+fn dispatch_irq(irq: u8) {
+    // ハンドラの登録は割り込み禁止で行うので、ここでロックが取れないことはない
+    let handlers = IRQ_HANDLERS.lock()[usize::from(irq)];
+    for handler in handlers.iter().flatten() {
+        handler();
+    }
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq);
+    }
+}
+
+// IRQごとの割り込みハンドラを作る
+macro_rules! irq_handlers {
+    ($($irq:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch_irq($irq);
+            }
+        )*
+    };
+}
+
+irq_handlers! {
+    3 => irq3_handler,
+    4 => irq4_handler,
+    5 => irq5_handler,
+    6 => irq6_handler,
+    7 => irq7_handler,
+    8 => irq8_handler,
+    9 => irq9_handler,
+    10 => irq10_handler,
+    11 => irq11_handler,
+    12 => irq12_handler,
+    13 => irq13_handler,
+    14 => irq14_handler,
+    15 => irq15_handler,
+}
+
+type IrqEntry = Option<extern "x86-interrupt" fn(InterruptStackFrame)>;
+
+// IRQ番号ごとのIDTのエントリ
+// タイマ(0)とキーボード(1)は専用のハンドラ、2はスレーブPICのカスケードなので登録できない
+const IRQ_ENTRIES: [IrqEntry; 16] = [
+    None,
+    None,
+    None,
+    Some(irq3_handler),
+    Some(irq4_handler),
+    Some(irq5_handler),
+    Some(irq6_handler),
+    Some(irq7_handler),
+    Some(irq8_handler),
+    Some(irq9_handler),
+    Some(irq10_handler),
+    Some(irq11_handler),
+    Some(irq12_handler),
+    Some(irq13_handler),
+    Some(irq14_handler),
+    Some(irq15_handler),
+];
+
 // 各種ハンドラ
 // breakpointハンドラ
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
@@ -82,12 +209,8 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
 
 // Keyboard割り込みハンドラ
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
-
-    // 入力されたデータを読み込むためのポート
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    crate::task::keyboard::add_scancode(scancode);
+    // PS/2コントローラから入力されたデータを読み込んで、ボトムハーフに積む
+    crate::ps2::handle_interrupt();
 
     unsafe {
         PICS.lock()
@@ -102,6 +225,11 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
+    // Copy-on-Writeページへの書き込みならコピーして再実行する
+    if crate::memory::cow::handle_page_fault(Cr2::read(), error_code) {
+        return;
+    }
+
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read()); // CR2レジスタにページフォルトした仮想アドれるが入る
     println!("Error Code: {:?}", error_code);
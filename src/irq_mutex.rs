@@ -0,0 +1,217 @@
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+/// 持っている間は割り込みを止めるMutex
+///
+/// 割り込みハンドラからも使うロックを普通の`spin::Mutex`で守ると、ロックを持っている
+/// ところに割り込みが来てハンドラが同じロックを取ろうとしたときに、シングルコアでは
+/// 永遠に回り続ける。`IrqSafeMutex`はロックを取る前に割り込みを止め、
+/// 離したときに元の状態に戻す。
+///
+/// デバッグビルドでは、ロックを取った順番を覚えておき、同じロックを二重に取ったときと、
+/// 以前と逆の順番で取ったとき(別の場所でデッドロックしうる)にパニックする。
+pub struct IrqSafeMutex<T: ?Sized> {
+    name: &'static str,
+    inner: Mutex<T>,
+}
+
+impl<T> IrqSafeMutex<T> {
+    /// `name`はロックの順番がおかしいときのメッセージに使う
+    pub const fn new(name: &'static str, value: T) -> Self {
+        IrqSafeMutex {
+            name,
+            inner: Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqSafeMutex<T> {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // ロックの順番を調べるときに使うID。staticに置くので場所は変わらない
+    fn id(&self) -> usize {
+        self as *const Self as *const () as usize
+    }
+
+    /// 割り込みを止めてからロックする
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, T> {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        #[cfg(debug_assertions)]
+        lockdep::acquire(self.id(), self.name);
+        IrqSafeMutexGuard {
+            mutex: self,
+            guard: ManuallyDrop::new(self.inner.lock()),
+            were_enabled,
+        }
+    }
+
+    /// ロックできなければ、割り込みの状態を戻してNoneを返す
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<'_, T>> {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                #[cfg(debug_assertions)]
+                lockdep::acquire(self.id(), self.name);
+                Some(IrqSafeMutexGuard {
+                    mutex: self,
+                    guard: ManuallyDrop::new(guard),
+                    were_enabled,
+                })
+            }
+            None => {
+                if were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// ロックを無理やり外す
+    ///
+    /// # Safety
+    ///
+    /// ロックを持っているコードが二度と実行されないとき(パニックした後など)だけ使うこと。
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(debug_assertions)]
+        lockdep::release(self.id());
+        self.inner.force_unlock();
+    }
+}
+
+/// `IrqSafeMutex::lock`で取ったロック。dropするとロックを離し、割り込みの状態を戻す
+pub struct IrqSafeMutexGuard<'a, T: ?Sized> {
+    mutex: &'a IrqSafeMutex<T>,
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    // ロックする前に割り込みが有効だったか
+    were_enabled: bool,
+}
+
+impl<T: ?Sized> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // 割り込みを有効にする前にロックを離す
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        #[cfg(debug_assertions)]
+        lockdep::release(self.mutex.id());
+        #[cfg(not(debug_assertions))]
+        let _ = self.mutex;
+        if self.were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+// ロックの順番の検査。IrqSafeMutexは割り込みを止めてから呼ぶので、割り込みハンドラと
+// 重なることはない。例外ハンドラの中から呼ばれて状態がロックされていたら調べない。
+#[cfg(debug_assertions)]
+mod lockdep {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use spin::Mutex;
+
+    // 同時に持てるロックの数
+    const MAX_HELD: usize = 16;
+    // 覚えておく「AのあとにBを取った」の数
+    const MAX_EDGES: usize = 64;
+
+    struct LockDep {
+        // 今持っているロックのIDと名前
+        held: [(usize, &'static str); MAX_HELD],
+        depth: usize,
+        // (先に取ったロック, 後に取ったロック)
+        edges: [(usize, usize); MAX_EDGES],
+        edge_count: usize,
+    }
+
+    impl LockDep {
+        fn has_edge(&self, before: usize, after: usize) -> bool {
+            self.edges[..self.edge_count].contains(&(before, after))
+        }
+
+        fn add_edge(&mut self, before: usize, after: usize) {
+            if self.edge_count < MAX_EDGES && !self.has_edge(before, after) {
+                self.edges[self.edge_count] = (before, after);
+                self.edge_count += 1;
+            }
+        }
+    }
+
+    static STATE: Mutex<LockDep> = Mutex::new(LockDep {
+        held: [(0, ""); MAX_HELD],
+        depth: 0,
+        edges: [(0, 0); MAX_EDGES],
+        edge_count: 0,
+    });
+    // 一度パニックしたら、パニックの表示でロックを取るときに引っかからないよう止める
+    static ENABLED: AtomicBool = AtomicBool::new(true);
+
+    fn violation(args: core::fmt::Arguments) -> ! {
+        ENABLED.store(false, Ordering::SeqCst);
+        panic!("{}", args)
+    }
+
+    pub(super) fn acquire(id: usize, name: &'static str) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut state = match STATE.try_lock() {
+            Some(state) => state,
+            None => return,
+        };
+        let depth = state.depth;
+        for i in 0..depth {
+            let (held, held_name) = state.held[i];
+            if held == id {
+                drop(state);
+                violation(format_args!("ロック{}を二重に取ろうとしました", name));
+            }
+            if state.has_edge(id, held) {
+                drop(state);
+                violation(format_args!(
+                    "ロック{}を持ったまま{}を取ろうとしました。以前は逆の順番で取っています",
+                    held_name, name
+                ));
+            }
+            state.add_edge(held, id);
+        }
+        if depth < MAX_HELD {
+            state.held[depth] = (id, name);
+            state.depth += 1;
+        }
+    }
+
+    pub(super) fn release(id: usize) {
+        let mut state = match STATE.try_lock() {
+            Some(state) => state,
+            None => return,
+        };
+        let depth = state.depth;
+        // 取った順と逆に離すとは限らないので、見つけたところを詰める
+        if let Some(index) = state.held[..depth]
+            .iter()
+            .rposition(|&(held, _)| held == id)
+        {
+            state.held.copy_within(index + 1..depth, index);
+            state.depth -= 1;
+        }
+    }
+}
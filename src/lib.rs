@@ -8,13 +8,22 @@
 
 extern crate alloc;
 
+pub mod acpi;
 pub mod allocator;
+pub mod block;
+pub mod cmdline;
+pub mod fs;
 pub mod gdt;
 pub mod interrupts;
+pub mod irq_mutex;
 pub mod memory;
+pub mod pci;
+pub mod ps2;
 pub mod serial;
+pub mod shell;
 pub mod task;
 pub mod vga_buffer;
+pub mod virtio;
 
 use core::panic::PanicInfo;
 
@@ -31,6 +40,17 @@ pub fn hlt_loop() -> ! {
     }
 }
 
+/// パニックハンドラで表示する前に、WRITERとSERIAL1のロックを外す
+///
+/// パニックしたコードがどちらかを持ったままだと、表示しようとして永遠に回り続ける。
+/// パニックハンドラから戻ることはないので、ロックを持っていたコードが続きを実行することもない。
+pub fn unlock_output_for_panic() {
+    unsafe {
+        vga_buffer::WRITER.force_unlock();
+        serial::SERIAL1.force_unlock();
+    }
+}
+
 pub trait Testable {
     fn run(&self);
 }
@@ -55,6 +75,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    unlock_output_for_panic();
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
     exit_qemu(QemuExitCode::Failed);
@@ -13,11 +13,9 @@ use alloc::vec::Vec;
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use my_os::memory::BootInfoFrameAllocator;
-use my_os::task::executor::Executor;
-use my_os::task::keyboard::print_keypresses;
-use my_os::task::simple_executor::SimpleExecutor;
-use my_os::task::Task;
-use my_os::{allocator, memory, println};
+use my_os::task::executor::{Executor, Priority};
+use my_os::task::simple_executor::{block_on, SimpleExecutor};
+use my_os::{allocator, block, fs, memory, pci, println, ps2, shell, task};
 
 entry_point!(kernel_main);
 
@@ -29,10 +27,32 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    // カーネルのセグメントとスタックの権限を設定する(W^X)
+    memory::protect::harden_kernel(&mut mapper).expect("kernel hardening failed");
     // flame allocator作成
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    // ページフォルトハンドラから使えるようにフレームアロケータを登録する
+    memory::install_frame_allocator(frame_allocator);
+    // 割り込みハンドラが後半の処理を積めるようにする(実行はbottom-halfタスクが行う)
+    task::deferred::init();
+    // ルートにtmpfsをマウントし、埋め込んだinitramfsを展開する
+    fs::init();
+    fs::initramfs::init();
+    // コマンドラインで指定されたキーボードの配列などを反映する
+    task::keyboard::init();
+    // PS/2コントローラとキーボードを初期化する
+    if let Err(err) = ps2::init() {
+        println!("ps2: {}", err);
+    }
+    // PCIバスをスキャンする(ECAMのマッピングにフレームアロケータを使う)
+    pci::register_driver(&block::virtio::DRIVER);
+    pci::init();
+    // IDEのディスクを探す
+    block::ata::init();
+    // 見つかったディスクのパーティションをブロックデバイスとして登録する
+    block_on(block::partition::register_partitions());
 
     // ヒープに数字をアロケートする
     let heap_value = Box::new(41);
@@ -60,8 +80,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     // 非同期関数実行
     let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(print_keypresses()));
+    executor.spawn_named("example", Priority::Background, example_task());
+    executor.spawn_named("bottom-half", Priority::BottomHalf, task::deferred::run());
+    executor.spawn_named("keyboard", Priority::BottomHalf, task::keyboard::run());
+    executor.spawn_named("shell", Priority::Interactive, shell::run());
     executor.run();
 
     #[cfg(test)]
@@ -74,6 +96,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    my_os::unlock_output_for_panic();
     println!("{}", info);
     my_os::hlt_loop();
 }
@@ -0,0 +1,194 @@
+use super::{phys_to_virt, BootInfoFrameAllocator, ENTRY_SPAN, FRAME_ALLOCATOR};
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageRange, PageSize, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+/// Copy-on-Writeなページであることを示すフラグ
+/// OSが自由に使えるビットを使っている
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// レベル4テーブルのフレームで表されるアドレス空間
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// 現在CR3に載っているアドレス空間を返す。
+    pub fn current() -> Self {
+        let (level_4_frame, _) = Cr3::read();
+        AddressSpace { level_4_frame }
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// このアドレス空間のOffsetPageTableを作る。
+    ///
+    /// # Safety
+    /// 返されたOffsetPageTableを使っている間は、同じテーブルへの
+    /// 他の可変参照を使ってはならない。
+    pub unsafe fn mapper(&self) -> OffsetPageTable<'static> {
+        OffsetPageTable::new(
+            table_mut(self.level_4_frame),
+            super::physical_memory_offset(),
+        )
+    }
+
+    /// CR3を書き換えてこのアドレス空間に切り替える。
+    ///
+    /// # Safety
+    /// 切り替え後も実行中のコードやスタックがマップされていなければならない。
+    pub unsafe fn activate(&self) {
+        Cr3::write(self.level_4_frame, Cr3Flags::empty());
+    }
+
+    /// このアドレス空間をfork風に複製する。
+    ///
+    /// `private`に含まれるページは複製元と複製先の両方で読み込み専用＋`COPY_ON_WRITE`になり、
+    /// 書き込まれた時点でページフォルトハンドラがフレームをコピーする。
+    /// `private`の外側は、ページテーブルごと複製元と共有される(カーネルのコードやスタックなど)。
+    /// `private`内のhuge pageはコピーせずに共有される。
+    ///
+    /// 複製元のページテーブルを書き換えるので、複製元がアクティブでもTLBはここでflushする。
+    pub fn clone_cow(
+        &self,
+        private: PageRange<Size4KiB>,
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> Result<AddressSpace, MapToError<Size4KiB>> {
+        let range = (
+            private.start.start_address().as_u64(),
+            private.end.start_address().as_u64(),
+        );
+        let level_4_frame =
+            unsafe { clone_table(self.level_4_frame, 4, 0, range, frame_allocator)? };
+        x86_64::instructions::tlb::flush_all();
+        Ok(AddressSpace { level_4_frame })
+    }
+}
+
+/// 物理フレーム上のページテーブルへの可変参照を返す。
+unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+// ページテーブルを再帰的に複製する
+// `base`はこのテーブルがカバーする仮想アドレスの先頭、`range`はCOWにする範囲(終端はページの先頭で、範囲に含む)
+unsafe fn clone_table(
+    source_frame: PhysFrame,
+    level: usize,
+    base: u64,
+    range: (u64, u64),
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<PhysFrame, MapToError<Size4KiB>> {
+    let frame: PhysFrame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let source = table_mut(source_frame);
+    let table = table_mut(frame);
+    table.zero();
+
+    for (i, entry) in source.iter_mut().enumerate() {
+        if entry.is_unused() {
+            continue;
+        }
+        // 上位半分のアドレスは符号拡張して比較する
+        let start = VirtAddr::new_truncate(base + i as u64 * ENTRY_SPAN[level]).as_u64();
+        let end = start + (ENTRY_SPAN[level] - 1);
+        let flags = entry.flags();
+        if end < range.0 || start > range.1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            // 範囲外は共有する
+            table[i] = entry.clone();
+        } else if level == 1 {
+            // 書き込み可能なページは両方を読み込み専用にしてCOWの印をつける
+            let frame = PhysFrame::containing_address(entry.addr());
+            let flags = if flags.contains(PageTableFlags::WRITABLE) {
+                let flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                entry.set_flags(flags);
+                flags
+            } else {
+                flags
+            };
+            frame_allocator.share_frame(frame);
+            table[i].set_frame(frame, flags);
+        } else {
+            let child = PhysFrame::containing_address(entry.addr());
+            let child = clone_table(child, level - 1, start, range, frame_allocator)?;
+            table[i].set_frame(child, flags);
+        }
+    }
+
+    Ok(frame)
+}
+
+/// ページフォルトがCOWページへの書き込みであれば、フレームをコピーして書き込み可能にする。
+/// 処理した場合はtrueを返し、COWと関係ないフォルトならfalseを返す
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+    use x86_64::structures::paging::Translate;
+
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if !error_code.contains(expected) {
+        return false;
+    }
+    // フレームアロケータのロック中にフォルトした場合はどうしようもない
+    let mut guard = match FRAME_ALLOCATOR.try_lock() {
+        Some(guard) => guard,
+        None => return false,
+    };
+    let frame_allocator = match guard.as_mut() {
+        Some(frame_allocator) => frame_allocator,
+        None => return false,
+    };
+
+    let mut mapper = unsafe { AddressSpace::current().mapper() };
+    let page: Page<Size4KiB> = Page::containing_address(addr);
+    let (old_frame, flags) = match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } if flags.contains(COPY_ON_WRITE) => (frame, flags),
+        _ => return false,
+    };
+    let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+    if frame_allocator.ref_count(old_frame) == 1 {
+        // もう共有されていないのでコピーせずに書き込み可能にするだけ
+        unsafe {
+            match mapper.update_flags(page, flags) {
+                Ok(flush) => flush.flush(),
+                Err(_) => return false,
+            }
+        }
+        return true;
+    }
+
+    let new_frame: PhysFrame = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            phys_to_virt(old_frame.start_address()).as_ptr::<u8>(),
+            phys_to_virt(new_frame.start_address()).as_mut_ptr::<u8>(),
+            Size4KiB::SIZE as usize,
+        );
+        match mapper.unmap(page) {
+            Ok((_, flush)) => flush.ignore(),
+            Err(_) => return false,
+        }
+        match mapper.map_to(page, new_frame, flags, frame_allocator) {
+            Ok(flush) => flush.flush(),
+            Err(_) => return false,
+        }
+        frame_allocator.release_frame(old_frame);
+    }
+    true
+}
@@ -0,0 +1,154 @@
+use x86_64::structures::paging::mapper::{MapToError, MapperAllSizes};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// 全てのページサイズのフレームを確保できるFrameAllocator
+/// (`MapperAllSizes`と同じように、各サイズのFrameAllocatorをまとめたもの)
+pub trait FrameAllocatorAllSizes:
+    FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB> + FrameAllocator<Size1GiB>
+{
+}
+
+impl<T> FrameAllocatorAllSizes for T where
+    T: FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB> + FrameAllocator<Size1GiB>
+{
+}
+
+/// CPUが1GiBページに対応しているかどうか
+/// CPUID 0x80000001のEDXのbit26(Page1GB)で判定する
+pub fn supports_1gib_pages() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended_leaf < 0x8000_0001 {
+        return false;
+    }
+    unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0
+}
+
+/// `start`から`size`バイトの仮想アドレス範囲に、新しく確保したフレームをマップする。
+///
+/// アラインメントと残りのサイズが許す限り1GiB/2MiBページを使い、それ以外は4KiBページでマップする。
+/// 大きいページ用の連続フレームが確保できなかった場合も小さいページにフォールバックする。
+pub fn map_range<M, A>(
+    mapper: &mut M,
+    frame_allocator: &mut A,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    M: MapperAllSizes,
+    A: FrameAllocatorAllSizes,
+{
+    let use_1gib = supports_1gib_pages();
+    // 範囲の先頭と末尾を4KiB境界に揃える
+    let end = (start + size).align_up(Size4KiB::SIZE);
+    let mut virt = start.align_down(Size4KiB::SIZE);
+
+    while virt < end {
+        let remaining = end - virt;
+        if use_1gib && fits::<Size1GiB>(virt, None, remaining) {
+            let frame = FrameAllocator::<Size1GiB>::allocate_frame(frame_allocator);
+            if let Some(frame) = frame {
+                map_page(mapper, frame_allocator, virt, frame, flags)?;
+                virt += Size1GiB::SIZE;
+                continue;
+            }
+        }
+        if fits::<Size2MiB>(virt, None, remaining) {
+            let frame = FrameAllocator::<Size2MiB>::allocate_frame(frame_allocator);
+            if let Some(frame) = frame {
+                map_page(mapper, frame_allocator, virt, frame, flags)?;
+                virt += Size2MiB::SIZE;
+                continue;
+            }
+        }
+        let frame = FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator)
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        map_page(mapper, frame_allocator, virt, frame, flags)?;
+        virt += Size4KiB::SIZE;
+    }
+
+    Ok(())
+}
+
+/// `phys`から始まる物理アドレス範囲を`start`から`size`バイトの仮想アドレス範囲にマップする。
+/// フレームバッファやECAMのように、マップ先の物理アドレスが決まっている場合に使う(`map_mmio`から呼ばれる)。
+/// 物理メモリ全体の窓はbootloaderがマップするので、ここでは扱わない。
+///
+/// 仮想アドレスと物理アドレスの両方がアラインされている部分は1GiB/2MiBページでマップする。
+pub fn map_physical_range<M, A>(
+    mapper: &mut M,
+    frame_allocator: &mut A,
+    start: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    M: MapperAllSizes,
+    A: FrameAllocator<Size4KiB>,
+{
+    let use_1gib = supports_1gib_pages();
+    // 範囲の先頭と末尾を4KiB境界に揃える
+    let end = (start + size).align_up(Size4KiB::SIZE);
+    let mut virt = start.align_down(Size4KiB::SIZE);
+    let mut phys = phys.align_down(Size4KiB::SIZE);
+
+    while virt < end {
+        let remaining = end - virt;
+        let step = if use_1gib && fits::<Size1GiB>(virt, Some(phys), remaining) {
+            let frame: PhysFrame<Size1GiB> = PhysFrame::containing_address(phys);
+            map_page(mapper, frame_allocator, virt, frame, flags)?;
+            Size1GiB::SIZE
+        } else if fits::<Size2MiB>(virt, Some(phys), remaining) {
+            let frame: PhysFrame<Size2MiB> = PhysFrame::containing_address(phys);
+            map_page(mapper, frame_allocator, virt, frame, flags)?;
+            Size2MiB::SIZE
+        } else {
+            let frame: PhysFrame<Size4KiB> = PhysFrame::containing_address(phys);
+            map_page(mapper, frame_allocator, virt, frame, flags)?;
+            Size4KiB::SIZE
+        };
+        virt += step;
+        phys += step;
+    }
+
+    Ok(())
+}
+
+// 残りのサイズとアラインメントからページサイズ`S`が使えるかどうか
+fn fits<S: PageSize>(virt: VirtAddr, phys: Option<PhysAddr>, remaining: u64) -> bool {
+    remaining >= S::SIZE && virt.is_aligned(S::SIZE) && phys.map_or(true, |p| p.is_aligned(S::SIZE))
+}
+
+// ページサイズ`S`で1ページだけマップする
+// HUGE_PAGEフラグはmap_toの中で立てられる
+fn map_page<S, M, A>(
+    mapper: &mut M,
+    frame_allocator: &mut A,
+    virt: VirtAddr,
+    frame: PhysFrame<S>,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    S: PageSize,
+    M: Mapper<S>,
+    A: FrameAllocator<Size4KiB>,
+{
+    let page: Page<S> = Page::containing_address(virt);
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(MapToError::FrameAllocationFailed) => Err(MapToError::FrameAllocationFailed),
+        Err(MapToError::ParentEntryHugePage) => Err(MapToError::ParentEntryHugePage),
+        Err(MapToError::PageAlreadyMapped(frame)) => Err(MapToError::PageAlreadyMapped(
+            PhysFrame::containing_address(frame.start_address()),
+        )),
+    }
+}
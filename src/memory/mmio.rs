@@ -0,0 +1,139 @@
+use super::huge::map_physical_range;
+use super::{active_mapper, FRAME_ALLOCATOR};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{PageSize, PageTableFlags, Size2MiB, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// MMIO用の仮想アドレス空間の先頭
+pub const MMIO_START: u64 = 0x_6666_0000_0000;
+/// MMIO用の仮想アドレス空間の大きさ
+pub const MMIO_SIZE: u64 = 1 << 39;
+
+// 次に割り当てるMMIO用の仮想アドレス
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// 物理アドレス`phys`から`len`バイトのデバイスのレジスタを、キャッシュ無効で仮想アドレス空間にマップする。
+///
+/// 仮想アドレスはMMIO用の領域から割り当てる。割り当てた領域は解放されない。
+/// 物理アドレスが2MiBにアラインされていれば2MiBページでマップされるように、仮想アドレスも揃えている。
+pub fn map_mmio(phys: PhysAddr, len: usize) -> Result<MmioRegion, MapToError<Size4KiB>> {
+    assert!(len > 0, "長さ0のMMIO領域はマップできません");
+    let phys_start = phys.align_down(Size4KiB::SIZE);
+    let offset = phys - phys_start;
+    let size = (offset + len as u64 + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1);
+    let virt_start = allocate_virt(size, phys_start);
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard
+        .as_mut()
+        .expect("フレームアロケータが登録されていません");
+    let mut mapper = unsafe { active_mapper() };
+    map_physical_range(
+        &mut mapper,
+        frame_allocator,
+        virt_start,
+        phys_start,
+        size,
+        flags,
+    )?;
+
+    Ok(MmioRegion {
+        base: virt_start + offset,
+        phys,
+        len,
+    })
+}
+
+// MMIO用の仮想アドレスを割り当てる
+// 物理アドレスの2MiB内でのオフセットと同じオフセットにすることで、大きい領域は2MiBページでマップできる
+fn allocate_virt(size: u64, phys_start: PhysAddr) -> VirtAddr {
+    let huge_offset = phys_start.as_u64() & (Size2MiB::SIZE - 1);
+    let mut current = NEXT_MMIO.load(Ordering::Relaxed);
+    loop {
+        let mut start = (current & !(Size2MiB::SIZE - 1)) + huge_offset;
+        if start < current {
+            start += Size2MiB::SIZE;
+        }
+        let end = start + size;
+        assert!(
+            end <= MMIO_START + MMIO_SIZE,
+            "MMIO用の仮想アドレス空間が足りません"
+        );
+        match NEXT_MMIO.compare_exchange(current, end, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return VirtAddr::new(start),
+            Err(next) => current = next,
+        }
+    }
+}
+
+/// `map_mmio`でマップされたデバイスのレジスタの領域
+///
+/// レジスタへのアクセスは全てvolatileで、オフセットは領域の先頭からのバイト数で指定する。
+#[derive(Debug)]
+pub struct MmioRegion {
+    base: VirtAddr,
+    phys: PhysAddr,
+    len: usize,
+}
+
+impl MmioRegion {
+    /// 領域の先頭の仮想アドレス
+    pub fn base(&self) -> VirtAddr {
+        self.base
+    }
+
+    /// 領域の先頭の物理アドレス
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// `offset`にあるレジスタを読み込む。
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { self.ptr::<T>(offset).read_volatile() }
+    }
+
+    /// `offset`にあるレジスタに書き込む。
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { self.ptr::<T>(offset).write_volatile(value) }
+    }
+
+    /// `offset`にあるレジスタを読み込み、`f`で変更した値を書き戻す。
+    pub fn update<T: Copy>(&self, offset: usize, f: impl FnOnce(T) -> T) {
+        let value = self.read(offset);
+        self.write(offset, f(value));
+    }
+
+    // 範囲とアラインメントを確認してレジスタへのポインタを返す
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        let size = core::mem::size_of::<T>();
+        assert!(
+            offset
+                .checked_add(size)
+                .map_or(false, |end| end <= self.len),
+            "MMIO領域の範囲外です: offset={:#x}, len={:#x}",
+            offset,
+            self.len
+        );
+        let addr = self.base + offset;
+        assert!(
+            addr.is_aligned(core::mem::align_of::<T>() as u64),
+            "MMIOのレジスタがアラインされていません: {:?}",
+            addr
+        );
+        addr.as_mut_ptr()
+    }
+}
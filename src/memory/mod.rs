@@ -0,0 +1,268 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, OffsetPageTable, PageSize, PhysFrame, Size1GiB, Size2MiB,
+    Size4KiB,
+};
+use x86_64::{structures::paging::PageTable, PhysAddr, VirtAddr};
+
+pub mod cow;
+pub mod huge;
+pub mod mmio;
+pub mod protect;
+pub mod walk;
+
+pub use mmio::{map_mmio, MmioRegion};
+
+// 物理メモリがマップされている仮想アドレスのオフセット
+// ページフォルトハンドラなど、mapperを受け取れない場所から物理メモリにアクセスするために保持しておく
+static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+
+// 各レベルのページテーブルのエントリ1つがカバーする仮想アドレスの大きさ
+// インデックスはページテーブルのレベル(1~4)
+const ENTRY_SPAN: [u64; 5] = [0, 1 << 12, 1 << 21, 1 << 30, 1 << 39];
+
+/// ページフォルトハンドラなどからも使えるように登録されたフレームアロケータ
+/// `install_frame_allocator`で登録されるまではNone
+pub static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+/// 有効なレベル4テーブルへの可変参照を返す。
+///
+/// # Safety
+/// 全物理メモリが、渡された
+/// `physical_memory_offset`（だけずらしたうえ）で
+/// 仮想メモリへとマップされていることを呼び出し元が
+/// 保証しなければならない。また、`&mut`参照が複数の
+/// 名称を持つこと (mutable aliasingといい、動作が未定義)
+/// につながるため、この関数は一度しか呼び出してはならない。
+/// 中身を見るだけなら`walk`モジュールの関数を使うこと。
+pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr // ここがunsafe
+}
+
+/// 新しいOffsetPageTableを初期化する。
+///
+/// # Safety
+/// 全物理メモリが、渡された
+/// `physical_memory_offset`（だけずらしたうえ）で
+/// 仮想メモリへとマップされていることを呼び出し元が
+/// 保証しなければならない。また、`&mut`参照が複数の
+/// 名称を持つこと (mutable aliasingといい、動作が未定義)
+/// につながるため、この関数は一度しか呼び出してはならない。
+pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET
+        .try_init_once(|| physical_memory_offset)
+        .expect("memory::initは一度しか呼び出せません");
+    // CR0.WPを立てないとCopy-on-Writeが機能しないし、NXEを立てないとNO_EXECUTEが使えない
+    protect::enable_protection();
+
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+/// `init`で渡された物理メモリのオフセットを返す。
+pub fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET
+        .try_get()
+        .expect("memory::initが呼ばれていません")
+}
+
+/// 物理アドレスを、物理メモリがマップされている仮想アドレスに変換する。
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + phys.as_u64()
+}
+
+/// 現在CR3に載っているレベル4テーブルからOffsetPageTableを作る。
+///
+/// # Safety
+/// `init`の後に呼び出さなければならない。また返されたOffsetPageTableを
+/// 使っている間は、同じテーブルへの他の可変参照を使ってはならない。
+pub unsafe fn active_mapper() -> OffsetPageTable<'static> {
+    let physical_memory_offset = physical_memory_offset();
+    OffsetPageTable::new(
+        active_level_4_table(physical_memory_offset),
+        physical_memory_offset,
+    )
+}
+
+/// フレームアロケータを`FRAME_ALLOCATOR`に登録する。
+/// ヒープの初期化が終わった後に呼び出す想定(参照カウントの管理にヒープを使うため)
+pub fn install_frame_allocator(frame_allocator: BootInfoFrameAllocator) {
+    let mut global = FRAME_ALLOCATOR.lock();
+    if global.is_some() {
+        panic!("フレームアロケータはすでに登録されています");
+    }
+    *global = Some(frame_allocator);
+}
+
+/// ブートローダのメモリマップから、使用可能な
+/// フレームを返すFrameAllocator
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    next: usize,
+    // 連続したフレームは使用可能領域の末尾から下に向かって確保する
+    // これより上のアドレスは連続確保で使用済み(Noneならまだ使っていない)
+    contiguous_floor: Option<u64>,
+    // 解放されたフレーム、次のallocate_frameではこちらから優先して返す
+    free_frames: Vec<PhysFrame>,
+    // 複数のアドレス空間で共有されているフレームの参照カウント
+    // 共有されていないフレーム(参照カウント1)はここに入らない
+    ref_counts: BTreeMap<PhysFrame, usize>,
+}
+
+impl BootInfoFrameAllocator {
+    /// 渡されたメモリマップからFrameAllocatorを作る。
+    ///
+    /// # Safety
+    /// 呼び出し元は渡された
+    /// メモリマップが有効であることを保証しなければ
+    /// ならない。特に、`USABLE`なフレームは実際に
+    /// 未使用でなくてはならない。
+    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        BootInfoFrameAllocator {
+            memory_map,
+            next: 0,
+            contiguous_floor: None,
+            free_frames: Vec::new(),
+            ref_counts: BTreeMap::new(),
+        }
+    }
+}
+
+impl BootInfoFrameAllocator {
+    /// メモリマップによって指定されたusableなフレームのイテレータを返す。
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        // メモリマップからusableな領域を得る
+        // 予約済みの領域は除外したい
+        let regions = self.memory_map.iter();
+        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
+        // それぞれの領域をアドレス範囲にmapで変換する
+        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
+        // フレームの開始アドレスのイテレータへと変換する
+        // 1Pageが4096B(4KiB)なので、4096ごとになる
+        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+        // 開始アドレスから`PhysFrame`型を作る
+        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+    /// 使用可能な領域のアドレス範囲を返す。
+    fn usable_regions(&self) -> impl DoubleEndedIterator<Item = (u64, u64)> + '_ {
+        self.memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| (r.range.start_addr(), r.range.end_addr()))
+    }
+
+    /// 物理的に連続した`count`個のフレームを確保し、先頭のフレームを返す。
+    /// 先頭のアドレスは`align`(2のべき乗)にアラインされる
+    ///
+    /// 連続領域は使用可能領域の末尾から確保するので、`allocate_frame`で確保される
+    /// フレームとは重ならない。ここで確保したフレームは解放できない。
+    pub fn allocate_contiguous(&mut self, count: usize, align: u64) -> Option<PhysFrame> {
+        assert!(align.is_power_of_two() && align >= Size4KiB::SIZE);
+        let size = count as u64 * Size4KiB::SIZE;
+        // 単体のフレームとして次に返すアドレス、これより下は使えない
+        let floor = match self.usable_frames().nth(self.next) {
+            Some(frame) => frame.start_address().as_u64(),
+            None => return None,
+        };
+        let ceiling = self.contiguous_floor.unwrap_or(u64::MAX);
+
+        for (region_start, region_end) in self.usable_regions().rev() {
+            let end = region_end.min(ceiling);
+            if end < size {
+                continue;
+            }
+            let start = (end - size) & !(align - 1);
+            if start >= region_start && start >= floor {
+                self.contiguous_floor = Some(start);
+                return Some(PhysFrame::containing_address(PhysAddr::new(start)));
+            }
+        }
+        None
+    }
+
+    /// フレームの参照カウントを返す。
+    /// 共有されていないフレームは1になる
+    pub fn ref_count(&self, frame: PhysFrame) -> usize {
+        self.ref_counts.get(&frame).copied().unwrap_or(1)
+    }
+
+    /// フレームを別のアドレス空間と共有するために参照カウントを1増やす。
+    pub fn share_frame(&mut self, frame: PhysFrame) {
+        *self.ref_counts.entry(frame).or_insert(1) += 1;
+    }
+
+    /// フレームへの参照を一つ手放す。
+    /// 参照カウントが0になった場合はフレームを解放してtrueを返す
+    ///
+    /// # Safety
+    /// 呼び出し元は、手放す参照を今後使わないことを保証しなければならない。
+    pub unsafe fn release_frame(&mut self, frame: PhysFrame) -> bool {
+        match self.ref_counts.get_mut(&frame) {
+            Some(count) => {
+                *count -= 1;
+                // 参照カウントが1に戻ったら共有されていないので管理対象から外す
+                if *count == 1 {
+                    self.ref_counts.remove(&frame);
+                }
+                false
+            }
+            None => {
+                self.deallocate_frame(frame);
+                true
+            }
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        if let Some(frame) = self.free_frames.pop() {
+            return Some(frame);
+        }
+        let frame = self.usable_frames().nth(self.next)?;
+        // 連続確保で使われた領域に到達したら空きがない
+        if let Some(floor) = self.contiguous_floor {
+            if frame.start_address().as_u64() >= floor {
+                return None;
+            }
+        }
+        self.next += 1;
+        Some(frame)
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let count = (Size2MiB::SIZE / Size4KiB::SIZE) as usize;
+        let frame = self.allocate_contiguous(count, Size2MiB::SIZE)?;
+        PhysFrame::from_start_address(frame.start_address()).ok()
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        let count = (Size1GiB::SIZE / Size4KiB::SIZE) as usize;
+        let frame = self.allocate_contiguous(count, Size1GiB::SIZE)?;
+        PhysFrame::from_start_address(frame.start_address()).ok()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.ref_counts.remove(&frame);
+        self.free_frames.push(frame);
+    }
+}
@@ -0,0 +1,153 @@
+use super::walk;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::FlagUpdateError;
+use x86_64::structures::paging::{Mapper, Page, PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+// ELFのプログラムヘッダのタイプとフラグ
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+extern "C" {
+    // リンカ(lld)が定義する、ELFヘッダの先頭を指すシンボル
+    static __ehdr_start: u8;
+}
+
+// ELF64のプログラムヘッダ
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct ProgramHeader {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+/// NX(EFER.NXE)とCR0.WPを有効にする。
+///
+/// NXEが無効なままNO_EXECUTEを立てたページにアクセスすると予約ビット違反になるので、
+/// NO_EXECUTEなマッピングを作る前に呼び出さなければならない。
+pub fn enable_protection() {
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        // CR0.WPを立てないとカーネルモードからの書き込みは読み込み専用ページでもフォルトしない
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+}
+
+/// カーネル自身のセグメントを、ELFのプログラムヘッダに従った権限でマップし直す。
+///
+/// .textは読み込み専用で実行可能、.rodataは読み込み専用で実行不可、
+/// .dataと.bssは書き込み可能で実行不可になる(W^X)。
+pub fn remap_kernel(mapper: &mut impl Mapper<Size4KiB>) -> Result<(), FlagUpdateError> {
+    for header in kernel_program_headers() {
+        if header.p_type != PT_LOAD || header.p_memsz == 0 {
+            continue;
+        }
+        let mut flags = PageTableFlags::PRESENT;
+        if header.p_flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if header.p_flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        let start = VirtAddr::new(header.p_vaddr);
+        update_range(mapper, start, header.p_memsz, flags)?;
+    }
+    Ok(())
+}
+
+/// 現在のスタックを実行不可にする。
+///
+/// RSPを含むページから上下に、4KiBで書き込み可能なページが続く限りをスタックとみなす。
+/// (ブートローダはスタックの下にガードページを置くので、そこで止まる)
+pub fn protect_current_stack(mapper: &mut impl Mapper<Size4KiB>) -> Result<(), FlagUpdateError> {
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp) };
+    let current: Page<Size4KiB> = Page::containing_address(VirtAddr::new(rsp));
+
+    let is_stack_page = |page: Page<Size4KiB>| match walk::translate(page.start_address()) {
+        Some(translation) => {
+            translation.mapping.size == Size4KiB::SIZE
+                && translation.mapping.flags.contains(PageTableFlags::WRITABLE)
+        }
+        None => false,
+    };
+    let mut bottom = current;
+    while let Some(page) = neighbor(bottom, false).filter(|&page| is_stack_page(page)) {
+        bottom = page;
+    }
+    let mut top = current;
+    while let Some(page) = neighbor(top, true).filter(|&page| is_stack_page(page)) {
+        top = page;
+    }
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for page in Page::range_inclusive(bottom, top) {
+        unsafe { mapper.update_flags(page, flags)?.flush() };
+    }
+    Ok(())
+}
+
+// 隣のページを返す(アドレス空間の端や正規でないアドレスになる場合はNone)
+fn neighbor(page: Page<Size4KiB>, up: bool) -> Option<Page<Size4KiB>> {
+    let addr = page.start_address().as_u64();
+    let addr = if up {
+        addr.checked_add(Size4KiB::SIZE)?
+    } else {
+        addr.checked_sub(Size4KiB::SIZE)?
+    };
+    VirtAddr::try_new(addr).ok().map(Page::containing_address)
+}
+
+/// カーネルのセグメントと現在のスタックの権限を設定し直す。
+pub fn harden_kernel(mapper: &mut impl Mapper<Size4KiB>) -> Result<(), FlagUpdateError> {
+    remap_kernel(mapper)?;
+    protect_current_stack(mapper)
+}
+
+// start..start+sizeを含むページのフラグを書き換える
+fn update_range(
+    mapper: &mut impl Mapper<Size4KiB>,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), FlagUpdateError> {
+    let start_page: Page<Size4KiB> = Page::containing_address(start);
+    let end_page: Page<Size4KiB> = Page::containing_address(start + (size - 1));
+    for page in Page::range_inclusive(start_page, end_page) {
+        unsafe { mapper.update_flags(page, flags)?.flush() };
+    }
+    Ok(())
+}
+
+// カーネルのELFのプログラムヘッダを返す
+// lldはELFヘッダとプログラムヘッダを最初のLOADセグメントに含めるので、メモリ上から読める
+fn kernel_program_headers() -> &'static [ProgramHeader] {
+    unsafe {
+        let ehdr = &__ehdr_start as *const u8;
+        assert_eq!(
+            core::slice::from_raw_parts(ehdr, 4),
+            b"\x7fELF",
+            "カーネルのELFヘッダが見つかりません"
+        );
+        let phoff = (ehdr.add(0x20) as *const u64).read_unaligned();
+        let phentsize = (ehdr.add(0x36) as *const u16).read_unaligned();
+        let phnum = (ehdr.add(0x38) as *const u16).read_unaligned();
+        assert_eq!(
+            usize::from(phentsize),
+            core::mem::size_of::<ProgramHeader>()
+        );
+        core::slice::from_raw_parts(
+            ehdr.add(phoff as usize) as *const ProgramHeader,
+            usize::from(phnum),
+        )
+    }
+}
@@ -0,0 +1,239 @@
+use super::{phys_to_virt, ENTRY_SPAN};
+use crate::println;
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+// 領域をまとめるときに比較する権限のフラグ
+// ACCESSEDやDIRTYはCPUが勝手に立てるので比較しない
+const PERMISSION_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::WRITABLE.bits()
+        | PageTableFlags::USER_ACCESSIBLE.bits()
+        | PageTableFlags::NO_EXECUTE.bits()
+        | PageTableFlags::WRITE_THROUGH.bits()
+        | PageTableFlags::NO_CACHE.bits()
+        | PageTableFlags::GLOBAL.bits()
+        | super::cow::COPY_ON_WRITE.bits(),
+);
+
+/// ページテーブルの末端で見つかった1つのマッピング
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub virt: VirtAddr,
+    pub phys: PhysAddr,
+    /// ページサイズ(4KiB, 2MiB, 1GiB)
+    pub size: u64,
+    /// 全レベルのエントリを考慮した実効的なフラグ
+    /// WRITABLEとUSER_ACCESSIBLEは全レベルで立っている場合だけ、NO_EXECUTEはどこかで立っていれば立つ
+    pub flags: PageTableFlags,
+}
+
+/// 仮想アドレスを変換した結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    pub phys: PhysAddr,
+    /// アドレスを含むマッピング
+    pub mapping: Mapping,
+}
+
+/// 仮想アドレスが連続していて権限が同じマッピングをまとめた領域
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: VirtAddr,
+    /// 領域の大きさ(バイト)
+    pub size: u64,
+    pub flags: PageTableFlags,
+    /// 領域に含まれるページの数
+    pub pages: usize,
+}
+
+impl Region {
+    /// 領域の終端のアドレス(この値は含まない)
+    /// 下位半分の末尾などで正規でないアドレスになることがあるのでu64で返す
+    pub fn end(&self) -> u64 {
+        self.start.as_u64().wrapping_add(self.size)
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let size = self.size;
+        let (size, unit) = if size >= 1 << 30 {
+            (size >> 30, "G")
+        } else if size >= 1 << 20 {
+            (size >> 20, "M")
+        } else {
+            (size >> 10, "K")
+        };
+        write!(
+            f,
+            "{:#018x}-{:#018x} {:>5}{} {}",
+            self.start.as_u64(),
+            self.end(),
+            size,
+            unit,
+            Permissions(self.flags)
+        )
+    }
+}
+
+/// フラグを`rwxu`のような権限の文字列として表示する
+pub struct Permissions(pub PageTableFlags);
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |flag, c| if self.0.contains(flag) { c } else { '-' };
+        let executable = if self.0.contains(PageTableFlags::NO_EXECUTE) {
+            '-'
+        } else {
+            'x'
+        };
+        write!(
+            f,
+            "r{}{}{}{}{}",
+            flag(PageTableFlags::WRITABLE, 'w'),
+            executable,
+            flag(PageTableFlags::USER_ACCESSIBLE, 'u'),
+            flag(PageTableFlags::NO_CACHE, 'c'),
+            flag(super::cow::COPY_ON_WRITE, 'C'),
+        )
+    }
+}
+
+/// アクティブなページテーブルの全てのマッピングを仮想アドレス順に`f`に渡す。
+pub fn for_each_mapping(mut f: impl FnMut(Mapping)) {
+    let (level_4_frame, _) = Cr3::read();
+    let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    walk_table(level_4_frame, 4, 0, inherited, &mut f);
+}
+
+// テーブルを再帰的にたどる
+// `inherited`は上位のエントリから引き継いだ実効的なフラグ
+fn walk_table(
+    frame: PhysFrame,
+    level: usize,
+    base: u64,
+    inherited: PageTableFlags,
+    f: &mut impl FnMut(Mapping),
+) {
+    let table = table(frame);
+    for (i, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        // 上位半分のアドレスは符号拡張する
+        let virt = VirtAddr::new_truncate(base + i as u64 * ENTRY_SPAN[level]);
+        let effective = effective_flags(inherited, flags);
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            f(Mapping {
+                virt,
+                phys: entry.addr(),
+                size: ENTRY_SPAN[level],
+                flags: effective,
+            });
+        } else {
+            let child = PhysFrame::containing_address(entry.addr());
+            walk_table(child, level - 1, virt.as_u64(), effective, f);
+        }
+    }
+}
+
+// 上位のエントリのフラグと合わせた実効的なフラグを返す
+fn effective_flags(inherited: PageTableFlags, flags: PageTableFlags) -> PageTableFlags {
+    let restricted = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let mut effective = flags - restricted;
+    effective |= flags & inherited & restricted;
+    effective |= inherited & PageTableFlags::NO_EXECUTE;
+    effective
+}
+
+fn table(frame: PhysFrame) -> &'static PageTable {
+    unsafe { &*phys_to_virt(frame.start_address()).as_ptr() }
+}
+
+/// アクティブなページテーブルで仮想アドレスを物理アドレスに変換する。
+/// マップされていない場合はNoneを返す
+pub fn translate(addr: VirtAddr) -> Option<Translation> {
+    let (level_4_frame, _) = Cr3::read();
+    let indexes = [
+        0,
+        u16::from(addr.p1_index()),
+        u16::from(addr.p2_index()),
+        u16::from(addr.p3_index()),
+        u16::from(addr.p4_index()),
+    ];
+
+    let mut frame = level_4_frame;
+    let mut inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    for level in (1..=4).rev() {
+        let entry = &table(frame)[indexes[level] as usize];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        inherited = effective_flags(inherited, flags);
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            let size = ENTRY_SPAN[level];
+            let offset = addr.as_u64() & (size - 1);
+            let mapping = Mapping {
+                virt: addr.align_down(size),
+                phys: entry.addr(),
+                size,
+                flags: inherited,
+            };
+            return Some(Translation {
+                phys: entry.addr() + offset,
+                mapping,
+            });
+        }
+        frame = PhysFrame::containing_address(entry.addr());
+    }
+    unreachable!()
+}
+
+/// アクティブなページテーブルのマッピングを、仮想アドレスが連続していて
+/// 権限が同じもの同士でまとめて返す。
+pub fn regions() -> Vec<Region> {
+    let mut regions: Vec<Region> = Vec::new();
+    for_each_mapping(|mapping| {
+        let flags = mapping.flags & PERMISSION_FLAGS;
+        if let Some(last) = regions.last_mut() {
+            if last.end() == mapping.virt.as_u64() && last.flags == flags {
+                last.size += mapping.size;
+                last.pages += 1;
+                return;
+            }
+        }
+        regions.push(Region {
+            start: mapping.virt,
+            size: mapping.size,
+            flags,
+            pages: 1,
+        });
+    });
+    regions
+}
+
+/// アクティブなページテーブルの領域の一覧を表示する。
+pub fn print_regions() {
+    for region in regions() {
+        println!("{}", region);
+    }
+}
+
+/// 仮想アドレスの変換結果を表示する。
+pub fn print_translation(addr: VirtAddr) {
+    match translate(addr) {
+        Some(translation) => println!(
+            "{:#x} -> {:#x} ({}K page, {})",
+            addr.as_u64(),
+            translation.phys.as_u64(),
+            translation.mapping.size >> 10,
+            Permissions(translation.mapping.flags)
+        ),
+        None => println!("{:#x} is not mapped", addr.as_u64()),
+    }
+}
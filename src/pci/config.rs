@@ -0,0 +1,175 @@
+use super::PciAddress;
+use crate::acpi;
+use crate::memory::{map_mmio, MmioRegion};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+// レガシーなコンフィギュレーション空間のアクセスに使うポート
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+// MCFGテーブルのヘッダ(36バイト)と予約領域(8バイト)の後にエントリが並ぶ
+const MCFG_ENTRIES_OFFSET: usize = 44;
+
+/// コンフィギュレーション空間へのアクセス方法
+pub enum ConfigSpace {
+    /// 0xCF8/0xCFCのポートを使う方法(オフセットは256バイトまで)
+    Legacy(Mutex<LegacyPorts>),
+    /// ACPIのMCFGで示されるメモリマップドな方法(ECAM、オフセットは4KiBまで)
+    Ecam(Ecam),
+}
+
+pub struct LegacyPorts {
+    address: Port<u32>,
+    data: Port<u32>,
+}
+
+pub struct Ecam {
+    region: MmioRegion,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+impl ConfigSpace {
+    /// MCFGがあればECAMを、なければレガシーなポートを使う。
+    pub fn detect() -> ConfigSpace {
+        match Ecam::from_mcfg() {
+            Some(ecam) => ConfigSpace::Ecam(ecam),
+            None => ConfigSpace::legacy(),
+        }
+    }
+
+    pub fn legacy() -> ConfigSpace {
+        ConfigSpace::Legacy(Mutex::new(LegacyPorts {
+            address: Port::new(CONFIG_ADDRESS),
+            data: Port::new(CONFIG_DATA),
+        }))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConfigSpace::Legacy(_) => "legacy",
+            ConfigSpace::Ecam(_) => "ECAM",
+        }
+    }
+
+    /// `offset`(4バイト境界)の32bitの値を読む。
+    pub fn read_u32(&self, address: PciAddress, offset: u16) -> u32 {
+        assert_eq!(offset % 4, 0, "オフセットは4バイト境界でなければなりません");
+        match self {
+            ConfigSpace::Legacy(ports) => {
+                if offset >= 256 {
+                    return u32::MAX;
+                }
+                x86_64::instructions::interrupts::without_interrupts(|| {
+                    let mut ports = ports.lock();
+                    unsafe {
+                        ports.address.write(legacy_address(address, offset));
+                        ports.data.read()
+                    }
+                })
+            }
+            ConfigSpace::Ecam(ecam) => match ecam.offset(address, offset) {
+                Some(offset) => ecam.region.read(offset),
+                None => u32::MAX,
+            },
+        }
+    }
+
+    /// `offset`(4バイト境界)に32bitの値を書く。
+    pub fn write_u32(&self, address: PciAddress, offset: u16, value: u32) {
+        assert_eq!(offset % 4, 0, "オフセットは4バイト境界でなければなりません");
+        match self {
+            ConfigSpace::Legacy(ports) => {
+                if offset >= 256 {
+                    return;
+                }
+                x86_64::instructions::interrupts::without_interrupts(|| {
+                    let mut ports = ports.lock();
+                    unsafe {
+                        ports.address.write(legacy_address(address, offset));
+                        ports.data.write(value);
+                    }
+                })
+            }
+            ConfigSpace::Ecam(ecam) => {
+                if let Some(offset) = ecam.offset(address, offset) {
+                    ecam.region.write(offset, value);
+                }
+            }
+        }
+    }
+
+    pub fn read_u16(&self, address: PciAddress, offset: u16) -> u16 {
+        (self.read_u32(address, offset & !3) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(&self, address: PciAddress, offset: u16) -> u8 {
+        (self.read_u32(address, offset & !3) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn write_u16(&self, address: PciAddress, offset: u16, value: u16) {
+        let shift = (offset & 2) * 8;
+        let old = self.read_u32(address, offset & !3);
+        let new = (old & !(0xffff << shift)) | (u32::from(value) << shift);
+        self.write_u32(address, offset & !3, new);
+    }
+}
+
+// CONFIG_ADDRESSに書く値
+// bit31: 有効, bit23-16: バス, bit15-11: デバイス, bit10-8: ファンクション, bit7-2: レジスタ
+fn legacy_address(address: PciAddress, offset: u16) -> u32 {
+    0x8000_0000
+        | u32::from(address.bus) << 16
+        | u32::from(address.device) << 11
+        | u32::from(address.function) << 8
+        | u32::from(offset & 0xfc)
+}
+
+impl Ecam {
+    // MCFGのセグメント0のエントリからECAMの領域をマップする
+    fn from_mcfg() -> Option<Ecam> {
+        let mcfg = acpi::find_table(b"MCFG")?;
+        let header = unsafe { acpi::read_header(mcfg) };
+        let entries = (header.length as usize).checked_sub(MCFG_ENTRIES_OFFSET)? / 16;
+        let entries_start = crate::memory::phys_to_virt(mcfg) + MCFG_ENTRIES_OFFSET;
+
+        (0..entries).find_map(|i| {
+            let entry = (entries_start + i * 16).as_ptr::<u8>();
+            let (base, segment, start_bus, end_bus) = unsafe {
+                (
+                    (entry as *const u64).read_unaligned(),
+                    (entry.add(8) as *const u16).read_unaligned(),
+                    entry.add(10).read(),
+                    entry.add(11).read(),
+                )
+            };
+            if segment != 0 || end_bus < start_bus {
+                return None;
+            }
+            // バスごとに1MiB(32デバイス x 8ファンクション x 4KiB)
+            let len = (usize::from(end_bus - start_bus) + 1) << 20;
+            let base = PhysAddr::new(base + (u64::from(start_bus) << 20));
+            let region = map_mmio(base, len).ok()?;
+            Some(Ecam {
+                region,
+                start_bus,
+                end_bus,
+            })
+        })
+    }
+
+    // ECAMの領域内でのオフセット、範囲外のバスならNone
+    fn offset(&self, address: PciAddress, offset: u16) -> Option<usize> {
+        if address.bus < self.start_bus || address.bus > self.end_bus || offset >= 4096 {
+            return None;
+        }
+        Some(
+            usize::from(address.bus - self.start_bus) << 20
+                | usize::from(address.device) << 15
+                | usize::from(address.function) << 12
+                | usize::from(offset),
+        )
+    }
+}
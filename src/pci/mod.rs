@@ -0,0 +1,538 @@
+use crate::println;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use spin::Mutex;
+
+pub mod config;
+
+use config::ConfigSpace;
+
+// コンフィギュレーション空間のレジスタのオフセット
+const VENDOR_ID: u16 = 0x00;
+const DEVICE_ID: u16 = 0x02;
+const COMMAND: u16 = 0x04;
+const STATUS: u16 = 0x06;
+const REVISION_ID: u16 = 0x08;
+const PROG_IF: u16 = 0x09;
+const SUBCLASS: u16 = 0x0a;
+const CLASS: u16 = 0x0b;
+const HEADER_TYPE: u16 = 0x0e;
+const BAR0: u16 = 0x10;
+const CAPABILITIES_POINTER: u16 = 0x34;
+const INTERRUPT_LINE: u16 = 0x3c;
+const INTERRUPT_PIN: u16 = 0x3d;
+
+// COMMANDレジスタのビット
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+// STATUSレジスタのケーパビリティリストがあることを示すビット
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+
+static CONFIG_SPACE: OnceCell<ConfigSpace> = OnceCell::uninit();
+// initで見つかったデバイス
+static DEVICES: Mutex<Vec<DeviceEntry>> = Mutex::new(Vec::new());
+// 登録されたドライバ
+static DRIVERS: Mutex<Vec<&'static dyn PciDriver>> = Mutex::new(Vec::new());
+
+// 見つかったデバイスと、それを使っているドライバ
+struct DeviceEntry {
+    device: PciDevice,
+    driver: Option<&'static dyn PciDriver>,
+}
+
+/// バス・デバイス・ファンクションの組
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// デコードしたBAR(Base Address Register)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        is_64bit: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+/// ケーパビリティリストの1要素
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// コンフィギュレーション空間でのオフセット
+    pub offset: u8,
+}
+
+/// 見つかったPCIのファンクション
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    pub bars: [Option<Bar>; 6],
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    pub capabilities: Vec<Capability>,
+}
+
+impl PciDevice {
+    /// `id`のケーパビリティを探す。
+    pub fn find_capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities.iter().copied().find(|cap| cap.id == id)
+    }
+
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        config_space().read_u32(self.address, offset)
+    }
+
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        config_space().write_u32(self.address, offset, value)
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        config_space().read_u16(self.address, offset)
+    }
+
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        config_space().write_u16(self.address, offset, value)
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        config_space().read_u8(self.address, offset)
+    }
+
+    /// メモリ空間・I/O空間のデコードとバスマスタ(DMA)を有効にする。
+    pub fn enable(&self) {
+        let command = self.read_u16(COMMAND);
+        self.write_u16(
+            COMMAND,
+            command | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER,
+        );
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x} {}",
+            self.address,
+            self.vendor_id,
+            self.device_id,
+            self.class,
+            self.subclass,
+            self.prog_if,
+            class_name(self.class, self.subclass)
+        )
+    }
+}
+
+/// ドライバが対応するデバイスの条件
+/// Noneのフィールドは何にでもマッチする
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceId {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+}
+
+impl DeviceId {
+    /// ベンダIDとデバイスIDで指定する。
+    pub const fn new(vendor_id: u16, device_id: u16) -> DeviceId {
+        DeviceId {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            class: None,
+            subclass: None,
+        }
+    }
+
+    /// クラスとサブクラスで指定する。
+    pub const fn class(class: u8, subclass: u8) -> DeviceId {
+        DeviceId {
+            vendor_id: None,
+            device_id: None,
+            class: Some(class),
+            subclass: Some(subclass),
+        }
+    }
+
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        self.vendor_id.map_or(true, |id| id == device.vendor_id)
+            && self.device_id.map_or(true, |id| id == device.device_id)
+            && self.class.map_or(true, |class| class == device.class)
+            && self
+                .subclass
+                .map_or(true, |subclass| subclass == device.subclass)
+    }
+}
+
+/// PCIデバイスのドライバ
+pub trait PciDriver: Sync {
+    fn name(&self) -> &'static str;
+
+    /// 対応するデバイスの一覧
+    fn id_table(&self) -> &'static [DeviceId];
+
+    /// マッチしたデバイスを初期化する。
+    fn probe(&self, device: &PciDevice) -> Result<(), &'static str>;
+}
+
+/// ドライバを登録する。
+/// `init`の後に登録された場合は、その場で既存のデバイスのうち
+/// まだどのドライバも使っていないものとマッチングする
+pub fn register_driver(driver: &'static dyn PciDriver) {
+    DRIVERS.lock().push(driver);
+    for device in unbound_devices() {
+        if bind(driver, &device) {
+            set_driver(device.address, driver);
+        }
+    }
+}
+
+/// コンフィギュレーション空間のアクセス方法を決めて、全てのバスをスキャンする。
+/// 登録済みのドライバはここで見つかったデバイスとマッチングされる
+///
+/// ECAMを使う場合はMMIOのマッピングが必要なので、フレームアロケータを登録した後に呼び出すこと。
+pub fn init() {
+    CONFIG_SPACE
+        .try_init_once(ConfigSpace::detect)
+        .expect("pci::initは一度しか呼び出せません");
+
+    *DEVICES.lock() = scan()
+        .into_iter()
+        .map(|device| DeviceEntry {
+            device,
+            driver: None,
+        })
+        .collect();
+    let drivers = DRIVERS.lock().clone();
+    for device in unbound_devices() {
+        for &driver in &drivers {
+            if bind(driver, &device) {
+                set_driver(device.address, driver);
+                break;
+            }
+        }
+    }
+}
+
+// ドライバが決まっていないデバイス
+// probeの中でdevicesなどを呼べるように、ロックを外してから使うためにcloneして返す
+fn unbound_devices() -> Vec<PciDevice> {
+    DEVICES
+        .lock()
+        .iter()
+        .filter(|entry| entry.driver.is_none())
+        .map(|entry| entry.device.clone())
+        .collect()
+}
+
+fn set_driver(address: PciAddress, driver: &'static dyn PciDriver) {
+    if let Some(entry) = DEVICES
+        .lock()
+        .iter_mut()
+        .find(|entry| entry.device.address == address)
+    {
+        entry.driver = Some(driver);
+    }
+}
+
+// ドライバがデバイスにマッチすればprobeする
+fn bind(driver: &'static dyn PciDriver, device: &PciDevice) -> bool {
+    if !driver.id_table().iter().any(|id| id.matches(device)) {
+        return false;
+    }
+    match driver.probe(device) {
+        Ok(()) => {
+            println!("pci: {} bound to {}", driver.name(), device.address);
+            true
+        }
+        Err(err) => {
+            println!(
+                "pci: {} failed on {}: {}",
+                driver.name(),
+                device.address,
+                err
+            );
+            false
+        }
+    }
+}
+
+fn config_space() -> &'static ConfigSpace {
+    CONFIG_SPACE.try_get().expect("pci::initが呼ばれていません")
+}
+
+/// `init`で見つかったデバイスの一覧を返す。
+pub fn devices() -> Vec<PciDevice> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|entry| entry.device.clone())
+        .collect()
+}
+
+/// `address`のデバイスを使っているドライバの名前を返す。
+pub fn driver_name(address: PciAddress) -> Option<&'static str> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|entry| entry.device.address == address)
+        .and_then(|entry| entry.driver)
+        .map(|driver| driver.name())
+}
+
+/// `id`にマッチするデバイスを探す。
+pub fn find_device(id: DeviceId) -> Option<PciDevice> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|entry| &entry.device)
+        .find(|device| id.matches(device))
+        .cloned()
+}
+
+/// 見つかったデバイスの一覧を表示する。
+pub fn print_devices() {
+    if CONFIG_SPACE.try_get().is_err() {
+        println!("pci: not initialized");
+        return;
+    }
+    println!("pci: using {} configuration access", config_space().name());
+    for entry in DEVICES.lock().iter() {
+        let device = &entry.device;
+        println!("{}", device);
+        if let Some(driver) = entry.driver {
+            println!("    driver: {}", driver.name());
+        }
+        for (i, bar) in device.bars.iter().enumerate() {
+            match bar {
+                Some(Bar::Memory {
+                    address,
+                    size,
+                    prefetchable,
+                    is_64bit,
+                }) => println!(
+                    "    BAR{}: mem {:#x} size {:#x}{}{}",
+                    i,
+                    address,
+                    size,
+                    if *is_64bit { " 64bit" } else { "" },
+                    if *prefetchable { " prefetchable" } else { "" }
+                ),
+                Some(Bar::Io { port, size }) => {
+                    println!("    BAR{}: io {:#x} size {:#x}", i, port, size)
+                }
+                None => {}
+            }
+        }
+        if !device.capabilities.is_empty() {
+            let ids: Vec<u8> = device.capabilities.iter().map(|cap| cap.id).collect();
+            println!("    capabilities: {:02x?}", ids);
+        }
+    }
+}
+
+// 全てのバスを総当たりでスキャンする
+fn scan() -> Vec<PciDevice> {
+    let config = config_space();
+    let mut devices = Vec::new();
+    for bus in 0..=255u8 {
+        for device in 0..32u8 {
+            let address = PciAddress {
+                bus,
+                device,
+                function: 0,
+            };
+            if config.read_u16(address, VENDOR_ID) == 0xffff {
+                continue;
+            }
+            // bit7が立っていればマルチファンクションデバイス
+            let functions = if config.read_u8(address, HEADER_TYPE) & 0x80 != 0 {
+                8
+            } else {
+                1
+            };
+            for function in 0..functions {
+                let address = PciAddress {
+                    bus,
+                    device,
+                    function,
+                };
+                if config.read_u16(address, VENDOR_ID) != 0xffff {
+                    devices.push(read_device(config, address));
+                }
+            }
+        }
+    }
+    devices
+}
+
+fn read_device(config: &ConfigSpace, address: PciAddress) -> PciDevice {
+    let header_type = config.read_u8(address, HEADER_TYPE) & 0x7f;
+    // 通常のデバイス(ヘッダタイプ0)はBARが6個、PCI-PCIブリッジ(1)は2個
+    let bar_count = match header_type {
+        0 => 6,
+        1 => 2,
+        _ => 0,
+    };
+    let mut bars = [None; 6];
+    let mut i = 0;
+    while i < bar_count {
+        let (bar, slots) = read_bar(config, address, i);
+        bars[i] = bar;
+        i += slots;
+    }
+
+    let capabilities = if config.read_u16(address, STATUS) & STATUS_CAPABILITIES_LIST != 0 {
+        read_capabilities(config, address)
+    } else {
+        Vec::new()
+    };
+
+    PciDevice {
+        address,
+        vendor_id: config.read_u16(address, VENDOR_ID),
+        device_id: config.read_u16(address, DEVICE_ID),
+        class: config.read_u8(address, CLASS),
+        subclass: config.read_u8(address, SUBCLASS),
+        prog_if: config.read_u8(address, PROG_IF),
+        revision: config.read_u8(address, REVISION_ID),
+        header_type,
+        bars,
+        interrupt_line: config.read_u8(address, INTERRUPT_LINE),
+        interrupt_pin: config.read_u8(address, INTERRUPT_PIN),
+        capabilities,
+    }
+}
+
+// i番目のBARをデコードする
+// 全ビットを1にして読み返すことでサイズを調べる
+// 64bitのBARは2つ分使うので、使ったBARの数も返す
+fn read_bar(config: &ConfigSpace, address: PciAddress, i: usize) -> (Option<Bar>, usize) {
+    let offset = BAR0 + i as u16 * 4;
+    let original = config.read_u32(address, offset);
+
+    // サイズを調べている間にデバイスが変なアドレスをデコードしないように無効にしておく
+    let command = config.read_u16(address, COMMAND);
+    config.write_u16(
+        address,
+        COMMAND,
+        command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+    );
+
+    let result = if original & 1 != 0 {
+        // I/O空間
+        config.write_u32(address, offset, u32::MAX);
+        let mask = config.read_u32(address, offset) & !0x3;
+        config.write_u32(address, offset, original);
+        let size = (!mask).wrapping_add(1) & 0xffff;
+        let bar = if mask == 0 {
+            None
+        } else {
+            Some(Bar::Io {
+                port: (original & !0x3) as u16,
+                size,
+            })
+        };
+        (bar, 1)
+    } else {
+        // メモリ空間、bit2-1が2なら64bit
+        let is_64bit = (original >> 1) & 0x3 == 0x2;
+        let prefetchable = original & 0x8 != 0;
+        config.write_u32(address, offset, u32::MAX);
+        let low_mask = config.read_u32(address, offset) & !0xf;
+        config.write_u32(address, offset, original);
+
+        let (address_value, mask) = if is_64bit && i < 5 {
+            let original_high = config.read_u32(address, offset + 4);
+            config.write_u32(address, offset + 4, u32::MAX);
+            let high_mask = config.read_u32(address, offset + 4);
+            config.write_u32(address, offset + 4, original_high);
+            (
+                u64::from(original_high) << 32 | u64::from(original & !0xf),
+                u64::from(high_mask) << 32 | u64::from(low_mask),
+            )
+        } else {
+            (
+                u64::from(original & !0xf),
+                0xffff_ffff_0000_0000 | u64::from(low_mask),
+            )
+        };
+        let bar = if low_mask == 0 && mask >> 32 == 0xffff_ffff {
+            None
+        } else {
+            Some(Bar::Memory {
+                address: address_value,
+                size: (!mask).wrapping_add(1),
+                prefetchable,
+                is_64bit,
+            })
+        };
+        (bar, if is_64bit { 2 } else { 1 })
+    };
+
+    config.write_u16(address, COMMAND, command);
+    result
+}
+
+// ケーパビリティリストをたどる
+fn read_capabilities(config: &ConfigSpace, address: PciAddress) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+    let mut offset = config.read_u8(address, CAPABILITIES_POINTER) & !0x3;
+    // 壊れたリストで無限ループしないように回数を制限する
+    while offset != 0 && capabilities.len() < 48 {
+        let id = config.read_u8(address, u16::from(offset));
+        capabilities.push(Capability { id, offset });
+        offset = config.read_u8(address, u16::from(offset) + 1) & !0x3;
+    }
+    capabilities
+}
+
+/// クラスコードの名前
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVMe controller",
+        (0x01, _) => "mass storage controller",
+        (0x02, 0x00) => "ethernet controller",
+        (0x02, _) => "network controller",
+        (0x03, _) => "display controller",
+        (0x04, _) => "multimedia controller",
+        (0x05, _) => "memory controller",
+        (0x06, 0x00) => "host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "bridge",
+        (0x07, _) => "communication controller",
+        (0x08, _) => "system peripheral",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus controller",
+        (0x0c, _) => "serial bus controller",
+        _ => "unknown",
+    }
+}
@@ -0,0 +1,115 @@
+use super::{with_controller, Controller, Ps2Error, Ps2Port};
+use crate::task::keyboard::{KeyboardConfig, Scancodes};
+use core::ops::{BitOr, BitXor};
+
+// キーボードへのコマンド
+const COMMAND_SET_LEDS: u8 = 0xed;
+const COMMAND_SCANCODE_SET: u8 = 0xf0;
+const COMMAND_SET_TYPEMATIC: u8 = 0xf3;
+const COMMAND_ENABLE_SCANNING: u8 = 0xf4;
+
+/// キーボードのLED
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Leds(u8);
+
+impl Leds {
+    pub const NONE: Leds = Leds(0);
+    pub const SCROLL_LOCK: Leds = Leds(1 << 0);
+    pub const NUM_LOCK: Leds = Leds(1 << 1);
+    pub const CAPS_LOCK: Leds = Leds(1 << 2);
+
+    pub fn contains(self, other: Leds) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Leds {
+    type Output = Leds;
+
+    fn bitor(self, other: Leds) -> Leds {
+        Leds(self.0 | other.0)
+    }
+}
+
+impl BitXor for Leds {
+    type Output = Leds;
+
+    fn bitxor(self, other: Leds) -> Leds {
+        Leds(self.0 ^ other.0)
+    }
+}
+
+/// キーを押し続けたときのリピートの設定バイトを作る
+///
+/// 遅延は250msから1000msまでの250ms刻み、速度は毎秒2回から30回までの32段階なので、
+/// それぞれ一番近いものを選ぶ。
+pub fn typematic_byte(delay_ms: u32, rate: u32) -> u8 {
+    let delay = ((delay_ms + 125) / 250).clamp(1, 4) - 1;
+    // 速度は 240 / ((8 + A) * 2^B) 回/秒 (Aはbit0-2、Bはbit3-4)
+    let rate_of = |code: u32| 2400 / ((8 + (code & 7)) << (code >> 3));
+    let target = rate * 10;
+    let code = (0..32)
+        .min_by_key(|&code| (rate_of(code) as i32 - target as i32).abs())
+        .unwrap();
+    (delay << 5 | code) as u8
+}
+
+// 初期化されていて、キーボードが使えるときだけ`f`を呼ぶ
+fn with_keyboard<R>(f: impl FnOnce(&mut Controller) -> Result<R, Ps2Error>) -> Result<R, Ps2Error> {
+    if !super::info().map_or(false, |info| info.keyboard) {
+        return Err(Ps2Error::NoDevice);
+    }
+    with_controller(f)
+}
+
+/// LEDを点ける
+pub fn set_leds(leds: Leds) -> Result<(), Ps2Error> {
+    with_keyboard(|controller| unsafe {
+        controller.device_command(Ps2Port::First, COMMAND_SET_LEDS)?;
+        controller.device_command(Ps2Port::First, leds.0)
+    })
+}
+
+/// リピートの遅延(ms)と速度(回/秒)を設定する
+pub fn set_typematic(delay_ms: u32, rate: u32) -> Result<(), Ps2Error> {
+    with_keyboard(|controller| unsafe {
+        controller.device_command(Ps2Port::First, COMMAND_SET_TYPEMATIC)?;
+        controller.device_command(Ps2Port::First, typematic_byte(delay_ms, rate))
+    })
+}
+
+/// キーボードの設定をコントローラに反映する
+///
+/// キーボード自体は常にセット2を送るので、セット1を使うときはコントローラに変換させる。
+pub fn apply(config: &KeyboardConfig) -> Result<(), Ps2Error> {
+    with_keyboard(|controller| unsafe {
+        controller.set_translation(config.scancodes == Scancodes::Set1)?;
+        controller.device_command(Ps2Port::First, COMMAND_SET_TYPEMATIC)?;
+        controller.device_command(
+            Ps2Port::First,
+            typematic_byte(config.repeat_delay, config.repeat_rate),
+        )
+    })
+}
+
+// キーボードをリセットしてセット2、リピート、LEDを設定し、スキャンを始める
+pub(super) unsafe fn initialize(
+    controller: &mut Controller,
+    config: &KeyboardConfig,
+) -> Result<(), Ps2Error> {
+    controller.reset_device(Ps2Port::First)?;
+
+    // セット2に対応していないキーボードもあるので、失敗しても続ける
+    let _ = controller
+        .device_command(Ps2Port::First, COMMAND_SCANCODE_SET)
+        .and_then(|()| controller.device_command(Ps2Port::First, 2));
+    controller.device_command(Ps2Port::First, COMMAND_SET_TYPEMATIC)?;
+    controller.device_command(
+        Ps2Port::First,
+        typematic_byte(config.repeat_delay, config.repeat_rate),
+    )?;
+    // pc_keyboardはNum Lockがオンの状態から始まる
+    controller.device_command(Ps2Port::First, COMMAND_SET_LEDS)?;
+    controller.device_command(Ps2Port::First, Leds::NUM_LOCK.0)?;
+    controller.device_command(Ps2Port::First, COMMAND_ENABLE_SCANNING)
+}
@@ -0,0 +1,432 @@
+pub mod keyboard;
+pub mod mouse;
+
+use crate::interrupts::register_irq_handler;
+use crate::task::deferred;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
+
+// I/Oポート
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+
+// ステータスレジスタのビット
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+// 出力バッファのデータが2つ目のポートから来たもの
+const STATUS_AUX: u8 = 1 << 5;
+
+// コントローラへのコマンド
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const COMMAND_DISABLE_SECOND: u8 = 0xa7;
+const COMMAND_ENABLE_SECOND: u8 = 0xa8;
+const COMMAND_TEST_SECOND: u8 = 0xa9;
+const COMMAND_SELF_TEST: u8 = 0xaa;
+const COMMAND_TEST_FIRST: u8 = 0xab;
+const COMMAND_DISABLE_FIRST: u8 = 0xad;
+const COMMAND_ENABLE_FIRST: u8 = 0xae;
+const COMMAND_WRITE_SECOND: u8 = 0xd4;
+
+// 設定バイトのビット
+const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+const CONFIG_FIRST_CLOCK_DISABLED: u8 = 1 << 4;
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+// セット2のスキャンコードをセット1に変換する
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+// コントローラとデバイスの応答
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+
+// ステータスをポーリングする回数の上限
+const POLL_LIMIT: usize = 100_000;
+// デバイスがRESENDを返したときに送り直す回数
+const RESEND_LIMIT: usize = 3;
+// リセット後の自己診断には時間がかかるので、タイムアウトしても何回か待つ
+const RESET_RETRIES: usize = 10;
+// リセット後の自己診断(BAT)の結果
+const BAT_PASSED: u8 = 0xaa;
+// COMMAND_RESETはキーボードとマウスで共通
+const COMMAND_RESET: u8 = 0xff;
+
+// 1つ目のポート(キーボード)のIRQ
+const FIRST_PORT_IRQ: u8 = 1;
+// 2つ目のポート(マウス)のIRQ
+const SECOND_PORT_IRQ: u8 = 12;
+
+/// コントローラの2つのポート。普通は1つ目にキーボード、2つ目にマウスがつながる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Port {
+    First,
+    Second,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// コントローラかデバイスが応答しない
+    Timeout,
+    /// コントローラのセルフテストが0x55以外を返した
+    SelfTestFailed(u8),
+    /// ポートのテストが0x00以外を返した
+    PortTestFailed(Ps2Port, u8),
+    /// 送り直してもRESENDが返ってくる
+    Resend,
+    /// 予期しない応答
+    UnexpectedResponse(u8),
+    /// コントローラが初期化されていないか、ポートにデバイスがない
+    NoDevice,
+}
+
+impl fmt::Display for Ps2Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ps2Error::Timeout => write!(f, "timed out"),
+            Ps2Error::SelfTestFailed(response) => {
+                write!(f, "controller self-test failed ({:#04x})", response)
+            }
+            Ps2Error::PortTestFailed(port, response) => {
+                write!(f, "{:?} port test failed ({:#04x})", port, response)
+            }
+            Ps2Error::Resend => write!(f, "device keeps asking to resend"),
+            Ps2Error::UnexpectedResponse(response) => {
+                write!(f, "unexpected response {:#04x}", response)
+            }
+            Ps2Error::NoDevice => write!(f, "no device"),
+        }
+    }
+}
+
+/// 初期化でわかったコントローラの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControllerInfo {
+    /// 2つ目のポートがある
+    pub dual_channel: bool,
+    /// ポートのテストに通った
+    pub first_port: bool,
+    pub second_port: bool,
+    /// キーボードの初期化に成功した
+    pub keyboard: bool,
+    /// 初期化できたマウスの種類
+    pub mouse: Option<mouse::MouseKind>,
+}
+
+/// 8042のレジスタ
+pub struct Controller {
+    data: Port<u8>,
+    status: PortReadOnly<u8>,
+    command: PortWriteOnly<u8>,
+}
+
+/// PS/2コントローラ
+///
+/// データの読み書きはすべてこれをロックして行う。コマンドの応答はポーリングで待つので、
+/// その間にキーボードの割り込みハンドラがデータを横取りしないよう、割り込みを禁止してロックする。
+/// 割り込みや応答を待っている間に届いたデータは、ボトムハーフ(`task::deferred`)で
+/// キーボードとマウスのドライバに渡す。
+pub static CONTROLLER: Mutex<Controller> = Mutex::new(Controller::new());
+
+// initが成功したときの状態
+static INFO: Mutex<Option<ControllerInfo>> = Mutex::new(None);
+
+impl Controller {
+    pub const fn new() -> Controller {
+        Controller {
+            data: Port::new(DATA_PORT),
+            status: PortReadOnly::new(STATUS_PORT),
+            command: PortWriteOnly::new(COMMAND_PORT),
+        }
+    }
+
+    // 入力バッファが空く(書き込める)のを待つ
+    unsafe fn wait_input_empty(&mut self) -> Result<(), Ps2Error> {
+        for _ in 0..POLL_LIMIT {
+            if self.status.read() & STATUS_INPUT_FULL == 0 {
+                return Ok(());
+            }
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    // 出力バッファにデータが来るのを待って、どのポートから来たかと一緒に返す
+    unsafe fn read_with_port(&mut self) -> Result<(Ps2Port, u8), Ps2Error> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.status.read();
+            if status & STATUS_OUTPUT_FULL != 0 {
+                let port = if status & STATUS_AUX != 0 {
+                    Ps2Port::Second
+                } else {
+                    Ps2Port::First
+                };
+                return Ok((port, self.data.read()));
+            }
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    unsafe fn read(&mut self) -> Result<u8, Ps2Error> {
+        self.read_with_port().map(|(_, byte)| byte)
+    }
+
+    // 出力バッファに残っているデータを捨てる
+    unsafe fn flush(&mut self) {
+        for _ in 0..POLL_LIMIT {
+            if self.status.read() & STATUS_OUTPUT_FULL == 0 {
+                return;
+            }
+            self.data.read();
+        }
+    }
+
+    unsafe fn send_command(&mut self, command: u8) -> Result<(), Ps2Error> {
+        self.wait_input_empty()?;
+        self.command.write(command);
+        Ok(())
+    }
+
+    unsafe fn write_data(&mut self, data: u8) -> Result<(), Ps2Error> {
+        self.wait_input_empty()?;
+        self.data.write(data);
+        Ok(())
+    }
+
+    unsafe fn read_config(&mut self) -> Result<u8, Ps2Error> {
+        self.send_command(COMMAND_READ_CONFIG)?;
+        self.read()
+    }
+
+    unsafe fn write_config(&mut self, config: u8) -> Result<(), Ps2Error> {
+        self.send_command(COMMAND_WRITE_CONFIG)?;
+        self.write_data(config)
+    }
+
+    // ポートのテストコマンドを送り、応答(0x00なら成功)を返す
+    unsafe fn test_port(&mut self, port: Ps2Port) -> Result<u8, Ps2Error> {
+        self.send_command(match port {
+            Ps2Port::First => COMMAND_TEST_FIRST,
+            Ps2Port::Second => COMMAND_TEST_SECOND,
+        })?;
+        self.read()
+    }
+
+    // ポートにつながっているデバイスに1バイト送る
+    unsafe fn send_to(&mut self, port: Ps2Port, byte: u8) -> Result<(), Ps2Error> {
+        if port == Ps2Port::Second {
+            self.send_command(COMMAND_WRITE_SECOND)?;
+        }
+        self.write_data(byte)
+    }
+
+    // デバイスから1バイト読む。ほかのポートから来たデータはそのデバイスのドライバに渡す
+    unsafe fn read_from(&mut self, port: Ps2Port) -> Result<u8, Ps2Error> {
+        loop {
+            let (from, byte) = self.read_with_port()?;
+            if from == port {
+                return Ok(byte);
+            }
+            forward(from, byte);
+        }
+    }
+
+    // デバイスに1バイト送ってACKを待つ。RESENDが返ってきたら送り直す
+    // 待っている間に届いたACKとRESEND以外のデータは、そのデバイスのドライバに渡す
+    unsafe fn device_command(&mut self, port: Ps2Port, byte: u8) -> Result<(), Ps2Error> {
+        for _ in 0..RESEND_LIMIT {
+            self.send_to(port, byte)?;
+            loop {
+                match self.read_from(port)? {
+                    ACK => return Ok(()),
+                    RESEND => break,
+                    other => forward(port, other),
+                }
+            }
+        }
+        Err(Ps2Error::Resend)
+    }
+
+    // デバイスをリセットして、自己診断に通るのを待つ
+    unsafe fn reset_device(&mut self, port: Ps2Port) -> Result<(), Ps2Error> {
+        self.device_command(port, COMMAND_RESET)?;
+        let mut result = Err(Ps2Error::Timeout);
+        for _ in 0..RESET_RETRIES {
+            result = self.read_from(port);
+            if result != Err(Ps2Error::Timeout) {
+                break;
+            }
+        }
+        match result? {
+            BAT_PASSED => Ok(()),
+            response => Err(Ps2Error::UnexpectedResponse(response)),
+        }
+    }
+
+    // ポートのクロックと割り込みを有効にする
+    unsafe fn enable_port(&mut self, port: Ps2Port) -> Result<(), Ps2Error> {
+        let (command, clock, irq) = match port {
+            Ps2Port::First => (
+                COMMAND_ENABLE_FIRST,
+                CONFIG_FIRST_CLOCK_DISABLED,
+                CONFIG_FIRST_IRQ,
+            ),
+            Ps2Port::Second => (
+                COMMAND_ENABLE_SECOND,
+                CONFIG_SECOND_CLOCK_DISABLED,
+                CONFIG_SECOND_IRQ,
+            ),
+        };
+        self.send_command(command)?;
+        let config = self.read_config()?;
+        self.write_config((config & !clock) | irq)
+    }
+
+    // ポートのクロックと割り込みを無効にする
+    unsafe fn disable_port(&mut self, port: Ps2Port) -> Result<(), Ps2Error> {
+        let (command, irq) = match port {
+            Ps2Port::First => (COMMAND_DISABLE_FIRST, CONFIG_FIRST_IRQ),
+            Ps2Port::Second => (COMMAND_DISABLE_SECOND, CONFIG_SECOND_IRQ),
+        };
+        self.send_command(command)?;
+        let config = self.read_config()?;
+        self.write_config(config & !irq)
+    }
+
+    /// 設定バイトの変換ビットを書き換える
+    pub unsafe fn set_translation(&mut self, enabled: bool) -> Result<(), Ps2Error> {
+        let config = self.read_config()?;
+        let config = if enabled {
+            config | CONFIG_TRANSLATION
+        } else {
+            config & !CONFIG_TRANSLATION
+        };
+        self.write_config(config)
+    }
+
+    // セルフテストとポートのテストをする。ポートはどちらも無効のまま
+    unsafe fn initialize(&mut self) -> Result<ControllerInfo, Ps2Error> {
+        self.send_command(COMMAND_DISABLE_FIRST)?;
+        self.send_command(COMMAND_DISABLE_SECOND)?;
+        self.flush();
+
+        let mut config = self.read_config()?;
+        config &= !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ | CONFIG_TRANSLATION);
+        self.write_config(config)?;
+
+        self.send_command(COMMAND_SELF_TEST)?;
+        match self.read()? {
+            SELF_TEST_PASSED => {}
+            response => return Err(Ps2Error::SelfTestFailed(response)),
+        }
+        // セルフテストで設定が初期化されるコントローラがあるので書き直す
+        self.write_config(config)?;
+
+        // 無効にしたのにクロックが止まっていなければ、2つ目のポートはない
+        let mut dual_channel = false;
+        if config & CONFIG_SECOND_CLOCK_DISABLED != 0 {
+            self.send_command(COMMAND_ENABLE_SECOND)?;
+            dual_channel = self.read_config()? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+            self.send_command(COMMAND_DISABLE_SECOND)?;
+        }
+
+        let response = self.test_port(Ps2Port::First)?;
+        let first_port = response == PORT_TEST_PASSED;
+        let second_port = dual_channel && self.test_port(Ps2Port::Second)? == PORT_TEST_PASSED;
+        if !first_port && !second_port {
+            return Err(Ps2Error::PortTestFailed(Ps2Port::First, response));
+        }
+
+        Ok(ControllerInfo {
+            dual_channel,
+            first_port,
+            second_port,
+            keyboard: false,
+            mouse: None,
+        })
+    }
+}
+
+// 割り込みやコマンドの応答を待っている間に届いたデータを、ボトムハーフでデバイスのドライバに渡す
+// 割り込みを禁止しているところから呼ばれるので、ここでは積むだけにする
+fn forward(port: Ps2Port, byte: u8) {
+    let byte = usize::from(byte);
+    match port {
+        Ps2Port::First => deferred::defer(FIRST_PORT_IRQ, deliver_scancode, byte),
+        Ps2Port::Second => deferred::defer(SECOND_PORT_IRQ, deliver_mouse_byte, byte),
+    };
+}
+
+fn deliver_scancode(byte: usize) {
+    crate::task::keyboard::add_scancode(byte as u8);
+}
+
+fn deliver_mouse_byte(byte: usize) {
+    mouse::add_byte(byte as u8);
+}
+
+/// 割り込みを禁止し、コントローラをロックして`f`を呼ぶ
+pub fn with_controller<R>(f: impl FnOnce(&mut Controller) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut CONTROLLER.lock()))
+}
+
+/// キーボード(IRQ1)とマウス(IRQ12)の割り込みハンドラから呼び、届いたデータをボトムハーフに積む
+///
+/// 応答をポーリングで読んだ後に遅れて割り込みが来た場合などは、出力バッファが空なので何もしない。
+pub(crate) fn handle_interrupt() {
+    // 割り込みハンドラの中なので、ほかにロックを持っているコードは動いていない
+    let received = {
+        let mut controller = CONTROLLER.lock();
+        unsafe {
+            let status = controller.status.read();
+            if status & STATUS_OUTPUT_FULL == 0 {
+                return;
+            }
+            let port = if status & STATUS_AUX != 0 {
+                Ps2Port::Second
+            } else {
+                Ps2Port::First
+            };
+            (port, controller.data.read())
+        }
+    };
+    forward(received.0, received.1);
+}
+
+/// コントローラとキーボード、マウスを初期化する
+///
+/// キーボードはセット2に切り替え、`task::keyboard`の設定がセット1なら
+/// コントローラに変換させる。マウスが見つかればIRQ12のハンドラを登録する。
+pub fn init() -> Result<ControllerInfo, Ps2Error> {
+    let config = crate::task::keyboard::config();
+    let translation = config.scancodes == crate::task::keyboard::Scancodes::Set1;
+    let info = with_controller(|controller| unsafe {
+        let mut info = controller.initialize()?;
+        if info.first_port {
+            controller.enable_port(Ps2Port::First)?;
+            controller.set_translation(translation)?;
+            info.keyboard = keyboard::initialize(controller, &config).is_ok();
+        }
+        if info.second_port {
+            controller.enable_port(Ps2Port::Second)?;
+            info.mouse = mouse::initialize(controller).ok();
+            if info.mouse.is_none() {
+                controller.disable_port(Ps2Port::Second)?;
+            }
+        }
+        Ok(info)
+    })?;
+    *INFO.lock() = Some(info);
+    if info.mouse.is_some() {
+        register_irq_handler(SECOND_PORT_IRQ, handle_interrupt).expect("IRQ12を登録できません");
+    }
+    Ok(info)
+}
+
+/// initで初期化したコントローラの状態。初期化されていなければNone
+pub fn info() -> Option<ControllerInfo> {
+    *INFO.lock()
+}
@@ -0,0 +1,190 @@
+use super::{Controller, Ps2Error, Ps2Port};
+use core::ops::BitOr;
+use spin::Mutex;
+
+// マウスへのコマンド
+const COMMAND_GET_ID: u8 = 0xf2;
+const COMMAND_SET_SAMPLE_RATE: u8 = 0xf3;
+const COMMAND_ENABLE_REPORTING: u8 = 0xf4;
+
+// 1バイト目のビット
+const FLAG_LEFT: u8 = 1 << 0;
+const FLAG_RIGHT: u8 = 1 << 1;
+const FLAG_MIDDLE: u8 = 1 << 2;
+// 常に1なので、パケットの区切りがずれていないかの確認に使う
+const FLAG_ALWAYS_ONE: u8 = 1 << 3;
+const FLAG_X_SIGN: u8 = 1 << 4;
+const FLAG_Y_SIGN: u8 = 1 << 5;
+const FLAG_X_OVERFLOW: u8 = 1 << 6;
+const FLAG_Y_OVERFLOW: u8 = 1 << 7;
+
+// 4バイト目のボタン(5ボタンのマウスのみ)
+const EXTRA_BUTTON_4: u8 = 1 << 4;
+const EXTRA_BUTTON_5: u8 = 1 << 5;
+
+/// マウスの種類。サンプルレートを決まった順に設定すると、対応しているマウスはIDが変わる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseKind {
+    /// 3ボタン、3バイトのパケット(ID 0)
+    Standard,
+    /// ホイール付き、4バイトのパケット(ID 3)
+    Wheel,
+    /// ホイールと5つのボタン、4バイトのパケット(ID 4)
+    FiveButtons,
+}
+
+impl MouseKind {
+    /// 1パケットのバイト数
+    pub fn packet_len(self) -> usize {
+        match self {
+            MouseKind::Standard => 3,
+            MouseKind::Wheel | MouseKind::FiveButtons => 4,
+        }
+    }
+}
+
+/// 押されているボタン
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseButtons(u8);
+
+impl MouseButtons {
+    pub const NONE: MouseButtons = MouseButtons(0);
+    pub const LEFT: MouseButtons = MouseButtons(1 << 0);
+    pub const RIGHT: MouseButtons = MouseButtons(1 << 1);
+    pub const MIDDLE: MouseButtons = MouseButtons(1 << 2);
+    pub const BUTTON4: MouseButtons = MouseButtons(1 << 3);
+    pub const BUTTON5: MouseButtons = MouseButtons(1 << 4);
+
+    pub fn contains(self, other: MouseButtons) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for MouseButtons {
+    type Output = MouseButtons;
+
+    fn bitor(self, other: MouseButtons) -> MouseButtons {
+        MouseButtons(self.0 | other.0)
+    }
+}
+
+/// 1パケット分のマウスの動きとボタンの状態
+///
+/// 画面の座標に合わせて、`dy`は下向き、`wheel`は手前に回したときを正にする。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub wheel: i8,
+    pub buttons: MouseButtons,
+}
+
+/// マウスから1バイトずつ受け取ってパケットにする
+pub struct PacketDecoder {
+    kind: MouseKind,
+    bytes: [u8; 4],
+    len: usize,
+}
+
+impl PacketDecoder {
+    pub const fn new(kind: MouseKind) -> PacketDecoder {
+        PacketDecoder {
+            kind,
+            bytes: [0; 4],
+            len: 0,
+        }
+    }
+
+    /// 1バイト渡して、パケットがそろったらイベントを返す
+    ///
+    /// 1バイト目の常に1のビットが0ならずれているので、そのバイトを捨てる。
+    /// 移動量があふれたパケットは捨てる。
+    pub fn add_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        if self.len == 0 && byte & FLAG_ALWAYS_ONE == 0 {
+            return None;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < self.kind.packet_len() {
+            return None;
+        }
+        self.len = 0;
+
+        let flags = self.bytes[0];
+        if flags & (FLAG_X_OVERFLOW | FLAG_Y_OVERFLOW) != 0 {
+            return None;
+        }
+        // 移動量は符号ビットを合わせた9ビットの2の補数
+        let dx = i16::from(self.bytes[1]) - if flags & FLAG_X_SIGN != 0 { 256 } else { 0 };
+        let dy = i16::from(self.bytes[2]) - if flags & FLAG_Y_SIGN != 0 { 256 } else { 0 };
+
+        let mut buttons = MouseButtons(flags & (FLAG_LEFT | FLAG_RIGHT | FLAG_MIDDLE));
+        let mut wheel = 0;
+        if self.kind != MouseKind::Standard {
+            let extra = self.bytes[3];
+            // ホイールは下位4ビットの2の補数
+            wheel = ((extra << 4) as i8) >> 4;
+            if self.kind == MouseKind::FiveButtons {
+                if extra & EXTRA_BUTTON_4 != 0 {
+                    buttons = buttons | MouseButtons::BUTTON4;
+                }
+                if extra & EXTRA_BUTTON_5 != 0 {
+                    buttons = buttons | MouseButtons::BUTTON5;
+                }
+            }
+        }
+        Some(MouseEvent {
+            dx,
+            dy: -dy,
+            wheel,
+            buttons,
+        })
+    }
+}
+
+// ボトムハーフで使うデコーダ。initializeでマウスの種類に合わせて作り直す
+static DECODER: Mutex<PacketDecoder> = Mutex::new(PacketDecoder::new(MouseKind::Standard));
+
+// コントローラから受け取ったバイトをデコーダに渡し、パケットがそろったらキューに入れる
+pub(super) fn add_byte(byte: u8) {
+    if let Some(event) = DECODER.lock().add_byte(byte) {
+        crate::task::mouse::add_event(event);
+    }
+}
+
+// サンプルレートを順に設定する
+unsafe fn set_sample_rates(controller: &mut Controller, rates: &[u8]) -> Result<(), Ps2Error> {
+    for &rate in rates {
+        controller.device_command(Ps2Port::Second, COMMAND_SET_SAMPLE_RATE)?;
+        controller.device_command(Ps2Port::Second, rate)?;
+    }
+    Ok(())
+}
+
+unsafe fn get_id(controller: &mut Controller) -> Result<u8, Ps2Error> {
+    controller.device_command(Ps2Port::Second, COMMAND_GET_ID)?;
+    controller.read_from(Ps2Port::Second)
+}
+
+// マウスをリセットし、ホイールと5ボタンを有効にしてからデータの送信を始めさせる
+pub(super) unsafe fn initialize(controller: &mut Controller) -> Result<MouseKind, Ps2Error> {
+    controller.reset_device(Ps2Port::Second)?;
+    // リセットの後にはIDが送られてくる(送ってこないマウスもあるので、タイムアウトしても気にしない)
+    let _ = controller.read_from(Ps2Port::Second);
+
+    // 200, 100, 80でホイール、200, 200, 80で5ボタンが有効になる
+    let mut kind = MouseKind::Standard;
+    set_sample_rates(controller, &[200, 100, 80])?;
+    if get_id(controller)? == 3 {
+        kind = MouseKind::Wheel;
+        set_sample_rates(controller, &[200, 200, 80])?;
+        if get_id(controller)? == 4 {
+            kind = MouseKind::FiveButtons;
+        }
+    }
+    set_sample_rates(controller, &[100])?;
+
+    *DECODER.lock() = PacketDecoder::new(kind);
+    controller.device_command(Ps2Port::Second, COMMAND_ENABLE_REPORTING)?;
+    Ok(kind)
+}
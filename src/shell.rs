@@ -0,0 +1,381 @@
+use crate::fs::{self, FileType, OpenFlags};
+use crate::task::keyboard::{self, KeyEventStream, Layout, Subscription};
+use crate::{print, println, vga_buffer};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use futures_util::StreamExt;
+use pc_keyboard::DecodedKey;
+use x86_64::VirtAddr;
+
+const PROMPT: &str = "> ";
+
+// シェルのコマンド
+// runには空白で区切ったコマンド名以降の引数が渡される
+// VGAバッファはASCIIしか表示できないので、出力は英語にしている
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: Run,
+}
+
+// 非同期のコマンドが返すfuture
+type CommandFuture<'a> = Pin<Box<dyn Future<Output = ()> + 'a>>;
+
+// コマンドの実装
+// ファイルシステムを使うコマンドはAsyncにして、シェルのタスクの中でawaitする
+enum Run {
+    Sync(fn(&[&str])),
+    Async(for<'a> fn(&'a [&'a str]) -> CommandFuture<'a>),
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "cat",
+        help: "<path>... print files",
+        run: Run::Async(cat),
+    },
+    Command {
+        name: "help",
+        help: "show available commands",
+        run: Run::Sync(help),
+    },
+    Command {
+        name: "irqs",
+        help: "show deferred interrupt work per IRQ",
+        run: Run::Sync(irqs),
+    },
+    Command {
+        name: "keyboard",
+        help: "[option value] show or change keyboard settings",
+        run: Run::Sync(keyboard),
+    },
+    Command {
+        name: "ls",
+        help: "[path] list directory entries",
+        run: Run::Async(ls),
+    },
+    Command {
+        name: "lsblk",
+        help: "list block devices",
+        run: Run::Sync(lsblk),
+    },
+    Command {
+        name: "lspci",
+        help: "list PCI devices",
+        run: Run::Sync(lspci),
+    },
+    Command {
+        name: "mkdir",
+        help: "<path>... create directories",
+        run: Run::Async(mkdir),
+    },
+    Command {
+        name: "mount",
+        help: "[device path] list or mount file systems",
+        run: Run::Async(mount),
+    },
+    Command {
+        name: "pagetable",
+        help: "[addr] show mapped regions, or translate addr",
+        run: Run::Sync(pagetable),
+    },
+    Command {
+        name: "ps",
+        help: "list tasks",
+        run: Run::Sync(ps),
+    },
+    Command {
+        name: "rm",
+        help: "<path>... remove files or empty directories",
+        run: Run::Async(rm),
+    },
+    Command {
+        name: "stat",
+        help: "<path>... show file information",
+        run: Run::Async(stat),
+    },
+    Command {
+        name: "sync",
+        help: "write cached file system changes to disk",
+        run: Run::Async(sync),
+    },
+    Command {
+        name: "touch",
+        help: "<path>... create empty files",
+        run: Run::Async(touch),
+    },
+    Command {
+        name: "umount",
+        help: "<path> unmount a file system",
+        run: Run::Async(umount),
+    },
+    Command {
+        name: "write",
+        help: "<path> <text>... write text to a file",
+        run: Run::Async(write),
+    },
+];
+
+/// キーボードから1行ずつ読み込んでコマンドを実行するシェル
+///
+/// キー入力は`task::keyboard::run`のタスクから受け取るので、一緒に動かすこと。
+pub async fn run() {
+    let mut events = KeyEventStream::subscribe(Subscription::Focused);
+    let mut line = String::new();
+
+    print!("{}", PROMPT);
+    while let Some(event) = events.next().await {
+        match event.key {
+            Some(DecodedKey::Unicode('\n')) => {
+                println!();
+                execute(&line).await;
+                line.clear();
+                print!("{}", PROMPT);
+            }
+            // バックスペース
+            Some(DecodedKey::Unicode('\u{8}')) => {
+                if line.pop().is_some() {
+                    vga_buffer::backspace();
+                }
+            }
+            // Ctrl+Cで入力中の行を捨てる
+            Some(DecodedKey::Unicode('\u{3}')) => {
+                println!("^C");
+                line.clear();
+                print!("{}", PROMPT);
+            }
+            Some(DecodedKey::Unicode(character)) if !character.is_control() => {
+                line.push(character);
+                print!("{}", character);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// 1行分のコマンドを実行する。
+pub async fn execute(line: &str) {
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return,
+    };
+    let args: Vec<&str> = words.collect();
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(Command {
+            run: Run::Sync(run),
+            ..
+        }) => run(&args),
+        Some(Command {
+            run: Run::Async(run),
+            ..
+        }) => run(&args).await,
+        None => println!("{}: command not found", name),
+    }
+}
+
+fn help(_args: &[&str]) {
+    for command in COMMANDS {
+        println!("{:<10} {}", command.name, command.help);
+    }
+}
+
+fn cat<'a>(args: &'a [&'a str]) -> CommandFuture<'a> {
+    Box::pin(async move {
+        for path in args {
+            match fs::read_file(path).await {
+                Ok(data) => print!("{}", String::from_utf8_lossy(&data)),
+                Err(err) => println!("cat: {}: {}", path, err),
+            }
+        }
+    })
+}
+
+fn irqs(_args: &[&str]) {
+    crate::task::deferred::print_stats();
+}
+
+fn keyboard(args: &[&str]) {
+    match args {
+        [] => println!("{}", keyboard::config()),
+        [key, value] => {
+            let mut config = keyboard::config();
+            match config.set(key, value) {
+                Ok(()) => keyboard::set_config(config),
+                Err(_) => println!("keyboard: invalid option: {} {}", key, value),
+            }
+        }
+        _ => {
+            let layouts: Vec<&str> = Layout::ALL.iter().map(|layout| layout.name()).collect();
+            println!(
+                "usage: keyboard [layout {} | set 1|2 | ctrl map|ignore | delay ms | rate cps]",
+                layouts.join("|")
+            );
+        }
+    }
+}
+
+fn ls<'a>(args: &'a [&'a str]) -> CommandFuture<'a> {
+    Box::pin(async move {
+        let path = args.first().copied().unwrap_or("/");
+        match fs::readdir(path).await {
+            Ok(entries) => {
+                for entry in entries {
+                    let suffix = if entry.file_type == FileType::Directory {
+                        "/"
+                    } else {
+                        ""
+                    };
+                    println!("{}{}", entry.name, suffix);
+                }
+            }
+            Err(err) => println!("ls: {}: {}", path, err),
+        }
+    })
+}
+
+fn mkdir<'a>(args: &'a [&'a str]) -> CommandFuture<'a> {
+    Box::pin(async move {
+        for path in args {
+            if let Err(err) = fs::create_dir(path).await {
+                println!("mkdir: {}: {}", path, err);
+            }
+        }
+    })
+}
+
+fn mount<'a>(args: &'a [&'a str]) -> CommandFuture<'a> {
+    Box::pin(async move {
+        match args {
+            [] => {
+                for (path, fs_name) in fs::mounts() {
+                    println!("{} on {}", fs_name, path);
+                }
+            }
+            [device, path] => {
+                let device = match crate::block::find(device) {
+                    Some(device) => device,
+                    None => {
+                        println!("mount: {}: no such device", device);
+                        return;
+                    }
+                };
+                if let Err(err) = fs::mount_device(path, device).await {
+                    println!("mount: {}: {}", path, err);
+                }
+            }
+            _ => println!("usage: mount [device path]"),
+        }
+    })
+}
+
+fn rm<'a>(args: &'a [&'a str]) -> CommandFuture<'a> {
+    Box::pin(async move {
+        for path in args {
+            if let Err(err) = fs::remove(path).await {
+                println!("rm: {}: {}", path, err);
+            }
+        }
+    })
+}
+
+fn stat<'a>(args: &'a [&'a str]) -> CommandFuture<'a> {
+    Box::pin(async move {
+        for path in args {
+            match fs::stat(path).await {
+                Ok(metadata) => println!(
+                    "{}: inode {} {:?} {} bytes",
+                    path, metadata.ino, metadata.file_type, metadata.size
+                ),
+                Err(err) => println!("stat: {}: {}", path, err),
+            }
+        }
+    })
+}
+
+fn sync<'a>(_args: &'a [&'a str]) -> CommandFuture<'a> {
+    Box::pin(async move {
+        if let Err(err) = fs::sync().await {
+            println!("sync: {}", err);
+        }
+    })
+}
+
+fn touch<'a>(args: &'a [&'a str]) -> CommandFuture<'a> {
+    Box::pin(async move {
+        for path in args {
+            let result = async {
+                let file = fs::open(path, OpenFlags::WRITE | OpenFlags::CREATE).await?;
+                fs::close(file.fd())
+            }
+            .await;
+            if let Err(err) = result {
+                println!("touch: {}: {}", path, err);
+            }
+        }
+    })
+}
+
+fn umount<'a>(args: &'a [&'a str]) -> CommandFuture<'a> {
+    Box::pin(async move {
+        for path in args {
+            if let Err(err) = fs::unmount(path).await {
+                println!("umount: {}: {}", path, err);
+            }
+        }
+    })
+}
+
+// 引数を空白1つでつないで、最後に改行を付けて書き込む
+fn write<'a>(args: &'a [&'a str]) -> CommandFuture<'a> {
+    Box::pin(async move {
+        let (path, words) = match args.split_first() {
+            Some(split) => split,
+            None => {
+                println!("write: missing path");
+                return;
+            }
+        };
+        let mut text = words.join(" ");
+        text.push('\n');
+        if let Err(err) = fs::write_file(path, text.as_bytes()).await {
+            println!("write: {}: {}", path, err);
+        }
+    })
+}
+
+fn lsblk(_args: &[&str]) {
+    crate::block::print_devices();
+}
+
+fn lspci(_args: &[&str]) {
+    crate::pci::print_devices();
+}
+
+fn ps(_args: &[&str]) {
+    crate::task::executor::print_tasks();
+}
+
+fn pagetable(args: &[&str]) {
+    use crate::memory::walk;
+
+    match args.first() {
+        Some(addr) => match parse_addr(addr) {
+            Some(addr) => walk::print_translation(addr),
+            None => println!("pagetable: invalid address: {}", addr),
+        },
+        None => walk::print_regions(),
+    }
+}
+
+// 0xから始まる16進数、またはそれ以外を10進数として仮想アドレスを読み取る
+fn parse_addr(s: &str) -> Option<VirtAddr> {
+    let addr = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16).ok()?,
+        None => s.parse().ok()?,
+    };
+    VirtAddr::try_new(addr).ok()
+}
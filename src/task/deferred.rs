@@ -0,0 +1,198 @@
+use crate::println;
+use crate::task::queue::{OverflowPolicy, Queue, QueueConfig};
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::Poll;
+use futures_util::future::poll_fn;
+use futures_util::task::AtomicWaker;
+
+// PICのIRQの数
+const IRQ_COUNT: usize = 16;
+// 溜めておける処理の数。いっぱいのときは捨てて、IRQごとに数える
+const WORK_QUEUE_SIZE: usize = 256;
+// ワーカーが1回のpollで実行する数。残りは次の順番で行い、ほかのタスクを待たせすぎない
+const BATCH: usize = 32;
+
+// 割り込みハンドラが積んだ処理
+struct Work {
+    irq: u8,
+    func: fn(usize),
+    arg: usize,
+    // 積んだときのTSC。実行されるまでの時間を測る
+    queued_at: u64,
+}
+
+static WORK_QUEUE: OnceCell<Queue<Work>> = OnceCell::uninit();
+// ワーカーのwaker
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+struct IrqCounters {
+    queued: AtomicU64,
+    dropped: AtomicU64,
+    run: AtomicU64,
+    cycles: AtomicU64,
+    max_latency: AtomicU64,
+}
+
+impl IrqCounters {
+    const NEW: IrqCounters = IrqCounters {
+        queued: AtomicU64::new(0),
+        dropped: AtomicU64::new(0),
+        run: AtomicU64::new(0),
+        cycles: AtomicU64::new(0),
+        max_latency: AtomicU64::new(0),
+    };
+}
+
+static COUNTERS: [IrqCounters; IRQ_COUNT] = [IrqCounters::NEW; IRQ_COUNT];
+
+/// IRQごとのボトムハーフの統計
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IrqStats {
+    pub irq: u8,
+    /// 積まれた処理の数
+    pub queued: u64,
+    /// キューがいっぱいか、初期化前で捨てた数
+    pub dropped: u64,
+    /// 実行し終わった数
+    pub run: u64,
+    /// 実行にかかったTSCのサイクル数の合計
+    pub cycles: u64,
+    /// 積まれてから実行が始まるまでのサイクル数の最大
+    pub max_latency: u64,
+}
+
+fn read_tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// キューを用意する。これより前に積まれた処理は捨てられる
+///
+/// ヒープを使うので、ヒープの初期化の後、割り込みハンドラが`defer`を呼ぶ前に呼ぶ。
+/// 二度目以降は何もしない。
+pub fn init() {
+    let _ = WORK_QUEUE.try_init_once(|| {
+        Queue::new(QueueConfig::new(
+            WORK_QUEUE_SIZE,
+            OverflowPolicy::Backpressure,
+        ))
+    });
+}
+
+/// `irq`番の割り込みの後半の処理として、ワーカーに`func(arg)`を実行させる
+///
+/// `irq`は0~15。割り込みハンドラから呼ぶ。積めなかったとき(範囲外の`irq`も含む)はfalseを返す
+/// (数は`stats`で見る)。
+///
+/// 割り込みハンドラは割り込みを止めたまま動き、割り込まれたコードがロックを持っているかも
+/// しれないので、VGAへの表示や時間のかかる処理はしたくない。ハンドラはデバイスから読んだ値を
+/// これで積むだけにし、残りの処理はワーカーのタスク(`run`)が割り込みを有効にしたまま行う。
+///
+/// 処理を積むのも、ワーカーを起こす(executorのキューにタスクのIDを積む)のもロックのいらない
+/// キューへのpushだけで、ロックもヒープも使わないので、割り込みハンドラから呼べる。
+pub fn defer(irq: u8, func: fn(usize), arg: usize) -> bool {
+    let counters = match COUNTERS.get(usize::from(irq)) {
+        Some(counters) => counters,
+        None => return false,
+    };
+    let work = Work {
+        irq,
+        func,
+        arg,
+        queued_at: read_tsc(),
+    };
+    let queued = match WORK_QUEUE.try_get() {
+        Ok(queue) => queue.push(work).is_ok(),
+        Err(_) => false,
+    };
+    if queued {
+        counters.queued.fetch_add(1, Ordering::Relaxed);
+        WAKER.wake();
+    } else {
+        counters.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+    queued
+}
+
+/// `irq`番のIRQの統計。範囲外の`irq`では全て0
+pub fn stats(irq: u8) -> IrqStats {
+    let counters = match COUNTERS.get(usize::from(irq)) {
+        Some(counters) => counters,
+        None => {
+            return IrqStats {
+                irq,
+                ..IrqStats::default()
+            }
+        }
+    };
+    IrqStats {
+        irq,
+        queued: counters.queued.load(Ordering::Relaxed),
+        dropped: counters.dropped.load(Ordering::Relaxed),
+        run: counters.run.load(Ordering::Relaxed),
+        cycles: counters.cycles.load(Ordering::Relaxed),
+        max_latency: counters.max_latency.load(Ordering::Relaxed),
+    }
+}
+
+/// 一度でも処理が積まれたか捨てられたIRQの統計を表示する
+pub fn print_stats() {
+    println!(
+        "{:>3} {:>8} {:>8} {:>8} {:>14} {:>12}",
+        "IRQ", "QUEUED", "DROPPED", "RUN", "CYCLES", "MAX LATENCY"
+    );
+    for irq in 0..IRQ_COUNT as u8 {
+        let stats = stats(irq);
+        if stats.queued == 0 && stats.dropped == 0 {
+            continue;
+        }
+        println!(
+            "{:>3} {:>8} {:>8} {:>8} {:>14} {:>12}",
+            stats.irq, stats.queued, stats.dropped, stats.run, stats.cycles, stats.max_latency
+        );
+    }
+}
+
+// 積まれている処理を最大BATCH個実行する。残っていたらtrue
+fn run_batch(queue: &Queue<Work>) -> bool {
+    for _ in 0..BATCH {
+        let work = match queue.pop() {
+            Some(work) => work,
+            None => return false,
+        };
+        let start = read_tsc();
+        (work.func)(work.arg);
+        let counters = &COUNTERS[usize::from(work.irq)];
+        counters.run.fetch_add(1, Ordering::Relaxed);
+        counters
+            .cycles
+            .fetch_add(read_tsc().wrapping_sub(start), Ordering::Relaxed);
+        counters
+            .max_latency
+            .fetch_max(start.wrapping_sub(work.queued_at), Ordering::Relaxed);
+    }
+    !queue.is_empty()
+}
+
+/// ボトムハーフのワーカー。`Priority::BottomHalf`で動かす
+///
+/// 積まれた処理を積まれた順に実行し続ける。
+pub async fn run() {
+    init();
+    let queue = WORK_QUEUE.try_get().expect("初期化されてません");
+    poll_fn(|cx| -> Poll<()> {
+        if run_batch(queue) {
+            // まだ残っているので、ほかのタスクの後でもう一度pollしてもらう
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        // 空かもしれないのでWAKERを登録してからもう一度見る
+        WAKER.register(cx.waker());
+        if !queue.is_empty() {
+            WAKER.take();
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    })
+    .await
+}
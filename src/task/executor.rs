@@ -1,18 +1,201 @@
 use crate::println;
+use crate::task::join::{self, JoinHandle};
+use crate::task::queue::{OverflowPolicy, Queue, QueueConfig, QueueStats};
 use crate::task::{Task, TaskId};
-use alloc::collections::BTreeMap;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::future::Future;
 use core::task::{Context, Poll, Waker};
-use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// `Executor::new`で使うtask_queueの設定
+///
+/// wakerは割り込みハンドラからも呼ばれるので、Growは使えない。
+pub const DEFAULT_TASK_QUEUE: QueueConfig = QueueConfig::new(100, OverflowPolicy::DropNewest);
+
+/// タスクの優先度。高いものから順に、同じ優先度の中では起こされた順に実行する
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// 割り込みの後半処理
+    BottomHalf,
+    /// シェルなど、ユーザの入力に応えるタスク
+    Interactive,
+    /// 急がないタスク
+    Background,
+}
+
+impl Priority {
+    /// 高い順
+    pub const ALL: [Priority; 3] = [
+        Priority::BottomHalf,
+        Priority::Interactive,
+        Priority::Background,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Priority::BottomHalf => "bottom-half",
+            Priority::Interactive => "interactive",
+            Priority::Background => "background",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    // 1ラウンドで1つのタスクをpollできる回数の初期値
+    fn default_budget(self) -> u32 {
+        match self {
+            Priority::BottomHalf => 4,
+            Priority::Interactive => 2,
+            Priority::Background => 1,
+        }
+    }
+}
+
+/// タスクの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// 起こされて、pollされるのを待っている
+    Ready,
+    /// pollされている最中
+    Running,
+    /// 起こされるのを待っている
+    Pending,
+    /// 終わった
+    Completed,
+}
+
+impl TaskState {
+    pub fn name(self) -> &'static str {
+        match self {
+            TaskState::Ready => "ready",
+            TaskState::Running => "running",
+            TaskState::Pending => "pending",
+            TaskState::Completed => "completed",
+        }
+    }
+}
+
+/// タスクごとのpollの統計
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskStats {
+    pub priority: Priority,
+    /// pollした回数
+    pub polls: u64,
+    /// pollにかかった時間の合計(TSCのサイクル数)
+    pub cycles: u64,
+    /// 1回のpollにかかった時間の最大(TSCのサイクル数)
+    pub max_cycles: u64,
+    /// 予算を使い切って次のラウンドに回された回数
+    pub deferred: u64,
+}
+
+impl TaskStats {
+    fn new(priority: Priority) -> Self {
+        TaskStats {
+            priority,
+            polls: 0,
+            cycles: 0,
+            max_cycles: 0,
+            deferred: 0,
+        }
+    }
+}
+
+/// `tasks`で返すタスクの情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    /// spawnしたときに付けた名前。付けなかったときは空
+    pub name: String,
+    pub state: TaskState,
+    pub stats: TaskStats,
+}
+
+// 実行中のタスクの情報。executorがpollするたびに更新し、`tasks`で読む
+static TASKS: Mutex<Vec<Arc<Mutex<TaskInfo>>>> = Mutex::new(Vec::new());
+// 終わったタスクの情報。古いものから捨てる
+static COMPLETED: Mutex<Vec<TaskInfo>> = Mutex::new(Vec::new());
+const COMPLETED_HISTORY: usize = 16;
+
+/// 全部のexecutorで実行中のタスクと、最近終わったタスクの情報をIDの順に返す
+pub fn tasks() -> Vec<TaskInfo> {
+    let mut tasks: Vec<TaskInfo> = COMPLETED.lock().clone();
+    tasks.extend(TASKS.lock().iter().map(|info| info.lock().clone()));
+    tasks.sort_by_key(|info| info.id);
+    tasks
+}
+
+/// タスクの一覧を表示する
+pub fn print_tasks() {
+    println!(
+        "{:>4} {:<16} {:<9} {:<11} {:>8} {:>14}",
+        "ID", "NAME", "STATE", "PRIORITY", "POLLS", "CYCLES"
+    );
+    for info in tasks() {
+        let name = if info.name.is_empty() {
+            "-"
+        } else {
+            &info.name
+        };
+        println!(
+            "{:>4} {:<16} {:<9} {:<11} {:>8} {:>14}",
+            info.id,
+            name,
+            info.state.name(),
+            info.stats.priority.name(),
+            info.stats.polls,
+            info.stats.cycles
+        );
+    }
+}
+
+fn unregister(info: &Arc<Mutex<TaskInfo>>) {
+    TASKS.lock().retain(|other| !Arc::ptr_eq(other, info));
+}
+
+// executorが持つタスクと、その実行の状態
+struct TaskEntry {
+    task: Task,
+    priority: Priority,
+    info: Arc<Mutex<TaskInfo>>,
+    // readyに入っているか。何度起こされても1回だけ入れる
+    queued: bool,
+}
+
+impl TaskEntry {
+    fn set_state(&self, state: TaskState) {
+        self.info.lock().state = state;
+    }
+}
+
+fn read_tsc() -> u64 {
+    // RDTSCは特権やメモリに関係なく使える
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
 
 pub struct Executor {
     // 実際にTaskを格納しているBtreeMap
-    tasks: BTreeMap<TaskId, Task>,
-    // タスクIDを格納するqueue
+    tasks: BTreeMap<TaskId, TaskEntry>,
+    // 優先度ごとに、起こされたタスクIDを格納するqueue
     // Arcを使っているのはtask_queueがexecutorとwakerで共有されるから
     // wakerが起こされたTaskIdをqueueに入れて、executorはそれを受けてTaskを実行する
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    task_queues: [Arc<Queue<TaskId>>; 3],
+    // Spawnerからspawnされ、まだtasksに入れていないTask
+    new_tasks: Arc<Mutex<VecDeque<NewTask>>>,
+    // 優先度ごとの、次にpollするタスク。spawnされたばかりのタスクもここに入れる
+    ready: [VecDeque<TaskId>; 3],
+    // 優先度ごとに、1ラウンドで1つのタスクをpollできる回数
+    budgets: [u32; 3],
+    // task_queueがあふれて捨てられたwakeupの数。増えていたら全部のタスクをpollし直す
+    lost_wakeups: u64,
     // Taskが作成された後にそのタスクのWakerをcacheする
     // - 同じタスクのwakeupに対してはwakerを使いまわしたい
     // - 参照カウントされるwakerが割り込みハンドラ内で解放されないようにするため
@@ -21,57 +204,263 @@ pub struct Executor {
 
 impl Executor {
     pub fn new() -> Self {
+        Self::with_queue(DEFAULT_TASK_QUEUE)
+    }
+
+    /// task_queueの容量とあふれたときの扱いを指定して作る
+    ///
+    /// task_queueは優先度ごとにあり、それぞれがこの設定になる。
+    /// あふれて捨てられたwakeupがあったときは、全部のタスクをpollし直すので、
+    /// どのポリシーでもタスクが起こされないままになることはない。
+    pub fn with_queue(config: QueueConfig) -> Self {
+        assert!(
+            config.policy != OverflowPolicy::Grow,
+            "task_queueは割り込みハンドラからpushされるのでGrowは使えません"
+        );
+        let queue = || Arc::new(Queue::new(config));
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_queues: [queue(), queue(), queue()],
+            new_tasks: Arc::new(Mutex::new(VecDeque::new())),
+            ready: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            budgets: [
+                Priority::BottomHalf.default_budget(),
+                Priority::Interactive.default_budget(),
+                Priority::Background.default_budget(),
+            ],
+            lost_wakeups: 0,
             waker_cache: BTreeMap::new(),
         }
     }
 
-    pub fn spawn(&mut self, task: Task) {
-        println!("[Executor::spawn] task spawned: {}", &task.id.0);
+    /// 優先度`priority`のtask_queueの統計
+    pub fn queue_stats(&self, priority: Priority) -> QueueStats {
+        self.task_queues[priority.index()].stats()
+    }
+
+    /// 優先度`priority`のタスクを1ラウンドでpollできる回数を設定する
+    ///
+    /// 予算を使い切ったタスクは、起こされても次のラウンドまで待たされるので、
+    /// 自分を起こし続けるタスクがあっても他のタスクが止まらない。
+    pub fn set_poll_budget(&mut self, priority: Priority, budget: u32) {
+        assert!(budget > 0, "予算が0だとタスクが実行されません");
+        self.budgets[priority.index()] = budget;
+    }
+
+    /// `future`をタスクとして実行し、その結果を受け取るJoinHandleを返す
+    pub fn spawn<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.spawn_named("", Priority::Interactive, future)
+    }
+
+    /// 優先度を指定してspawnする
+    pub fn spawn_with_priority<F>(&mut self, priority: Priority, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.spawn_named("", priority, future)
+    }
+
+    /// 名前と優先度を指定してspawnする。名前は`tasks`で見える
+    pub fn spawn_named<F>(
+        &mut self,
+        name: &str,
+        priority: Priority,
+        future: F,
+    ) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let (task, handle) = NewTask::new(name, priority, future);
+        self.spawn_task(task);
+        handle
+    }
+
+    /// 実行中のタスクからspawnするためのSpawnerを返す
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            new_tasks: self.new_tasks.clone(),
+        }
+    }
+
+    fn spawn_task(&mut self, new_task: NewTask) {
+        let NewTask {
+            task,
+            name,
+            priority,
+        } = new_task;
         let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
+        let info = Arc::new(Mutex::new(TaskInfo {
+            id: task_id,
+            name,
+            state: TaskState::Ready,
+            stats: TaskStats::new(priority),
+        }));
+        TASKS.lock().push(info.clone());
+        let entry = TaskEntry {
+            task,
+            priority,
+            info,
+            queued: false,
+        };
+        if self.tasks.insert(task_id, entry).is_some() {
             panic!("同じTaskIdがすでにtasks内にあります");
         }
-        self.task_queue
-            .push(task_id)
-            .expect("queueへのTaskIdの追加に失敗");
+        self.make_ready(task_id);
+    }
+
+    // タスクをreadyに入れる。すでに入っていたら何もしない
+    fn make_ready(&mut self, task_id: TaskId) {
+        if let Some(entry) = self.tasks.get_mut(&task_id) {
+            if !entry.queued {
+                entry.queued = true;
+                entry.set_state(TaskState::Ready);
+                self.ready[entry.priority.index()].push_back(task_id);
+            }
+        }
+    }
+
+    // Spawnerからspawnされたタスクを受け取る
+    fn take_new_tasks(&mut self) {
+        let new_tasks = interrupts::without_interrupts(|| {
+            core::mem::replace(&mut *self.new_tasks.lock(), VecDeque::new())
+        });
+        for new_task in new_tasks {
+            self.spawn_task(new_task);
+        }
     }
 
+    // 起こされたタスクをtask_queueからreadyに移す
+    fn take_woken_tasks(&mut self) {
+        for index in 0..self.task_queues.len() {
+            while let Some(task_id) = self.task_queues[index].pop() {
+                self.make_ready(task_id);
+            }
+        }
+    }
+
+    fn count_lost_wakeups(&self) -> u64 {
+        self.task_queues
+            .iter()
+            .map(|queue| {
+                let stats = queue.stats();
+                stats.dropped + stats.rejected
+            })
+            .sum()
+    }
+
+    // 捨てられたwakeupがあれば、どのタスクが起こされたかわからないので全部をpollし直す
+    fn recover_lost_wakeups(&mut self) {
+        let lost = self.count_lost_wakeups();
+        if lost != self.lost_wakeups {
+            self.lost_wakeups = lost;
+            let task_ids: Vec<TaskId> = self.tasks.keys().copied().collect();
+            for task_id in task_ids {
+                self.make_ready(task_id);
+            }
+        }
+    }
+
+    // 実行できるタスクがあるか
+    fn has_ready_tasks(&self) -> bool {
+        self.ready.iter().any(|ready| !ready.is_empty())
+            || self.task_queues.iter().any(|queue| !queue.is_empty())
+            || self.count_lost_wakeups() != self.lost_wakeups
+            || !interrupts::without_interrupts(|| self.new_tasks.lock().is_empty())
+    }
+
+    // 1ラウンド実行する
+    //
+    // 優先度の高いreadyから順にpollし、起こされたタスクはその都度readyに入れる。
+    // 予算を使い切ったタスクは次のラウンドに回すので、readyが空になればラウンドが終わる。
     fn run_ready_tasks(&mut self) {
-        // self.task_queueをclosureの中でアクセスするがその際、selfを完全に借用してしまうので分配
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
+        self.take_new_tasks();
+        self.recover_lost_wakeups();
+        self.take_woken_tasks();
 
-        while let Ok(task_id) = task_queue.pop() {
-            println!("[Executor::run_ready_tasks] popped: {}", &task_id.0);
+        // このラウンドで各タスクをpollした回数
+        let mut polled: BTreeMap<TaskId, u32> = BTreeMap::new();
+        let mut deferred = Vec::new();
+
+        while let Some(task_id) = self.ready.iter_mut().find_map(|ready| ready.pop_front()) {
+            let Self {
+                tasks,
+                task_queues,
+                budgets,
+                waker_cache,
+                ..
+            } = self;
             // popされたTaskIdに対して、Taskを取得
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
+            let entry = match tasks.get_mut(&task_id) {
+                Some(entry) => entry,
                 None => continue,
             };
+            let priority = entry.priority;
+            let count = polled.entry(task_id).or_insert(0);
+            if *count >= budgets[priority.index()] {
+                // readyに入ったままにしておき、ラウンドの後で戻す
+                entry.info.lock().stats.deferred += 1;
+                deferred.push(task_id);
+                continue;
+            }
+            *count += 1;
+            entry.queued = false;
+
             // waker_cacheからwakerの取得
             let waker = waker_cache
                 .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            println!("[Executor::run_ready_tasks] waker got");
+                .or_insert_with(|| TaskWaker::new(task_id, task_queues[priority.index()].clone()));
 
             let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                // TaskがReadyを返したら完了しているので、TaskIdに紐づくものを消す
-                Poll::Ready(()) => {
-                    println!("[Executor::run_ready_tasks] polling Ready");
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
-                Poll::Pending => {
-                    println!("[Executor::run_ready_tasks] polling Pending");
+            entry.set_state(TaskState::Running);
+            let start = read_tsc();
+            let poll = entry.task.poll(&mut context);
+            let cycles = read_tsc().wrapping_sub(start);
+            {
+                let mut info = entry.info.lock();
+                info.stats.polls += 1;
+                info.stats.cycles += cycles;
+                info.stats.max_cycles = info.stats.max_cycles.max(cycles);
+                // pollの間に起こされていたら、この後のtake_woken_tasksでReadyになる
+                info.state = match poll {
+                    Poll::Ready(()) => TaskState::Completed,
+                    Poll::Pending => TaskState::Pending,
+                };
+            }
+            // TaskがReadyを返したら完了しているので、TaskIdに紐づくものを消す
+            if poll.is_ready() {
+                if let Some(entry) = tasks.remove(&task_id) {
+                    unregister(&entry.info);
+                    let mut completed = COMPLETED.lock();
+                    if completed.len() == COMPLETED_HISTORY {
+                        completed.remove(0);
+                    }
+                    completed.push(entry.info.lock().clone());
                 }
+                waker_cache.remove(&task_id);
             }
+            // pollの間に起こされたタスクや、spawnされたタスクもこのラウンドで実行する
+            self.take_new_tasks();
+            self.take_woken_tasks();
+        }
+
+        for task_id in deferred {
+            if let Some(entry) = self.tasks.get(&task_id) {
+                entry.set_state(TaskState::Ready);
+                self.ready[entry.priority.index()].push_back(task_id);
+            }
+        }
+    }
+
+    /// 実行できるタスクがなくなるまで実行して戻る
+    pub fn run_until_stalled(&mut self) {
+        while self.has_ready_tasks() {
+            self.run_ready_tasks();
         }
     }
 
@@ -83,11 +472,11 @@ impl Executor {
     }
 
     fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+        use x86_64::instructions::interrupts::enable_and_hlt;
 
         // 一旦割り込みを無効にして
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if !self.has_ready_tasks() {
             // queueが空なら割り込み有効にしてhlt
             enable_and_hlt();
         } else {
@@ -97,16 +486,95 @@ impl Executor {
     }
 }
 
+impl Drop for Executor {
+    // 実行されないまま捨てられるタスクを一覧から消す
+    fn drop(&mut self) {
+        for entry in self.tasks.values() {
+            unregister(&entry.info);
+        }
+    }
+}
+
+// spawnされ、まだexecutorに入れていないタスク
+struct NewTask {
+    task: Task,
+    name: String,
+    priority: Priority,
+}
+
+impl NewTask {
+    fn new<F>(name: &str, priority: Priority, future: F) -> (NewTask, JoinHandle<F::Output>)
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let id = TaskId::new();
+        let (future, handle) = join::joinable(id, future);
+        let task = Task {
+            id,
+            future: Box::pin(future),
+        };
+        let new_task = NewTask {
+            task,
+            name: String::from(name),
+            priority,
+        };
+        (new_task, handle)
+    }
+}
+
+/// 実行中のタスクから新しいタスクをspawnするためのハンドル
+///
+/// クローンして好きなタスクに渡せる。spawnしたタスクは、executorが次に
+/// 実行できるタスクを探すときに取り込まれる。タスクを作るときにヒープを使うので、
+/// 割り込みハンドラの中からは使えない。
+#[derive(Clone)]
+pub struct Spawner {
+    new_tasks: Arc<Mutex<VecDeque<NewTask>>>,
+}
+
+impl Spawner {
+    /// `future`をタスクとして実行し、その結果を受け取るJoinHandleを返す
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.spawn_named("", Priority::Interactive, future)
+    }
+
+    /// 優先度を指定してspawnする
+    pub fn spawn_with_priority<F>(&self, priority: Priority, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.spawn_named("", priority, future)
+    }
+
+    /// 名前と優先度を指定してspawnする。名前は`tasks`で見える
+    pub fn spawn_named<F>(&self, name: &str, priority: Priority, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let (task, handle) = NewTask::new(name, priority, future);
+        // ロックしている間に割り込まれないようにする
+        interrupts::without_interrupts(|| self.new_tasks.lock().push_back(task));
+        handle
+    }
+}
+
 // 起こされたTaskIdをtask_queueにpushするためのWaker
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    task_queue: Arc<Queue<TaskId>>,
 }
 
 impl TaskWaker {
     // 渡されたtask_idとtask_queueを使ってWakerを作る
     // Arcでラップしている
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+    fn new(task_id: TaskId, task_queue: Arc<Queue<TaskId>>) -> Waker {
         Waker::from(Arc::new(TaskWaker {
             task_id,
             task_queue,
@@ -114,10 +582,8 @@ impl TaskWaker {
     }
 
     fn wake_task(&self) {
-        println!("[TaskWaker::wake_task] wake task");
-        self.task_queue
-            .push(self.task_id)
-            .expect("task_queueが満タンです")
+        // あふれたときはキューが数えておき、executorが全部のタスクをpollし直す
+        let _ = self.task_queue.push(self.task_id);
     }
 }
 
@@ -0,0 +1,194 @@
+use super::TaskId;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+/// タスクが値を返さずに終わった理由
+///
+/// このカーネルはpanic = "abort"でビルドしているので、タスクがパニックしたときは
+/// カーネルごと止まり、JoinHandleに結果が返ることはない。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// `JoinHandle::abort`で中断されたか、終わる前にexecutorごと捨てられた
+    Aborted,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Aborted => write!(f, "task was aborted"),
+        }
+    }
+}
+
+enum Stage<T> {
+    Running,
+    Finished(Result<T, JoinError>),
+    // JoinHandleが結果を受け取った後
+    Taken,
+}
+
+// タスクとJoinHandleで共有する状態
+struct JoinState<T> {
+    stage: Stage<T>,
+    aborted: bool,
+    // 結果を待っているタスク
+    joiner: Option<Waker>,
+    // abortしたときに起こすため、最後にpollされたときのwaker
+    task: Option<Waker>,
+}
+
+impl<T> JoinState<T> {
+    fn new() -> Self {
+        JoinState {
+            stage: Stage::Running,
+            aborted: false,
+            joiner: None,
+            task: None,
+        }
+    }
+}
+
+// 結果を書き込んで、待っているタスクを起こす
+fn finish<T>(state: &Mutex<JoinState<T>>, result: Result<T, JoinError>) {
+    let joiner = {
+        let mut state = state.lock();
+        state.stage = Stage::Finished(result);
+        state.task = None;
+        state.joiner.take()
+    };
+    // ロックを持ったまま起こすと、起こされた側が同じロックを取ろうとするかもしれない
+    if let Some(waker) = joiner {
+        waker.wake();
+    }
+}
+
+// futureの結果をJoinStateに書き込むラッパー。executorにはこれをTaskとして渡す
+struct Joinable<F: Future> {
+    // 終わったか中断されたらすぐに捨てる
+    future: Option<Pin<Box<F>>>,
+    state: Arc<Mutex<JoinState<F::Output>>>,
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = &mut *self;
+        let aborted = {
+            let mut state = this.state.lock();
+            if !state.aborted {
+                let update = match &state.task {
+                    Some(waker) => !waker.will_wake(cx.waker()),
+                    None => true,
+                };
+                if update {
+                    state.task = Some(cx.waker().clone());
+                }
+            }
+            state.aborted
+        };
+        if aborted {
+            this.future = None;
+            finish(&this.state, Err(JoinError::Aborted));
+            return Poll::Ready(());
+        }
+
+        let future = match this.future.as_mut() {
+            Some(future) => future,
+            None => return Poll::Ready(()),
+        };
+        let poll = future.as_mut().poll(cx);
+        match poll {
+            Poll::Ready(output) => {
+                this.future = None;
+                finish(&this.state, Ok(output));
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<F: Future> Drop for Joinable<F> {
+    fn drop(&mut self) {
+        if self.future.take().is_some() {
+            finish(&self.state, Err(JoinError::Aborted));
+        }
+    }
+}
+
+/// spawnしたタスクの結果を待つためのハンドル
+///
+/// awaitすると、タスクが返した値か、値を返さずに終わった理由が返る。
+/// JoinHandleをdropしてもタスクは止まらない。
+pub struct JoinHandle<T> {
+    id: TaskId,
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// タスクのID
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// タスクを中断する。次にpollされるときにfutureがdropされ、結果は`JoinError::Aborted`になる
+    ///
+    /// すでに終わっていたら何もしない。
+    pub fn abort(&self) {
+        let task = {
+            let mut state = self.state.lock();
+            if !matches!(state.stage, Stage::Running) {
+                return;
+            }
+            state.aborted = true;
+            state.task.take()
+        };
+        if let Some(waker) = task {
+            waker.wake();
+        }
+    }
+
+    /// タスクが終わっているか(中断されたときも含む)
+    pub fn is_finished(&self) -> bool {
+        !matches!(self.state.lock().stage, Stage::Running)
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        match core::mem::replace(&mut state.stage, Stage::Taken) {
+            Stage::Finished(result) => Poll::Ready(result),
+            Stage::Running => {
+                state.stage = Stage::Running;
+                state.joiner = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            Stage::Taken => panic!("JoinHandleが完了後にpollされました"),
+        }
+    }
+}
+
+// IDが`id`のタスクとして実行する`future`を、結果をJoinHandleに渡すfutureに包む
+pub(crate) fn joinable<F>(
+    id: TaskId,
+    future: F,
+) -> (impl Future<Output = ()>, JoinHandle<F::Output>)
+where
+    F: Future,
+{
+    let state = Arc::new(Mutex::new(JoinState::new()));
+    let joinable = Joinable {
+        future: Some(Box::pin(future)),
+        state: state.clone(),
+    };
+    (joinable, JoinHandle { id, state })
+}
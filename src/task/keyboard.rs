@@ -1,25 +1,35 @@
-use crate::{print, println};
+use crate::ps2::keyboard::Leds;
+use crate::ps2::{self, Ps2Error};
+use crate::task::queue::{OverflowPolicy, Queue, QueueConfig, QueueStats};
+use crate::{cmdline, print, println};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use conquer_once::spin::OnceCell;
+use core::fmt;
 use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use core::task::{Context, Poll};
-use crossbeam_queue::ArrayQueue;
 use futures_util::task::AtomicWaker;
 use futures_util::{Stream, StreamExt};
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::{
+    layouts, DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet1, ScancodeSet2,
+};
+use spin::Mutex;
 
 // コンパイル時にヒープ割り当てできないので、OnceCellで静的な値の安全な一回限りの初期化をする
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static SCANCODE_QUEUE: OnceCell<Queue<u8>> = OnceCell::uninit();
+
+/// `ScancodeStream::new`で使うキューの設定
+pub const DEFAULT_SCANCODE_QUEUE: QueueConfig = QueueConfig::new(100, OverflowPolicy::DropNewest);
 
 // キューが初期化されていなかったら警告を出す
-// この関数は割り込みハンドラから呼び出され、キューの初期化は割り込みハンドラから行うべきではない
+// この関数はキーボードの割り込みのボトムハーフ(task::deferred)から呼び出される
+// 割り込みハンドラの外なので表示してもいいが、キューの初期化はScancodeStream::newで行う
 // main.rsからも呼び出し可能であってはいけないので、pub(crate)にしている
 pub(crate) fn add_scancode(scancode: u8) {
-    println!("[add_scancode]add scancode!!");
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if let Err(_) = queue.push(scancode) {
-            println!("WARNING: SCANCODE_QUEUEが満タンです")
-        } else {
-            println!("[add_scancode]Wakeup!!");
+        // あふれたときはポリシーに従って捨て、数はscancode_statsで見る
+        if queue.push(scancode).is_ok() {
             WAKER.wake(); // ここを追加
         }
     } else {
@@ -34,40 +44,50 @@ pub struct ScancodeStream {
 
 impl ScancodeStream {
     pub fn new() -> Self {
+        Self::with_queue(DEFAULT_SCANCODE_QUEUE)
+    }
+
+    /// キューの容量とあふれたときの扱いを指定して作る
+    ///
+    /// ボトムハーフから待たずにpushするので、Backpressureは使えない。
+    pub fn with_queue(config: QueueConfig) -> Self {
+        assert!(
+            config.policy != OverflowPolicy::Backpressure,
+            "スキャンコードのキューにBackpressureは使えません"
+        );
         SCANCODE_QUEUE
-            .try_init_once(|| ArrayQueue::new(100))
+            .try_init_once(|| Queue::new(config))
             .expect("ScancodeStream::newは一度しか呼び出せません");
         ScancodeStream { _private: () }
     }
 }
 
+/// スキャンコードのキューの統計。ScancodeStreamを作る前はNone
+pub fn scancode_stats() -> Option<QueueStats> {
+    SCANCODE_QUEUE.try_get().ok().map(Queue::stats)
+}
+
 impl Stream for ScancodeStream {
     type Item = u8;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        println!("[poll_next]poll_next!!");
         // キューへの参照取得(newで初期化しているので、失敗しないはず)
         let queue = SCANCODE_QUEUE.try_get().expect("初期化されてません");
 
         // キューが空ではなかったらWAKERを登録しなくていいので早期リターン
-        if let Ok(scancode) = queue.pop() {
-            println!("[poll_next][1st]queue is not empty!!");
+        if let Some(scancode) = queue.pop() {
             return Poll::Ready(Some(scancode));
         }
         // キューが空かもしれないのでWAKER登録
-        println!("[poll_next]waker register!!");
         WAKER.register(&cx.waker());
         match queue.pop() {
-            Ok(scancode) => {
+            Some(scancode) => {
                 // 通知が不要なのでWAKERを消す
-                println!("[poll_next][2nd]queue is not empty!!");
                 WAKER.take();
                 Poll::Ready(Some(scancode))
             }
-            Err(crossbeam_queue::PopError) => {
-                println!("[poll_next][2nd]queue is empty!!");
-                Poll::Pending
-            } // queueが空の場合
+            // queueが空の場合
+            None => Poll::Pending,
         }
     }
 }
@@ -76,20 +96,555 @@ impl Stream for ScancodeStream {
 // add_scancodeではこのWAKERを呼び出す
 static WAKER: AtomicWaker = AtomicWaker::new();
 
-pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+/// キーボードの配列
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us104,
+    Jis109,
+    Uk105,
+    Dvorak104,
+}
 
-    println!("[print_keypresses]start wait!!");
-    while let Some(scancode) = scancodes.next().await {
-        println!("[print_keypresses]key pressed!!");
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
+impl Layout {
+    pub const ALL: [Layout; 4] = [
+        Layout::Us104,
+        Layout::Jis109,
+        Layout::Uk105,
+        Layout::Dvorak104,
+    ];
+
+    /// コマンドラインやシェルで使う名前
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us104 => "us",
+            Layout::Jis109 => "jis",
+            Layout::Uk105 => "uk",
+            Layout::Dvorak104 => "dvorak",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Layout::ALL
+            .iter()
+            .copied()
+            .find(|layout| layout.name() == name)
+    }
+}
+
+/// キーボードが送ってくるスキャンコードの種類
+///
+/// PS/2キーボードはセット2を送り、セット1のときは8042コントローラに変換させる。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scancodes {
+    Set1,
+    Set2,
+}
+
+impl Scancodes {
+    pub fn name(self) -> &'static str {
+        match self {
+            Scancodes::Set1 => "1",
+            Scancodes::Set2 => "2",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Scancodes> {
+        match name {
+            "1" | "set1" => Some(Scancodes::Set1),
+            "2" | "set2" => Some(Scancodes::Set2),
+            _ => None,
+        }
+    }
+}
+
+/// キーボードの設定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyboardConfig {
+    pub layout: Layout,
+    pub scancodes: Scancodes,
+    /// trueならCtrl+AからCtrl+Zを制御文字(U+0001からU+001A)にする
+    pub map_ctrl: bool,
+    /// キーを押し続けてからリピートが始まるまで(250msから1000ms)
+    pub repeat_delay: u32,
+    /// リピートの速度(毎秒2回から30回)
+    pub repeat_rate: u32,
+}
+
+impl KeyboardConfig {
+    pub const fn new() -> Self {
+        KeyboardConfig {
+            layout: Layout::Us104,
+            scancodes: Scancodes::Set1,
+            map_ctrl: true,
+            repeat_delay: 500,
+            repeat_rate: 10,
+        }
+    }
+
+    /// `layout`、`set`、`ctrl`、`delay`、`rate`のどれかを文字列で設定する
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), InvalidOption> {
+        match key {
+            "layout" => self.layout = Layout::from_name(value).ok_or(InvalidOption)?,
+            "set" => self.scancodes = Scancodes::from_name(value).ok_or(InvalidOption)?,
+            "ctrl" => {
+                self.map_ctrl = match value {
+                    "map" | "on" => true,
+                    "ignore" | "off" => false,
+                    _ => return Err(InvalidOption),
                 }
             }
+            "delay" => self.repeat_delay = parse_in(value, 250, 1000)?,
+            "rate" => self.repeat_rate = parse_in(value, 2, 30)?,
+            _ => return Err(InvalidOption),
+        }
+        Ok(())
+    }
+}
+
+// `min`から`max`までの10進数を読む
+fn parse_in(value: &str, min: u32, max: u32) -> Result<u32, InvalidOption> {
+    match value.parse() {
+        Ok(n) if (min..=max).contains(&n) => Ok(n),
+        _ => Err(InvalidOption),
+    }
+}
+
+impl Default for KeyboardConfig {
+    fn default() -> Self {
+        KeyboardConfig::new()
+    }
+}
+
+impl fmt::Display for KeyboardConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "layout={} set={} ctrl={} delay={} rate={}",
+            self.layout.name(),
+            self.scancodes.name(),
+            if self.map_ctrl { "map" } else { "ignore" },
+            self.repeat_delay,
+            self.repeat_rate
+        )
+    }
+}
+
+/// 設定の名前か値が正しくない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidOption;
+
+static CONFIG: Mutex<KeyboardConfig> = Mutex::new(KeyboardConfig::new());
+// 設定を変えるたびに増やす。KeyDecoderはこれを見て作り直す
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// 今のキーボードの設定
+pub fn config() -> KeyboardConfig {
+    *CONFIG.lock()
+}
+
+/// キーボードの設定を変える。動いているKeyDecoderは次のスキャンコードから新しい設定を使う
+///
+/// PS/2コントローラが初期化されていれば、スキャンコードの変換とリピートの設定も変える。
+pub fn set_config(config: KeyboardConfig) {
+    *CONFIG.lock() = config;
+    GENERATION.fetch_add(1, Ordering::Release);
+    match ps2::keyboard::apply(&config) {
+        Ok(()) | Err(Ps2Error::NoDevice) => {}
+        Err(err) => println!("WARNING: failed to configure keyboard: {}", err),
+    }
+}
+
+/// カーネルのコマンドラインの`keyboard.layout=`や`keyboard.set=`などを反映する
+pub fn init() {
+    let mut config = config();
+    for (key, value) in cmdline::params(cmdline::get()) {
+        if let Some(option) = key.strip_prefix("keyboard.") {
+            if config.set(option, value).is_err() {
+                println!("WARNING: invalid keyboard option {}={}", key, value);
+            }
+        }
+    }
+    set_config(config);
+}
+
+// pc_keyboardのKeyboardは配列とスキャンコードセットが型引数なので、組み合わせごとにvariantを作る
+macro_rules! decoders {
+    ($($variant:ident: $layout:ident, $set:ident => $keyboard:ident, $scancodes:ident;)*) => {
+        enum Decoder {
+            $($variant(Keyboard<layouts::$keyboard, $scancodes>),)*
+        }
+
+        impl Decoder {
+            fn new(config: KeyboardConfig) -> Decoder {
+                let ctrl = if config.map_ctrl {
+                    HandleControl::MapLettersToUnicode
+                } else {
+                    HandleControl::Ignore
+                };
+                match (config.layout, config.scancodes) {
+                    $((Layout::$layout, Scancodes::$set) => {
+                        Decoder::$variant(Keyboard::new(layouts::$keyboard, $scancodes, ctrl))
+                    })*
+                }
+            }
+
+            fn add_byte(&mut self, scancode: u8) -> Option<pc_keyboard::KeyEvent> {
+                match self {
+                    $(Decoder::$variant(keyboard) => keyboard.add_byte(scancode).ok().flatten(),)*
+                }
+            }
+
+            fn process_keyevent(&mut self, key_event: pc_keyboard::KeyEvent) -> Option<DecodedKey> {
+                match self {
+                    $(Decoder::$variant(keyboard) => keyboard.process_keyevent(key_event),)*
+                }
+            }
+        }
+    };
+}
+
+decoders! {
+    Us104Set1: Us104, Set1 => Us104Key, ScancodeSet1;
+    Us104Set2: Us104, Set2 => Us104Key, ScancodeSet2;
+    Jis109Set1: Jis109, Set1 => Jis109Key, ScancodeSet1;
+    Jis109Set2: Jis109, Set2 => Jis109Key, ScancodeSet2;
+    Uk105Set1: Uk105, Set1 => Uk105Key, ScancodeSet1;
+    Uk105Set2: Uk105, Set2 => Uk105Key, ScancodeSet2;
+    Dvorak104Set1: Dvorak104, Set1 => Dvorak104Key, ScancodeSet1;
+    Dvorak104Set2: Dvorak104, Set2 => Dvorak104Key, ScancodeSet2;
+}
+
+/// 修飾キーとロックの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub lshift: bool,
+    pub rshift: bool,
+    pub lctrl: bool,
+    pub rctrl: bool,
+    pub lalt: bool,
+    pub ralt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    /// pc_keyboardと同じく、Num Lockだけがオンの状態
+    pub const fn new() -> Self {
+        Modifiers {
+            lshift: false,
+            rshift: false,
+            lctrl: false,
+            rctrl: false,
+            lalt: false,
+            ralt: false,
+            caps_lock: false,
+            num_lock: true,
+            scroll_lock: false,
+        }
+    }
+
+    pub fn shift(&self) -> bool {
+        self.lshift || self.rshift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.lctrl || self.rctrl
+    }
+
+    pub fn alt(&self) -> bool {
+        self.lalt || self.ralt
+    }
+
+    /// ロックの状態に対応するLED
+    pub fn leds(&self) -> Leds {
+        let mut leds = Leds::NONE;
+        if self.caps_lock {
+            leds = leds | Leds::CAPS_LOCK;
+        }
+        if self.num_lock {
+            leds = leds | Leds::NUM_LOCK;
+        }
+        if self.scroll_lock {
+            leds = leds | Leds::SCROLL_LOCK;
+        }
+        leds
+    }
+
+    // キーの状態を反映する。ロックが切り替わったらtrue
+    fn update(&mut self, code: KeyCode, down: bool) -> bool {
+        match code {
+            KeyCode::ShiftLeft => self.lshift = down,
+            KeyCode::ShiftRight => self.rshift = down,
+            KeyCode::ControlLeft => self.lctrl = down,
+            KeyCode::ControlRight => self.rctrl = down,
+            KeyCode::AltLeft => self.lalt = down,
+            KeyCode::AltRight => self.ralt = down,
+            KeyCode::CapsLock if down => self.caps_lock = !self.caps_lock,
+            KeyCode::NumpadLock if down => self.num_lock = !self.num_lock,
+            KeyCode::ScrollLock if down => self.scroll_lock = !self.scroll_lock,
+            _ => return false,
+        }
+        matches!(
+            code,
+            KeyCode::CapsLock | KeyCode::NumpadLock | KeyCode::ScrollLock
+        )
+    }
+}
+
+impl Default for Modifiers {
+    fn default() -> Self {
+        Modifiers::new()
+    }
+}
+
+/// デコードしたキーのイベント
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    /// 押したときの文字またはキー。離したときや修飾キーはNone
+    pub key: Option<DecodedKey>,
+    /// このイベントを反映した後の修飾キーとロックの状態
+    pub modifiers: Modifiers,
+}
+
+/// スキャンコードを今の設定でキーに変換する
+///
+/// 設定が変わったら(押されているキーとロックの状態は捨てて)作り直す。
+/// ロックキーが押されたらキーボードのLEDも変える。
+pub struct KeyDecoder {
+    generation: usize,
+    decoder: Decoder,
+    modifiers: Modifiers,
+}
+
+impl KeyDecoder {
+    pub fn new() -> Self {
+        let generation = GENERATION.load(Ordering::Acquire);
+        KeyDecoder {
+            generation,
+            decoder: Decoder::new(config()),
+            modifiers: Modifiers::new(),
+        }
+    }
+
+    /// 1バイト渡して、キーが押されるか離されたらイベントを返す
+    pub fn decode_event(&mut self, scancode: u8) -> Option<KeyEvent> {
+        if self.generation != GENERATION.load(Ordering::Acquire) {
+            let leds = self.leds();
+            *self = KeyDecoder::new();
+            if leds != self.leds() {
+                self.update_leds();
+            }
+        }
+        let raw = self.decoder.add_byte(scancode)?;
+        let (code, state) = (raw.code, raw.state);
+        if self.modifiers.update(code, matches!(state, KeyState::Down)) {
+            self.update_leds();
+        }
+        Some(KeyEvent {
+            code,
+            state,
+            key: self.decoder.process_keyevent(raw),
+            modifiers: self.modifiers,
+        })
+    }
+
+    /// 1バイト渡して、文字かキーが確定したら返す
+    pub fn decode(&mut self, scancode: u8) -> Option<DecodedKey> {
+        self.decode_event(scancode)?.key
+    }
+
+    /// 今の修飾キーとロックの状態
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    /// 今のロックの状態
+    pub fn leds(&self) -> Leds {
+        self.modifiers.leds()
+    }
+
+    // PS/2キーボードがなければ何もしない
+    fn update_leds(&self) {
+        if let Err(err) = ps2::keyboard::set_leds(self.leds()) {
+            if err != Ps2Error::NoDevice {
+                println!("WARNING: failed to set keyboard LEDs: {}", err);
+            }
+        }
+    }
+}
+
+impl Default for KeyDecoder {
+    fn default() -> Self {
+        KeyDecoder::new()
+    }
+}
+
+/// KeyEventStreamがどのイベントを受け取るか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subscription {
+    /// フォーカスを持っているときだけ受け取る(シェルやゲームなど)
+    Focused,
+    /// フォーカスに関係なくすべて受け取る(デバッガのホットキーなど)
+    All,
+}
+
+/// `KeyEventStream::subscribe`で使う購読者ごとのキューの設定
+/// 読むのが遅れている購読者には新しいイベントを残したいので、古いものから捨てる
+pub const DEFAULT_SUBSCRIBER_QUEUE: QueueConfig = QueueConfig::new(64, OverflowPolicy::DropOldest);
+
+struct Subscriber {
+    id: u64,
+    subscription: Subscription,
+    queue: Queue<KeyEvent>,
+    waker: AtomicWaker,
+}
+
+struct Subscribers {
+    list: Vec<Arc<Subscriber>>,
+    // Focusedの購読者のidを積んだもの。最後の購読者がフォーカスを持つ
+    focus: Vec<u64>,
+}
+
+static SUBSCRIBERS: Mutex<Subscribers> = Mutex::new(Subscribers {
+    list: Vec::new(),
+    focus: Vec::new(),
+});
+
+/// デコードしたキーのイベントの非同期ストリーム
+///
+/// 複数のタスクがそれぞれ購読できる。`Subscription::Focused`の購読者は、フォーカスを
+/// 持っている1つだけがイベントを受け取る。フォーカスは最後に購読したか`focus`を呼んだ
+/// ストリームが持ち、そのストリームがdropされると前に持っていたストリームに戻る。
+pub struct KeyEventStream {
+    subscriber: Arc<Subscriber>,
+}
+
+impl KeyEventStream {
+    pub fn subscribe(subscription: Subscription) -> Self {
+        Self::subscribe_with_queue(subscription, DEFAULT_SUBSCRIBER_QUEUE)
+    }
+
+    /// キューの容量とあふれたときの扱いを指定して購読する
+    ///
+    /// `dispatch`は待たずにpushするので、Backpressureは使えない。
+    pub fn subscribe_with_queue(subscription: Subscription, config: QueueConfig) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        assert!(
+            config.policy != OverflowPolicy::Backpressure,
+            "KeyEventStreamのキューにBackpressureは使えません"
+        );
+        let subscriber = Arc::new(Subscriber {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            subscription,
+            queue: Queue::new(config),
+            waker: AtomicWaker::new(),
+        });
+        let mut subscribers = SUBSCRIBERS.lock();
+        subscribers.list.push(subscriber.clone());
+        if subscription == Subscription::Focused {
+            subscribers.focus.push(subscriber.id);
+        }
+        KeyEventStream { subscriber }
+    }
+
+    /// フォーカスを取る。`Subscription::All`のストリームでは何もしない
+    pub fn focus(&self) {
+        if self.subscriber.subscription != Subscription::Focused {
+            return;
+        }
+        let mut subscribers = SUBSCRIBERS.lock();
+        subscribers.focus.retain(|&id| id != self.subscriber.id);
+        subscribers.focus.push(self.subscriber.id);
+    }
+
+    pub fn has_focus(&self) -> bool {
+        SUBSCRIBERS.lock().focus.last() == Some(&self.subscriber.id)
+    }
+
+    /// 溜まっているイベントがあれば待たずに返す
+    pub fn try_next(&mut self) -> Option<KeyEvent> {
+        self.subscriber.queue.pop()
+    }
+
+    /// このストリームのキューの統計。`dropped`は読むのが間に合わずに捨てたイベントの数
+    pub fn stats(&self) -> QueueStats {
+        self.subscriber.queue.stats()
+    }
+}
+
+impl Drop for KeyEventStream {
+    fn drop(&mut self) {
+        let id = self.subscriber.id;
+        let mut subscribers = SUBSCRIBERS.lock();
+        subscribers.list.retain(|subscriber| subscriber.id != id);
+        subscribers.focus.retain(|&focused| focused != id);
+    }
+}
+
+impl Stream for KeyEventStream {
+    type Item = KeyEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let subscriber = &self.subscriber;
+        if let Some(event) = subscriber.queue.pop() {
+            return Poll::Ready(Some(event));
+        }
+        subscriber.waker.register(&cx.waker());
+        match subscriber.queue.pop() {
+            Some(event) => {
+                subscriber.waker.take();
+                Poll::Ready(Some(event))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// イベントを購読者に配る
+///
+/// 普通は`run`から呼ばれるが、シリアルからの入力やテストでイベントを送るのにも使える。
+pub fn dispatch(event: KeyEvent) {
+    let subscribers = SUBSCRIBERS.lock();
+    let focused = subscribers.focus.last().copied();
+    for subscriber in &subscribers.list {
+        let receives = match subscriber.subscription {
+            Subscription::Focused => Some(subscriber.id) == focused,
+            Subscription::All => true,
+        };
+        if !receives {
+            continue;
+        }
+        // あふれたときはポリシーに従って捨て、数はKeyEventStream::statsで見る
+        if subscriber.queue.push(event.clone()).is_ok() {
+            subscriber.waker.wake();
+        }
+    }
+}
+
+/// キーボードのタスク。スキャンコードをデコードして購読者に配る
+pub async fn run() {
+    let mut scancodes = ScancodeStream::new();
+    let mut decoder = KeyDecoder::new();
+
+    while let Some(scancode) = scancodes.next().await {
+        if let Some(event) = decoder.decode_event(scancode) {
+            dispatch(event);
+        }
+    }
+}
+
+/// 押されたキーを表示し続ける(`run`と一緒に動かす)
+pub async fn print_keypresses() {
+    let mut events = KeyEventStream::subscribe(Subscription::Focused);
+
+    while let Some(event) = events.next().await {
+        match event.key {
+            Some(DecodedKey::Unicode(character)) => print!("{}", character),
+            Some(DecodedKey::RawKey(key)) => print!("{:?}", key),
+            None => {}
         }
     }
 }
@@ -1,16 +1,23 @@
 use alloc::boxed::Box;
+use core::fmt;
 use core::future::Future;
 use core::pin::Pin;
 use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::{Context, Poll};
 
+pub mod deferred;
 pub mod executor;
+pub mod join;
 pub mod keyboard;
+pub mod mouse;
+pub mod queue;
 pub mod simple_executor;
+pub mod sync;
 
+/// タスクのID
 // OrdをつけてるのはBTreeMapのキーとして利用したいから
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
@@ -18,6 +25,17 @@ impl TaskId {
         // 一回のアトミックな操作で値を増やし前の値を返す
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // 表で幅を揃えられるよう、書式の指定をそのまま渡す
+        fmt::Display::fmt(&self.0, f)
+    }
 }
 
 // Task構造体はピン留めされて、ヒープに割り当てられ、空の型を出力する動的ディスパッチされるfutureのラッパー
@@ -0,0 +1,79 @@
+use crate::ps2::mouse::MouseEvent;
+use crate::task::queue::{OverflowPolicy, Queue, QueueConfig, QueueStats};
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+use futures_util::Stream;
+
+// ScancodeStreamと同じく、MouseStream::newで一度だけ初期化する
+static EVENT_QUEUE: OnceCell<Queue<MouseEvent>> = OnceCell::uninit();
+
+/// `MouseStream::new`で使うキューの設定
+/// 動きは新しいものほど大事なので、あふれたら古いものから捨てる
+pub const DEFAULT_MOUSE_QUEUE: QueueConfig = QueueConfig::new(100, OverflowPolicy::DropOldest);
+
+// poll_nextでctxに含まれるwakerをここに格納する
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+// PS/2マウスの割り込みのボトムハーフから呼び出される
+// MouseStreamがまだ作られていなければイベントは捨てる(マウスを使わないときに警告を出し続けないため)
+pub(crate) fn add_event(event: MouseEvent) {
+    if let Ok(queue) = EVENT_QUEUE.try_get() {
+        // あふれたときはポリシーに従って捨て、数はmouse_statsで見る
+        if queue.push(event).is_ok() {
+            WAKER.wake();
+        }
+    }
+}
+
+/// マウスのイベントの非同期ストリーム
+pub struct MouseStream {
+    _private: (),
+}
+
+impl MouseStream {
+    pub fn new() -> Self {
+        Self::with_queue(DEFAULT_MOUSE_QUEUE)
+    }
+
+    /// キューの容量とあふれたときの扱いを指定して作る
+    ///
+    /// ボトムハーフから待たずにpushするので、Backpressureは使えない。
+    pub fn with_queue(config: QueueConfig) -> Self {
+        assert!(
+            config.policy != OverflowPolicy::Backpressure,
+            "マウスのキューにBackpressureは使えません"
+        );
+        EVENT_QUEUE
+            .try_init_once(|| Queue::new(config))
+            .expect("MouseStream::newは一度しか呼び出せません");
+        MouseStream { _private: () }
+    }
+}
+
+/// マウスのイベントのキューの統計。MouseStreamを作る前はNone
+pub fn mouse_stats() -> Option<QueueStats> {
+    EVENT_QUEUE.try_get().ok().map(Queue::stats)
+}
+
+impl Stream for MouseStream {
+    type Item = MouseEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let queue = EVENT_QUEUE.try_get().expect("初期化されてません");
+
+        if let Some(event) = queue.pop() {
+            return Poll::Ready(Some(event));
+        }
+        // 空かもしれないのでWAKERを登録してからもう一度見る
+        WAKER.register(&cx.waker());
+        match queue.pop() {
+            Some(event) => {
+                WAKER.take();
+                Poll::Ready(Some(event))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
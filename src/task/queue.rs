@@ -0,0 +1,237 @@
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::task::Poll;
+use crossbeam_queue::ArrayQueue;
+use futures_util::future::poll_fn;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// キューがいっぱいのときにpushされたらどうするか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// 一番古いものを捨てて入れる
+    DropOldest,
+    /// 入れようとしたものを捨てる
+    DropNewest,
+    /// 容量を超えた分をヒープに確保して入れる
+    ///
+    /// 割り込みハンドラの中でヒープを使うと、割り込まれたコードがアロケータを
+    /// ロックしていたときにデッドロックするので、割り込みハンドラからpushするキューでは使えない。
+    Grow,
+    /// 入れずに`push`がErrを返す。タスクからは`push_wait`で空くのを待てる
+    Backpressure,
+}
+
+/// キューの容量とあふれたときの扱い
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+impl QueueConfig {
+    pub const fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        QueueConfig { capacity, policy }
+    }
+}
+
+/// キューの統計
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QueueStats {
+    /// 入ったものの数
+    pub pushed: u64,
+    /// DropOldestかDropNewestで捨てたものの数
+    pub dropped: u64,
+    /// Backpressureで断ったものの数
+    pub rejected: u64,
+    /// Growで容量を超えて入れたものの数
+    pub grown: u64,
+    /// 一度に入っていたものの数の最大
+    pub high_water: usize,
+}
+
+/// 容量とあふれたときの扱いを設定できるキュー
+///
+/// スキャンコードやタスクのキューは割り込みハンドラからもpushされるので、普段は
+/// ロックのいらないArrayQueueを使う。`OverflowPolicy::Grow`のときだけ、あふれた分を
+/// ロックしたVecDequeに入れる。
+pub struct Queue<T> {
+    policy: OverflowPolicy,
+    fixed: ArrayQueue<T>,
+    // Growのときに容量を超えた分。順番を守るため、空でない間はpushもこちらに入れる
+    overflow: Mutex<VecDeque<T>>,
+    overflow_len: AtomicUsize,
+    // Backpressureでpush_waitしているタスク
+    space: AtomicWaker,
+    pushed: AtomicU64,
+    dropped: AtomicU64,
+    rejected: AtomicU64,
+    grown: AtomicU64,
+    high_water: AtomicUsize,
+}
+
+impl<T> Queue<T> {
+    pub fn new(config: QueueConfig) -> Self {
+        assert!(config.capacity > 0, "キューの容量が0です");
+        Queue {
+            policy: config.policy,
+            fixed: ArrayQueue::new(config.capacity),
+            overflow: Mutex::new(VecDeque::new()),
+            overflow_len: AtomicUsize::new(0),
+            space: AtomicWaker::new(),
+            pushed: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            grown: AtomicU64::new(0),
+            high_water: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    /// 設定された容量(Growで増えた分は含まない)
+    pub fn capacity(&self) -> usize {
+        self.fixed.capacity()
+    }
+
+    pub fn len(&self) -> usize {
+        self.fixed.len() + self.overflow_len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// ポリシーに従って入れる。Backpressureでいっぱいのときだけ、入れられなかったものを返す
+    pub fn push(&self, item: T) -> Result<(), T> {
+        match self.policy {
+            OverflowPolicy::DropOldest => {
+                let mut item = item;
+                while let Err(back) = self.try_push(item) {
+                    item = back;
+                    if self.fixed.pop().is_ok() {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+            OverflowPolicy::DropNewest => {
+                if self.try_push(item).is_err() {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            OverflowPolicy::Grow => self.push_growing(item),
+            OverflowPolicy::Backpressure => {
+                if let Err(back) = self.try_push(item) {
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
+                    return Err(back);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 固定部分に空きがあれば入れる
+    fn try_push(&self, item: T) -> Result<(), T> {
+        self.fixed
+            .push(item)
+            .map_err(|crossbeam_queue::PushError(back)| back)?;
+        self.count_pushed();
+        Ok(())
+    }
+
+    fn count_pushed(&self) {
+        self.pushed.fetch_add(1, Ordering::Relaxed);
+        self.high_water.fetch_max(self.len(), Ordering::Relaxed);
+    }
+
+    fn push_growing(&self, item: T) {
+        interrupts::without_interrupts(|| {
+            let mut overflow = self.overflow.lock();
+            let item = if overflow.is_empty() {
+                match self.try_push(item) {
+                    Ok(()) => return,
+                    Err(back) => back,
+                }
+            } else {
+                item
+            };
+            overflow.push_back(item);
+            self.overflow_len.store(overflow.len(), Ordering::Relaxed);
+            self.grown.fetch_add(1, Ordering::Relaxed);
+            self.count_pushed();
+        })
+    }
+
+    /// 空きができるまで待ってから入れる
+    ///
+    /// Backpressure以外のポリシーでは`push`と同じですぐに終わる。
+    pub async fn push_wait(&self, item: T) {
+        if self.policy != OverflowPolicy::Backpressure {
+            let _ = self.push(item);
+            return;
+        }
+        let mut item = Some(item);
+        poll_fn(|cx| {
+            let pending = item.take().expect("push_waitが完了後にpollされました");
+            let pending = match self.try_push(pending) {
+                Ok(()) => return Poll::Ready(()),
+                Err(back) => back,
+            };
+            // 登録する前に空いたかもしれないので、登録してからもう一度試す
+            self.space.register(cx.waker());
+            match self.try_push(pending) {
+                Ok(()) => Poll::Ready(()),
+                Err(back) => {
+                    item = Some(back);
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let item = match self.fixed.pop() {
+            Ok(item) => Some(item),
+            Err(_) if self.overflow_len.load(Ordering::Relaxed) > 0 => self.pop_overflow(),
+            Err(_) => None,
+        };
+        if item.is_some() && self.policy == OverflowPolicy::Backpressure {
+            self.space.wake();
+        }
+        item
+    }
+
+    // あふれた分から取り出す。固定部分が空の間に入れられたものがあれば、そちらが先
+    fn pop_overflow(&self) -> Option<T> {
+        interrupts::without_interrupts(|| {
+            let mut overflow = self.overflow.lock();
+            if let Ok(item) = self.fixed.pop() {
+                return Some(item);
+            }
+            let item = overflow.pop_front();
+            // 残りを固定部分に戻して、次からはロックせずに取り出せるようにする
+            while let Some(front) = overflow.pop_front() {
+                if let Err(crossbeam_queue::PushError(back)) = self.fixed.push(front) {
+                    overflow.push_front(back);
+                    break;
+                }
+            }
+            self.overflow_len.store(overflow.len(), Ordering::Relaxed);
+            item
+        })
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            pushed: self.pushed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            grown: self.grown.load(Ordering::Relaxed),
+            high_water: self.high_water.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use super::Task;
+use alloc::boxed::Box;
 use alloc::collections::vec_deque::VecDeque;
+use core::future::Future;
 use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
 
 pub struct SimpleExecutor {
@@ -33,6 +35,19 @@ impl SimpleExecutor {
     }
 }
 
+/// futureが完了するまでpollし続ける。
+/// Wakerを使わずにビジーループするので、すぐに完了するfuture向け
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = dummy_waker();
+    let mut context = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+    }
+}
+
 // 何もしないダミーのWakerを作成する
 fn dummy_raw_waker() -> RawWaker {
     // *const ()ポインタを受け取り何もしない
@@ -0,0 +1,205 @@
+use super::SendError;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use core::fmt;
+use core::task::{Context, Poll, Waker};
+use futures_util::future::poll_fn;
+use spin::Mutex;
+
+/// `recv`で受け取れなかった理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// 送り手が全部dropされ、もう値が来ない
+    Closed,
+    /// 受け取るのが遅れて、古い値が上書きされた。飛ばした数を持つ
+    ///
+    /// 次の`recv`は残っている一番古い値を返す。
+    Lagged(u64),
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecvError::Closed => write!(f, "channel closed"),
+            RecvError::Lagged(skipped) => write!(f, "receiver lagged by {}", skipped),
+        }
+    }
+}
+
+/// `try_recv`で受け取れなかった理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+    Lagged(u64),
+}
+
+struct State<T> {
+    // 送られた値。capacityを超えたら古いものから捨てる
+    buffer: VecDeque<T>,
+    capacity: usize,
+    // 次に送る値の通し番号
+    next_seq: u64,
+    senders: usize,
+    receivers: usize,
+    // 値を待っている受け手
+    waiters: BTreeMap<u64, Waker>,
+    next_receiver_id: u64,
+}
+
+impl<T> State<T> {
+    fn oldest_seq(&self) -> u64 {
+        self.next_seq - self.buffer.len() as u64
+    }
+
+    fn wake_all(&mut self) {
+        for (_, waker) in core::mem::take(&mut self.waiters) {
+            waker.wake();
+        }
+    }
+
+    fn subscribe(&mut self, chan: &Arc<Mutex<State<T>>>) -> Receiver<T> {
+        let id = self.next_receiver_id;
+        self.next_receiver_id += 1;
+        self.receivers += 1;
+        Receiver {
+            chan: chan.clone(),
+            id,
+            next: self.next_seq,
+        }
+    }
+}
+
+/// 受け手ごとに最大`capacity`個の値を残しておけるチャネルを作る
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "チャネルの容量が0です");
+    let chan = Arc::new(Mutex::new(State {
+        buffer: VecDeque::with_capacity(capacity),
+        capacity,
+        next_seq: 0,
+        senders: 1,
+        receivers: 0,
+        waiters: BTreeMap::new(),
+        next_receiver_id: 0,
+    }));
+    let receiver = chan.lock().subscribe(&chan);
+    (Sender { chan }, receiver)
+}
+
+/// broadcastチャネルの送り手
+pub struct Sender<T> {
+    chan: Arc<Mutex<State<T>>>,
+}
+
+impl<T: Clone> Sender<T> {
+    /// 値を送り、受け取る受け手の数を返す。受け手がいなければ値を返す
+    ///
+    /// 待つことはなく、遅れている受け手の分の古い値は上書きする。
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let mut state = self.chan.lock();
+        if state.receivers == 0 {
+            return Err(SendError(value));
+        }
+        if state.buffer.len() == state.capacity {
+            state.buffer.pop_front();
+        }
+        state.buffer.push_back(value);
+        state.next_seq += 1;
+        state.wake_all();
+        Ok(state.receivers)
+    }
+
+    /// これから送る値を受け取る受け手を作る
+    pub fn subscribe(&self) -> Receiver<T> {
+        self.chan.lock().subscribe(&self.chan)
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.chan.lock().receivers
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.lock().senders += 1;
+        Sender {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.chan.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.wake_all();
+        }
+    }
+}
+
+/// broadcastチャネルの受け手
+pub struct Receiver<T> {
+    chan: Arc<Mutex<State<T>>>,
+    id: u64,
+    // 次に受け取る値の通し番号
+    next: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+    /// 次の値を待つ
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// 待たずに受け取る
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let chan = self.chan.clone();
+        let mut state = chan.lock();
+        match self.take(&mut state) {
+            Some(Ok(value)) => Ok(value),
+            Some(Err(RecvError::Lagged(skipped))) => Err(TryRecvError::Lagged(skipped)),
+            Some(Err(RecvError::Closed)) => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        let chan = self.chan.clone();
+        let mut state = chan.lock();
+        match self.take(&mut state) {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waiters.insert(self.id, cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    // 受け取れるものがあれば返す。まだ来ていなければNone
+    fn take(&mut self, state: &mut State<T>) -> Option<Result<T, RecvError>> {
+        let oldest = state.oldest_seq();
+        if self.next < oldest {
+            let skipped = oldest - self.next;
+            self.next = oldest;
+            return Some(Err(RecvError::Lagged(skipped)));
+        }
+        if self.next < state.next_seq {
+            let value = state.buffer[(self.next - oldest) as usize].clone();
+            self.next += 1;
+            return Some(Ok(value));
+        }
+        if state.senders == 0 {
+            return Some(Err(RecvError::Closed));
+        }
+        None
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.chan.lock();
+        state.receivers -= 1;
+        state.waiters.remove(&self.id);
+    }
+}
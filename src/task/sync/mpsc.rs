@@ -0,0 +1,184 @@
+use super::semaphore::Semaphore;
+use super::SendError;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use futures_util::future::poll_fn;
+use futures_util::Stream;
+use spin::Mutex;
+
+/// `try_send`で送れなかった理由。送ろうとした値を返す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// チャネルがいっぱい
+    Full(T),
+    /// 受け手がdropされた
+    Closed(T),
+}
+
+/// `try_recv`で受け取れなかった理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// 送り手が全部dropされ、もう値が来ない
+    Disconnected,
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receiver_alive: bool,
+    waker: Option<Waker>,
+}
+
+struct Chan<T> {
+    state: Mutex<State<T>>,
+    // 空いている場所の数。送り手は1つ取ってから送り、受け手が受け取ったら返す
+    space: Semaphore,
+}
+
+impl<T> Chan<T> {
+    // 場所を取ってあるので、受け手がいればそのまま入れる
+    fn push(&self, value: T) -> Result<(), T> {
+        let mut state = self.state.lock();
+        if !state.receiver_alive {
+            return Err(value);
+        }
+        state.queue.push_back(value);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+/// 容量が`capacity`のチャネルを作る
+///
+/// いっぱいのときは、受け手が受け取って空くまで`send`が待つ。
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "チャネルの容量が0です");
+    let chan = Arc::new(Chan {
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(capacity),
+            senders: 1,
+            receiver_alive: true,
+            waker: None,
+        }),
+        space: Semaphore::new(capacity),
+    });
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+/// mpscチャネルの送り手。クローンして複数のタスクから送れる
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Sender<T> {
+    /// 空くまで待ってから送る。受け手がdropされていたら値を返す
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        // 受け手がdropされるとセマフォが閉じる
+        match self.chan.space.acquire().await {
+            Ok(permit) => permit.forget(),
+            Err(_) => return Err(SendError(value)),
+        }
+        self.chan.push(value).map_err(SendError)
+    }
+
+    /// 待たずに送る
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        match self.chan.space.try_acquire() {
+            Ok(permit) => permit.forget(),
+            Err(super::TryAcquireError::Closed) => return Err(TrySendError::Closed(value)),
+            Err(super::TryAcquireError::NoPermits) => return Err(TrySendError::Full(value)),
+        }
+        self.chan.push(value).map_err(TrySendError::Closed)
+    }
+
+    /// 受け手がdropされたか
+    pub fn is_closed(&self) -> bool {
+        !self.chan.state.lock().receiver_alive
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.state.lock().senders += 1;
+        Sender {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    // 最後の送り手がいなくなったら、受け手を起こしてNoneを返させる
+    fn drop(&mut self) {
+        let mut state = self.chan.state.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// mpscチャネルの受け手
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// 次の値を待つ。送り手が全部dropされ、残りもなければNone
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// 待たずに受け取る
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let value = {
+            let mut state = self.chan.state.lock();
+            match state.queue.pop_front() {
+                Some(value) => value,
+                None if state.senders == 0 => return Err(TryRecvError::Disconnected),
+                None => return Err(TryRecvError::Empty),
+            }
+        };
+        self.chan.space.add_permits(1);
+        Ok(value)
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        let value = {
+            let mut state = self.chan.state.lock();
+            match state.queue.pop_front() {
+                Some(value) => value,
+                None if state.senders == 0 => return Poll::Ready(None),
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        };
+        // 空いた場所を、待っている送り手に渡す
+        self.chan.space.add_permits(1);
+        Poll::Ready(Some(value))
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    // 送り手が待ち続けないように、セマフォを閉じて起こす
+    fn drop(&mut self) {
+        self.chan.state.lock().receiver_alive = false;
+        self.chan.space.close();
+    }
+}
@@ -0,0 +1,77 @@
+use super::semaphore::Semaphore;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+/// `.await`をまたいで持てる非同期のMutex
+///
+/// `spin::Mutex`を持ったままawaitすると、同じロックを取ろうとした別のタスクが
+/// 回り続けてexecutorに戻らなくなる(シングルコアではデッドロックする)。こちらはロックを
+/// 待つ間executorに戻り、空いたらwakerで起こしてもらう。ロックは待ち始めた順に渡す。
+pub struct Mutex<T: ?Sized> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+// ロックを持っているタスクだけがvalueにアクセスできる
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub fn new(value: T) -> Self {
+        Mutex {
+            semaphore: Semaphore::new(1),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// ロックを取るまで待つ
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        // セマフォは閉じないので、失敗しない
+        let permit = self.semaphore.acquire().await.unwrap();
+        permit.forget();
+        MutexGuard { mutex: self }
+    }
+
+    /// 待たずにロックを取る。取れなければNone
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let permit = self.semaphore.try_acquire().ok()?;
+        permit.forget();
+        Some(MutexGuard { mutex: self })
+    }
+
+    /// `&mut self`があれば、ほかに誰もロックしていないのでそのまま触れる
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+/// `Mutex::lock`で取ったロック。dropすると次に待っているタスクに渡す
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.semaphore.add_permits(1);
+    }
+}
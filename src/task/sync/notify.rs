@@ -0,0 +1,162 @@
+use alloc::collections::VecDeque;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Notification {
+    // notify_oneで起こされた。受け取らずに捨てられたら次の待ち手に渡す
+    One,
+    // notify_waitersで起こされた
+    All,
+}
+
+struct Waiter {
+    id: u64,
+    waker: Option<Waker>,
+    notified: Option<Notification>,
+}
+
+struct State {
+    // 待ち手がいないときのnotify_oneを1つだけ覚えておく
+    permit: bool,
+    waiters: VecDeque<Waiter>,
+    next_id: u64,
+}
+
+impl State {
+    fn notify_one(&mut self) {
+        match self
+            .waiters
+            .iter_mut()
+            .find(|waiter| waiter.notified.is_none())
+        {
+            Some(waiter) => {
+                waiter.notified = Some(Notification::One);
+                if let Some(waker) = waiter.waker.take() {
+                    waker.wake();
+                }
+            }
+            None => self.permit = true,
+        }
+    }
+}
+
+/// タスクに何かが起きたことを知らせる
+///
+/// `notify_one`は待っているタスクを1つ起こす。待っているタスクがいなければ覚えておき、
+/// 次に`notified`を待ったタスクがすぐに起きる。
+///
+/// 中の状態は`spin::Mutex`で守っているので、割り込みハンドラからは使わないこと。
+pub struct Notify {
+    state: Mutex<State>,
+}
+
+impl Notify {
+    pub fn new() -> Self {
+        Notify {
+            state: Mutex::new(State {
+                permit: false,
+                waiters: VecDeque::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    /// 待っているタスクを1つ起こす
+    pub fn notify_one(&self) {
+        self.state.lock().notify_one();
+    }
+
+    /// 今待っているタスクを全部起こす。待っているタスクがいなければ何もしない
+    pub fn notify_waiters(&self) {
+        let mut state = self.state.lock();
+        for waiter in state.waiters.iter_mut() {
+            if waiter.notified.is_none() {
+                waiter.notified = Some(Notification::All);
+                if let Some(waker) = waiter.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+
+    /// 知らせを待つ
+    ///
+    /// 待ち手として登録されるのは最初にpollされたときなので、それより前の
+    /// `notify_waiters`は届かない。
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            id: None,
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `Notify::notified`が返すfuture
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    id: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let notify = self.notify;
+        let mut state = notify.state.lock();
+        let id = match self.id {
+            Some(id) => id,
+            None => {
+                if state.permit {
+                    state.permit = false;
+                    return Poll::Ready(());
+                }
+                let id = state.next_id;
+                state.next_id += 1;
+                state.waiters.push_back(Waiter {
+                    id,
+                    waker: Some(cx.waker().clone()),
+                    notified: None,
+                });
+                self.id = Some(id);
+                return Poll::Pending;
+            }
+        };
+
+        let index = state
+            .waiters
+            .iter()
+            .position(|waiter| waiter.id == id)
+            .expect("待ち手がNotifyから消えています");
+        if state.waiters[index].notified.is_some() {
+            state.waiters.remove(index);
+            self.id = None;
+            return Poll::Ready(());
+        }
+        state.waiters[index].waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for Notified<'_> {
+    // notify_oneの知らせを受け取らずに捨てられたら、ほかの待ち手に渡す
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut state = self.notify.state.lock();
+            if let Some(index) = state.waiters.iter().position(|waiter| waiter.id == id) {
+                let waiter = state.waiters.remove(index).unwrap();
+                if waiter.notified == Some(Notification::One) {
+                    state.notify_one();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use alloc::sync::Arc;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+/// 送り手が値を送らずにdropされた
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sender dropped")
+    }
+}
+
+struct Inner<T> {
+    value: Option<T>,
+    sender_alive: bool,
+    receiver_alive: bool,
+    waker: Option<Waker>,
+}
+
+/// 値を1つだけ送るチャネルを作る
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Mutex::new(Inner {
+        value: None,
+        sender_alive: true,
+        receiver_alive: true,
+        waker: None,
+    }));
+    (
+        Sender {
+            inner: inner.clone(),
+        },
+        Receiver { inner },
+    )
+}
+
+/// oneshotチャネルの送り手
+pub struct Sender<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// 値を送る。受け手がすでにdropされていたら値を返す
+    pub fn send(self, value: T) -> Result<(), T> {
+        let mut inner = self.inner.lock();
+        if !inner.receiver_alive {
+            return Err(value);
+        }
+        inner.value = Some(value);
+        if let Some(waker) = inner.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// 受け手がdropされたか
+    pub fn is_closed(&self) -> bool {
+        !self.inner.lock().receiver_alive
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    // 送らずに捨てられたら、受け手を起こしてErrを返させる
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock();
+        inner.sender_alive = false;
+        if let Some(waker) = inner.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// oneshotチャネルの受け手。awaitすると送られた値が返る
+pub struct Receiver<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+impl<T> Receiver<T> {
+    /// 待たずに受け取る。まだ送られていなければ`Ok(None)`
+    pub fn try_recv(&mut self) -> Result<Option<T>, RecvError> {
+        let mut inner = self.inner.lock();
+        match inner.value.take() {
+            Some(value) => Ok(Some(value)),
+            None if !inner.sender_alive => Err(RecvError),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut inner = self.inner.lock();
+        if let Some(value) = inner.value.take() {
+            return Poll::Ready(Ok(value));
+        }
+        if !inner.sender_alive {
+            return Poll::Ready(Err(RecvError));
+        }
+        inner.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.inner.lock().receiver_alive = false;
+    }
+}
@@ -0,0 +1,108 @@
+use super::semaphore::Semaphore;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+// 同時に読める数。書き込みはこれを全部取る
+const MAX_READERS: usize = 32;
+
+/// 非同期の読み書きロック
+///
+/// 読むだけなら`MAX_READERS`個まで同時にロックできる。ロックは待ち始めた順に渡すので、
+/// 読み手が続いても書き手が待たされ続けることはない。
+pub struct RwLock<T: ?Sized> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub fn new(value: T) -> Self {
+        RwLock {
+            semaphore: Semaphore::new(MAX_READERS),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// 読むためのロックを取るまで待つ
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        // セマフォは閉じないので、失敗しない
+        let permit = self.semaphore.acquire().await.unwrap();
+        permit.forget();
+        RwLockReadGuard { lock: self }
+    }
+
+    /// 書くためのロックを取るまで待つ
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let permit = self.semaphore.acquire_many(MAX_READERS).await.unwrap();
+        permit.forget();
+        RwLockWriteGuard { lock: self }
+    }
+
+    /// 待たずに読むためのロックを取る
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.semaphore.try_acquire().ok()?.forget();
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    /// 待たずに書くためのロックを取る
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.semaphore.try_acquire_many(MAX_READERS).ok()?.forget();
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+/// `RwLock::read`で取ったロック
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.semaphore.add_permits(1);
+    }
+}
+
+/// `RwLock::write`で取ったロック
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.semaphore.add_permits(MAX_READERS);
+    }
+}
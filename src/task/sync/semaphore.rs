@@ -0,0 +1,251 @@
+use alloc::collections::VecDeque;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+/// セマフォが閉じられていて、許可を取れなかった
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcquireError;
+
+impl fmt::Display for AcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "semaphore closed")
+    }
+}
+
+/// `try_acquire`で許可を取れなかった理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryAcquireError {
+    Closed,
+    /// 許可が足りないか、先に待っている待ち手がいる
+    NoPermits,
+}
+
+struct Waiter {
+    id: u64,
+    needed: usize,
+    waker: Option<Waker>,
+    // 許可を受け取った。Acquireが次にpollされたときに取り除く
+    granted: bool,
+}
+
+struct State {
+    permits: usize,
+    waiters: VecDeque<Waiter>,
+    next_id: u64,
+    closed: bool,
+}
+
+impl State {
+    // まだ許可を受け取っていない待ち手がいるか
+    fn has_waiting(&self) -> bool {
+        self.waiters.iter().any(|waiter| !waiter.granted)
+    }
+
+    // 先頭から順に許可を渡す。足りない待ち手がいたら、順番を守るためそこで止める
+    fn grant(&mut self) {
+        for waiter in self.waiters.iter_mut() {
+            if waiter.granted {
+                continue;
+            }
+            if waiter.needed > self.permits {
+                break;
+            }
+            self.permits -= waiter.needed;
+            waiter.granted = true;
+            if let Some(waker) = waiter.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    fn position(&self, id: u64) -> usize {
+        self.waiters
+            .iter()
+            .position(|waiter| waiter.id == id)
+            .expect("待ち手がセマフォから消えています")
+    }
+}
+
+/// 非同期のセマフォ
+///
+/// 許可は待ち始めた順に渡すので、たくさん必要な待ち手が後から来た少しでいい待ち手に
+/// 追い越されて、いつまでも待たされることはない。
+///
+/// 中の状態は`spin::Mutex`で守っていて、ロックは短い間しか持たない。割り込みを止めずに
+/// ロックするので、割り込みハンドラからは使わないこと(これを使うMutexやチャネルも同じ)。
+pub struct Semaphore {
+    state: Mutex<State>,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            state: Mutex::new(State {
+                permits,
+                waiters: VecDeque::new(),
+                next_id: 0,
+                closed: false,
+            }),
+        }
+    }
+
+    /// 今すぐ取れる許可の数
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    /// 許可を`permits`個増やし、待っている順に渡す
+    pub fn add_permits(&self, permits: usize) {
+        let mut state = self.state.lock();
+        state.permits += permits;
+        state.grant();
+    }
+
+    /// セマフォを閉じる。待っている`acquire`と、これからの`acquire`はErrを返す
+    ///
+    /// すでに渡した許可はそのまま使える。
+    pub fn close(&self) {
+        let mut state = self.state.lock();
+        state.closed = true;
+        for waiter in state.waiters.iter_mut() {
+            if let Some(waker) = waiter.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().closed
+    }
+
+    /// 許可を1つ取る
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    /// 許可を`permits`個まとめて取る
+    pub fn acquire_many(&self, permits: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            needed: permits,
+            id: None,
+        }
+    }
+
+    /// 待たずに許可を1つ取る
+    pub fn try_acquire(&self) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.try_acquire_many(1)
+    }
+
+    /// 待たずに許可を`permits`個取る
+    pub fn try_acquire_many(&self, permits: usize) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        let mut state = self.state.lock();
+        if state.closed {
+            return Err(TryAcquireError::Closed);
+        }
+        if state.has_waiting() || state.permits < permits {
+            return Err(TryAcquireError::NoPermits);
+        }
+        state.permits -= permits;
+        Ok(SemaphorePermit {
+            semaphore: self,
+            permits,
+        })
+    }
+}
+
+/// `Semaphore::acquire`が返すfuture
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    needed: usize,
+    // 待ち手として登録したときのID
+    id: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Result<SemaphorePermit<'a>, AcquireError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let semaphore = self.semaphore;
+        let needed = self.needed;
+        let permit = || SemaphorePermit {
+            semaphore,
+            permits: needed,
+        };
+        let mut state = semaphore.state.lock();
+        let id = match self.id {
+            Some(id) => id,
+            None => {
+                if state.closed {
+                    return Poll::Ready(Err(AcquireError));
+                }
+                if !state.has_waiting() && state.permits >= needed {
+                    state.permits -= needed;
+                    return Poll::Ready(Ok(permit()));
+                }
+                let id = state.next_id;
+                state.next_id += 1;
+                state.waiters.push_back(Waiter {
+                    id,
+                    needed,
+                    waker: Some(cx.waker().clone()),
+                    granted: false,
+                });
+                self.id = Some(id);
+                return Poll::Pending;
+            }
+        };
+
+        let index = state.position(id);
+        if state.waiters[index].granted {
+            state.waiters.remove(index);
+            self.id = None;
+            return Poll::Ready(Ok(permit()));
+        }
+        if state.closed {
+            state.waiters.remove(index);
+            self.id = None;
+            return Poll::Ready(Err(AcquireError));
+        }
+        state.waiters[index].waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire<'_> {
+    // 待っている途中で捨てられたら、待ち手を取り除く。受け取っていた許可は返す
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut state = self.semaphore.state.lock();
+            let index = state.position(id);
+            let waiter = state.waiters.remove(index).unwrap();
+            if waiter.granted {
+                state.permits += waiter.needed;
+            }
+            // 先頭で止まっていた待ち手がいなくなったので、後ろの待ち手に渡せるかもしれない
+            state.grant();
+        }
+    }
+}
+
+/// 取った許可。dropするとセマフォに返す
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// 許可をセマフォに返さずに捨てる。返すときは`Semaphore::add_permits`を使う
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(self.permits);
+    }
+}
@@ -1,15 +1,18 @@
+use crate::irq_mutex::IrqSafeMutex;
 use core::fmt;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
 
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCoder::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
+    pub static ref WRITER: IrqSafeMutex<Writer> = IrqSafeMutex::new(
+        "WRITER",
+        Writer {
+            column_position: 0,
+            color_code: ColorCoder::new(Color::Yellow, Color::Black),
+            buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        }
+    );
 }
 
 #[allow(dead_code)]
@@ -96,6 +99,19 @@ impl Writer {
         }
     }
 
+    /// 最終行の1文字前を消してカーソルを戻す
+    pub fn backspace(&mut self) {
+        if self.column_position == 0 {
+            return;
+        }
+        self.column_position -= 1;
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        };
+        self.buffer.chars[BUFFER_HEIGHT - 1][self.column_position].write(blank);
+    }
+
     fn new_line(&mut self) {
         for row in 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
@@ -139,12 +155,14 @@ macro_rules! println {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    // 割り込み中はロックしない
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    // ロックしている間は割り込みが止まる
+    WRITER.lock().write_fmt(args).unwrap();
+}
+
+/// 画面上の直前の1文字を消す
+pub fn backspace() {
+    WRITER.lock().backspace();
 }
 
 // printlnで1行出力できるか
@@ -0,0 +1,234 @@
+use crate::memory::{map_mmio, MmioRegion};
+use crate::pci::{Bar, PciDevice};
+use x86_64::PhysAddr;
+
+pub mod queue;
+
+use queue::VirtQueue;
+
+/// virtioデバイスのPCIベンダID
+pub const VENDOR_ID: u16 = 0x1af4;
+
+/// 全てのvirtio 1.0デバイスが対応しなければならない機能
+pub const F_VERSION_1: u64 = 1 << 32;
+
+// デバイスステータスのビット
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+// ISRステータスのビット
+pub const ISR_QUEUE: u8 = 1;
+pub const ISR_CONFIG: u8 = 2;
+
+// ベンダ固有のPCIケーパビリティ
+const PCI_CAP_ID_VENDOR: u8 = 0x09;
+// virtioのケーパビリティの種類(cfg_type)
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG: u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+
+// common configのレジスタのオフセット
+const DEVICE_FEATURE_SELECT: usize = 0x00;
+const DEVICE_FEATURE: usize = 0x04;
+const DRIVER_FEATURE_SELECT: usize = 0x08;
+const DRIVER_FEATURE: usize = 0x0c;
+const MSIX_CONFIG: usize = 0x10;
+const DEVICE_STATUS: usize = 0x14;
+const CONFIG_GENERATION: usize = 0x15;
+const QUEUE_SELECT: usize = 0x16;
+const QUEUE_SIZE: usize = 0x18;
+const QUEUE_MSIX_VECTOR: usize = 0x1a;
+const QUEUE_ENABLE: usize = 0x1c;
+const QUEUE_NOTIFY_OFF: usize = 0x1e;
+const QUEUE_DESC: usize = 0x20;
+const QUEUE_DRIVER: usize = 0x28;
+const QUEUE_DEVICE: usize = 0x30;
+
+// MSI-Xを使わないことを示すベクタ
+const NO_VECTOR: u16 = 0xffff;
+
+/// virtio over PCI(modern)のトランスポート
+///
+/// レジスタの場所はベンダ固有のケーパビリティで示されるので、それぞれをMMIOとしてマップする。
+pub struct VirtioPci {
+    common: MmioRegion,
+    notify: MmioRegion,
+    notify_multiplier: u32,
+    isr: MmioRegion,
+    device: MmioRegion,
+}
+
+impl VirtioPci {
+    /// ケーパビリティからレジスタの領域を探してマップする。
+    pub fn new(pci: &PciDevice) -> Result<VirtioPci, &'static str> {
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device = None;
+        for cap in pci.capabilities.iter() {
+            if cap.id != PCI_CAP_ID_VENDOR {
+                continue;
+            }
+            let offset = u16::from(cap.offset);
+            let cfg_type = pci.read_u8(offset + 3);
+            let bar = pci.read_u8(offset + 4);
+            let region_offset = pci.read_u32(offset + 8);
+            let length = pci.read_u32(offset + 12);
+            let slot = match cfg_type {
+                CAP_COMMON_CFG => &mut common,
+                CAP_NOTIFY_CFG => &mut notify,
+                CAP_ISR_CFG => &mut isr,
+                CAP_DEVICE_CFG => &mut device,
+                _ => continue,
+            };
+            // 同じ種類が複数あれば最初のものを使う
+            if slot.is_some() {
+                continue;
+            }
+            let address = match pci.bars.get(usize::from(bar)) {
+                Some(Some(Bar::Memory { address, .. })) => *address,
+                _ => continue,
+            };
+            let region = map_mmio(
+                PhysAddr::new(address + u64::from(region_offset)),
+                length as usize,
+            )
+            .map_err(|_| "failed to map virtio registers")?;
+            // notifyのケーパビリティの後ろにはnotify_off_multiplierがある
+            let multiplier = if cfg_type == CAP_NOTIFY_CFG {
+                pci.read_u32(offset + 16)
+            } else {
+                0
+            };
+            *slot = Some((region, multiplier));
+        }
+
+        let (notify, notify_multiplier) = notify.ok_or("no virtio notify capability")?;
+        Ok(VirtioPci {
+            common: common.ok_or("no virtio common capability")?.0,
+            notify,
+            notify_multiplier,
+            isr: isr.ok_or("no virtio ISR capability")?.0,
+            device: device.ok_or("no virtio device capability")?.0,
+        })
+    }
+
+    /// デバイスをリセットして機能のネゴシエーションまで行い、ネゴシエーションした機能を返す。
+    ///
+    /// `supported`はドライバが対応しているデバイス固有の機能で、`F_VERSION_1`は常に要求する。
+    /// この後キューを`setup_queue`で設定し、`driver_ok`を呼ぶ。
+    pub fn initialize(&self, supported: u64) -> Result<u64, &'static str> {
+        self.set_status(0);
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let features = self.device_features() & (supported | F_VERSION_1);
+        if features & F_VERSION_1 == 0 {
+            self.fail();
+            return Err("device does not support virtio 1.0");
+        }
+        self.common.write::<u32>(DRIVER_FEATURE_SELECT, 0);
+        self.common.write::<u32>(DRIVER_FEATURE, features as u32);
+        self.common.write::<u32>(DRIVER_FEATURE_SELECT, 1);
+        self.common
+            .write::<u32>(DRIVER_FEATURE, (features >> 32) as u32);
+
+        let status = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK;
+        self.set_status(status);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.fail();
+            return Err("device rejected features");
+        }
+        // 割り込みはMSI-Xではなく従来のINTxで受け取る
+        self.common.write::<u16>(MSIX_CONFIG, NO_VECTOR);
+        Ok(features)
+    }
+
+    /// `index`番目のキューを最大`max_size`個のディスクリプタで設定して有効にする。
+    pub fn setup_queue(&self, index: u16, max_size: u16) -> Result<VirtQueue, &'static str> {
+        self.common.write::<u16>(QUEUE_SELECT, index);
+        let device_size = self.common.read::<u16>(QUEUE_SIZE);
+        if device_size == 0 {
+            return Err("virtqueue not available");
+        }
+        // split virtqueueのサイズは2のべき乗
+        let size = device_size.min(max_size);
+        let size: u16 = 1 << (15 - size.leading_zeros());
+        let notify_off = self.common.read::<u16>(QUEUE_NOTIFY_OFF);
+        let queue =
+            VirtQueue::new(index, size, notify_off).ok_or("failed to allocate virtqueue")?;
+
+        self.common.write::<u16>(QUEUE_SIZE, size);
+        self.common.write::<u16>(QUEUE_MSIX_VECTOR, NO_VECTOR);
+        self.common
+            .write::<u64>(QUEUE_DESC, queue.desc_phys().as_u64());
+        self.common
+            .write::<u64>(QUEUE_DRIVER, queue.avail_phys().as_u64());
+        self.common
+            .write::<u64>(QUEUE_DEVICE, queue.used_phys().as_u64());
+        self.common.write::<u16>(QUEUE_ENABLE, 1);
+        Ok(queue)
+    }
+
+    /// 初期化が終わったことをデバイスに伝える。
+    pub fn driver_ok(&self) {
+        self.set_status(self.status() | STATUS_DRIVER_OK);
+    }
+
+    /// 初期化に失敗したことをデバイスに伝える。
+    pub fn fail(&self) {
+        self.set_status(self.status() | STATUS_FAILED);
+    }
+
+    /// キューに新しいバッファを追加したことをデバイスに伝える。
+    pub fn notify(&self, queue: &VirtQueue) {
+        let offset = usize::from(queue.notify_off()) * self.notify_multiplier as usize;
+        self.notify.write::<u16>(offset, queue.index());
+    }
+
+    /// ISRステータスを読む。読むと割り込みが解除される
+    pub fn read_isr(&self) -> u8 {
+        self.isr.read::<u8>(0)
+    }
+
+    /// デバイス固有のコンフィギュレーションの`offset`にある32bitの値を読む。
+    pub fn read_config_u32(&self, offset: usize) -> u32 {
+        self.device.read::<u32>(offset)
+    }
+
+    /// デバイス固有のコンフィギュレーションの`offset`にある64bitの値を読む。
+    /// 32bitずつ読むので、途中で値が変わっていないかconfig_generationで確認する
+    pub fn read_config_u64(&self, offset: usize) -> u64 {
+        loop {
+            let generation = self.common.read::<u8>(CONFIG_GENERATION);
+            let low = u64::from(self.device.read::<u32>(offset));
+            let high = u64::from(self.device.read::<u32>(offset + 4));
+            if generation == self.common.read::<u8>(CONFIG_GENERATION) {
+                return high << 32 | low;
+            }
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        self.common.write::<u32>(DEVICE_FEATURE_SELECT, 0);
+        let low = u64::from(self.common.read::<u32>(DEVICE_FEATURE));
+        self.common.write::<u32>(DEVICE_FEATURE_SELECT, 1);
+        let high = u64::from(self.common.read::<u32>(DEVICE_FEATURE));
+        high << 32 | low
+    }
+
+    fn status(&self) -> u8 {
+        self.common.read::<u8>(DEVICE_STATUS)
+    }
+
+    fn set_status(&self, status: u8) {
+        self.common.write::<u8>(DEVICE_STATUS, status);
+    }
+}
@@ -0,0 +1,247 @@
+use crate::memory::{phys_to_virt, walk, FRAME_ALLOCATOR};
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use x86_64::structures::paging::{PageSize, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+// ディスクリプタのフラグ
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+// ディスクリプタテーブルの要素
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+// usedリングの要素
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// DMAでデバイスとやりとりする、物理的に連続したバッファ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer {
+    pub phys: PhysAddr,
+    pub len: u32,
+    /// デバイスが書き込むバッファならtrue
+    pub device_writable: bool,
+}
+
+/// 仮想アドレス`addr`から`len`バイトの領域を、物理的に連続した断片に分けて返す。
+/// マップされていないページがあればNone
+pub fn dma_buffers(addr: VirtAddr, len: usize, device_writable: bool) -> Option<Vec<Buffer>> {
+    let mut buffers: Vec<Buffer> = Vec::new();
+    let mut addr = addr;
+    let mut remaining = len as u64;
+    while remaining > 0 {
+        let translation = walk::translate(addr)?;
+        let mapping = translation.mapping;
+        // このページ(ヒュージページを含む)の終わりまで
+        let page_left = mapping.size - (addr.as_u64() - mapping.virt.as_u64());
+        let len = page_left.min(remaining);
+        match buffers.last_mut() {
+            // 前の断片と物理的に連続していればまとめる
+            Some(last) if last.phys + u64::from(last.len) == translation.phys => {
+                last.len += len as u32;
+            }
+            _ => buffers.push(Buffer {
+                phys: translation.phys,
+                len: len as u32,
+                device_writable,
+            }),
+        }
+        addr += len;
+        remaining -= len;
+    }
+    Some(buffers)
+}
+
+/// split virtqueue
+///
+/// ディスクリプタテーブル、availリング、usedリングを物理的に連続したフレームに置く。
+/// ディスクリプタのチェーンの先頭のインデックスで、追加したバッファと完了を対応させる。
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    notify_off: u16,
+    desc_phys: PhysAddr,
+    avail_phys: PhysAddr,
+    used_phys: PhysAddr,
+    desc: *mut Descriptor,
+    avail: VirtAddr,
+    used: VirtAddr,
+    // 空いているディスクリプタ
+    free: Vec<u16>,
+    // 次にavailリングに書くインデックス
+    avail_idx: u16,
+    // 次に読むusedリングのインデックス
+    last_used_idx: u16,
+}
+
+// ポインタはこのキューのためだけに割り当てたフレームを指している
+unsafe impl Send for VirtQueue {}
+
+impl VirtQueue {
+    /// `size`(2のべき乗)個のディスクリプタを持つキューを割り当てる。
+    pub fn new(index: u16, size: u16, notify_off: u16) -> Option<VirtQueue> {
+        assert!(size.is_power_of_two(), "キューのサイズは2のべき乗です");
+        let n = usize::from(size);
+        let desc_size = size_of::<Descriptor>() * n;
+        // flags, idx, ring[n], used_event
+        let avail_size = 2 * (3 + n);
+        // flags, idx, ring[n], avail_event
+        let used_size = 4 + size_of::<UsedElem>() * n + 2;
+        let avail_offset = desc_size;
+        let used_offset = (avail_offset + avail_size + 3) & !3;
+        let total = used_offset + used_size;
+        let frames = (total + Size4KiB::SIZE as usize - 1) / Size4KiB::SIZE as usize;
+
+        let frame = FRAME_ALLOCATOR
+            .lock()
+            .as_mut()
+            .expect("フレームアロケータが登録されていません")
+            .allocate_contiguous(frames, Size4KiB::SIZE)?;
+        let phys = frame.start_address();
+        let virt = phys_to_virt(phys);
+        unsafe { ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, frames * Size4KiB::SIZE as usize) };
+
+        Some(VirtQueue {
+            index,
+            size,
+            notify_off,
+            desc_phys: phys,
+            avail_phys: phys + avail_offset,
+            used_phys: phys + used_offset,
+            desc: virt.as_mut_ptr(),
+            avail: virt + avail_offset,
+            used: virt + used_offset,
+            free: (0..size).rev().collect(),
+            avail_idx: 0,
+            last_used_idx: 0,
+        })
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// 通知に使うオフセット(common configのqueue_notify_off)
+    pub fn notify_off(&self) -> u16 {
+        self.notify_off
+    }
+
+    pub fn desc_phys(&self) -> PhysAddr {
+        self.desc_phys
+    }
+
+    pub fn avail_phys(&self) -> PhysAddr {
+        self.avail_phys
+    }
+
+    pub fn used_phys(&self) -> PhysAddr {
+        self.used_phys
+    }
+
+    /// 空いているディスクリプタの数
+    pub fn num_free(&self) -> usize {
+        self.free.len()
+    }
+
+    /// `buffers`をディスクリプタのチェーンにしてavailリングに追加し、チェーンの先頭を返す。
+    /// ディスクリプタが足りなければNone
+    ///
+    /// デバイスへの通知は呼び出し側で行う。
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free.len() {
+            return None;
+        }
+        let ids: Vec<u16> = (0..buffers.len())
+            .map(|_| self.free.pop().unwrap())
+            .collect();
+        for (i, buffer) in buffers.iter().enumerate() {
+            let mut flags = 0;
+            if buffer.device_writable {
+                flags |= DESC_F_WRITE;
+            }
+            let next = match ids.get(i + 1) {
+                Some(&next) => {
+                    flags |= DESC_F_NEXT;
+                    next
+                }
+                None => 0,
+            };
+            let descriptor = Descriptor {
+                addr: buffer.phys.as_u64(),
+                len: buffer.len,
+                flags,
+                next,
+            };
+            unsafe {
+                self.desc
+                    .add(usize::from(ids[i]))
+                    .write_volatile(descriptor)
+            };
+        }
+
+        let head = ids[0];
+        let slot = usize::from(self.avail_idx % self.size);
+        unsafe {
+            self.avail_ptr(2 + slot).write_volatile(head);
+            // デバイスがidxを見る前にリングの中身が見えているようにする
+            fence(Ordering::SeqCst);
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            self.avail_ptr(1).write_volatile(self.avail_idx);
+        }
+        Some(head)
+    }
+
+    /// デバイスが処理し終えたチェーンを1つ取り出し、先頭と書き込まれたバイト数を返す。
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_idx = unsafe { (self.used.as_ptr::<u16>()).add(1).read_volatile() };
+        if used_idx == self.last_used_idx {
+            return None;
+        }
+        // idxを読んでから要素を読む
+        fence(Ordering::SeqCst);
+        let slot = usize::from(self.last_used_idx % self.size);
+        let elem = unsafe {
+            (self.used + 4u64)
+                .as_ptr::<UsedElem>()
+                .add(slot)
+                .read_volatile()
+        };
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+
+        // チェーンのディスクリプタを空きに戻す
+        let head = elem.id as u16;
+        let mut id = head;
+        loop {
+            let descriptor = unsafe { self.desc.add(usize::from(id)).read_volatile() };
+            self.free.push(id);
+            if descriptor.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            id = descriptor.next;
+        }
+        Some((head, elem.len))
+    }
+
+    // availリングの`index`番目のu16へのポインタ(0: flags, 1: idx, 2~: ring)
+    fn avail_ptr(&self, index: usize) -> *mut u16 {
+        unsafe { self.avail.as_mut_ptr::<u16>().add(index) }
+    }
+}
@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use my_os::block::ata::{self, SECTOR_SIZE};
+use my_os::block::{self, BlockError};
+use my_os::memory::BootInfoFrameAllocator;
+use my_os::task::simple_executor::block_on;
+use my_os::{allocator, memory};
+use x86_64::VirtAddr;
+
+// build.rsで作成する1MiBのテスト用ディスク
+const TEST_DISK_SECTORS: u64 = 2048;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    ata::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
+
+// ブートイメージがマスタ(ata0)、テスト用ディスクがスレーブ(ata1)になる
+fn test_disk() -> alloc::sync::Arc<dyn block::BlockDevice> {
+    block::find("ata1").expect("test disk not found")
+}
+
+#[test_case]
+fn identify_test_disk() {
+    let disk = test_disk();
+    assert_eq!(disk.block_size(), SECTOR_SIZE);
+    assert_eq!(disk.block_count(), TEST_DISK_SECTORS);
+}
+
+#[test_case]
+fn write_and_read_back() {
+    let disk = test_disk();
+    let data: alloc::vec::Vec<u8> = (0..SECTOR_SIZE * 3).map(|i| (i % 251) as u8).collect();
+    block_on(disk.write_blocks(100, &data)).expect("write failed");
+
+    let mut buf = vec![0; SECTOR_SIZE * 3];
+    block_on(disk.read_blocks(100, &mut buf)).expect("read failed");
+    assert_eq!(buf, data);
+}
+
+#[test_case]
+fn rejects_invalid_requests() {
+    let disk = test_disk();
+    let mut buf = vec![0; SECTOR_SIZE];
+    assert_eq!(
+        block_on(disk.read_blocks(TEST_DISK_SECTORS, &mut buf)),
+        Err(BlockError::OutOfRange)
+    );
+    let mut short = vec![0; SECTOR_SIZE - 1];
+    assert_eq!(
+        block_on(disk.read_blocks(0, &mut short)),
+        Err(BlockError::InvalidBufferSize)
+    );
+}
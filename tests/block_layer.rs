@@ -0,0 +1,278 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use my_os::block::cache::BufferCache;
+use my_os::block::partition::{self, crc32, Guid, PartitionKind};
+use my_os::block::ramdisk::RamDisk;
+use my_os::block::{BlockDevice, BlockError, BlockFuture};
+use my_os::memory::BootInfoFrameAllocator;
+use my_os::task::simple_executor::block_on;
+use my_os::{allocator, memory};
+use x86_64::VirtAddr;
+
+const BLOCK_SIZE: usize = 512;
+// ヒープが小さいので32KiBのディスクを使う
+const BLOCK_COUNT: u64 = 64;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
+
+fn ramdisk() -> Arc<RamDisk> {
+    Arc::new(RamDisk::new("ram0", BLOCK_SIZE, BLOCK_COUNT))
+}
+
+fn read_block(device: &dyn BlockDevice, lba: u64) -> alloc::vec::Vec<u8> {
+    let mut buf = vec![0; device.block_size()];
+    block_on(device.read_blocks(lba, &mut buf)).expect("read failed");
+    buf
+}
+
+#[test_case]
+fn ramdisk_round_trip() {
+    let disk = ramdisk();
+    let data = vec![0xab; BLOCK_SIZE * 2];
+    block_on(disk.write_blocks(3, &data)).expect("write failed");
+    assert_eq!(read_block(&*disk, 3), vec![0xab; BLOCK_SIZE]);
+    assert_eq!(read_block(&*disk, 4), vec![0xab; BLOCK_SIZE]);
+    assert_eq!(read_block(&*disk, 5), vec![0; BLOCK_SIZE]);
+    assert_eq!(
+        block_on(disk.read_blocks(BLOCK_COUNT - 1, &mut vec![0; BLOCK_SIZE * 2])),
+        Err(BlockError::OutOfRange)
+    );
+}
+
+#[test_case]
+fn cache_writes_back_on_sync() {
+    let disk = ramdisk();
+    let cache = BufferCache::new(disk.clone(), 4);
+    block_on(cache.write_blocks(5, &[0x11; BLOCK_SIZE])).expect("write failed");
+    assert_eq!(read_block(&cache, 5), vec![0x11; BLOCK_SIZE]);
+    // まだディスクには書かれていない
+    assert_eq!(read_block(&*disk, 5), vec![0; BLOCK_SIZE]);
+    assert_eq!(cache.dirty_blocks(), 1);
+
+    block_on(cache.sync()).expect("sync failed");
+    assert_eq!(read_block(&*disk, 5), vec![0x11; BLOCK_SIZE]);
+    assert_eq!(cache.dirty_blocks(), 0);
+}
+
+#[test_case]
+fn cache_evicts_least_recently_used() {
+    let disk = ramdisk();
+    let cache = BufferCache::new(disk.clone(), 2);
+    read_block(&cache, 0);
+    read_block(&cache, 1);
+    read_block(&cache, 0);
+    // 1が最も長く使われていないので追い出される
+    read_block(&cache, 2);
+    let before = cache.stats();
+    read_block(&cache, 0);
+    assert_eq!(cache.stats().hits, before.hits + 1);
+    read_block(&cache, 1);
+    assert_eq!(cache.stats().misses, before.misses + 1);
+    assert_eq!(cache.cached_blocks(), 2);
+}
+
+#[test_case]
+fn cache_writes_back_dirty_block_on_eviction() {
+    let disk = ramdisk();
+    let cache = BufferCache::new(disk.clone(), 1);
+    block_on(cache.write_blocks(3, &[0x22; BLOCK_SIZE])).expect("write failed");
+    block_on(cache.write_blocks(4, &[0x33; BLOCK_SIZE])).expect("write failed");
+    assert_eq!(read_block(&*disk, 3), vec![0x22; BLOCK_SIZE]);
+    assert_eq!(read_block(&*disk, 4), vec![0; BLOCK_SIZE]);
+    assert_eq!(cache.stats().writebacks, 1);
+}
+
+// 指定したブロックへの書き込みだけ失敗するディスク
+struct FailingDisk {
+    disk: RamDisk,
+    // 書き込みに失敗するLBA。u64::MAXなら失敗しない
+    fail_lba: AtomicU64,
+}
+
+impl BlockDevice for FailingDisk {
+    fn name(&self) -> &str {
+        self.disk.name()
+    }
+
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.disk.block_count()
+    }
+
+    fn read_blocks<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BlockFuture<'a, ()> {
+        self.disk.read_blocks(lba, buf)
+    }
+
+    fn write_blocks<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a, ()> {
+        let fail_lba = self.fail_lba.load(Ordering::Relaxed);
+        let blocks = (buf.len() / self.block_size()) as u64;
+        if (lba..lba + blocks).contains(&fail_lba) {
+            return Box::pin(async { Err(BlockError::DeviceError(1)) });
+        }
+        self.disk.write_blocks(lba, buf)
+    }
+}
+
+// 書き戻しに失敗しても、失敗したブロックとその後のブロックはdirtyのまま残る
+#[test_case]
+fn cache_keeps_unwritten_blocks_dirty_on_sync_error() {
+    let disk = Arc::new(FailingDisk {
+        disk: RamDisk::new("ram0", BLOCK_SIZE, BLOCK_COUNT),
+        fail_lba: AtomicU64::new(2),
+    });
+    let cache = BufferCache::new(disk.clone(), 4);
+    for lba in 1..4 {
+        block_on(cache.write_blocks(lba, &[lba as u8; BLOCK_SIZE])).expect("write failed");
+    }
+
+    assert_eq!(block_on(cache.sync()), Err(BlockError::DeviceError(1)));
+    assert_eq!(read_block(&*disk, 1), vec![1; BLOCK_SIZE]);
+    assert_eq!(read_block(&*disk, 3), vec![0; BLOCK_SIZE]);
+    assert_eq!(cache.dirty_blocks(), 2);
+
+    // 失敗しなくなれば、次のsyncで残りが書かれる
+    disk.fail_lba.store(u64::MAX, Ordering::Relaxed);
+    block_on(cache.sync()).expect("sync failed");
+    assert_eq!(read_block(&*disk, 2), vec![2; BLOCK_SIZE]);
+    assert_eq!(read_block(&*disk, 3), vec![3; BLOCK_SIZE]);
+    assert_eq!(cache.dirty_blocks(), 0);
+}
+
+// 追い出しの書き戻しに失敗しても、呼び出し元は失敗せず、ブロックはdirtyのまま残る
+#[test_case]
+fn cache_keeps_evicted_block_on_writeback_error() {
+    let disk = Arc::new(FailingDisk {
+        disk: RamDisk::new("ram0", BLOCK_SIZE, BLOCK_COUNT),
+        fail_lba: AtomicU64::new(2),
+    });
+    let cache = BufferCache::new(disk.clone(), 1);
+    block_on(cache.write_blocks(2, &[2; BLOCK_SIZE])).expect("write failed");
+    // ブロック2が追い出されるが、書き戻しは失敗する
+    block_on(cache.write_blocks(3, &[3; BLOCK_SIZE])).expect("write failed");
+    assert_eq!(cache.stats().writeback_errors, 1);
+    assert_eq!(read_block(&*disk, 2), vec![0; BLOCK_SIZE]);
+    assert_eq!(read_block(&cache, 2), vec![2; BLOCK_SIZE]);
+    assert_eq!(cache.dirty_blocks(), 2);
+
+    disk.fail_lba.store(u64::MAX, Ordering::Relaxed);
+    block_on(cache.sync()).expect("sync failed");
+    assert_eq!(read_block(&*disk, 2), vec![2; BLOCK_SIZE]);
+    assert_eq!(cache.dirty_blocks(), 0);
+}
+
+// MBRのパーティションエントリを書く
+fn write_mbr_entry(mbr: &mut [u8], index: usize, kind: u8, start: u32, count: u32) {
+    let entry = &mut mbr[0x1be + index * 16..][..16];
+    entry[4] = kind;
+    entry[8..12].copy_from_slice(&start.to_le_bytes());
+    entry[12..16].copy_from_slice(&count.to_le_bytes());
+}
+
+#[test_case]
+fn mbr_partitions() {
+    let disk = ramdisk();
+    let mut mbr = vec![0; BLOCK_SIZE];
+    write_mbr_entry(&mut mbr, 0, 0x0c, 2, 10);
+    write_mbr_entry(&mut mbr, 1, 0x83, 20, 30);
+    mbr[510] = 0x55;
+    mbr[511] = 0xaa;
+    block_on(disk.write_blocks(0, &mbr)).expect("write failed");
+
+    let partitions = block_on(partition::scan(disk.clone())).expect("scan failed");
+    assert_eq!(partitions.len(), 2);
+    assert_eq!(partitions[0].name(), "ram0p1");
+    assert_eq!(partitions[0].kind, PartitionKind::Mbr(0x0c));
+    assert_eq!((partitions[0].start, partitions[0].block_count()), (2, 10));
+    assert_eq!((partitions[1].start, partitions[1].block_count()), (20, 30));
+
+    // パーティションの0番目のブロックはディスクの20番目
+    let second = &partitions[1];
+    block_on(second.write_blocks(0, &[0x44; BLOCK_SIZE])).expect("write failed");
+    assert_eq!(read_block(&*disk, 20), vec![0x44; BLOCK_SIZE]);
+    assert_eq!(
+        block_on(second.read_blocks(30, &mut vec![0; BLOCK_SIZE])),
+        Err(BlockError::OutOfRange)
+    );
+}
+
+#[test_case]
+fn gpt_partitions() {
+    let disk = ramdisk();
+    let mut mbr = vec![0; BLOCK_SIZE];
+    write_mbr_entry(&mut mbr, 0, 0xee, 1, BLOCK_COUNT as u32 - 1);
+    mbr[510] = 0x55;
+    mbr[511] = 0xaa;
+
+    // パーティションエントリ(4個)はLBA 2に置く
+    let mut entries = vec![0; BLOCK_SIZE];
+    entries[0..16].copy_from_slice(&Guid::LINUX_FILESYSTEM.0);
+    entries[16] = 1;
+    entries[32..40].copy_from_slice(&8u64.to_le_bytes());
+    entries[40..48].copy_from_slice(&39u64.to_le_bytes());
+    for (i, c) in "root".encode_utf16().enumerate() {
+        entries[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+    }
+
+    let mut header = vec![0; BLOCK_SIZE];
+    header[0..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&92u32.to_le_bytes());
+    header[24..32].copy_from_slice(&1u64.to_le_bytes());
+    header[32..40].copy_from_slice(&(BLOCK_COUNT - 1).to_le_bytes());
+    header[40..48].copy_from_slice(&3u64.to_le_bytes());
+    header[48..56].copy_from_slice(&(BLOCK_COUNT - 2).to_le_bytes());
+    header[72..80].copy_from_slice(&2u64.to_le_bytes());
+    header[80..84].copy_from_slice(&4u32.to_le_bytes());
+    header[84..88].copy_from_slice(&128u32.to_le_bytes());
+    header[88..92].copy_from_slice(&crc32(&entries).to_le_bytes());
+    let header_crc = crc32(&header[..92]);
+    header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+
+    block_on(disk.write_blocks(0, &mbr)).expect("write failed");
+    block_on(disk.write_blocks(1, &header)).expect("write failed");
+    block_on(disk.write_blocks(2, &entries)).expect("write failed");
+
+    let partitions = block_on(partition::scan(disk.clone())).expect("scan failed");
+    assert_eq!(partitions.len(), 1);
+    let root = &partitions[0];
+    assert_eq!(root.label, "root");
+    assert_eq!(root.kind, PartitionKind::Gpt(Guid::LINUX_FILESYSTEM));
+    assert_eq!((root.start, root.block_count()), (8, 32));
+
+    // CRCが壊れていればGPTとして扱わない
+    header[16] ^= 0xff;
+    block_on(disk.write_blocks(1, &header)).expect("write failed");
+    let partitions = block_on(partition::scan(disk.clone())).expect("scan failed");
+    assert!(partitions.is_empty());
+}
@@ -0,0 +1,130 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use my_os::memory::cow::{AddressSpace, COPY_ON_WRITE};
+use my_os::memory::{BootInfoFrameAllocator, FRAME_ALLOCATOR};
+use my_os::{allocator, memory};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+// テストに使うページ(ヒープやカーネルとはレベル4のエントリが被らないアドレス)
+const TEST_PAGE_START: u64 = 0x_5555_0000_0000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    // テスト用のページをマップしておく
+    let page = test_page();
+    let frame: PhysFrame = frame_allocator
+        .allocate_frame()
+        .expect("frame allocation failed");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe {
+        mapper
+            .map_to(page, frame, flags, &mut frame_allocator)
+            .expect("map_to failed")
+            .flush()
+    };
+    memory::install_frame_allocator(frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+fn test_page() -> Page<Size4KiB> {
+    Page::containing_address(VirtAddr::new(TEST_PAGE_START))
+}
+
+fn test_ptr() -> *mut u64 {
+    test_page().start_address().as_mut_ptr()
+}
+
+fn mapped_frame(space: &AddressSpace) -> (PhysFrame, PageTableFlags) {
+    use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+
+    let mapper = unsafe { space.mapper() };
+    match mapper.translate(test_page().start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } => (frame, flags),
+        _ => panic!("テスト用のページがマップされていません"),
+    }
+}
+
+fn fork() -> (AddressSpace, AddressSpace) {
+    let parent = AddressSpace::current();
+    let range = Page::range_inclusive(test_page(), test_page());
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().expect("frame allocator not installed");
+    let child = parent
+        .clone_cow(range, frame_allocator)
+        .expect("clone_cow failed");
+    (parent, child)
+}
+
+// 以下test case
+#[test_case]
+// 複製直後は同じフレームを読み込み専用で共有している
+fn shares_frame_after_fork() {
+    unsafe { test_ptr().write_volatile(1) };
+    let (parent, child) = fork();
+
+    let (parent_frame, parent_flags) = mapped_frame(&parent);
+    let (child_frame, child_flags) = mapped_frame(&child);
+    assert_eq!(parent_frame, child_frame);
+    assert!(parent_flags.contains(COPY_ON_WRITE));
+    assert!(!child_flags.contains(PageTableFlags::WRITABLE));
+    assert_eq!(
+        FRAME_ALLOCATOR
+            .lock()
+            .as_ref()
+            .unwrap()
+            .ref_count(parent_frame),
+        2
+    );
+}
+
+#[test_case]
+// 書き込んだ側だけがコピーを持ち、もう一方からは元の値が見える
+fn write_copies_frame() {
+    unsafe { test_ptr().write_volatile(10) };
+    let (parent, child) = fork();
+
+    unsafe {
+        child.activate();
+        assert_eq!(test_ptr().read_volatile(), 10);
+        test_ptr().write_volatile(20);
+        assert_eq!(test_ptr().read_volatile(), 20);
+        parent.activate();
+    }
+    assert_eq!(unsafe { test_ptr().read_volatile() }, 10);
+    assert_ne!(mapped_frame(&parent).0, mapped_frame(&child).0);
+
+    // 最後の参照になったフレームはコピーせずに書き込み可能になる
+    let (frame, _) = mapped_frame(&parent);
+    unsafe { test_ptr().write_volatile(30) };
+    assert_eq!(mapped_frame(&parent).0, frame);
+    assert_eq!(unsafe { test_ptr().read_volatile() }, 30);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
@@ -0,0 +1,105 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use my_os::memory::BootInfoFrameAllocator;
+use my_os::task::deferred;
+use my_os::task::executor::{Executor, Priority};
+use my_os::{allocator, memory};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
+
+// ワーカーが実行した処理の引数
+static RECEIVED: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+fn record(arg: usize) {
+    RECEIVED.lock().push(arg);
+}
+
+// テストごとに別のIRQの番号を使い、統計が混ざらないようにする
+// 最初のテストなので、まだキューは用意されていない
+#[test_case]
+fn dropped_before_init() {
+    assert!(!deferred::defer(3, record, 0));
+    let stats = deferred::stats(3);
+    assert_eq!(stats.queued, 0);
+    assert_eq!(stats.dropped, 1);
+}
+
+#[test_case]
+fn worker_runs_in_order() {
+    deferred::init();
+    RECEIVED.lock().clear();
+    // バッチの大きさを超える数を積んでも、順番どおりにすべて実行される
+    for i in 0..100 {
+        assert!(deferred::defer(4, record, i));
+    }
+    let mut executor = Executor::new();
+    executor.spawn_named("bottom-half", Priority::BottomHalf, deferred::run());
+    executor.run_until_stalled();
+    assert_eq!(*RECEIVED.lock(), (0..100).collect::<Vec<_>>());
+
+    // 待っているワーカーは、積まれたら起こされる
+    assert!(deferred::defer(4, record, 100));
+    executor.run_until_stalled();
+    assert_eq!(RECEIVED.lock().last(), Some(&100));
+
+    let stats = deferred::stats(4);
+    assert_eq!(stats.queued, 101);
+    assert_eq!(stats.run, 101);
+    assert_eq!(stats.dropped, 0);
+}
+
+#[test_case]
+fn full_queue_drops() {
+    deferred::init();
+    let mut queued = 0;
+    for i in 0..1000 {
+        if deferred::defer(5, record, i) {
+            queued += 1;
+        }
+    }
+    let stats = deferred::stats(5);
+    assert!(queued < 1000);
+    assert_eq!(stats.queued, queued);
+    assert_eq!(stats.dropped, 1000 - queued);
+
+    let mut executor = Executor::new();
+    executor.spawn(deferred::run());
+    executor.run_until_stalled();
+    assert_eq!(deferred::stats(5).run, queued);
+}
+
+// PICにない番号のIRQはパニックせずに捨てる
+#[test_case]
+fn out_of_range_irq() {
+    deferred::init();
+    assert!(!deferred::defer(16, record, 0));
+    assert!(!deferred::defer(u8::MAX, record, 0));
+    assert_eq!(deferred::stats(16).queued, 0);
+}
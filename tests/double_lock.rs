@@ -0,0 +1,40 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use my_os::serial::SERIAL1;
+use my_os::vga_buffer::WRITER;
+use my_os::{exit_qemu, println, serial_print, serial_println, QemuExitCode};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("double_lock::print_after_double_lock...\t");
+    my_os::init();
+    // 二重ロックはデバッグビルドでしか検出しない(リリースビルドでは止まったままになる)
+    if !cfg!(debug_assertions) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+        loop {}
+    }
+    double_lock();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+// SERIAL1とWRITERを持ったままWRITERをもう一度取ると、デバッグビルドではパニックする
+fn double_lock() {
+    let _serial = SERIAL1.lock();
+    let _writer = WRITER.lock();
+    let _again = WRITER.lock();
+}
+
+// 本物のパニックハンドラと同じようにロックを外してから、VGAとシリアルの両方に表示できる
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::unlock_output_for_panic();
+    println!("{}", info);
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
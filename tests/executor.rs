@@ -0,0 +1,232 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::cell::{Cell, RefCell};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::future;
+use my_os::memory::BootInfoFrameAllocator;
+use my_os::task::executor::{tasks, Executor, Priority, Spawner, TaskState};
+use my_os::task::join::JoinError;
+use my_os::task::simple_executor::block_on;
+use my_os::{allocator, memory};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
+
+// dropされたらフラグを立てる
+struct DropFlag(Rc<Cell<bool>>);
+
+impl Drop for DropFlag {
+    fn drop(&mut self) {
+        self.0.set(true);
+    }
+}
+
+#[test_case]
+fn await_output() {
+    let mut executor = Executor::new();
+    let first = executor.spawn(async { 1 + 2 });
+    // 別のタスクから結果を待つ
+    let second = executor.spawn(async move { first.await.map(|value| value * 10) });
+    assert!(!second.is_finished());
+    executor.run_until_stalled();
+    assert!(second.is_finished());
+    assert_eq!(block_on(second), Ok(Ok(30)));
+}
+
+#[test_case]
+fn abort_before_poll() {
+    let mut executor = Executor::new();
+    let dropped = Rc::new(Cell::new(false));
+    let flag = DropFlag(dropped.clone());
+    let handle = executor.spawn(async move {
+        let _flag = flag;
+        future::pending::<()>().await
+    });
+    handle.abort();
+    executor.run_until_stalled();
+    assert!(dropped.get());
+    assert_eq!(block_on(handle), Err(JoinError::Aborted));
+}
+
+#[test_case]
+fn abort_pending_task() {
+    let mut executor = Executor::new();
+    let dropped = Rc::new(Cell::new(false));
+    let flag = DropFlag(dropped.clone());
+    let handle = executor.spawn(async move {
+        let _flag = flag;
+        future::pending::<()>().await
+    });
+    executor.run_until_stalled();
+    assert!(!handle.is_finished());
+    assert!(!dropped.get());
+
+    // abortでタスクが起こされ、次のpollでfutureが捨てられる
+    handle.abort();
+    executor.run_until_stalled();
+    assert!(dropped.get());
+    assert_eq!(block_on(handle), Err(JoinError::Aborted));
+}
+
+#[test_case]
+fn abort_after_finish() {
+    let mut executor = Executor::new();
+    let handle = executor.spawn(async { "done" });
+    executor.run_until_stalled();
+    handle.abort();
+    assert_eq!(block_on(handle), Ok("done"));
+}
+
+// `depth`段まで子タスクをspawnし、spawnしたタスクの数を返す
+async fn spawn_tree(spawner: Spawner, depth: u32) -> u32 {
+    if depth == 0 {
+        return 0;
+    }
+    let left = spawner.spawn(spawn_tree(spawner.clone(), depth - 1));
+    let right = spawner.spawn(spawn_tree(spawner.clone(), depth - 1));
+    2 + left.await.unwrap() + right.await.unwrap()
+}
+
+#[test_case]
+fn spawn_from_task() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let handle = executor.spawn(spawn_tree(spawner, 4));
+    executor.run_until_stalled();
+    assert_eq!(block_on(handle), Ok(30));
+}
+
+#[test_case]
+fn spawn_before_run() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let handle = spawner.spawn(async { 42 });
+    drop(spawner);
+    assert!(!handle.is_finished());
+    executor.run_until_stalled();
+    assert_eq!(block_on(handle), Ok(42));
+}
+
+// 1回だけPendingを返し、すぐに自分を起こす
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn priority_order() {
+    let mut executor = Executor::new();
+    let order = Rc::new(RefCell::new(Vec::new()));
+    for &priority in &[
+        Priority::Background,
+        Priority::Interactive,
+        Priority::BottomHalf,
+    ] {
+        let order = order.clone();
+        executor.spawn_with_priority(priority, async move { order.borrow_mut().push(priority) });
+    }
+    executor.run_until_stalled();
+    assert_eq!(*order.borrow(), Priority::ALL);
+}
+
+#[test_case]
+fn poll_budget() {
+    let mut executor = Executor::new();
+    executor.set_poll_budget(Priority::BottomHalf, 4);
+    let yields = Rc::new(Cell::new(0));
+    let counter = yields.clone();
+    // 自分を起こし続ける優先度の高いタスク
+    executor.spawn_with_priority(Priority::BottomHalf, async move {
+        for _ in 0..20 {
+            YieldNow(false).await;
+            counter.set(counter.get() + 1);
+        }
+    });
+    let observed = yields.clone();
+    let background =
+        executor.spawn_with_priority(Priority::Background, async move { observed.get() });
+    executor.run_until_stalled();
+    // 予算を使い切った時点で、優先度の低いタスクに順番が回る
+    assert_eq!(block_on(background), Ok(3));
+    assert_eq!(yields.get(), 20);
+}
+
+#[test_case]
+fn poll_accounting() {
+    let mut executor = Executor::new();
+    let handle = executor.spawn_named("yield-once", Priority::Background, async {
+        YieldNow(false).await;
+        future::pending::<()>().await
+    });
+    executor.run_until_stalled();
+    let info = tasks()
+        .into_iter()
+        .find(|info| info.id == handle.id())
+        .unwrap();
+    assert_eq!(info.name, "yield-once");
+    assert_eq!(info.state, TaskState::Pending);
+    assert_eq!(info.stats.priority, Priority::Background);
+    assert_eq!(info.stats.polls, 2);
+    assert_eq!(info.stats.deferred, 1);
+    assert!(info.stats.cycles >= info.stats.max_cycles);
+}
+
+#[test_case]
+fn task_states() {
+    let mut executor = Executor::new();
+    let finished = executor.spawn_named("finished", Priority::Interactive, async {});
+    let waiting = executor.spawn_named("waiting", Priority::Interactive, future::pending::<()>());
+    let state = |id| {
+        tasks()
+            .into_iter()
+            .find(|info| info.id == id)
+            .map(|info| info.state)
+    };
+    assert_eq!(state(finished.id()), Some(TaskState::Ready));
+    executor.run_until_stalled();
+    assert_eq!(state(finished.id()), Some(TaskState::Completed));
+    assert_eq!(state(waiting.id()), Some(TaskState::Pending));
+
+    // executorを捨てると、実行中のタスクは一覧から消える
+    drop(executor);
+    assert_eq!(state(waiting.id()), None);
+    assert_eq!(state(finished.id()), Some(TaskState::Completed));
+}
@@ -0,0 +1,340 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use my_os::block::ramdisk::RamDisk;
+use my_os::block::BlockDevice;
+use my_os::fs::ext2::Ext2Fs;
+use my_os::fs::{self, FileSystem, FileType, FsError};
+use my_os::memory::BootInfoFrameAllocator;
+use my_os::task::simple_executor::block_on;
+use my_os::{allocator, memory};
+use x86_64::VirtAddr;
+
+// ヒープが小さいので、1KiBのブロックが32個のイメージをテストの中で作る
+const BLOCK_SIZE: usize = 1024;
+const BLOCK_COUNT: usize = 32;
+const INODE_COUNT: usize = 16;
+const INODE_SIZE: usize = 128;
+// ブロック1はスーパーブロック、2はグループディスクリプタ、3と4はビットマップ
+const INODE_TABLE: usize = 5;
+const FIRST_DATA: usize = 7;
+
+// 12個の直接ブロックと、間接ブロックから2つ(1つは穴)を使うファイル
+const BIG_BLOCKS: usize = 14;
+const BIG_HOLE: usize = 12;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    fs::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
+
+fn put_u16(image: &mut [u8], offset: usize, value: u16) {
+    image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(image: &mut [u8], offset: usize, value: u32) {
+    image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+// inodeを書く。`blocks`はi_blockにそのまま入れる
+fn put_inode(image: &mut [u8], ino: usize, mode: u16, size: u32, blocks: &[u32]) {
+    let offset = INODE_TABLE * BLOCK_SIZE + (ino - 1) * INODE_SIZE;
+    put_u16(image, offset, mode);
+    put_u32(image, offset + 4, size);
+    put_u16(image, offset + 26, 1);
+    let used = blocks.iter().filter(|&&b| b != 0).count() as u32;
+    put_u32(image, offset + 28, used * (BLOCK_SIZE / 512) as u32);
+    for (i, &block) in blocks.iter().enumerate() {
+        put_u32(image, offset + 40 + i * 4, block);
+    }
+}
+
+// ディレクトリのブロックを書く。最後のエントリがブロックの終わりまでを使う
+fn put_dir(image: &mut [u8], block: usize, entries: &[(&str, u32, u8)]) {
+    let mut offset = block * BLOCK_SIZE;
+    for (i, &(name, ino, kind)) in entries.iter().enumerate() {
+        let rec_len = if i + 1 == entries.len() {
+            (block + 1) * BLOCK_SIZE - offset
+        } else {
+            (8 + name.len() + 3) & !3
+        };
+        put_u32(image, offset, ino);
+        put_u16(image, offset + 4, rec_len as u16);
+        image[offset + 6] = name.len() as u8;
+        image[offset + 7] = kind;
+        image[offset + 8..offset + 8 + name.len()].copy_from_slice(name.as_bytes());
+        offset += rec_len;
+    }
+}
+
+fn big_file_byte(block: usize, i: usize) -> u8 {
+    if block == BIG_HOLE {
+        0
+    } else {
+        (block * 7 + i) as u8
+    }
+}
+
+// /hello.txt, /dir/big.bin, /link -> dir/big.bin のあるイメージ
+fn image() -> Vec<u8> {
+    let mut image = vec![0; BLOCK_SIZE * BLOCK_COUNT];
+    let sb = BLOCK_SIZE;
+    put_u32(&mut image, sb, INODE_COUNT as u32);
+    put_u32(&mut image, sb + 4, BLOCK_COUNT as u32);
+    put_u32(&mut image, sb + 20, 1);
+    put_u32(&mut image, sb + 32, 8192);
+    put_u32(&mut image, sb + 40, INODE_COUNT as u32);
+    put_u16(&mut image, sb + 56, 0xef53);
+    put_u32(&mut image, sb + 76, 1);
+    put_u32(&mut image, sb + 84, 11);
+    put_u16(&mut image, sb + 88, INODE_SIZE as u16);
+    // FILETYPE
+    put_u32(&mut image, sb + 96, 0x0002);
+    image[sb + 120..sb + 124].copy_from_slice(b"test");
+    put_u32(&mut image, 2 * BLOCK_SIZE + 8, INODE_TABLE as u32);
+
+    let root = FIRST_DATA;
+    let dir = FIRST_DATA + 1;
+    let hello = FIRST_DATA + 2;
+    let indirect = FIRST_DATA + 3;
+    let big = FIRST_DATA + 4;
+    put_inode(&mut image, 2, 0o040755, BLOCK_SIZE as u32, &[root as u32]);
+    put_dir(
+        &mut image,
+        root,
+        &[
+            (".", 2, 2),
+            ("..", 2, 2),
+            ("hello.txt", 12, 1),
+            ("dir", 13, 2),
+            ("link", 14, 7),
+        ],
+    );
+    put_inode(&mut image, 13, 0o040755, BLOCK_SIZE as u32, &[dir as u32]);
+    put_dir(
+        &mut image,
+        dir,
+        &[(".", 13, 2), ("..", 2, 2), ("big.bin", 15, 1)],
+    );
+
+    let text = b"Hello, ext2!\n";
+    put_inode(&mut image, 12, 0o100644, text.len() as u32, &[hello as u32]);
+    image[hello * BLOCK_SIZE..][..text.len()].copy_from_slice(text);
+
+    // 短いシンボリックリンクはi_blockにリンク先が入る
+    let target = b"dir/big.bin";
+    let offset = INODE_TABLE * BLOCK_SIZE + 13 * INODE_SIZE;
+    put_u16(&mut image, offset, 0o120777);
+    put_u32(&mut image, offset + 4, target.len() as u32);
+    image[offset + 40..offset + 40 + target.len()].copy_from_slice(target);
+
+    let mut blocks = [0u32; 15];
+    let mut next = big;
+    for pointer in blocks.iter_mut().take(12) {
+        *pointer = next as u32;
+        next += 1;
+    }
+    blocks[12] = indirect as u32;
+    for block in 0..BIG_BLOCKS {
+        if block == BIG_HOLE {
+            continue;
+        }
+        let disk_block = if block < 12 {
+            blocks[block] as usize
+        } else {
+            let slot = indirect * BLOCK_SIZE + (block - 12) * 4;
+            put_u32(&mut image, slot, next as u32);
+            next += 1;
+            next - 1
+        };
+        for i in 0..BLOCK_SIZE {
+            image[disk_block * BLOCK_SIZE + i] = big_file_byte(block, i);
+        }
+    }
+    assert!(next <= BLOCK_COUNT);
+    put_inode(
+        &mut image,
+        15,
+        0o100644,
+        (BIG_BLOCKS * BLOCK_SIZE - 100) as u32,
+        &blocks,
+    );
+    image
+}
+
+fn open() -> Ext2Fs {
+    open_image(image())
+}
+
+fn open_image(image: Vec<u8>) -> Ext2Fs {
+    let disk = Arc::new(RamDisk::from_bytes("ram0", 512, image));
+    block_on(Ext2Fs::new(disk)).expect("open failed")
+}
+
+fn inode_offset(ino: usize) -> usize {
+    INODE_TABLE * BLOCK_SIZE + (ino - 1) * INODE_SIZE
+}
+
+#[test_case]
+fn superblock_and_root() {
+    let ext2 = open();
+    assert_eq!(ext2.label(), "test");
+    assert_eq!(ext2.block_size(), BLOCK_SIZE);
+    let root = ext2.root();
+    assert_eq!(root.metadata().ino, 2);
+    let entries = block_on(root.readdir()).unwrap();
+    let listed: Vec<(String, FileType)> = entries
+        .into_iter()
+        .map(|entry| (entry.name, entry.file_type))
+        .collect();
+    assert_eq!(
+        listed,
+        [
+            (String::from("hello.txt"), FileType::File),
+            (String::from("dir"), FileType::Directory),
+            (String::from("link"), FileType::Symlink),
+        ]
+    );
+}
+
+#[test_case]
+fn read_direct_and_indirect_blocks() {
+    let ext2 = open();
+    let dir = block_on(ext2.root().lookup("dir")).unwrap();
+    let big = block_on(dir.lookup("big.bin")).unwrap();
+    let size = BIG_BLOCKS * BLOCK_SIZE - 100;
+    assert_eq!(big.metadata().size, size as u64);
+
+    // 直接ブロックから間接ブロック(穴を含む)にまたがって読む
+    let mut buf = vec![0; 3 * BLOCK_SIZE];
+    let offset = 11 * BLOCK_SIZE;
+    let n = block_on(big.read_at(offset as u64, &mut buf)).unwrap();
+    assert_eq!(n, size - offset);
+    for (i, &byte) in buf[..n].iter().enumerate() {
+        let pos = offset + i;
+        assert_eq!(byte, big_file_byte(pos / BLOCK_SIZE, pos % BLOCK_SIZE));
+    }
+    assert_eq!(block_on(big.read_at(size as u64, &mut buf)), Ok(0));
+}
+
+#[test_case]
+fn symlink_target() {
+    let ext2 = open();
+    let link = block_on(ext2.root().lookup("link")).unwrap();
+    assert_eq!(link.metadata().file_type, FileType::Symlink);
+    let mut buf = [0; 32];
+    let n = block_on(link.read_at(0, &mut buf)).unwrap();
+    assert_eq!(&buf[..n], b"dir/big.bin");
+}
+
+// 拡張属性のブロックがあっても、i_blockにリンク先が入っていれば短いシンボリックリンク
+#[test_case]
+fn symlink_with_xattr_block() {
+    let mut image = image();
+    let link = inode_offset(14);
+    put_u32(&mut image, link + 28, (BLOCK_SIZE / 512) as u32);
+    put_u32(&mut image, link + 104, (BLOCK_COUNT - 1) as u32);
+    let ext2 = open_image(image);
+    let link = block_on(ext2.root().lookup("link")).unwrap();
+    let mut buf = [0; 32];
+    let n = block_on(link.read_at(0, &mut buf)).unwrap();
+    assert_eq!(&buf[..n], b"dir/big.bin");
+}
+
+// ディスク上の大きさが実際のブロックより大きいディレクトリは、確保せずに壊れていると扱う
+#[test_case]
+fn oversized_directory() {
+    let mut image = image();
+    put_u32(&mut image, inode_offset(13) + 4, 0xffff_fc00);
+    let ext2 = open_image(image);
+    let dir = block_on(ext2.root().lookup("dir")).unwrap();
+    assert!(matches!(block_on(dir.readdir()), Err(FsError::Corrupted)));
+}
+
+// グループの数はスーパーブロックの値からあふれずに計算し、inodeが収まらなければ壊れていると扱う
+#[test_case]
+fn group_count_from_superblock() {
+    // ヒープが小さいので、イメージは1つずつ作る
+    {
+        let mut image = image();
+        put_u32(&mut image, BLOCK_SIZE + 32, u32::MAX);
+        let ext2 = open_image(image);
+        assert!(block_on(ext2.root().lookup("hello.txt")).is_ok());
+    }
+
+    let mut image = image();
+    put_u32(&mut image, BLOCK_SIZE + 32, u32::MAX);
+    put_u32(&mut image, BLOCK_SIZE + 40, 1);
+    let disk = Arc::new(RamDisk::from_bytes("ram0", 512, image));
+    assert!(matches!(
+        block_on(Ext2Fs::new(disk)),
+        Err(FsError::Corrupted)
+    ));
+}
+
+#[test_case]
+fn rejects_other_devices() {
+    let disk = Arc::new(RamDisk::new("ram0", 512, 8));
+    assert!(matches!(
+        block_on(Ext2Fs::new(disk)),
+        Err(FsError::Unsupported)
+    ));
+}
+
+#[test_case]
+fn mount_read_only() {
+    let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::from_bytes("ram0", 512, image()));
+    block_on(fs::create_dir("/ext2")).unwrap();
+    block_on(fs::mount_device("/ext2", disk)).expect("mount failed");
+    assert!(fs::mounts()
+        .iter()
+        .any(|(path, name)| path == "/ext2" && name == "ext2"));
+
+    assert_eq!(
+        block_on(fs::read_file("/ext2/dir/../hello.txt")).unwrap(),
+        b"Hello, ext2!\n"
+    );
+    assert_eq!(
+        block_on(fs::stat("/ext2/hello.txt/x")),
+        Err(FsError::NotADirectory)
+    );
+    assert_eq!(block_on(fs::stat("/ext2/missing")), Err(FsError::NotFound));
+    assert_eq!(
+        block_on(fs::write_file("/ext2/hello.txt", b"x")),
+        Err(FsError::ReadOnly)
+    );
+    assert_eq!(
+        block_on(fs::create_dir("/ext2/new")),
+        Err(FsError::ReadOnly)
+    );
+    assert_eq!(
+        block_on(fs::remove("/ext2/hello.txt")),
+        Err(FsError::ReadOnly)
+    );
+    block_on(fs::unmount("/ext2")).expect("unmount failed");
+}
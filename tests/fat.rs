@@ -0,0 +1,228 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use my_os::block::ramdisk::RamDisk;
+use my_os::block::BlockDevice;
+use my_os::fs::fat::{self, FatFs};
+use my_os::fs::{self, FileSystem, FileType, FsError, Inode};
+use my_os::memory::BootInfoFrameAllocator;
+use my_os::task::simple_executor::block_on;
+use my_os::{allocator, memory};
+use x86_64::VirtAddr;
+
+const BLOCK_SIZE: usize = 512;
+// ヒープが小さいので32KiBのディスクを使う(クラスタは512バイトで30個)
+const BLOCK_COUNT: u64 = 64;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    fs::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
+
+fn formatted_disk() -> Arc<RamDisk> {
+    let disk = Arc::new(RamDisk::new("ram0", BLOCK_SIZE, BLOCK_COUNT));
+    block_on(fat::format(&*disk, "test")).expect("format failed");
+    disk
+}
+
+fn names(dir: &dyn Inode) -> Vec<String> {
+    block_on(dir.readdir())
+        .expect("readdir failed")
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+fn read_all(inode: &dyn Inode) -> Vec<u8> {
+    let mut data = vec![0; inode.metadata().size as usize];
+    let n = block_on(inode.read_at(0, &mut data)).expect("read failed");
+    data.truncate(n);
+    data
+}
+
+// 位置ごとに違う値のデータ
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[test_case]
+fn format_and_open() {
+    let disk = formatted_disk();
+    let fat = block_on(FatFs::new(disk)).expect("open failed");
+    assert_eq!(fat.label(), "TEST");
+    assert_eq!(fat.cluster_size(), BLOCK_SIZE);
+    // ルートディレクトリに1つ使っている
+    assert_eq!(block_on(fat.free_clusters()), Ok(29));
+    assert!(names(&*fat.root()).is_empty());
+}
+
+#[test_case]
+fn rejects_other_devices() {
+    let disk = Arc::new(RamDisk::new("ram0", BLOCK_SIZE, BLOCK_COUNT));
+    assert!(matches!(
+        block_on(FatFs::new(disk)),
+        Err(FsError::Unsupported)
+    ));
+}
+
+#[test_case]
+fn short_and_long_names() {
+    let fat = block_on(FatFs::new(formatted_disk())).unwrap();
+    let root = fat.root();
+    let short = block_on(root.create("README.TXT", FileType::File)).unwrap();
+    let long = block_on(root.create("A long file name.text", FileType::File)).unwrap();
+    block_on(short.write_at(0, b"short")).unwrap();
+    block_on(long.write_at(0, b"long")).unwrap();
+
+    assert_eq!(names(&*root), ["README.TXT", "A long file name.text"]);
+    // 大文字と小文字を区別せず、生成された短い名前でも引ける
+    let found = block_on(root.lookup("readme.txt")).unwrap();
+    assert_eq!(read_all(&*found), b"short");
+    let found = block_on(root.lookup("ALONGF~1.TEX")).unwrap();
+    assert_eq!(read_all(&*found), b"long");
+    assert_eq!(found.metadata().ino, long.metadata().ino);
+
+    assert!(matches!(
+        block_on(root.create("readme.txt", FileType::File)),
+        Err(FsError::AlreadyExists)
+    ));
+    assert!(matches!(
+        block_on(root.create("a:b", FileType::File)),
+        Err(FsError::InvalidPath)
+    ));
+}
+
+#[test_case]
+fn files_span_clusters() {
+    let fat = block_on(FatFs::new(formatted_disk())).unwrap();
+    let root = fat.root();
+    let file = block_on(root.create("data.bin", FileType::File)).unwrap();
+    let data = pattern(BLOCK_SIZE * 3 + 100);
+    assert_eq!(block_on(file.write_at(0, &data)), Ok(data.len()));
+    assert_eq!(block_on(fat.free_clusters()), Ok(25));
+
+    // クラスタをまたいで途中から読む
+    let mut buf = [0; 200];
+    assert_eq!(block_on(file.read_at(500, &mut buf)), Ok(200));
+    assert_eq!(&buf[..], &data[500..700]);
+    assert_eq!(block_on(file.read_at(data.len() as u64, &mut buf)), Ok(0));
+
+    // 終わりより後ろに書くと、間は0で埋められる
+    block_on(file.write_at(2200, b"end")).unwrap();
+    let all = read_all(&*file);
+    assert_eq!(all.len(), 2203);
+    assert!(all[data.len()..2200].iter().all(|&b| b == 0));
+
+    block_on(file.truncate(10)).unwrap();
+    assert_eq!(read_all(&*file), &data[..10]);
+    assert_eq!(block_on(fat.free_clusters()), Ok(28));
+    block_on(file.truncate(0)).unwrap();
+    assert_eq!(block_on(fat.free_clusters()), Ok(29));
+}
+
+#[test_case]
+fn directories() {
+    let fat = block_on(FatFs::new(formatted_disk())).unwrap();
+    let root = fat.root();
+    let dir = block_on(root.create("Documents", FileType::Directory)).unwrap();
+    let sub = block_on(dir.create("sub", FileType::Directory)).unwrap();
+    block_on(sub.create("note.txt", FileType::File)).unwrap();
+    assert_eq!(names(&*dir), ["sub"]);
+    assert_eq!(dir.metadata().file_type, FileType::Directory);
+
+    assert!(matches!(
+        block_on(dir.unlink("sub")),
+        Err(FsError::DirectoryNotEmpty)
+    ));
+    block_on(sub.unlink("note.txt")).unwrap();
+    block_on(dir.unlink("sub")).unwrap();
+    block_on(root.unlink("Documents")).unwrap();
+    assert!(names(&*root).is_empty());
+    assert_eq!(block_on(fat.free_clusters()), Ok(29));
+}
+
+#[test_case]
+fn directory_grows() {
+    let fat = block_on(FatFs::new(formatted_disk())).unwrap();
+    let root = fat.root();
+    // 長い名前は3エントリ使うので、512バイトのクラスタには5つしか入らない
+    for i in 0..12 {
+        let name = format!("long file name {:02}", i);
+        block_on(root.create(&name, FileType::File)).unwrap();
+    }
+    let listed = names(&*root);
+    assert_eq!(listed.len(), 12);
+    assert_eq!(listed[11], "long file name 11");
+    assert_eq!(block_on(fat.free_clusters()), Ok(27));
+
+    // 削除したエントリの場所は再利用される
+    block_on(root.unlink("long file name 03")).unwrap();
+    block_on(root.create("reused", FileType::File)).unwrap();
+    assert_eq!(names(&*root)[3], "reused");
+}
+
+#[test_case]
+fn contents_survive_remount() {
+    let disk = formatted_disk();
+    {
+        let fat = block_on(FatFs::new(disk.clone())).unwrap();
+        let dir = block_on(fat.root().create("dir", FileType::Directory)).unwrap();
+        let file = block_on(dir.create("file.txt", FileType::File)).unwrap();
+        block_on(file.write_at(0, b"persistent")).unwrap();
+        block_on(fat.sync()).unwrap();
+    }
+    let fat = block_on(FatFs::new(disk)).unwrap();
+    let dir = block_on(fat.root().lookup("dir")).unwrap();
+    let file = block_on(dir.lookup("file.txt")).unwrap();
+    assert_eq!(read_all(&*file), b"persistent");
+    assert_eq!(block_on(fat.free_clusters()), Ok(27));
+}
+
+#[test_case]
+fn mount_through_vfs() {
+    let disk: Arc<dyn BlockDevice> = formatted_disk();
+    block_on(fs::create_dir("/fat")).unwrap();
+    block_on(fs::mount_device("/fat", disk)).expect("mount failed");
+    assert!(fs::mounts()
+        .iter()
+        .any(|(path, name)| path == "/fat" && name == "fat32"));
+
+    block_on(fs::create_dir("/fat/dir")).unwrap();
+    block_on(fs::write_file("/fat/dir/hello.txt", b"hello")).unwrap();
+    assert_eq!(
+        block_on(fs::read_file("/fat/DIR/HELLO.TXT")).unwrap(),
+        b"hello"
+    );
+    block_on(fs::remove("/fat/dir/hello.txt")).unwrap();
+    assert_eq!(
+        block_on(fs::stat("/fat/dir/hello.txt")),
+        Err(FsError::NotFound)
+    );
+    block_on(fs::unmount("/fat")).expect("unmount failed");
+}
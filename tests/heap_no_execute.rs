@@ -0,0 +1,73 @@
+#![feature(abi_x86_interrupt)]
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use my_os::memory::BootInfoFrameAllocator;
+use my_os::{allocator, exit_qemu, memory, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("heap_no_execute::execute_from_heap...\t");
+
+    // GDT設定して
+    my_os::gdt::init();
+    // (テスト用の)IDT設定して
+    init_test_idt();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    // ヒープ上に`ret`命令を置いて呼び出す
+    let code = Box::new(0xc3u8);
+    let function: extern "C" fn() = unsafe { core::mem::transmute(&*code as *const u8) };
+    function();
+
+    // ここまで行き着いたらダメ
+    panic!("Execution continued after executing from the heap");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
+
+// テスト用のIDT
+// 命令フェッチによるページフォルトが起きたらqemu_exitするため
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+// 実行不可のページを実行しようとしたフォルトなら正常終了
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: unexpected page fault: {:?}", error_code);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
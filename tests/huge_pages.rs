@@ -0,0 +1,249 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use my_os::memory::huge::{map_physical_range, map_range, supports_1gib_pages};
+use my_os::memory::walk;
+use my_os::memory::{BootInfoFrameAllocator, FRAME_ALLOCATOR};
+use my_os::pci::{self, Bar, DeviceId};
+use my_os::{allocator, memory};
+use x86_64::structures::paging::{
+    FrameAllocator, PageSize, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// テストでマップする範囲(ヒープやMMIOの領域、カーネルとはレベル4のエントリが被らず、1GiBにアラインされたアドレス)
+// テストごとに1GiBずつずらして使う
+const TEST_AREA_START: u64 = 0x_7777_0000_0000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    pci::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
+
+fn test_area(index: u64) -> VirtAddr {
+    VirtAddr::new(TEST_AREA_START + index * Size1GiB::SIZE)
+}
+
+fn flags() -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+}
+
+// アドレスを含むページの大きさ
+fn page_size(addr: VirtAddr) -> u64 {
+    walk::translate(addr)
+        .expect("アドレスがマップされていません")
+        .mapping
+        .size
+}
+
+// 物理アドレスが決まっている範囲は、アラインされている部分だけ2MiBページになる
+#[test_case]
+fn physical_range_uses_2mib_pages() {
+    let start = test_area(0);
+    let phys = PhysAddr::new(0);
+    let size = 2 * Size2MiB::SIZE + Size4KiB::SIZE;
+    {
+        let mut guard = FRAME_ALLOCATOR.lock();
+        let frame_allocator = guard.as_mut().expect("frame allocator not installed");
+        let mut mapper = unsafe { memory::active_mapper() };
+        map_physical_range(&mut mapper, frame_allocator, start, phys, size, flags())
+            .expect("map_physical_range failed");
+    }
+
+    assert_eq!(page_size(start), Size2MiB::SIZE);
+    assert_eq!(page_size(start + Size2MiB::SIZE), Size2MiB::SIZE);
+    assert_eq!(page_size(start + 2 * Size2MiB::SIZE), Size4KiB::SIZE);
+    // 2MiBページの中の4KiBごとに、対応する物理アドレスに変換される
+    let addr = start + Size2MiB::SIZE + 0x1234u64;
+    let translation = walk::translate(addr).unwrap();
+    assert_eq!(translation.phys, phys + Size2MiB::SIZE + 0x1234u64);
+
+    // 物理メモリのオフセットマッピングと同じ内容が見える
+    let through_offset = memory::phys_to_virt(translation.phys).as_ptr::<u64>();
+    assert_eq!(unsafe { addr.as_ptr::<u64>().read_volatile() }, unsafe {
+        through_offset.read_volatile()
+    });
+}
+
+// 物理アドレスのアラインメントが合わなければ4KiBページになる
+#[test_case]
+fn misaligned_physical_range_uses_4kib_pages() {
+    let start = test_area(1);
+    let phys = PhysAddr::new(Size4KiB::SIZE);
+    {
+        let mut guard = FRAME_ALLOCATOR.lock();
+        let frame_allocator = guard.as_mut().expect("frame allocator not installed");
+        let mut mapper = unsafe { memory::active_mapper() };
+        map_physical_range(
+            &mut mapper,
+            frame_allocator,
+            start,
+            phys,
+            Size2MiB::SIZE,
+            flags(),
+        )
+        .expect("map_physical_range failed");
+    }
+
+    assert_eq!(page_size(start), Size4KiB::SIZE);
+    assert_eq!(page_size(start + Size2MiB::SIZE - 1u64), Size4KiB::SIZE);
+    assert_eq!(
+        walk::translate(start).unwrap().phys,
+        PhysAddr::new(Size4KiB::SIZE)
+    );
+}
+
+// 1GiBページはCPUが対応しているときだけ使う
+#[test_case]
+fn physical_range_uses_1gib_page_if_supported() {
+    let start = test_area(2);
+    {
+        let mut guard = FRAME_ALLOCATOR.lock();
+        let frame_allocator = guard.as_mut().expect("frame allocator not installed");
+        let mut mapper = unsafe { memory::active_mapper() };
+        map_physical_range(
+            &mut mapper,
+            frame_allocator,
+            start,
+            PhysAddr::new(0),
+            Size1GiB::SIZE,
+            flags(),
+        )
+        .expect("map_physical_range failed");
+    }
+
+    let expected = if supports_1gib_pages() {
+        Size1GiB::SIZE
+    } else {
+        Size2MiB::SIZE
+    };
+    assert_eq!(page_size(start), expected);
+    assert_eq!(page_size(start + Size1GiB::SIZE - 1u64), expected);
+}
+
+// 新しく確保するフレームでも、アラインされた部分は2MiBページでマップして使える
+#[test_case]
+fn map_range_uses_2mib_pages() {
+    let start = test_area(3);
+    let size = Size2MiB::SIZE + Size4KiB::SIZE;
+    {
+        let mut guard = FRAME_ALLOCATOR.lock();
+        let frame_allocator = guard.as_mut().expect("frame allocator not installed");
+        let mut mapper = unsafe { memory::active_mapper() };
+        map_range(&mut mapper, frame_allocator, start, size, flags()).expect("map_range failed");
+    }
+
+    assert_eq!(page_size(start), Size2MiB::SIZE);
+    assert_eq!(page_size(start + Size2MiB::SIZE), Size4KiB::SIZE);
+    let mapping = walk::translate(start).unwrap().mapping;
+    assert!(mapping.phys.is_aligned(Size2MiB::SIZE));
+
+    // 範囲の全体に書き込んで読み戻せる
+    let ptr = start.as_mut_ptr::<u64>();
+    let words = (size / 8) as usize;
+    for i in (0..words).step_by(512) {
+        unsafe { ptr.add(i).write_volatile(i as u64) };
+    }
+    for i in (0..words).step_by(512) {
+        assert_eq!(unsafe { ptr.add(i).read_volatile() }, i as u64);
+    }
+}
+
+// 先頭がアラインされていなければ、2MiBの境界までは4KiBページになる
+#[test_case]
+fn map_range_misaligned_start_uses_4kib_pages() {
+    let start = test_area(4) + Size4KiB::SIZE;
+    {
+        let mut guard = FRAME_ALLOCATOR.lock();
+        let frame_allocator = guard.as_mut().expect("frame allocator not installed");
+        let mut mapper = unsafe { memory::active_mapper() };
+        map_range(&mut mapper, frame_allocator, start, Size2MiB::SIZE, flags())
+            .expect("map_range failed");
+    }
+
+    assert_eq!(page_size(start), Size4KiB::SIZE);
+    assert_eq!(page_size(start + Size2MiB::SIZE - 1u64), Size4KiB::SIZE);
+}
+
+// 連続確保はアラインされ、前の確保や単体のフレームと重ならない
+#[test_case]
+fn allocate_contiguous_is_aligned_and_disjoint() {
+    const COUNT: usize = 16;
+    const ALIGN: u64 = 64 * 1024;
+    let size = COUNT as u64 * Size4KiB::SIZE;
+
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().expect("frame allocator not installed");
+    let first = frame_allocator
+        .allocate_contiguous(COUNT, ALIGN)
+        .expect("allocate_contiguous failed")
+        .start_address();
+    let second = frame_allocator
+        .allocate_contiguous(COUNT, ALIGN)
+        .expect("allocate_contiguous failed")
+        .start_address();
+    assert!(first.is_aligned(ALIGN));
+    assert!(second.is_aligned(ALIGN));
+    assert!(second + size <= first);
+
+    // 単体のフレームは連続確保した範囲から返されない
+    for _ in 0..64 {
+        let frame: PhysFrame = frame_allocator
+            .allocate_frame()
+            .expect("frame allocation failed");
+        let addr = frame.start_address();
+        assert!(addr < second || addr >= first + size);
+    }
+
+    // 確保した範囲は物理的に連続していて、オフセットマッピングを通して全体を使える
+    let ptr = memory::phys_to_virt(second).as_mut_ptr::<u8>();
+    for i in (0..size as usize).step_by(Size4KiB::SIZE as usize) {
+        unsafe { ptr.add(i).write_volatile(0xa5) };
+    }
+    for i in (0..size as usize).step_by(Size4KiB::SIZE as usize) {
+        assert_eq!(unsafe { ptr.add(i).read_volatile() }, 0xa5);
+    }
+}
+
+// QEMUの標準VGAのフレームバッファ(BAR0、16MiB)は、map_mmioで2MiBページでマップされる
+#[test_case]
+fn framebuffer_uses_2mib_pages() {
+    let vga = pci::find_device(DeviceId::class(0x03, 0x00)).expect("VGAが見つかりません");
+    let (address, size) = match vga.bars[0] {
+        Some(Bar::Memory { address, size, .. }) => (PhysAddr::new(address), size),
+        other => panic!("BAR0がメモリではありません: {:?}", other),
+    };
+    assert!(size >= Size2MiB::SIZE && address.is_aligned(Size2MiB::SIZE));
+
+    let framebuffer =
+        memory::map_mmio(address, size as usize).expect("フレームバッファをマップできません");
+    let translation = walk::translate(framebuffer.base()).unwrap();
+    assert!(translation.mapping.size >= Size2MiB::SIZE);
+    assert_eq!(translation.phys, address);
+    assert_eq!(
+        page_size(framebuffer.base() + (size - 1)),
+        translation.mapping.size
+    );
+}
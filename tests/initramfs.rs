@@ -0,0 +1,161 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use my_os::fs::initramfs::{self, EntryKind, Format, Summary};
+use my_os::fs::{self, FsError};
+use my_os::memory::BootInfoFrameAllocator;
+use my_os::task::simple_executor::block_on;
+use my_os::{allocator, memory};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    fs::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
+
+// newc形式のエントリを追加する
+fn cpio_entry(archive: &mut Vec<u8>, mode: u32, name: &str, data: &[u8]) {
+    let fields = [1, mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0];
+    archive.extend_from_slice(b"070701");
+    for field in &fields {
+        archive.extend_from_slice(format!("{:08x}", field).as_bytes());
+    }
+    archive.extend_from_slice(format!("{:08x}{:08x}", name.len() + 1, 0).as_bytes());
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    pad(archive, 4);
+    archive.extend_from_slice(data);
+    pad(archive, 4);
+}
+
+// ustarのエントリを追加する
+fn tar_entry(archive: &mut Vec<u8>, kind: u8, name: &str, data: &[u8]) {
+    let mut header = vec![0; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..107].copy_from_slice(b"0000644");
+    header[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[148..156].fill(b' ');
+    let sum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+    header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    pad(archive, 512);
+}
+
+fn pad(archive: &mut Vec<u8>, align: usize) {
+    while archive.len() % align != 0 {
+        archive.push(0);
+    }
+}
+
+#[test_case]
+fn unpack_cpio() {
+    let mut archive = Vec::new();
+    cpio_entry(&mut archive, 0o040755, ".", &[]);
+    cpio_entry(&mut archive, 0o040755, "bin", &[]);
+    cpio_entry(&mut archive, 0o100755, "bin/init", b"\x7fELF");
+    // 親ディレクトリのエントリがなくても作る
+    cpio_entry(&mut archive, 0o100644, "etc/conf/a.txt", b"abc");
+    cpio_entry(&mut archive, 0o120777, "link", b"bin/init");
+    cpio_entry(&mut archive, 0, "TRAILER!!!", &[]);
+    assert_eq!(initramfs::detect(&archive), Some(Format::Cpio));
+
+    block_on(fs::create_dir("/cpio")).unwrap();
+    let summary = block_on(initramfs::unpack(&archive, "/cpio")).expect("unpack failed");
+    assert_eq!(
+        summary,
+        Summary {
+            files: 2,
+            directories: 1,
+            skipped: 1,
+        }
+    );
+    assert_eq!(
+        block_on(fs::read_file("/cpio/bin/init")).unwrap(),
+        b"\x7fELF"
+    );
+    assert_eq!(
+        block_on(fs::read_file("/cpio/etc/conf/a.txt")).unwrap(),
+        b"abc"
+    );
+    assert_eq!(block_on(fs::stat("/cpio/link")), Err(FsError::NotFound));
+}
+
+#[test_case]
+fn unpack_ustar() {
+    let mut archive = Vec::new();
+    tar_entry(&mut archive, b'5', "./docs/", &[]);
+    tar_entry(&mut archive, b'0', "./docs/readme", b"read me");
+    tar_entry(&mut archive, b'0', "./empty", &[]);
+    archive.extend_from_slice(&[0; 1024]);
+    assert_eq!(initramfs::detect(&archive), Some(Format::Ustar));
+
+    let entries = initramfs::entries(&archive).expect("parse failed");
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0].kind, EntryKind::Directory);
+    assert_eq!(entries[1].path, "./docs/readme");
+
+    block_on(fs::create_dir("/tar")).unwrap();
+    let summary = block_on(initramfs::unpack(&archive, "/tar")).expect("unpack failed");
+    assert_eq!(summary.files, 2);
+    assert_eq!(
+        block_on(fs::read_file("/tar/docs/readme")).unwrap(),
+        b"read me"
+    );
+    assert_eq!(block_on(fs::stat("/tar/empty")).unwrap().size, 0);
+}
+
+#[test_case]
+fn rejects_corrupted_archives() {
+    let mut archive = Vec::new();
+    tar_entry(&mut archive, b'0', "file", b"data");
+    archive[0] = b'x';
+    assert_eq!(initramfs::entries(&archive), Err(FsError::Corrupted));
+
+    // 途中で切れているcpio
+    let mut archive = Vec::new();
+    cpio_entry(&mut archive, 0o100644, "file", b"data");
+    archive.truncate(archive.len() - 4);
+    assert_eq!(initramfs::entries(&archive), Err(FsError::Corrupted));
+
+    assert_eq!(
+        initramfs::entries(b"not an archive"),
+        Err(FsError::Corrupted)
+    );
+    assert_eq!(initramfs::entries(&[]), Ok(Vec::new()));
+}
+
+#[test_case]
+fn embedded_archive() {
+    assert_eq!(initramfs::detect(initramfs::ARCHIVE), Some(Format::Cpio));
+    block_on(initramfs::unpack(initramfs::ARCHIVE, "/")).expect("unpack failed");
+    let motd = block_on(fs::read_file("/etc/motd")).expect("motd not found");
+    assert!(motd.starts_with(b"Welcome"));
+}
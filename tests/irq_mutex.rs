@@ -0,0 +1,62 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use my_os::irq_mutex::IrqSafeMutex;
+use x86_64::instructions::interrupts;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    my_os::init();
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
+
+static OUTER: IrqSafeMutex<u32> = IrqSafeMutex::new("OUTER", 0);
+static INNER: IrqSafeMutex<u32> = IrqSafeMutex::new("INNER", 0);
+
+// ロックしている間だけ割り込みが止まる
+#[test_case]
+fn interrupts_disabled_while_held() {
+    assert!(interrupts::are_enabled());
+    {
+        let mut value = OUTER.lock();
+        *value += 1;
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*OUTER.lock(), 1);
+}
+
+// 内側のロックを離しても、外側のロックを持っている間は割り込みが止まったまま
+#[test_case]
+fn nested_locks_restore_state() {
+    let outer = OUTER.lock();
+    {
+        let _inner = INNER.lock();
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(!interrupts::are_enabled());
+    drop(outer);
+    assert!(interrupts::are_enabled());
+}
+
+// 取れなかったときも割り込みの状態は戻る
+#[test_case]
+fn try_lock_restores_state() {
+    let held = INNER.lock();
+    assert!(INNER.try_lock().is_none());
+    drop(held);
+    assert!(interrupts::are_enabled());
+    assert!(INNER.try_lock().is_some());
+    assert!(interrupts::are_enabled());
+}
@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use my_os::memory::walk::{self, Mapping};
+use my_os::memory::BootInfoFrameAllocator;
+use my_os::{allocator, memory};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+static READ_ONLY: [u8; 4] = [1, 2, 3, 4];
+static mut WRITABLE: [u8; 4] = [0; 4];
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::protect::harden_kernel(&mut mapper).expect("kernel hardening failed");
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+fn mapping_of<T>(ptr: *const T) -> Mapping {
+    walk::translate(VirtAddr::from_ptr(ptr))
+        .expect("アドレスがマップされていません")
+        .mapping
+}
+
+// 以下test case
+#[test_case]
+// .textは読み込み専用で実行可能
+fn text_is_read_only_and_executable() {
+    let flags = mapping_of(main as *const ()).flags;
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+// .rodataは読み込み専用で実行不可
+fn rodata_is_read_only_and_no_execute() {
+    let flags = mapping_of(&READ_ONLY).flags;
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+// .dataと.bssは書き込み可能で実行不可
+fn data_is_writable_and_no_execute() {
+    let flags = mapping_of(unsafe { &WRITABLE }).flags;
+    assert!(flags.contains(PageTableFlags::WRITABLE));
+    assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+// ヒープとスタックは実行不可
+fn heap_and_stack_are_no_execute() {
+    let heap_value = Box::new(0u64);
+    let stack_value = 0u64;
+    assert!(mapping_of(&*heap_value)
+        .flags
+        .contains(PageTableFlags::NO_EXECUTE));
+    assert!(mapping_of(&stack_value)
+        .flags
+        .contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
@@ -0,0 +1,127 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use futures_util::StreamExt;
+use my_os::memory::BootInfoFrameAllocator;
+use my_os::task::keyboard::{
+    self, KeyDecoder, KeyEvent, KeyEventStream, KeyboardConfig, Modifiers, Subscription,
+};
+use my_os::task::queue::{OverflowPolicy, QueueConfig};
+use my_os::task::simple_executor::block_on;
+use my_os::{allocator, memory};
+use pc_keyboard::{DecodedKey, KeyCode, KeyState};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    keyboard::set_config(KeyboardConfig::new());
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
+
+fn key_down(code: KeyCode, character: char) -> KeyEvent {
+    KeyEvent {
+        code,
+        state: KeyState::Down,
+        key: Some(DecodedKey::Unicode(character)),
+        modifiers: Modifiers::new(),
+    }
+}
+
+fn received(stream: &mut KeyEventStream) -> Option<DecodedKey> {
+    stream.try_next().and_then(|event| event.key)
+}
+
+#[test_case]
+fn events_carry_modifiers() {
+    let mut decoder = KeyDecoder::new();
+    // スキャンコードセット1: 左Shift、A、右Ctrl(E0 1D)
+    let shift = decoder.decode_event(0x2a).unwrap();
+    assert_eq!(shift.code, KeyCode::ShiftLeft);
+    assert_eq!(shift.key, None);
+    assert!(shift.modifiers.shift());
+
+    let a = decoder.decode_event(0x1e).unwrap();
+    assert_eq!(a.key, Some(DecodedKey::Unicode('A')));
+    assert!(a.modifiers.lshift && !a.modifiers.rshift);
+
+    // 離したときは文字にならない
+    let release = decoder.decode_event(0x9e).unwrap();
+    assert_eq!(release.state, KeyState::Up);
+    assert_eq!(release.key, None);
+
+    assert_eq!(decoder.decode_event(0xe0), None);
+    assert!(decoder.decode_event(0x1d).unwrap().modifiers.rctrl);
+    decoder.decode_event(0xaa);
+    decoder.decode_event(0xe0);
+    let modifiers = decoder.decode_event(0x9d).unwrap().modifiers;
+    assert!(!modifiers.shift() && !modifiers.ctrl());
+    assert!(modifiers.num_lock);
+}
+
+#[test_case]
+fn focus_and_monitors() {
+    let mut shell = KeyEventStream::subscribe(Subscription::Focused);
+    let mut monitor = KeyEventStream::subscribe(Subscription::All);
+    assert!(shell.has_focus());
+    keyboard::dispatch(key_down(KeyCode::A, 'a'));
+    assert_eq!(received(&mut shell), Some(DecodedKey::Unicode('a')));
+    assert_eq!(received(&mut monitor), Some(DecodedKey::Unicode('a')));
+
+    // 後から購読したストリームがフォーカスを取る
+    let mut game = KeyEventStream::subscribe(Subscription::Focused);
+    assert!(game.has_focus() && !shell.has_focus());
+    keyboard::dispatch(key_down(KeyCode::B, 'b'));
+    assert_eq!(received(&mut game), Some(DecodedKey::Unicode('b')));
+    assert_eq!(received(&mut shell), None);
+    assert_eq!(received(&mut monitor), Some(DecodedKey::Unicode('b')));
+
+    shell.focus();
+    keyboard::dispatch(key_down(KeyCode::C, 'c'));
+    assert_eq!(received(&mut shell), Some(DecodedKey::Unicode('c')));
+    assert_eq!(received(&mut game), None);
+
+    // フォーカスを持っていたストリームがなくなると、前のストリームに戻る
+    drop(shell);
+    assert!(game.has_focus());
+    keyboard::dispatch(key_down(KeyCode::D, 'd'));
+    let event = block_on(game.next()).unwrap();
+    assert_eq!(event.key, Some(DecodedKey::Unicode('d')));
+    assert_eq!(received(&mut monitor), Some(DecodedKey::Unicode('c')));
+    assert_eq!(received(&mut monitor), Some(DecodedKey::Unicode('d')));
+}
+
+// 読むのが遅れた購読者のキューは古いイベントから捨て、捨てた数を数える
+#[test_case]
+fn slow_subscriber_drops_oldest() {
+    let mut slow = KeyEventStream::subscribe_with_queue(
+        Subscription::All,
+        QueueConfig::new(2, OverflowPolicy::DropOldest),
+    );
+    keyboard::dispatch(key_down(KeyCode::A, 'a'));
+    keyboard::dispatch(key_down(KeyCode::B, 'b'));
+    keyboard::dispatch(key_down(KeyCode::C, 'c'));
+    assert_eq!(slow.stats().dropped, 1);
+    assert_eq!(received(&mut slow), Some(DecodedKey::Unicode('b')));
+    assert_eq!(received(&mut slow), Some(DecodedKey::Unicode('c')));
+    assert_eq!(received(&mut slow), None);
+}
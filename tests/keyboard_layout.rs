@@ -0,0 +1,137 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use my_os::ps2::keyboard::Leds;
+use my_os::task::keyboard::{self, InvalidOption, KeyDecoder, KeyboardConfig, Layout, Scancodes};
+use pc_keyboard::DecodedKey;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
+
+// スキャンコードセット1
+const LEFT_SHIFT: u8 = 0x2a;
+const LEFT_CTRL: u8 = 0x1d;
+const KEY_2: u8 = 0x03;
+const KEY_Q: u8 = 0x10;
+const KEY_C: u8 = 0x2e;
+const KEY_A: u8 = 0x1e;
+const CAPS_LOCK: u8 = 0x3a;
+const RELEASE: u8 = 0x80;
+
+fn configure(layout: Layout, scancodes: Scancodes, map_ctrl: bool) {
+    keyboard::set_config(KeyboardConfig {
+        layout,
+        scancodes,
+        map_ctrl,
+        ..KeyboardConfig::new()
+    });
+}
+
+// スキャンコードを順に渡して、確定した最後の文字を返す
+fn decode(decoder: &mut KeyDecoder, scancodes: &[u8]) -> Option<char> {
+    let mut last = None;
+    for &scancode in scancodes {
+        if let Some(DecodedKey::Unicode(character)) = decoder.decode(scancode) {
+            last = Some(character);
+        }
+    }
+    last
+}
+
+// Shiftを押しながら`key`を押して、両方離す
+fn shifted(decoder: &mut KeyDecoder, key: u8) -> Option<char> {
+    decode(
+        decoder,
+        &[LEFT_SHIFT, key, key | RELEASE, LEFT_SHIFT | RELEASE],
+    )
+}
+
+#[test_case]
+fn layouts() {
+    configure(Layout::Us104, Scancodes::Set1, true);
+    let mut decoder = KeyDecoder::new();
+    assert_eq!(decode(&mut decoder, &[KEY_Q, KEY_Q | RELEASE]), Some('q'));
+    assert_eq!(shifted(&mut decoder, KEY_2), Some('@'));
+
+    configure(Layout::Uk105, Scancodes::Set1, true);
+    assert_eq!(shifted(&mut decoder, KEY_2), Some('"'));
+
+    configure(Layout::Jis109, Scancodes::Set1, true);
+    assert_eq!(shifted(&mut decoder, KEY_2), Some('"'));
+
+    // Dvorakでは、QWERTYのQの位置が'になる
+    configure(Layout::Dvorak104, Scancodes::Set1, true);
+    assert_eq!(decode(&mut decoder, &[KEY_Q, KEY_Q | RELEASE]), Some('\''));
+}
+
+#[test_case]
+fn control_characters() {
+    configure(Layout::Us104, Scancodes::Set1, true);
+    let mut decoder = KeyDecoder::new();
+    let ctrl_c = [LEFT_CTRL, KEY_C, KEY_C | RELEASE, LEFT_CTRL | RELEASE];
+    assert_eq!(decode(&mut decoder, &ctrl_c), Some('\u{3}'));
+
+    configure(Layout::Us104, Scancodes::Set1, false);
+    assert_eq!(decode(&mut decoder, &ctrl_c), Some('c'));
+}
+
+#[test_case]
+fn caps_lock() {
+    configure(Layout::Us104, Scancodes::Set1, true);
+    let mut decoder = KeyDecoder::new();
+    assert_eq!(decoder.leds(), Leds::NUM_LOCK);
+    decode(&mut decoder, &[CAPS_LOCK, CAPS_LOCK | RELEASE]);
+    assert_eq!(decoder.leds(), Leds::NUM_LOCK | Leds::CAPS_LOCK);
+    assert_eq!(decode(&mut decoder, &[KEY_A, KEY_A | RELEASE]), Some('A'));
+
+    // 設定が変わるとロックの状態も戻る
+    configure(Layout::Us104, Scancodes::Set1, false);
+    assert_eq!(decode(&mut decoder, &[KEY_A, KEY_A | RELEASE]), Some('a'));
+    assert_eq!(decoder.leds(), Leds::NUM_LOCK);
+}
+
+#[test_case]
+fn scancode_set_2() {
+    configure(Layout::Us104, Scancodes::Set2, true);
+    let mut decoder = KeyDecoder::new();
+    // セット2では離したときにF0が前に付く
+    assert_eq!(decode(&mut decoder, &[0x1c, 0xf0, 0x1c]), Some('a'));
+    assert_eq!(decode(&mut decoder, &[0x15]), Some('q'));
+}
+
+#[test_case]
+fn options_from_strings() {
+    let mut config = KeyboardConfig::new();
+    assert_eq!(config.set("layout", "jis"), Ok(()));
+    assert_eq!(config.set("set", "2"), Ok(()));
+    assert_eq!(config.set("ctrl", "ignore"), Ok(()));
+    assert_eq!(config.layout, Layout::Jis109);
+    assert_eq!(config.scancodes, Scancodes::Set2);
+    assert!(!config.map_ctrl);
+
+    assert_eq!(config.set("layout", "azerty"), Err(InvalidOption));
+    assert_eq!(config.set("set", "3"), Err(InvalidOption));
+    assert_eq!(config.set("delay", "750"), Ok(()));
+    assert_eq!(config.repeat_delay, 750);
+    assert_eq!(config.set("delay", "2000"), Err(InvalidOption));
+    assert_eq!(config.set("rate", "fast"), Err(InvalidOption));
+    assert_eq!(config.set("repeat", "on"), Err(InvalidOption));
+    assert_eq!(config.layout, Layout::Jis109);
+
+    for &layout in &Layout::ALL {
+        assert_eq!(Layout::from_name(layout.name()), Some(layout));
+    }
+}
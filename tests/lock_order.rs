@@ -0,0 +1,42 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use my_os::irq_mutex::IrqSafeMutex;
+use my_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+static FIRST: IrqSafeMutex<()> = IrqSafeMutex::new("FIRST", ());
+static SECOND: IrqSafeMutex<()> = IrqSafeMutex::new("SECOND", ());
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("lock_order::inverted_order...\t");
+    my_os::init();
+    // ロックの順番はデバッグビルドでしか調べない
+    if !cfg!(debug_assertions) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+        loop {}
+    }
+    inverted_order();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+// 一度FIRST→SECONDの順で取ったあとに逆の順で取ると、デバッグビルドではパニックする
+fn inverted_order() {
+    {
+        let _first = FIRST.lock();
+        let _second = SECOND.lock();
+    }
+    let _second = SECOND.lock();
+    let _first = FIRST.lock();
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
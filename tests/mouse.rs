@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use my_os::ps2::mouse::{MouseButtons, MouseEvent, MouseKind, PacketDecoder};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
+
+// パケットを渡して、最後に確定したイベントを返す
+fn feed(decoder: &mut PacketDecoder, bytes: &[u8]) -> Option<MouseEvent> {
+    let mut last = None;
+    for &byte in bytes {
+        if let Some(event) = decoder.add_byte(byte) {
+            last = Some(event);
+        }
+    }
+    last
+}
+
+#[test_case]
+fn standard_packets() {
+    let mut decoder = PacketDecoder::new(MouseKind::Standard);
+    // 左ボタンを押しながら右上へ
+    let event = feed(&mut decoder, &[0x09, 5, 3]).unwrap();
+    assert_eq!(
+        event,
+        MouseEvent {
+            dx: 5,
+            dy: -3,
+            wheel: 0,
+            buttons: MouseButtons::LEFT,
+        }
+    );
+    // 符号ビットが立っていれば負(yは画面の下向きが正)
+    let event = feed(&mut decoder, &[0x38, 0xfe, 0xf0]).unwrap();
+    assert_eq!((event.dx, event.dy), (-2, 16));
+    assert_eq!(event.buttons, MouseButtons::NONE);
+}
+
+#[test_case]
+fn resynchronizes_and_drops_overflow() {
+    let mut decoder = PacketDecoder::new(MouseKind::Standard);
+    // bit3が0のバイトはパケットの先頭にならない
+    assert_eq!(feed(&mut decoder, &[0x00, 0x01]), None);
+    assert_eq!(
+        feed(&mut decoder, &[0x0a, 1, 1]).unwrap().buttons,
+        MouseButtons::RIGHT
+    );
+    // あふれたパケットは捨てる
+    assert_eq!(feed(&mut decoder, &[0x48, 0xff, 0]), None);
+    assert!(feed(&mut decoder, &[0x08, 0, 0]).is_some());
+}
+
+#[test_case]
+fn wheel_and_extra_buttons() {
+    let mut decoder = PacketDecoder::new(MouseKind::Wheel);
+    assert_eq!(feed(&mut decoder, &[0x0c, 0, 0]), None);
+    let event = feed(&mut decoder, &[0x0f]).unwrap();
+    assert_eq!(event.wheel, -1);
+    assert_eq!(event.buttons, MouseButtons::MIDDLE);
+
+    let mut decoder = PacketDecoder::new(MouseKind::FiveButtons);
+    let event = feed(&mut decoder, &[0x08, 0, 0, 0x31]).unwrap();
+    assert_eq!(event.wheel, 1);
+    assert!(event
+        .buttons
+        .contains(MouseButtons::BUTTON4 | MouseButtons::BUTTON5));
+    assert!(!event.buttons.contains(MouseButtons::LEFT));
+}
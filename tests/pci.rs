@@ -0,0 +1,107 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use my_os::memory::BootInfoFrameAllocator;
+use my_os::pci::{self, DeviceId, PciDevice, PciDriver};
+use my_os::{allocator, memory};
+use x86_64::VirtAddr;
+
+// QEMUのどちらのマシンにもあるホストブリッジとISA(LPC)ブリッジ
+const HOST_BRIDGE: DeviceId = DeviceId::class(0x06, 0x00);
+const ISA_BRIDGE: DeviceId = DeviceId::class(0x06, 0x01);
+
+// probeされた回数を数えるだけのドライバ
+struct CountingDriver {
+    name: &'static str,
+    ids: &'static [DeviceId],
+    probed: AtomicUsize,
+}
+
+impl CountingDriver {
+    const fn new(name: &'static str, ids: &'static [DeviceId]) -> Self {
+        CountingDriver {
+            name,
+            ids,
+            probed: AtomicUsize::new(0),
+        }
+    }
+
+    fn probed(&self) -> usize {
+        self.probed.load(Ordering::Relaxed)
+    }
+}
+
+impl PciDriver for CountingDriver {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn id_table(&self) -> &'static [DeviceId] {
+        self.ids
+    }
+
+    fn probe(&self, _device: &PciDevice) -> Result<(), &'static str> {
+        self.probed.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+static EARLY: CountingDriver = CountingDriver::new("early", &[HOST_BRIDGE]);
+static LATE_DUPLICATE: CountingDriver = CountingDriver::new("late-duplicate", &[HOST_BRIDGE]);
+static LATE_UNBOUND: CountingDriver = CountingDriver::new("late-unbound", &[ISA_BRIDGE]);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    pci::register_driver(&EARLY);
+    pci::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn driver_bound_during_init() {
+    let bridge = pci::find_device(HOST_BRIDGE).expect("host bridge not found");
+    assert_eq!(EARLY.probed(), 1);
+    assert_eq!(pci::driver_name(bridge.address), Some("early"));
+}
+
+// initの後に登録したドライバは、ほかのドライバが使っているデバイスにはマッチングされない
+#[test_case]
+fn late_driver_skips_bound_devices() {
+    let bridge = pci::find_device(HOST_BRIDGE).expect("host bridge not found");
+    pci::register_driver(&LATE_DUPLICATE);
+    assert_eq!(LATE_DUPLICATE.probed(), 0);
+    assert_eq!(EARLY.probed(), 1);
+    assert_eq!(pci::driver_name(bridge.address), Some("early"));
+}
+
+// まだドライバのないデバイスには、initの後に登録したドライバもマッチングされる
+#[test_case]
+fn late_driver_binds_unbound_devices() {
+    let bridge = pci::find_device(ISA_BRIDGE).expect("ISA bridge not found");
+    assert_eq!(pci::driver_name(bridge.address), None);
+    pci::register_driver(&LATE_UNBOUND);
+    assert_eq!(LATE_UNBOUND.probed(), 1);
+    assert_eq!(pci::driver_name(bridge.address), Some("late-unbound"));
+}
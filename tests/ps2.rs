@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use my_os::ps2;
+use my_os::ps2::keyboard::Leds;
+use my_os::ps2::mouse::MouseKind;
+use my_os::task::keyboard::{self, KeyboardConfig, Scancodes};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    my_os::init();
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn typematic_byte() {
+    // 遅延はbit5-6、速度はbit0-4
+    assert_eq!(ps2::keyboard::typematic_byte(250, 30), 0x00);
+    assert_eq!(ps2::keyboard::typematic_byte(500, 10), 0x2c);
+    assert_eq!(ps2::keyboard::typematic_byte(500, 11), 0x2b);
+    assert_eq!(ps2::keyboard::typematic_byte(1000, 2), 0x7f);
+    // 範囲外は一番近い値にする
+    assert_eq!(ps2::keyboard::typematic_byte(0, 100), 0x00);
+    assert_eq!(ps2::keyboard::typematic_byte(5000, 0), 0x7f);
+}
+
+#[test_case]
+fn initialize_controller() {
+    assert_eq!(ps2::info(), None);
+    assert_eq!(
+        ps2::keyboard::set_leds(Leds::NONE),
+        Err(ps2::Ps2Error::NoDevice)
+    );
+
+    // QEMUの8042には両方のポートがある
+    let info = ps2::init().expect("PS/2 initialization failed");
+    assert!(info.dual_channel);
+    assert!(info.first_port);
+    assert!(info.second_port);
+    assert!(info.keyboard);
+    // QEMUのマウスはIntelliMouse Explorer互換
+    assert_eq!(info.mouse, Some(MouseKind::FiveButtons));
+    assert_eq!(ps2::info(), Some(info));
+}
+
+#[test_case]
+fn keyboard_commands() {
+    assert_eq!(
+        ps2::keyboard::set_leds(Leds::CAPS_LOCK | Leds::NUM_LOCK),
+        Ok(())
+    );
+    assert_eq!(ps2::keyboard::set_leds(Leds::NONE), Ok(()));
+    assert_eq!(ps2::keyboard::set_typematic(250, 30), Ok(()));
+
+    // 設定を変えると変換とリピートもコントローラに送られる
+    let config = KeyboardConfig {
+        scancodes: Scancodes::Set2,
+        ..KeyboardConfig::new()
+    };
+    assert_eq!(ps2::keyboard::apply(&config), Ok(()));
+    keyboard::set_config(KeyboardConfig::new());
+}
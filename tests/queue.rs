@@ -0,0 +1,101 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::task::{Context, Poll};
+use futures_util::task::noop_waker_ref;
+use my_os::memory::BootInfoFrameAllocator;
+use my_os::task::queue::{OverflowPolicy, Queue, QueueConfig};
+use my_os::{allocator, memory};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
+
+// 0から`count`-1までを順に入れる
+fn fill(queue: &Queue<u32>, count: u32) {
+    for i in 0..count {
+        let _ = queue.push(i);
+    }
+}
+
+fn drain(queue: &Queue<u32>) -> alloc::vec::Vec<u32> {
+    core::iter::from_fn(|| queue.pop()).collect()
+}
+
+#[test_case]
+fn drop_oldest() {
+    let queue = Queue::new(QueueConfig::new(4, OverflowPolicy::DropOldest));
+    fill(&queue, 10);
+    assert_eq!(drain(&queue), [6, 7, 8, 9]);
+    let stats = queue.stats();
+    assert_eq!(stats.dropped, 6);
+    assert_eq!(stats.high_water, 4);
+}
+
+#[test_case]
+fn drop_newest() {
+    let queue = Queue::new(QueueConfig::new(4, OverflowPolicy::DropNewest));
+    fill(&queue, 10);
+    assert_eq!(drain(&queue), [0, 1, 2, 3]);
+    let stats = queue.stats();
+    assert_eq!(stats.pushed, 4);
+    assert_eq!(stats.dropped, 6);
+}
+
+#[test_case]
+fn grow() {
+    let queue = Queue::new(QueueConfig::new(4, OverflowPolicy::Grow));
+    fill(&queue, 10);
+    assert_eq!(queue.len(), 10);
+    assert_eq!(queue.capacity(), 4);
+    // あふれた分を取り出している途中で入れても順番は変わらない
+    assert_eq!(queue.pop(), Some(0));
+    let _ = queue.push(10);
+    assert_eq!(drain(&queue), [1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+    let stats = queue.stats();
+    assert_eq!(stats.dropped, 0);
+    assert_eq!(stats.grown, 7);
+    assert_eq!(stats.high_water, 10);
+}
+
+#[test_case]
+fn backpressure() {
+    let queue = Queue::new(QueueConfig::new(2, OverflowPolicy::Backpressure));
+    assert_eq!(queue.push(1), Ok(()));
+    assert_eq!(queue.push(2), Ok(()));
+    assert_eq!(queue.push(3), Err(3));
+    assert_eq!(queue.stats().rejected, 1);
+
+    let mut context = Context::from_waker(noop_waker_ref());
+    let mut waiting = Box::pin(queue.push_wait(3));
+    assert_eq!(waiting.as_mut().poll(&mut context), Poll::Pending);
+    assert_eq!(queue.pop(), Some(1));
+    assert_eq!(waiting.as_mut().poll(&mut context), Poll::Ready(()));
+    drop(waiting);
+    assert_eq!(drain(&queue), [2, 3]);
+    assert_eq!(queue.stats().pushed, 3);
+}
@@ -0,0 +1,295 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use my_os::memory::BootInfoFrameAllocator;
+use my_os::task::executor::Executor;
+use my_os::task::simple_executor::block_on;
+use my_os::task::sync::{broadcast, mpsc, oneshot, Mutex, Notify, RwLock, Semaphore};
+use my_os::{allocator, memory};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
+
+// 1回だけexecutorに戻る
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+async fn yield_now() {
+    YieldNow(false).await
+}
+
+// 同時に入っているタスクの数と、その最大を数える
+#[derive(Default)]
+struct Concurrency {
+    current: AtomicUsize,
+    max: AtomicUsize,
+}
+
+impl Concurrency {
+    fn enter(&self) {
+        let current = self.current.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max.fetch_max(current, Ordering::Relaxed);
+    }
+
+    fn exit(&self) {
+        self.current.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[test_case]
+fn mutex_across_await() {
+    let mut executor = Executor::new();
+    let counter = Arc::new(Mutex::new(0));
+    let inside = Arc::new(Concurrency::default());
+    for _ in 0..4 {
+        let counter = counter.clone();
+        let inside = inside.clone();
+        executor.spawn(async move {
+            for _ in 0..5 {
+                let mut value = counter.lock().await;
+                inside.enter();
+                // ロックを持ったまま別のタスクに譲っても、ほかのタスクは入ってこない
+                let read = *value;
+                yield_now().await;
+                *value = read + 1;
+                inside.exit();
+            }
+        });
+    }
+    executor.run_until_stalled();
+    assert_eq!(*counter.try_lock().unwrap(), 20);
+    assert_eq!(inside.max.load(Ordering::Relaxed), 1);
+}
+
+#[test_case]
+fn semaphore_limits_concurrency() {
+    let mut executor = Executor::new();
+    let semaphore = Arc::new(Semaphore::new(2));
+    let inside = Arc::new(Concurrency::default());
+    for _ in 0..6 {
+        let semaphore = semaphore.clone();
+        let inside = inside.clone();
+        executor.spawn(async move {
+            let _permit = semaphore.acquire().await.unwrap();
+            inside.enter();
+            yield_now().await;
+            yield_now().await;
+            inside.exit();
+        });
+    }
+    executor.run_until_stalled();
+    assert_eq!(inside.max.load(Ordering::Relaxed), 2);
+    assert_eq!(semaphore.available_permits(), 2);
+
+    semaphore.close();
+    assert!(block_on(semaphore.acquire()).is_err());
+}
+
+#[test_case]
+fn semaphore_cancelled_waiter() {
+    let semaphore = Semaphore::new(1);
+    let held = semaphore.try_acquire().unwrap();
+    // 先頭で待っていた待ち手が捨てられたら、後ろの待ち手に許可が渡る
+    let mut first = semaphore.acquire_many(1);
+    let mut second = semaphore.acquire();
+    let waker = futures_util::task::noop_waker();
+    let mut context = Context::from_waker(&waker);
+    assert!(Pin::new(&mut first).poll(&mut context).is_pending());
+    assert!(Pin::new(&mut second).poll(&mut context).is_pending());
+    drop(held);
+    drop(first);
+    assert!(matches!(
+        Pin::new(&mut second).poll(&mut context),
+        Poll::Ready(Ok(_))
+    ));
+}
+
+#[test_case]
+fn rwlock_readers_and_writer() {
+    let mut executor = Executor::new();
+    let lock = Arc::new(RwLock::new(Vec::new()));
+    let readers = Arc::new(Concurrency::default());
+    for i in 0..3 {
+        let lock = lock.clone();
+        let readers = readers.clone();
+        executor.spawn(async move {
+            let value = lock.read().await;
+            readers.enter();
+            yield_now().await;
+            assert!(value.is_empty() || value.len() == 1);
+            readers.exit();
+            drop(value);
+            lock.write().await.push(i);
+        });
+    }
+    executor.run_until_stalled();
+    assert_eq!(readers.max.load(Ordering::Relaxed), 3);
+    let mut values = lock.try_read().unwrap().clone();
+    values.sort_unstable();
+    assert_eq!(values, [0, 1, 2]);
+    assert!(lock.try_write().is_some());
+}
+
+#[test_case]
+fn notify_one_and_waiters() {
+    let mut executor = Executor::new();
+    let notify = Arc::new(Notify::new());
+    let woken = Arc::new(AtomicUsize::new(0));
+    for _ in 0..3 {
+        let notify = notify.clone();
+        let woken = woken.clone();
+        executor.spawn(async move {
+            notify.notified().await;
+            woken.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+    executor.run_until_stalled();
+    assert_eq!(woken.load(Ordering::Relaxed), 0);
+
+    notify.notify_one();
+    executor.run_until_stalled();
+    assert_eq!(woken.load(Ordering::Relaxed), 1);
+
+    notify.notify_waiters();
+    executor.run_until_stalled();
+    assert_eq!(woken.load(Ordering::Relaxed), 3);
+
+    // 待ち手がいないときのnotify_oneは覚えておかれる
+    notify.notify_one();
+    block_on(notify.notified());
+}
+
+#[test_case]
+fn mpsc_backpressure() {
+    let mut executor = Executor::new();
+    let (sender, mut receiver) = mpsc::channel(2);
+    for id in 0..3 {
+        let sender = sender.clone();
+        executor.spawn(async move {
+            for i in 0..10 {
+                sender.send(id * 100 + i).await.unwrap();
+            }
+        });
+    }
+    drop(sender);
+    let consumer = executor.spawn(async move {
+        let mut received = Vec::new();
+        while let Some(value) = receiver.recv().await {
+            received.push(value);
+        }
+        received
+    });
+    executor.run_until_stalled();
+    let received = block_on(consumer).unwrap();
+    assert_eq!(received.len(), 30);
+    // 送り手ごとの順番は変わらない
+    for id in 0..3 {
+        let mine: Vec<_> = received.iter().filter(|&&v| v / 100 == id).collect();
+        assert!(mine.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    let (sender, receiver) = mpsc::channel(1);
+    assert_eq!(sender.try_send(1), Ok(()));
+    assert_eq!(sender.try_send(2), Err(mpsc::TrySendError::Full(2)));
+    drop(receiver);
+    assert_eq!(sender.try_send(3), Err(mpsc::TrySendError::Closed(3)));
+    assert!(block_on(sender.send(4)).is_err());
+}
+
+#[test_case]
+fn oneshot_channel() {
+    let mut executor = Executor::new();
+    let (sender, receiver) = oneshot::channel();
+    let waiter = executor.spawn(receiver);
+    executor.run_until_stalled();
+    assert!(!waiter.is_finished());
+    assert_eq!(sender.send("done"), Ok(()));
+    executor.run_until_stalled();
+    assert_eq!(block_on(waiter), Ok(Ok("done")));
+
+    let (sender, receiver) = oneshot::channel::<u32>();
+    drop(sender);
+    assert_eq!(block_on(receiver), Err(oneshot::RecvError));
+
+    let (sender, receiver) = oneshot::channel();
+    drop(receiver);
+    assert_eq!(sender.send(1), Err(1));
+}
+
+#[test_case]
+fn broadcast_channel() {
+    let mut executor = Executor::new();
+    let (sender, first) = broadcast::channel(4);
+    let second = sender.subscribe();
+    let mut handles = Vec::new();
+    for mut receiver in [first, second] {
+        handles.push(executor.spawn(async move {
+            let mut sum = 0;
+            while let Ok(value) = receiver.recv().await {
+                sum += value;
+            }
+            sum
+        }));
+    }
+    executor.run_until_stalled();
+    for value in 1..=4 {
+        assert_eq!(sender.send(value), Ok(2));
+        executor.run_until_stalled();
+    }
+    drop(sender);
+    executor.run_until_stalled();
+    for handle in handles {
+        assert_eq!(block_on(handle), Ok(10));
+    }
+
+    // 遅れた受け手は飛ばした数を受け取り、残っている一番古い値から続ける
+    let (sender, mut receiver) = broadcast::channel(2);
+    for value in 0..5 {
+        sender.send(value).unwrap();
+    }
+    assert_eq!(receiver.try_recv(), Err(broadcast::TryRecvError::Lagged(3)));
+    assert_eq!(receiver.try_recv(), Ok(3));
+    assert_eq!(receiver.try_recv(), Ok(4));
+    assert_eq!(receiver.try_recv(), Err(broadcast::TryRecvError::Empty));
+}
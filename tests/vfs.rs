@@ -0,0 +1,190 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use my_os::fs::tmpfs::TmpFs;
+use my_os::fs::{self, path, Fd, FileType, FsError, OpenFlags, SeekFrom};
+use my_os::memory::BootInfoFrameAllocator;
+use my_os::task::simple_executor::block_on;
+use my_os::{allocator, memory};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    fs::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
+
+fn names(path: &str) -> Vec<String> {
+    block_on(fs::readdir(path))
+        .expect("readdir failed")
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+#[test_case]
+fn normalize_paths() {
+    assert_eq!(path::normalize("/a/./b//c/").unwrap(), "/a/b/c");
+    assert_eq!(path::normalize("/a/b/../c").unwrap(), "/a/c");
+    assert_eq!(path::normalize("/../..").unwrap(), "/");
+    assert_eq!(path::normalize("a/b").unwrap(), "/a/b");
+}
+
+#[test_case]
+fn write_read_and_seek() {
+    let file = block_on(fs::open(
+        "/hello.txt",
+        OpenFlags::READ_WRITE | OpenFlags::CREATE,
+    ))
+    .expect("open failed");
+    block_on(file.write_all(b"hello, world")).expect("write failed");
+    assert_eq!(file.seek(SeekFrom::Start(7)), Ok(7));
+    let mut buf = [0; 16];
+    let n = block_on(file.read(&mut buf)).expect("read failed");
+    assert_eq!(&buf[..n], b"world");
+    assert_eq!(file.seek(SeekFrom::End(-5)), Ok(7));
+    assert_eq!(
+        file.seek(SeekFrom::Current(-8)),
+        Err(FsError::InvalidArgument)
+    );
+    fs::close(file.fd()).expect("close failed");
+
+    assert_eq!(
+        block_on(fs::read_file("/hello.txt")).unwrap(),
+        b"hello, world"
+    );
+    let metadata = block_on(fs::stat("/hello.txt")).unwrap();
+    assert_eq!(metadata.file_type, FileType::File);
+    assert_eq!(metadata.size, 12);
+}
+
+#[test_case]
+fn append_and_truncate() {
+    block_on(fs::write_file("/log", b"one\n")).expect("write failed");
+    let file = block_on(fs::open("/log", OpenFlags::WRITE | OpenFlags::APPEND)).unwrap();
+    block_on(file.write_all(b"two\n")).unwrap();
+    fs::close(file.fd()).unwrap();
+    assert_eq!(block_on(fs::read_file("/log")).unwrap(), b"one\ntwo\n");
+
+    let file = block_on(fs::open("/log", OpenFlags::WRITE | OpenFlags::TRUNCATE)).unwrap();
+    fs::close(file.fd()).unwrap();
+    assert_eq!(block_on(fs::stat("/log")).unwrap().size, 0);
+}
+
+#[test_case]
+fn directories() {
+    block_on(fs::create_dir("/dir")).expect("mkdir failed");
+    block_on(fs::create_dir("/dir/sub")).expect("mkdir failed");
+    block_on(fs::write_file("/dir/file", b"x")).expect("write failed");
+    assert_eq!(names("/dir"), ["file", "sub"]);
+    assert_eq!(
+        block_on(fs::create_dir("/dir/sub")),
+        Err(FsError::AlreadyExists)
+    );
+    assert_eq!(block_on(fs::read_file("/dir/sub/../file")).unwrap(), b"x");
+    assert_eq!(
+        block_on(fs::read_file("/dir/file/x")),
+        Err(FsError::NotADirectory)
+    );
+    assert_eq!(block_on(fs::read_file("/dir")), Err(FsError::IsADirectory));
+    assert_eq!(
+        block_on(fs::read_file("/dir/sub")),
+        Err(FsError::IsADirectory)
+    );
+
+    assert_eq!(
+        block_on(fs::remove("/dir")),
+        Err(FsError::DirectoryNotEmpty)
+    );
+    block_on(fs::remove("/dir/file")).expect("rm failed");
+    block_on(fs::remove("/dir/sub")).expect("rm failed");
+    block_on(fs::remove("/dir")).expect("rm failed");
+    assert_eq!(block_on(fs::stat("/dir")), Err(FsError::NotFound));
+}
+
+#[test_case]
+fn mount_and_unmount() {
+    block_on(fs::create_dir("/mnt")).unwrap();
+    block_on(fs::write_file("/mnt/hidden", b"root")).unwrap();
+    block_on(fs::mount("/mnt", Arc::new(TmpFs::new()))).expect("mount failed");
+
+    // マウントしたファイルシステムの中身が見える
+    assert!(names("/mnt").is_empty());
+    block_on(fs::write_file("/mnt/inner", b"mounted")).unwrap();
+    assert_eq!(block_on(fs::read_file("/mnt/inner")).unwrap(), b"mounted");
+    assert_eq!(block_on(fs::remove("/mnt")), Err(FsError::Busy));
+    assert!(fs::mounts()
+        .iter()
+        .any(|(path, name)| path == "/mnt" && name == "tmpfs"));
+
+    block_on(fs::unmount("/mnt")).expect("unmount failed");
+    assert_eq!(names("/mnt"), ["hidden"]);
+}
+
+#[test_case]
+fn file_descriptors() {
+    block_on(fs::write_file("/fd", b"abc")).unwrap();
+    let first = block_on(fs::open("/fd", OpenFlags::READ)).unwrap().fd();
+    let second = block_on(fs::open("/fd", OpenFlags::READ)).unwrap().fd();
+    assert_ne!(first, second);
+
+    let mut buf = [0; 3];
+    assert_eq!(block_on(fs::read(second, &mut buf)), Ok(3));
+    assert_eq!(&buf, b"abc");
+    assert_eq!(block_on(fs::write(second, b"x")), Err(FsError::ReadOnly));
+    assert_eq!(fs::fstat(second).unwrap().size, 3);
+
+    // 閉じた番号は再利用される
+    fs::close(first).unwrap();
+    let reopened = block_on(fs::open("/fd", OpenFlags::READ)).unwrap().fd();
+    assert_eq!(reopened, first);
+
+    fs::close(reopened).unwrap();
+    fs::close(second).unwrap();
+    assert_eq!(fs::close(second), Err(FsError::BadFileDescriptor));
+    assert_eq!(fs::fstat(Fd(1000)), Err(FsError::BadFileDescriptor));
+}
+
+// 大きすぎるファイルはパニックせずにエラーになる
+#[test_case]
+fn tmpfs_size_limit() {
+    block_on(fs::write_file("/big", b"")).unwrap();
+    let inode = block_on(fs::resolve("/big")).unwrap();
+    assert_eq!(
+        block_on(inode.write_at(u64::MAX, b"x")),
+        Err(FsError::InvalidArgument)
+    );
+    assert_eq!(
+        block_on(inode.write_at(1024 * 1024, b"x")),
+        Err(FsError::NoSpace)
+    );
+    assert_eq!(block_on(inode.truncate(u64::MAX)), Err(FsError::NoSpace));
+    assert_eq!(inode.metadata().size, 0);
+
+    block_on(inode.truncate(4096)).expect("truncate failed");
+    assert_eq!(inode.metadata().size, 4096);
+    block_on(fs::remove("/big")).unwrap();
+}
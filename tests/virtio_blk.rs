@@ -0,0 +1,147 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use futures_util::future::join;
+use my_os::block::virtio::{self, SECTOR_SIZE};
+use my_os::block::{self, BlockDevice};
+use my_os::memory::BootInfoFrameAllocator;
+use my_os::task::executor::{tasks, Executor, Priority};
+use my_os::task::simple_executor::block_on;
+use my_os::{allocator, memory, pci};
+use x86_64::VirtAddr;
+
+// build.rsで作成する1MiBのテスト用ディスク
+const TEST_DISK_SECTORS: u64 = 2048;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    pci::register_driver(&virtio::DRIVER);
+    pci::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    my_os::test_panic_handler(info)
+}
+
+fn test_disk() -> Arc<dyn BlockDevice> {
+    block::find("vda").expect("virtio disk not found")
+}
+
+fn pattern_byte(i: usize, seed: u8) -> u8 {
+    (i % 251) as u8 ^ seed
+}
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| pattern_byte(i, seed)).collect()
+}
+
+#[test_case]
+fn capacity() {
+    let disk = test_disk();
+    assert_eq!(disk.block_size(), SECTOR_SIZE);
+    assert_eq!(disk.block_count(), TEST_DISK_SECTORS);
+}
+
+// 1リクエストの上限(64KiB)より大きい読み書き(完了は割り込みで通知される)
+// ヒープが小さいので、書き込んだバッファは読む前に解放する
+#[test_case]
+fn large_write_and_read_back() {
+    let disk = test_disk();
+    let len = SECTOR_SIZE * 136;
+    let data = pattern(len, 0x5a);
+    block_on(disk.write_blocks(10, &data)).expect("write failed");
+    block_on(disk.flush()).expect("flush failed");
+    drop(data);
+
+    let mut buf = vec![0; len];
+    block_on(disk.read_blocks(10, &mut buf)).expect("read failed");
+    assert!(buf
+        .iter()
+        .enumerate()
+        .all(|(i, &byte)| byte == pattern_byte(i, 0x5a)));
+}
+
+// 複数のリクエストを同時にキューに積む
+#[test_case]
+fn concurrent_requests() {
+    let disk = test_disk();
+    let first = pattern(SECTOR_SIZE * 4, 1);
+    let second = pattern(SECTOR_SIZE * 4, 2);
+    let (a, b) = block_on(join(
+        disk.write_blocks(500, &first),
+        disk.write_blocks(600, &second),
+    ));
+    a.expect("first write failed");
+    b.expect("second write failed");
+
+    let mut first_buf = vec![0; first.len()];
+    let mut second_buf = vec![0; second.len()];
+    let (a, b) = block_on(join(
+        disk.read_blocks(500, &mut first_buf),
+        disk.read_blocks(600, &mut second_buf),
+    ));
+    a.expect("first read failed");
+    b.expect("second read failed");
+    assert_eq!(first_buf, first);
+    assert_eq!(second_buf, second);
+}
+
+// 完了の割り込みでタスクが起こされる(executorが割り込みを待つ間、タスクはpollされない)
+#[test_case]
+fn completion_wakes_task() {
+    let disk = test_disk();
+    let data = pattern(SECTOR_SIZE * 8, 3);
+    block_on(disk.write_blocks(700, &data)).expect("write failed");
+
+    let mut executor = Executor::new();
+    let reader = executor.spawn_named("reader", Priority::Interactive, async move {
+        let mut buf = vec![0; SECTOR_SIZE * 8];
+        disk.read_blocks(700, &mut buf).await.map(|()| buf)
+    });
+    // リクエストを積んだところで止まる
+    executor.run_until_stalled();
+    let polls = || {
+        tasks()
+            .into_iter()
+            .find(|info| info.id == reader.id())
+            .expect("task not found")
+            .stats
+            .polls
+    };
+    let polls_before_irq = polls();
+
+    // 割り込みを待ち、起こされたタスクだけを実行する
+    for _ in 0..100 {
+        if reader.is_finished() {
+            break;
+        }
+        x86_64::instructions::hlt();
+        executor.run_until_stalled();
+    }
+    assert!(reader.is_finished(), "read did not complete");
+    // 割り込みが来るまでpollし続けていたら回数が増え続ける
+    assert!(polls() <= polls_before_irq + 2);
+    let buf = block_on(reader).expect("task failed").expect("read failed");
+    assert_eq!(buf, data);
+}